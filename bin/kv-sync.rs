@@ -1,8 +1,15 @@
 #![cfg(not(target_arch = "wasm32"))]
 
-use clap::{Clap, ValueHint};
-use kv_assets::{sync_assets, SyncConfig};
-use std::path::PathBuf;
+use clap::{ArgMatches, Clap, FromArgMatches, IntoApp, ValueHint};
+use kv_assets::{
+    adopt_index, apply_plan, builtin_template_vars, deploy_history, deployed_index, embed_assets,
+    import_s3, load_header_rules, load_headers_file, load_profile, load_redirects, login,
+    migrate_index, namespace_stats, plan_sync, retry_queued, sync_assets, CachePolicy, IndexDiff,
+    Profile, S3Credentials, S3Source, SyncConfig, SyncPlan, SyncReport, INDEX_FORMAT_VERSION,
+    PROFILE_ENV_VAR,
+};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 
 #[derive(Clap, Debug)]
 struct Opt {
@@ -25,12 +32,191 @@ struct Opt {
     /// Remove obsolete/unreferenced KV assets in the namespace. Use this flag only after successful publish
     #[clap(long)]
     prune: bool,
+
+    /// Don't prune keys uploaded less than this many seconds ago (e.g., 600),
+    /// which may belong to a concurrent deploy
+    #[clap(long)]
+    prune_grace: Option<u64>,
+
+    /// Expire uploaded assets from KV after this many seconds (minimum 60).
+    /// All assets are re-uploaded on each sync to refresh their expiration
+    #[clap(long)]
+    ttl: Option<u64>,
+
+    /// Embed the contents of files up to this many bytes (e.g., 2048) in the asset index,
+    /// so they are served without reading KV
+    #[clap(long)]
+    inline: Option<u64>,
+
+    /// Sync to a local directory instead of Workers KV, for offline development
+    /// with `LocalKV`
+    #[clap(long, parse(from_os_str), value_hint = ValueHint::DirPath)]
+    local: Option<PathBuf>,
+
+    /// Json file with response headers to store in the index for assets matching
+    /// glob patterns, e.g. [{"pattern": "downloads/**", "headers": {"Content-Disposition": "attachment"}}]
+    #[clap(long, parse(from_os_str), value_hint = ValueHint::FilePath)]
+    headers: Option<PathBuf>,
+
+    /// Cache-Control for assets matching a glob pattern, as PATTERN=VALUE (may be repeated;
+    /// the last matching rule wins), e.g. '*.css=max-age=31536000, immutable'
+    #[clap(long = "cache-control", number_of_values = 1)]
+    cache_rules: Vec<String>,
+
+    /// Headers that the worker adds to responses for matching paths, from a Netlify-style
+    /// _headers file, or the [[headers]] tables of a .toml file. Stored in the index
+    #[clap(long, parse(from_os_str), value_hint = ValueHint::FilePath)]
+    headers_file: Option<PathBuf>,
+
+    /// Redirect rules to embed in the index, from a Netlify-style _redirects file,
+    /// or the [[redirects]] tables of a .toml file
+    #[clap(long, parse(from_os_str), value_hint = ValueHint::FilePath)]
+    redirects: Option<PathBuf>,
+
+    /// Also upload the asset index to this KV key, for workers that load it at runtime
+    /// with KVAssets::load_from_kv
+    #[clap(long)]
+    publish_index: Option<String>,
+
+    /// Profile in ~/.config/kv-assets/credentials with the account, API token, and namespace
+    /// to use. Defaults to KV_ASSETS_PROFILE, if set
+    #[clap(long)]
+    profile: Option<String>,
+
+    /// Also sync to the namespace of this profile (may be repeated). The index is published
+    /// (--publish-index) only if uploads to every namespace succeed
+    #[clap(long = "replica", number_of_values = 1)]
+    replicas: Vec<String>,
+
+    /// Replace {{NAME}} in assets matching this glob pattern (e.g., "*.html") with the
+    /// value of variable NAME (may be repeated). DEPLOY_TIME and GIT_SHA are defined
+    #[clap(long = "template", number_of_values = 1)]
+    templates: Vec<String>,
+
+    /// Template variable, as NAME=VALUE (may be repeated), e.g. VERSION=1.2.0
+    #[clap(long = "var", number_of_values = 1)]
+    vars: Vec<String>,
+
+    /// After a successful sync, store the index and a deploy record in the namespace,
+    /// for 'kv-sync history'
+    #[clap(long)]
+    history: bool,
+
+    /// Also upload brotli (.br) and gzip (.gz) variants of compressible files, served
+    /// to clients that accept them
+    #[clap(long)]
+    precompress: bool,
+
+    /// Path prefix (e.g., private/) of assets that the worker only serves with a signed URL
+    /// (may be repeated)
+    #[clap(long = "protect", number_of_values = 1)]
+    protected: Vec<String>,
+
+    /// Language tag (e.g., en) of files with language suffixes, like index.en.html, served
+    /// at the path without the suffix by Accept-Language (may be repeated; the first is
+    /// the default)
+    #[clap(long = "language", number_of_values = 1)]
+    languages: Vec<String>,
+
+    /// Asset served as the body of error responses with a status, as STATUS=PATH
+    /// (may be repeated), e.g. 404=404.html
+    #[clap(long = "error-document", number_of_values = 1)]
+    error_documents: Vec<String>,
+
+    /// File where uploads and deletes that fail after retries are saved, for 'kv-sync retry'
+    #[clap(long, parse(from_os_str), value_hint = ValueHint::FilePath, default_value = ".kv-sync-queue.json")]
+    queue: PathBuf,
+
+    /// Without a subcommand, kv-sync syncs assets
+    #[clap(subcommand)]
+    cmd: Option<Command>,
+}
+
+#[derive(Clap, Debug)]
+enum Command {
+    /// Record the changes a sync would make (uploads, deletes, and index changes)
+    /// in a plan file, without making them
+    Plan {
+        /// Path of plan file to write
+        #[clap(long, parse(from_os_str), value_hint = ValueHint::FilePath)]
+        out: PathBuf,
+    },
+    /// Apply a plan created by 'kv-sync plan'. Fails if asset files changed since the plan
+    Apply {
+        /// Path of plan file
+        #[clap(parse(from_os_str), value_hint = ValueHint::FilePath)]
+        plan: PathBuf,
+    },
+    /// Generate the asset index from the keys already in the site's KV namespace
+    /// (e.g., uploaded by 'wrangler publish'), without uploading. If the namespace has
+    /// several versions of a file, the version matching the assets dir is used
+    Adopt,
+    /// Import assets from an S3-compatible bucket and sync them. Credentials, if needed,
+    /// are read from AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY
+    ImportS3 {
+        /// Bucket name
+        #[clap(long)]
+        bucket: String,
+        /// Import objects with keys starting with this prefix (removed from asset paths)
+        #[clap(long, default_value = "")]
+        prefix: String,
+        /// Bucket region
+        #[clap(long, default_value = "us-east-1")]
+        region: String,
+        /// Service url, for S3-compatible services other than AWS
+        #[clap(long)]
+        endpoint: Option<String>,
+        /// Download objects into this directory and keep it. Defaults to a temporary
+        /// directory that is removed after the sync
+        #[clap(long, parse(from_os_str), value_hint = ValueHint::DirPath)]
+        staging: Option<PathBuf>,
+    },
+    /// Rewrite an index file in the current format, without regenerating it from the asset files
+    MigrateIndex {
+        /// Index file to read, in any format written by kv-sync
+        #[clap(long, parse(from_os_str), value_hint = ValueHint::FilePath)]
+        from: PathBuf,
+        /// Index file to write. May be the same as --from
+        #[clap(long, parse(from_os_str), value_hint = ValueHint::FilePath)]
+        to: PathBuf,
+    },
+    /// Write an index with the contents of every asset embedded, for a worker that serves
+    /// from memory with KVAssets::embedded, without KV. Nothing is uploaded
+    Embed,
+    /// Print the effective configuration, and where each value came from
+    /// (flag, default, environment, wrangler.toml, or wrangler's config file)
+    Config,
+    /// Retry the uploads and deletes that failed in earlier syncs, saved in the queue file
+    Retry,
+    /// List the deploys recorded by syncs with --history, most recent first
+    History {
+        /// Compare the index of deploy N (from the list, 1 is the latest) with the latest
+        #[clap(long)]
+        show: Option<usize>,
+    },
+    /// Count the keys in the namespace, and the bytes stored for the assets in the index
+    Stats,
+    /// Log in to Cloudflare in the browser, and save the API token for later commands
+    /// (the same as 'wrangler login')
+    Login,
+    /// Save an API token in the OS credential store, for later commands. The token is
+    /// read from stdin. CF_API_TOKEN, if set, still takes precedence
+    #[cfg(feature = "keyring")]
+    SaveToken,
+    /// Remove the API token from the OS credential store
+    #[cfg(feature = "keyring")]
+    DeleteToken,
 }
 
 fn main() {
-    let opt = Opt::parse();
+    let matches = Opt::into_app().get_matches();
+    let opt = Opt::from_arg_matches(&matches);
+    if let Some(Command::Config) = &opt.cmd {
+        print_config(&opt, &matches);
+        return;
+    }
     if let Err(e) = run(opt) {
-        eprintln!("Error: {}", e.to_string());
+        eprintln!("Error: {}", e);
         std::process::exit(2);
     }
 }
@@ -39,31 +225,578 @@ fn run(opt: Opt) -> Result<(), kv_assets::Error> {
     if let Some(asset_file) = opt.dump {
         return dump(&asset_file);
     }
-    let args = SyncConfig {
+    if let Some(Command::Login) = &opt.cmd {
+        return login();
+    }
+    #[cfg(feature = "keyring")]
+    match &opt.cmd {
+        Some(Command::SaveToken) => return save_token_from_stdin(),
+        Some(Command::DeleteToken) => {
+            if kv_assets::delete_token()? {
+                println!("Removed the API token from the credential store");
+            } else {
+                println!("No API token in the credential store");
+            }
+            return Ok(());
+        }
+        _ => {}
+    }
+    if let Some(Command::ImportS3 { .. }) = &opt.cmd {
+        return run_import_s3(opt);
+    }
+    if let Some(Command::MigrateIndex { from, to }) = &opt.cmd {
+        let version = migrate_index(from, to)?;
+        println!(
+            "Migrated {} from format {} to {}",
+            from.display(),
+            version,
+            INDEX_FORMAT_VERSION
+        );
+        return Ok(());
+    }
+    let args = sync_config(&opt, &opt.assets)?;
+    if let Some(Command::Embed) = &opt.cmd {
+        let count = embed_assets(args)?;
+        println!("Embedded {} assets in {}", count, opt.output.display());
+        return Ok(());
+    }
+    if let Some(Command::History { show }) = &opt.cmd {
+        return history(args, *show);
+    }
+    if let Some(Command::Stats) = &opt.cmd {
+        let stats = namespace_stats(args)?;
+        println!("Keys: {} ({} expiring)", stats.keys, stats.expiring);
+        println!(
+            "Bytes: {} (for {} keys in the index)",
+            stats.bytes, stats.sized
+        );
+        return Ok(());
+    }
+    if let Some(Command::Retry) = &opt.cmd {
+        let report = retry_queued(args)?;
+        println!(
+            "Retried {} uploads and {} deletes",
+            report.uploaded + report.upload_failures.len(),
+            report.deleted + report.delete_failures.len()
+        );
+        return check_report(&report);
+    }
+    if let Err(errors) = args.validate() {
+        for e in errors.iter() {
+            eprintln!("Error: {}", e);
+        }
+        return Err(kv_assets::Error::Message(format!(
+            "{} configuration problem(s)",
+            errors.len()
+        )));
+    }
+    let report = match &opt.cmd {
+        None => sync_assets(args)?,
+        Some(Command::Plan { out }) => {
+            let plan = plan_sync(args)?;
+            plan.save(out)?;
+            print_plan(&plan);
+            println!("Plan saved to {}", out.display());
+            return Ok(());
+        }
+        Some(Command::Apply { plan }) => apply_plan(args, &SyncPlan::load(plan)?)?,
+        Some(Command::Adopt) => {
+            let report = adopt_index(args)?;
+            for key in report.unrecognized.iter() {
+                eprintln!("Skipped unrecognized key: {}", key);
+            }
+            for path in report.ambiguous.iter() {
+                eprintln!(
+                    "Skipped {}: several versions in KV, and none match the assets dir",
+                    path
+                );
+            }
+            println!("Adopted {} assets", report.adopted);
+            return Ok(());
+        }
+        Some(Command::ImportS3 { .. })
+        | Some(Command::MigrateIndex { .. })
+        | Some(Command::Config)
+        | Some(Command::Embed)
+        | Some(Command::Retry)
+        | Some(Command::History { .. })
+        | Some(Command::Stats)
+        | Some(Command::Login) => unreachable!(),
+        #[cfg(feature = "keyring")]
+        Some(Command::SaveToken) | Some(Command::DeleteToken) => unreachable!(),
+    };
+    check_report(&report)
+}
+
+#[cfg(feature = "keyring")]
+fn save_token_from_stdin() -> Result<(), kv_assets::Error> {
+    eprintln!("API token:");
+    let mut token = String::new();
+    std::io::stdin()
+        .read_line(&mut token)
+        .map_err(|e| kv_assets::Error::IO(format!("reading token: {}", e)))?;
+    let token = token.trim();
+    if token.is_empty() {
+        return Err(kv_assets::Error::Message("no token entered".to_string()));
+    }
+    kv_assets::save_token(token)?;
+    println!("Saved the API token in the credential store");
+    Ok(())
+}
+
+fn sync_config<'a>(opt: &'a Opt, asset_dir: &'a Path) -> Result<SyncConfig<'a>, kv_assets::Error> {
+    Ok(SyncConfig {
         output_path: &opt.output,
         wrangler_path: &opt.wrangler,
-        asset_dir: &opt.assets,
+        asset_dir,
         prune: opt.prune,
+        prune_grace: opt.prune_grace,
+        expiration_ttl: opt.ttl,
+        inline_max_size: opt.inline,
+        local_dir: opt.local.as_deref(),
+        retry_queue: Some(&opt.queue),
+        publish_index: opt.publish_index.as_deref(),
+        profile: opt.profile.as_deref(),
+        replicas: opt.replicas.iter().map(|r| r.as_str()).collect(),
+        header_rules: match &opt.headers {
+            Some(path) => load_header_rules(path)?,
+            None => Vec::new(),
+        },
+        cache_policy: cache_policy(opt)?,
+        response_header_rules: match &opt.headers_file {
+            Some(path) => load_headers_file(path)?,
+            None => Vec::new(),
+        },
+        redirects: match &opt.redirects {
+            Some(path) => load_redirects(path)?,
+            None => Vec::new(),
+        },
+        templates: opt.templates.clone(),
+        template_vars: template_vars(opt)?,
+        record_history: opt.history,
+        precompress: opt.precompress,
+        protected: opt.protected.clone(),
+        languages: opt.languages.clone(),
+        error_documents: error_documents(opt)?,
         ..Default::default()
+    })
+}
+
+/// Returns the error documents from --error-document
+fn error_documents(opt: &Opt) -> Result<Vec<(u16, String)>, kv_assets::Error> {
+    opt.error_documents
+        .iter()
+        .map(|doc| {
+            let invalid = || {
+                kv_assets::Error::Message(format!("--error-document {} must be STATUS=PATH", doc))
+            };
+            let (status, path) = doc.split_once('=').ok_or_else(invalid)?;
+            Ok((status.parse().map_err(|_| invalid())?, path.to_string()))
+        })
+        .collect()
+}
+
+/// Returns the builtin template variables, overridden by --var
+fn template_vars(opt: &Opt) -> Result<BTreeMap<String, String>, kv_assets::Error> {
+    if opt.templates.is_empty() {
+        return Ok(BTreeMap::new());
+    }
+    let mut vars = builtin_template_vars();
+    for var in opt.vars.iter() {
+        let (name, value) = var.split_once('=').ok_or_else(|| {
+            kv_assets::Error::Message(format!("--var {} must be NAME=VALUE", var))
+        })?;
+        vars.insert(name.to_string(), value.to_string());
+    }
+    Ok(vars)
+}
+
+/// Returns the cache policy from --cache-control
+fn cache_policy(opt: &Opt) -> Result<CachePolicy, kv_assets::Error> {
+    let mut policy = CachePolicy::new();
+    for rule in opt.cache_rules.iter() {
+        let (pattern, value) = rule.split_once('=').ok_or_else(|| {
+            kv_assets::Error::Message(format!("--cache-control {} must be PATTERN=VALUE", rule))
+        })?;
+        policy = policy.with_rule(pattern, value);
+    }
+    Ok(policy)
+}
+
+fn run_import_s3(mut opt: Opt) -> Result<(), kv_assets::Error> {
+    let (source, staging) = match opt.cmd.take() {
+        Some(Command::ImportS3 {
+            bucket,
+            prefix,
+            region,
+            endpoint,
+            staging,
+        }) => (
+            S3Source {
+                bucket,
+                prefix,
+                region,
+                endpoint,
+                credentials: S3Credentials::from_env(),
+            },
+            staging,
+        ),
+        _ => unreachable!(),
+    };
+    let is_temp = staging.is_none();
+    let dir = staging
+        .unwrap_or_else(|| std::env::temp_dir().join(format!("kv-sync-s3-{}", std::process::id())));
+    let result = import_s3(&source, &dir).and_then(|count| {
+        println!("Downloaded {} files from s3://{}", count, source.bucket);
+        sync_assets(sync_config(&opt, &dir)?)
+    });
+    if is_temp {
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+    check_report(&result?)
+}
+
+/// Prints the effective configuration, and where each value came from
+fn print_config(opt: &Opt, matches: &ArgMatches) {
+    use wrangler::settings::{get_global_config_path, global_user::GlobalUser, toml::Manifest};
+
+    let row = |name: &str, value: &dyn std::fmt::Display, source: &str| {
+        println!("{:14} {:44} ({})", name, value.to_string(), source)
+    };
+    // a flag's row is named with its clap id, so the name can't differ from the id
+    // its source is looked up with
+    let arg_row =
+        |id: &str, value: &dyn std::fmt::Display| row(id, value, flag_source(matches, id));
+    let or_none = |value: Option<String>| value.unwrap_or_else(|| "none".to_string());
+
+    arg_row("wrangler", &opt.wrangler.display());
+    arg_row("assets", &opt.assets.display());
+    arg_row("output", &opt.output.display());
+    arg_row("prune", &opt.prune);
+    arg_row(
+        "prune-grace",
+        &or_none(opt.prune_grace.map(|t| format!("{}s", t))),
+    );
+    arg_row("ttl", &or_none(opt.ttl.map(|t| format!("{}s", t))));
+    arg_row(
+        "inline",
+        &or_none(opt.inline.map(|n| format!("up to {} bytes", n))),
+    );
+    let headers = opt
+        .headers
+        .as_ref()
+        .map(|path| match load_header_rules(path) {
+            Ok(rules) => format!("{} ({} rules)", path.display(), rules.len()),
+            Err(e) => format!("{} (error: {})", path.display(), e),
+        });
+    arg_row("headers", &or_none(headers));
+    for rule in opt.cache_rules.iter() {
+        row("cache-control", rule, "flag");
+    }
+    let headers_file = opt
+        .headers_file
+        .as_ref()
+        .map(|path| match load_headers_file(path) {
+            Ok(rules) => format!("{} ({} rules)", path.display(), rules.len()),
+            Err(e) => format!("{} (error: {})", path.display(), e),
+        });
+    arg_row("headers-file", &or_none(headers_file));
+    let redirects = opt
+        .redirects
+        .as_ref()
+        .map(|path| match load_redirects(path) {
+            Ok(rules) => format!("{} ({} rules)", path.display(), rules.len()),
+            Err(e) => format!("{} (error: {})", path.display(), e),
+        });
+    arg_row("redirects", &or_none(redirects));
+    arg_row("precompress", &opt.precompress);
+    for prefix in opt.protected.iter() {
+        row("protect", prefix, "flag");
+    }
+    if !opt.languages.is_empty() {
+        row("languages", &opt.languages.join(", "), "flag");
+    }
+    for doc in opt.error_documents.iter() {
+        row("error-document", doc, "flag");
+    }
+    arg_row("publish-index", &or_none(opt.publish_index.clone()));
+    arg_row("queue", &opt.queue.display());
+    if !opt.replicas.is_empty() {
+        row("replicas", &opt.replicas.join(", "), "flag");
+    }
+    if !opt.templates.is_empty() {
+        row("templates", &opt.templates.join(", "), "flag");
+        match template_vars(opt) {
+            Ok(vars) => {
+                for (name, value) in vars.iter() {
+                    let source = if opt
+                        .vars
+                        .iter()
+                        .any(|v| v.starts_with(&format!("{}=", name)))
+                    {
+                        "flag"
+                    } else {
+                        "builtin"
+                    };
+                    row(&format!("  {}", name), value, source);
+                }
+            }
+            Err(e) => row("vars", &format!("error: {}", e), "flag"),
+        }
+    }
+    let (profile_name, profile_source) = match &opt.profile {
+        Some(name) => (Some(name.clone()), "flag".to_string()),
+        None => (
+            std::env::var(PROFILE_ENV_VAR)
+                .ok()
+                .filter(|n| !n.is_empty()),
+            format!("env {}", PROFILE_ENV_VAR),
+        ),
     };
-    sync_assets(args)?;
+    let profile = match &profile_name {
+        Some(name) => match load_profile(name) {
+            Ok(profile) => {
+                row("profile", name, &profile_source);
+                profile
+            }
+            Err(e) => {
+                row("profile", &format!("error: {}", e), &profile_source);
+                Profile::default()
+            }
+        },
+        None => {
+            row("profile", &"none", "default");
+            Profile::default()
+        }
+    };
+    let from_profile = format!("profile {}", profile_name.unwrap_or_default());
+
+    if let Some(dir) = &opt.local {
+        row(
+            "destination",
+            &format!("local kv {}", dir.display()),
+            "flag",
+        );
+        return;
+    }
+    match Manifest::new(&opt.wrangler).and_then(|m| m.get_target(None, false)) {
+        Ok(target) => {
+            row("worker", &target.name, "wrangler.toml");
+            match &profile.account_id {
+                Some(account_id) => row("account_id", account_id, &from_profile),
+                None => {
+                    let account_source = if std::env::var("CF_ACCOUNT_ID").is_ok() {
+                        "env CF_ACCOUNT_ID"
+                    } else {
+                        "wrangler.toml"
+                    };
+                    row("account_id", &target.account_id, account_source);
+                }
+            }
+            let bucket = target.site.as_ref().map(|s| s.bucket.display().to_string());
+            row("site bucket", &or_none(bucket), "wrangler.toml");
+            match &profile.namespace_id {
+                Some(namespace_id) => row("namespace", namespace_id, &from_profile),
+                // the namespace is looked up (or created) by title at sync time
+                None => row(
+                    "namespace",
+                    &format!("__{}-workers_sites_assets", target.name),
+                    "wrangler.toml name",
+                ),
+            }
+        }
+        Err(e) => row("wrangler.toml", &format!("error: {}", e), "wrangler.toml"),
+    }
+    if profile.api_token.is_some() {
+        return row("auth", &"api token", &from_profile);
+    }
+    let auth_from_env = ["CF_API_TOKEN", "CF_API_KEY", "CF_EMAIL"]
+        .iter()
+        .any(|var| std::env::var(var).is_ok());
+    #[cfg(feature = "keyring")]
+    if !auth_from_env {
+        match kv_assets::load_token() {
+            Ok(Some(_)) => return row("auth", &"api token", "credential store"),
+            Ok(None) => {}
+            Err(e) => row("auth", &format!("error: {}", e), "credential store"),
+        }
+    }
+    let auth_source = if auth_from_env {
+        "env".to_string()
+    } else {
+        get_global_config_path()
+            .map(|path| path.display().to_string())
+            .unwrap_or_else(|_| "wrangler config".to_string())
+    };
+    match GlobalUser::new() {
+        Ok(GlobalUser::TokenAuth { .. }) => row("auth", &"api token", &auth_source),
+        Ok(GlobalUser::GlobalKeyAuth { email, .. }) => row(
+            "auth",
+            &format!("global api key for {}", email),
+            &auth_source,
+        ),
+        Err(_) => row(
+            "auth",
+            &"none (run 'wrangler login' or 'wrangler config')",
+            &auth_source,
+        ),
+    }
+}
+
+/// Lists recorded deploys, or compares one with the latest
+fn history(args: SyncConfig, show: Option<usize>) -> Result<(), kv_assets::Error> {
+    let records = deploy_history(args.clone())?;
+    let n = match show {
+        Some(n) => n,
+        None => {
+            if records.is_empty() {
+                println!("No deploys recorded. Sync with --history to record them");
+            }
+            for (n, record) in records.iter().enumerate() {
+                println!(
+                    "{:3}  {}  index {}  {} assets, {} uploaded, {} deleted  by {}",
+                    n + 1,
+                    chrono::DateTime::from_timestamp(record.deployed as i64, 0)
+                        .map(|t| t.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+                        .unwrap_or_default(),
+                    &record.index_sha256[..12.min(record.index_sha256.len())],
+                    record.assets,
+                    record.uploaded,
+                    record.deleted,
+                    record.deployer.as_deref().unwrap_or("unknown")
+                );
+            }
+            return Ok(());
+        }
+    };
+    let record = match records.get(n.wrapping_sub(1)) {
+        Some(record) => record,
+        None => {
+            return Err(kv_assets::Error::Message(format!(
+                "no deploy {}: {} deploys recorded",
+                n,
+                records.len()
+            )))
+        }
+    };
+    let old = deployed_index(args.clone(), record)?;
+    let latest = deployed_index(args, &records[0])?;
+    let diff = IndexDiff::between(&old, &latest);
+    println!(
+        "{} added, {} changed, {} removed since deploy {}",
+        diff.added.len(),
+        diff.changed.len(),
+        diff.removed.len(),
+        n
+    );
+    print_diff(&diff);
     Ok(())
 }
 
+fn print_plan(plan: &SyncPlan) {
+    println!(
+        "{} uploads, {} deletes, {} stale keys not pruned",
+        plan.uploads.len(),
+        plan.deletes.len(),
+        plan.deferred_prune
+    );
+    print_diff(&plan.index_diff);
+}
+
+fn print_diff(diff: &IndexDiff) {
+    for (label, paths) in [
+        ("added", &diff.added),
+        ("changed", &diff.changed),
+        ("removed", &diff.removed),
+    ]
+    .iter()
+    {
+        for path in paths.iter() {
+            println!("  {:8} {}", label, path);
+        }
+    }
+}
+
+fn check_report(report: &SyncReport) -> Result<(), kv_assets::Error> {
+    for replica in report.replicas.iter() {
+        println!(
+            "{}: {} uploaded, {} deleted, {} failed",
+            replica.profile,
+            replica.report.uploaded,
+            replica.report.deleted,
+            replica.report.upload_failures.len() + replica.report.delete_failures.len()
+        );
+    }
+    if !report.is_success() {
+        let mut uploads_failed = report.upload_failures.len();
+        let mut deletes_failed = report.delete_failures.len();
+        print_failures(None, report);
+        for replica in report.replicas.iter() {
+            print_failures(Some(&replica.profile), &replica.report);
+            uploads_failed += replica.report.upload_failures.len();
+            deletes_failed += replica.report.delete_failures.len();
+        }
+        return Err(kv_assets::Error::Message(format!(
+            "{} uploads and {} deletes failed",
+            uploads_failed, deletes_failed
+        )));
+    }
+    Ok(())
+}
+
+/// Source of a configuration value set by the flag with the clap id: "flag" if the flag
+/// was given, otherwise "default"
+fn flag_source(matches: &ArgMatches, id: &str) -> &'static str {
+    debug_assert!(
+        Opt::into_app()
+            .get_arguments()
+            .any(|arg| arg.get_name() == id),
+        "no argument {}",
+        id
+    );
+    match matches.occurrences_of(id) > 0 {
+        true => "flag",
+        false => "default",
+    }
+}
+
+fn print_failures(replica: Option<&str>, report: &SyncReport) {
+    let prefix = replica.map(|r| format!("{}: ", r)).unwrap_or_default();
+    for f in report.upload_failures.iter() {
+        eprintln!("{}Upload failed: {}: {}", prefix, f.key, f.error);
+    }
+    for f in report.delete_failures.iter() {
+        eprintln!("{}Delete failed: {}: {}", prefix, f.key, f.error);
+    }
+}
+
 fn dump(path: &std::path::Path) -> Result<(), kv_assets::Error> {
-    use kv_assets::{AssetIndex, Error};
+    use kv_assets::{decode_index, Error};
 
     let blob = std::fs::read(path).map_err(|e| {
         Error::Message(format!(
             "Error reading asset file {} for dump: {}",
             path.display(),
-            e.to_string()
+            e
         ))
     })?;
-    let map: AssetIndex =
-        bincode::deserialize(&blob).map_err(|e| Error::DeserializeAssets(e.to_string()))?;
+    let map = decode_index(&blob)?;
     let json = serde_json::to_string_pretty(&map)
-        .map_err(|e| Error::Message(format!("json serialization error: {}", e.to_string())))?;
+        .map_err(|e| Error::Message(format!("json serialization error: {}", e)))?;
     println!("{}", json);
     Ok(())
 }
+
+#[test]
+fn test_flag_source() {
+    let matches = Opt::into_app().get_matches_from(vec![
+        "kv-sync",
+        "--publish-index",
+        "assets/index",
+        "--headers-file",
+        "_headers",
+    ]);
+    assert_eq!(flag_source(&matches, "publish-index"), "flag");
+    assert_eq!(flag_source(&matches, "headers-file"), "flag");
+    assert_eq!(flag_source(&matches, "prune-grace"), "default");
+}
@@ -0,0 +1,123 @@
+//! Access logging: a callback that receives a record of every asset served by
+//! `get_asset` and `handle_request`, for structured logs or analytics
+//! (see `KVAssets::with_access_logger`).
+
+use crate::{clock, AssetResponse, AssetStore, Error, KVAssets};
+
+/// Record of an asset request, passed to the access logger
+#[derive(Clone, Debug, PartialEq)]
+pub struct AccessRecord {
+    /// Request method: the method passed to `handle_request`, or "GET" for `get_asset`
+    pub method: String,
+    /// Requested path
+    pub path: String,
+    /// Response status. For `get_asset`, 200 if the asset was found, 404 if not,
+    /// and 500 for errors
+    pub status: u16,
+    /// True if the request was answered with an asset or a redirect (status below 400)
+    pub hit: bool,
+    /// Size of the response body
+    pub bytes: u64,
+    /// Time to answer the request, in milliseconds
+    pub latency_ms: f64,
+}
+
+/// Callback that receives a record of every request (see `KVAssets::with_access_logger`)
+#[cfg(not(feature = "thread-safe"))]
+pub type AccessLogger = Box<dyn Fn(AccessRecord)>;
+/// Callback that receives a record of every request (see `KVAssets::with_access_logger`)
+#[cfg(feature = "thread-safe")]
+pub type AccessLogger = Box<dyn Fn(AccessRecord) + Send + Sync>;
+
+impl<'ah, S: AssetStore> KVAssets<'ah, S> {
+    /// Passes a record of the request to the access logger, if one is set
+    pub(crate) fn log_access(
+        &self,
+        method: &str,
+        path: &str,
+        started: f64,
+        status: u16,
+        bytes: usize,
+    ) {
+        if let Some(logger) = &self.access_logger {
+            logger(AccessRecord {
+                method: method.to_string(),
+                path: path.to_string(),
+                status,
+                hit: status < 400,
+                bytes: bytes as u64,
+                latency_ms: clock::now_millis() - started,
+            });
+        }
+    }
+
+    /// Logs the result of `get_asset`
+    pub(crate) fn log_get(
+        &self,
+        path: &str,
+        started: f64,
+        result: &Result<Option<bytes::Bytes>, Error>,
+    ) {
+        let (status, bytes) = match result {
+            Ok(Some(doc)) => (200, doc.len()),
+            Ok(None) => (404, 0),
+            Err(_) => (500, 0),
+        };
+        self.log_access("GET", path, started, status, bytes);
+    }
+
+    /// Logs the result of `handle_request`
+    pub(crate) fn log_response(
+        &self,
+        method: &str,
+        path: &str,
+        started: f64,
+        result: &Result<AssetResponse, Error>,
+    ) {
+        let (status, bytes) = match result {
+            Ok(response) => (response.status, response.body.len()),
+            Err(_) => (500, 0),
+        };
+        self.log_access(method, path, started, status, bytes);
+    }
+}
+
+/// Tests the access logger (does not invoke cloudflare api)
+#[cfg(all(test, not(target_arch = "wasm32")))]
+#[tokio::test]
+async fn test_access_logger() {
+    use crate::{AssetIndex, AssetMetadata};
+    use std::sync::{Arc, Mutex};
+
+    let mut index = AssetIndex::new();
+    index.insert(
+        "a.txt".to_string(),
+        AssetMetadata {
+            path: "a.1.txt".to_string(),
+            size: 5,
+            inline: Some(b"hello".to_vec()),
+            ..Default::default()
+        },
+    );
+    let blob = crate::encode_index(&index).expect("serialize-index");
+    let records = Arc::new(Mutex::new(Vec::new()));
+    let r = records.clone();
+    let kv = KVAssets::init(&blob, "123", "namespace", "token")
+        .with_access_logger(move |record| r.lock().unwrap().push(record));
+
+    kv.get_asset("/a.txt").await.unwrap();
+    kv.handle_request("HEAD", "/missing", &[]).await.unwrap();
+    let records = records.lock().unwrap();
+    let summary: Vec<_> = records
+        .iter()
+        .map(|r| (r.method.as_str(), r.path.as_str(), r.status, r.hit, r.bytes))
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            ("GET", "/a.txt", 200, true, 5),
+            ("HEAD", "/missing", 404, false, 0)
+        ]
+    );
+    assert!(records[0].latency_ms >= 0.0);
+}
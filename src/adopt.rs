@@ -0,0 +1,205 @@
+#![cfg(not(target_arch = "wasm32"))]
+//! Builds an asset index from the keys in an existing Workers Sites namespace,
+//! so a site deployed with wrangler can switch to KVAssets without re-uploading.
+//!
+//! Wrangler keys are the asset path with a content hash inserted before the extension
+//! (`css/site.0123456789.css`). If a namespace holds several versions of a file
+//! (because it was never pruned), the version matching the file in the asset dir is used;
+//! without the file, the path is reported as ambiguous and left out of the index.
+
+use crate::{
+    bulk::BulkClient,
+    chunk::{chunk_key, split_chunk_key},
+    source::read_source,
+    upload::{connect_remote, content_type, mkdir_bin_parent, write_index},
+    AssetIndex, AssetMetadata, Error, LocalKV, SyncConfig, HISTORY_PREFIX,
+};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashSet};
+use wrangler::{
+    settings::toml::Manifest,
+    terminal::message::{Message, StdErr},
+};
+
+/// Result of adopting a namespace
+#[derive(Clone, Debug, Default)]
+pub struct AdoptReport {
+    /// Number of assets in the generated index
+    pub adopted: usize,
+    /// Keys that don't look like wrangler asset keys
+    pub unrecognized: Vec<String>,
+    /// Asset paths with several versions in the namespace, none matching the asset dir
+    pub ambiguous: Vec<String>,
+}
+
+/// Lists the keys in the site's namespace (or `local_dir`) and writes an index for them to
+/// `output_path`. Each value is read to get its size, and inlined if it's no larger than
+/// `inline_max_size`. Modified times are unknown, and recorded as 0.
+/// `asset_dir` (a directory or archive) is optional, and only used to choose between
+/// versions of a file.
+pub fn adopt_index(args: SyncConfig) -> Result<AdoptReport, Error> {
+    if !args.wrangler_path.is_file() {
+        return Err(Error::MissingWranglerFile(
+            args.wrangler_path.to_string_lossy().to_string(),
+        ));
+    }
+    let manifest = Manifest::new(args.wrangler_path)?;
+    let mut target = manifest.get_target(None, args.preview_env)?;
+
+    let source = match args.local_dir {
+        Some(dir) => Source::Local(LocalKV::open(dir)?),
+        None => Source::Remote(Box::new(connect_remote(&args, &mut target)?.0)),
+    };
+    let current: HashSet<String> = if args.asset_dir.exists() {
+        let source = read_source(&target, args.asset_dir, args.templates(), false)?;
+        source.pairs.into_iter().map(|p| p.key).collect()
+    } else {
+        HashSet::new()
+    };
+
+    let mut report = AdoptReport::default();
+    // chunks of large assets are listed under the asset key, with the number of chunks
+    let mut keys: BTreeMap<String, (Option<u64>, u32)> = BTreeMap::new();
+    for (key, expires) in source.list_keys()? {
+        if key.starts_with(HISTORY_PREFIX) {
+            continue;
+        }
+        match split_chunk_key(&key) {
+            Some((asset_key, _)) => {
+                keys.entry(asset_key.to_string()).or_insert((expires, 0)).1 += 1
+            }
+            None => {
+                keys.insert(key, (expires, 0));
+            }
+        }
+    }
+    let mut versions: BTreeMap<String, Vec<(String, Option<u64>, u32)>> = BTreeMap::new();
+    for (key, (expires, chunks)) in keys.into_iter() {
+        match asset_path(&key) {
+            Some(path) => versions
+                .entry(path)
+                .or_default()
+                .push((key, expires, chunks)),
+            None => report.unrecognized.push(key),
+        }
+    }
+
+    StdErr::working(&format!("Reading {} assets", versions.len()));
+    let mut index = AssetIndex::new();
+    for (path, mut keys) in versions.into_iter() {
+        if keys.len() > 1 {
+            keys.retain(|(key, _, _)| current.contains(key));
+            if keys.len() != 1 {
+                report.ambiguous.push(path);
+                continue;
+            }
+        }
+        let (key, expires, chunks) = keys.remove(0);
+        let mut hasher = Sha256::new();
+        let (size, inline) = if chunks > 0 {
+            let mut size = 0;
+            for n in 0..chunks {
+                let value = source.get(&chunk_key(&key, n))?;
+                hasher.update(&value);
+                size += value.len() as u64;
+            }
+            (size, None)
+        } else {
+            let value = source.get(&key)?;
+            hasher.update(&value);
+            let size = value.len() as u64;
+            match args.inline_max_size {
+                Some(max) if size <= max => (size, Some(value)),
+                _ => (size, None),
+            }
+        };
+        index.insert(
+            path.clone(),
+            AssetMetadata {
+                content_type: content_type(&path),
+                path: key,
+                size,
+                expires,
+                inline,
+                chunks,
+                sha256: Some(hasher.finalize().into()),
+                ..Default::default()
+            },
+        );
+    }
+    report.adopted = index.len();
+
+    mkdir_bin_parent(args.output_path)?;
+    write_index(&args, index)?;
+    Ok(report)
+}
+
+/// Namespace to adopt
+enum Source {
+    Remote(Box<BulkClient>),
+    Local(LocalKV),
+}
+
+impl Source {
+    fn list_keys(&self) -> Result<Vec<(String, Option<u64>)>, Error> {
+        match self {
+            Source::Remote(client) => Ok(client
+                .list_keys()?
+                .into_iter()
+                .map(|k| (k.name, k.expiration))
+                .collect()),
+            Source::Local(local) => Ok(local.keys()?.into_iter().map(|k| (k, None)).collect()),
+        }
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>, Error> {
+        match self {
+            Source::Remote(client) => client.get_value(key),
+            Source::Local(local) => Ok(local.get(key)?.to_vec()),
+        }
+    }
+}
+
+/// Returns the asset path for a wrangler key, by removing the hash,
+/// or None if the key doesn't contain a hash
+fn asset_path(key: &str) -> Option<String> {
+    let (dir, name) = match key.rfind('/') {
+        Some(pos) => key.split_at(pos + 1),
+        None => ("", key),
+    };
+    // wrangler's hash is the first 10 hex digits of the digest
+    let is_hash = |s: &str| s.len() == 10 && s.bytes().all(|b| b.is_ascii_hexdigit());
+    let mut parts: Vec<&str> = name.split('.').collect();
+    match parts.len() {
+        // name.hash
+        2 if is_hash(parts[1]) => {
+            parts.pop();
+        }
+        // name.hash.ext
+        n if n >= 3 && !parts[0].is_empty() && is_hash(parts[n - 2]) => {
+            parts.remove(n - 2);
+        }
+        _ => return None,
+    }
+    Some(format!("{}{}", dir, parts.join(".")))
+}
+
+#[test]
+fn test_asset_path() {
+    assert_eq!(
+        asset_path("css/site.f25c32ee58.css").as_deref(),
+        Some("css/site.css")
+    );
+    assert_eq!(
+        asset_path("js/app.min.0123456789.js").as_deref(),
+        Some("js/app.min.js")
+    );
+    assert_eq!(asset_path("LICENSE.abcdef0123").as_deref(), Some("LICENSE"));
+    assert_eq!(asset_path("index.html"), None);
+    assert_eq!(asset_path("LICENSE.abc"), None);
+    assert_eq!(asset_path("a/.htaccess"), None);
+    // version numbers and dates aren't hashes
+    assert_eq!(asset_path("data.2024.json"), None);
+    assert_eq!(asset_path("v1.2.3.js"), None);
+    assert_eq!(asset_path("lib.abc123.js"), None);
+}
@@ -0,0 +1,101 @@
+//! Errors reported by the Cloudflare API in its response envelope,
+//! `{"success": false, "errors": [{"code": 10000, "message": "..."}], ...}`.
+
+use crate::Error;
+use serde::Deserialize;
+use std::fmt;
+
+/// API code for an authentication error
+const AUTH_ERROR_CODE: u32 = 10000;
+/// API code for a namespace that doesn't exist
+const NAMESPACE_NOT_FOUND_CODE: u32 = 10013;
+
+/// Error returned by the Cloudflare API (see `Error::Api`)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CloudflareApiError {
+    /// Http status of the response
+    pub status: u16,
+    /// Errors in the response
+    pub errors: Vec<ApiErrorDetail>,
+}
+
+/// One error in an API response
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct ApiErrorDetail {
+    /// Cloudflare error code, e.g. 10000 for an authentication error
+    #[serde(default)]
+    pub code: u32,
+    /// Description of the error
+    #[serde(default)]
+    pub message: String,
+}
+
+impl CloudflareApiError {
+    /// Whether the response has an error with the code
+    pub fn has_code(&self, code: u32) -> bool {
+        self.errors.iter().any(|e| e.code == code)
+    }
+
+    /// Whether the credentials were missing, invalid, or lack permission for the namespace
+    pub fn is_auth_error(&self) -> bool {
+        self.status == 401 || self.status == 403 || self.has_code(AUTH_ERROR_CODE)
+    }
+
+    /// Whether the account has no namespace with the id
+    pub fn is_namespace_not_found(&self) -> bool {
+        self.has_code(NAMESPACE_NOT_FOUND_CODE)
+    }
+
+    /// Whether the request was larger than the API allows
+    pub fn is_payload_too_large(&self) -> bool {
+        self.status == 413
+    }
+}
+
+impl fmt::Display for CloudflareApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "status {}", self.status)?;
+        for e in self.errors.iter() {
+            write!(f, "; {} {}", e.code, e.message)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+struct Envelope {
+    #[serde(default)]
+    errors: Vec<ApiErrorDetail>,
+}
+
+/// Error for a response with the status and body: `Error::Api` if the body is an API
+/// envelope with errors, otherwise `Error::KVHttpStatus` with the body
+pub(crate) fn api_error(status: u16, body: &[u8]) -> Error {
+    match serde_json::from_slice::<Envelope>(body) {
+        Ok(envelope) if !envelope.errors.is_empty() => Error::Api(CloudflareApiError {
+            status,
+            errors: envelope.errors,
+        }),
+        _ => Error::KVHttpStatus(status, String::from_utf8_lossy(body).to_string()),
+    }
+}
+
+#[test]
+fn test_api_error() {
+    let body = br#"{"success":false,"errors":[{"code":10000,"message":"Authentication error"}],
+        "messages":[],"result":null}"#;
+    let e = match api_error(403, body) {
+        Error::Api(e) => e,
+        e => panic!("unexpected {:?}", e),
+    };
+    assert!(e.is_auth_error());
+    assert!(!e.is_namespace_not_found());
+    assert_eq!(e.to_string(), "status 403; 10000 Authentication error");
+
+    let body = br#"{"success":false,"errors":[{"code":10013,"message":"namespace not found"}]}"#;
+    assert!(matches!(api_error(404, body), Error::Api(e) if e.is_namespace_not_found()));
+    assert!(matches!(
+        api_error(413, b"<html>too large</html>"),
+        Error::KVHttpStatus(413, body) if body.contains("too large")
+    ));
+}
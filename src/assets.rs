@@ -1,15 +1,46 @@
-use crate::Error;
+use crate::{
+    access::AccessLogger,
+    cache::{AssetCache, AssetCacheConfig},
+    cell::{Lock, MaybeSendSync, Once, Shared},
+    chunk_key, clock,
+    coalesce::InFlight,
+    decode_index,
+    encoding::{choose_variant, EncodedAsset, EncodedVariant},
+    glob::glob_match,
+    init_kv,
+    lookup::{LookupOptions, TrailingSlash},
+    policy::HEADERS_PATH,
+    redirect::{RedirectRule, REDIRECTS_PATH},
+    timing::{ServerTiming, Timer},
+    AccessRecord, AssetKey, AssetStore, ByteStream, CachePolicy, CorsPolicy, Error, HeaderPolicy,
+    HeaderRule, ToAssetKey, CHUNK_SIZE, KV,
+};
+use futures_util::{
+    future,
+    stream::{self, StreamExt},
+};
 use serde::{Deserialize, Serialize};
-use std::cell::RefCell;
-
-const CLOUDFLARE_KV_ENDPOINT: &str = "https://api.cloudflare.com/client/v4";
+use std::{
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    rc::Rc,
+};
 
 /// Hashmap of asset paths to metadata
 /// Path strings have leading / removed
 pub type AssetIndex = std::collections::HashMap<String, AssetMetadata>;
 
 /// Asset metadata
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Ord, PartialOrd)]
+///
+/// The index is bincode, which isn't self-describing, so the fields of this struct are
+/// the index format (see `encode_index`). It's append-only: new fields go at the end,
+/// and a field's zero encoding must equal its default, since entries written before
+/// the field existed are padded with zero bytes. Nested structs inside `Vec`s
+/// (`EncodedVariant`, `RedirectRule`, `HeaderRule`) can never gain fields.
+/// The struct-level `serde(default)` only affects sync plans, which are JSON.
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq, Eq, Ord, PartialOrd)]
+#[serde(default)]
 pub struct AssetMetadata {
     /// Path to file within the namespace
     pub path: String,
@@ -17,34 +48,164 @@ pub struct AssetMetadata {
     pub modified: u64,
     /// Size of file
     pub size: u64,
+    /// If the asset was uploaded with a TTL, the time it expires from KV,
+    /// in UTC seconds since EPOCH
+    pub expires: Option<u64>,
+    /// Contents of small assets, embedded in the index at sync time,
+    /// so they can be served without reading KV
+    pub inline: Option<Vec<u8>>,
+    /// Response headers for the asset, set at sync time (see `SyncConfig::header_rules`),
+    /// and added to responses by `handle_request`
+    pub headers: Vec<(String, String)>,
+    /// Number of chunks the asset is split into, for assets larger than the KV value limit,
+    /// or 0 if it's stored under `path` (see `chunk_key`)
+    pub chunks: u32,
+    /// SHA-256 digest of the asset contents, recorded at sync time
+    /// (see `KVAssets::with_integrity_check`)
+    pub sha256: Option<[u8; 32]>,
+    /// Media type for the `Content-Type` header, from the file extension,
+    /// or None if the extension isn't recognized
+    pub content_type: Option<String>,
+    /// Precompressed variants of the asset in KV (see `SyncConfig::precompress`),
+    /// served by `get_asset_encoded` to clients that accept them
+    pub encoded: Vec<EncodedVariant>,
+    /// Redirect rules, only set on the index's entry for the rules (see `KVAssets::resolve`)
+    pub redirects: Vec<RedirectRule>,
+    /// Header rules, only set on the index's entry for the rules applied by `handle_request`
+    /// (see `SyncConfig::response_header_rules`)
+    pub header_rules: Vec<HeaderRule>,
+    /// Asset can only be read with a signed URL (see `SyncConfig::protected`
+    /// and `KVAssets::get_asset_signed`)
+    pub protected: bool,
+    /// Language tags and index paths of the asset's language variants, default first,
+    /// only set on the entry for the path without a language suffix
+    /// (see `SyncConfig::languages` and `KVAssets::get_asset_negotiated`)
+    pub languages: Vec<(String, String)>,
+    /// Subresource Integrity hash of scripts and stylesheets, e.g. `sha384-...`
+    /// (see `KVAssets::sri`)
+    pub sri: Option<String>,
+    /// Error statuses and the paths of the assets served as their error documents, only
+    /// set on the index's entry for them (see `SyncConfig::error_documents`)
+    pub error_documents: Vec<(u16, String)>,
+    /// SHA-256 digests of the contents of the `encoded` variants, in the same order,
+    /// recorded at sync time (see `KVAssets::with_integrity_check`). Kept here, not on
+    /// `EncodedVariant`, so indexes written before digests were recorded stay readable.
+    /// If the lengths differ when the index is read, the digests are dropped, and the
+    /// variants aren't checked.
+    pub encoded_sha256: Vec<[u8; 32]>,
 }
 
-/// Serves static assets out of Worker KV storage.
-#[allow(clippy::upper_case_acronyms)]
-pub struct KVAssets<'ah> {
-    index: &'ah [u8],
-    map: RefCell<Option<AssetIndex>>,
-    kv: KV,
+impl AssetMetadata {
+    /// Returns true if the asset has expired from KV at time `now` (UTC seconds since EPOCH)
+    pub fn is_expired(&self, now: u64) -> bool {
+        matches!(self.expires, Some(expires) if expires <= now)
+    }
+}
+
+/// Callback invoked when `get_asset` finds an asset, with the requested path and its metadata
+#[cfg(not(feature = "thread-safe"))]
+pub type HitHook = Box<dyn Fn(&str, &AssetMetadata)>;
+/// Callback invoked when `get_asset` doesn't find an asset in the index, with the requested path
+#[cfg(not(feature = "thread-safe"))]
+pub type MissHook = Box<dyn Fn(&str)>;
+/// Callback invoked when `get_asset` returns an error, with the requested path and the error
+#[cfg(not(feature = "thread-safe"))]
+pub type ErrorHook = Box<dyn Fn(&str, &Error)>;
+/// Callback invoked when `get_asset` finds an asset, with the requested path and its metadata
+#[cfg(feature = "thread-safe")]
+pub type HitHook = Box<dyn Fn(&str, &AssetMetadata) + Send + Sync>;
+/// Callback invoked when `get_asset` doesn't find an asset in the index, with the requested path
+#[cfg(feature = "thread-safe")]
+pub type MissHook = Box<dyn Fn(&str) + Send + Sync>;
+/// Callback invoked when `get_asset` returns an error, with the requested path and the error
+#[cfg(feature = "thread-safe")]
+pub type ErrorHook = Box<dyn Fn(&str, &Error) + Send + Sync>;
+/// Read of a stale asset cache entry, passed to the spawner set with
+/// `KVAssets::with_refresh_spawner`
+#[cfg(not(all(feature = "thread-safe", not(target_arch = "wasm32"))))]
+pub type RefreshFuture = std::pin::Pin<Box<dyn std::future::Future<Output = ()>>>;
+/// Read of a stale asset cache entry, passed to the spawner set with
+/// `KVAssets::with_refresh_spawner`
+#[cfg(all(feature = "thread-safe", not(target_arch = "wasm32")))]
+pub type RefreshFuture = std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send>>;
+
+// Starts the refresh of a stale cache entry, with the entry's store, the cache,
+// and whether to check the digest (see `with_refresh_spawner`)
+#[cfg(not(feature = "thread-safe"))]
+type Refresher<S> = Box<dyn Fn(&S, &Shared<Lock<AssetCache>>, AssetMetadata, bool)>;
+#[cfg(feature = "thread-safe")]
+type Refresher<S> = Box<dyn Fn(&S, &Shared<Lock<AssetCache>>, AssetMetadata, bool) + Send + Sync>;
+
+/// Which entry is used when a path is in more than one index (see `KVAssets::with_index`)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Precedence {
+    /// Entries for paths already in the index are kept
+    Lower,
+    /// Entries in the added index replace existing entries for the same path
+    Higher,
 }
 
-/// Workers KV Parameters
+/// Serves static assets out of Worker KV storage,
+/// or another `AssetStore` such as `LocalKV`.
 #[allow(clippy::upper_case_acronyms)]
-pub struct KV {
-    account_id: String,
-    namespace_id: String,
-    auth_token: String,
+pub struct KVAssets<'ah, S: AssetStore = KV> {
+    index: Cow<'ah, [u8]>,
+    // KV key the index was loaded from (see `load_from_kv`)
+    index_key: Option<String>,
+    more_indexes: Vec<(&'ah [u8], Precedence)>,
+    mounts: Vec<Mount<'ah, S>>,
+    map: Once<Shared<AssetIndex>>,
+    // KV keys of assets in mounts with their own store, mapped to the mount
+    mount_keys: Once<HashMap<String, usize>>,
+    cached: bool,
+    store: S,
+    fallbacks: Vec<Vec<String>>,
+    spa_fallback: Option<String>,
+    directory_index: Vec<String>,
+    trailing_slash: TrailingSlash,
+    // rules applied before those in the index (see `with_redirect_rule`)
+    pub(crate) redirect_rules: Vec<RedirectRule>,
+    // error documents by status (see `with_error_document`)
+    pub(crate) error_documents: Vec<(u16, String)>,
+    pub(crate) listable: Vec<String>,
+    pub(crate) lookup_options: LookupOptions,
+    // folded index paths, built on the first inexact lookup (see `LookupOptions::fold_index`)
+    folded: Once<HashMap<String, String>>,
+    // index paths by their versioned form, built on the first miss (see `versioned_url`)
+    pub(crate) versioned: Once<HashMap<String, String>>,
+    on_hit: Option<HitHook>,
+    on_miss: Option<MissHook>,
+    on_error: Option<ErrorHook>,
+    pub(crate) access_logger: Option<AccessLogger>,
+    pub(crate) header_policy: Option<HeaderPolicy>,
+    pub(crate) cors: Option<CorsPolicy>,
+    pub(crate) cache_policy: Option<CachePolicy>,
+    asset_cache: Option<Shared<Lock<AssetCache>>>,
+    // spawns reads of stale cache entries (see `with_refresh_spawner`)
+    refresher: Option<Refresher<S>>,
+    // stale cache entries to read again, without a spawner (see `revalidate`)
+    stale: Lock<Vec<AssetMetadata>>,
+    // KV reads in progress, shared by concurrent requests for the same asset
+    in_flight: InFlight,
+    #[cfg(feature = "compression")]
+    compress_min_size: Option<usize>,
+    // media types compressed on the fly, or empty for all but already compressed types
+    #[cfg(feature = "compression")]
+    compress_types: Vec<String>,
+    #[cfg(feature = "integrity")]
+    verify_integrity: bool,
 }
 
-/// Initialize KV parameters
-pub fn init_kv<T: ToString>(account: T, namespace: T, token: T) -> KV {
-    KV {
-        account_id: account.to_string(),
-        namespace_id: namespace.to_string(),
-        auth_token: token.to_string(),
-    }
+/// Index mounted at a URL prefix (see `KVAssets::with_mount`)
+struct Mount<'ah, S> {
+    /// Prefix without leading '/', ending in '/'
+    prefix: String,
+    index: &'ah [u8],
+    /// Store for the index's assets, if not the handler's store
+    store: Option<S>,
 }
 
-impl<'ah> KVAssets<'ah> {
+impl<'ah> KVAssets<'ah, KV> {
     /// Initialize handler
     /// - index: binary serialized index (created by cf_assets)
     /// - account_id: cloudflare account id
@@ -56,62 +217,1040 @@ impl<'ah> KVAssets<'ah> {
         namespace_id: &'_ str,
         auth_token: &'_ str,
     ) -> Self {
-        Self {
-            index,
-            map: RefCell::new(None),
-            kv: init_kv(account_id, namespace_id, auth_token),
-        }
+        Self::init_with(index, init_kv(account_id, namespace_id, auth_token))
+    }
+
+    /// Initializes with KV parameters from the worker's `env` object
+    /// (see `KV::from_worker_env`)
+    #[cfg(target_arch = "wasm32")]
+    pub fn from_worker_env(
+        index: &'ah [u8],
+        env: &js_sys::wasm_bindgen::JsValue,
+    ) -> Result<Self, Error> {
+        Ok(Self::init_with(index, KV::from_worker_env(env)?))
     }
 
     /// Initialize with exiting KV parameters
     pub fn init_with(index: &'ah [u8], kv: KV) -> Self {
+        Self::with_store(index, kv)
+    }
+
+    /// Initializes with an index obtained at runtime, such as one fetched from KV or
+    /// the network, rather than embedded with `include_bytes!`. The handler doesn't
+    /// borrow the index, so it can be `KVAssets<'static>`.
+    pub fn from_owned(index: Vec<u8>, kv: KV) -> Self {
+        Self::with_owned_store(index, kv)
+    }
+
+    /// Store a value in KV. Optionally, set expiration TTL, number of seconds in future
+    /// when content should be automatically deleted. TTL must be at least 60.
+    /// Optional JSON metadata is stored with the value.
+    pub async fn put_kv_value<
+        K: ToAssetKey + ?Sized,
+        T: Into<reqwest::Body>,
+        E: Into<crate::Expiration>,
+    >(
+        &self,
+        key: &K,
+        val: T,
+        expiration: E,
+        metadata: Option<serde_json::Value>,
+    ) -> Result<(), Error> {
+        self.store
+            .put_kv_value(key, val, expiration, metadata)
+            .await
+    }
+}
+
+impl<S: AssetStore> KVAssets<'static, S> {
+    /// Initializes with an index embedded in the worker, such as with `include_bytes!`,
+    /// that is deserialized once and reused by every handler created with `cached`
+    /// for the same index (and indexes added with `with_index`), for as long as the
+    /// worker isolate lives. Use this when creating a handler per request,
+    /// so large indexes aren't parsed on every request.
+    pub fn cached(index: &'static [u8], store: S) -> Self {
+        let mut assets = Self::with_store(index, store);
+        assets.cached = true;
+        assets
+    }
+}
+
+// address, length, and precedence of each index, and the prefix of mounted indexes
+type IndexCacheKey = Vec<(usize, usize, Precedence, Option<String>)>;
+
+thread_local! {
+    // Indexes parsed by handlers created with `KVAssets::cached`
+    static INDEX_CACHE: RefCell<Vec<(IndexCacheKey, Shared<AssetIndex>)>> = const { RefCell::new(Vec::new()) };
+}
+
+impl<'ah, S: AssetStore> KVAssets<'ah, S> {
+    /// Initialize with an alternate asset store, such as `LocalKV`
+    pub fn with_store(index: &'ah [u8], store: S) -> Self {
         Self {
-            index,
-            map: RefCell::new(None),
-            kv,
+            index: Cow::Borrowed(index),
+            more_indexes: Vec::new(),
+            mounts: Vec::new(),
+            index_key: None,
+            map: Once::new(),
+            mount_keys: Once::new(),
+            cached: false,
+            store,
+            fallbacks: Vec::new(),
+            spa_fallback: None,
+            directory_index: Vec::new(),
+            trailing_slash: TrailingSlash::None,
+            redirect_rules: Vec::new(),
+            error_documents: Vec::new(),
+            listable: Vec::new(),
+            lookup_options: LookupOptions::default(),
+            folded: Once::new(),
+            versioned: Once::new(),
+            on_hit: None,
+            on_miss: None,
+            on_error: None,
+            access_logger: None,
+            header_policy: None,
+            cors: None,
+            cache_policy: None,
+            asset_cache: None,
+            refresher: None,
+            stale: Lock::new(Vec::new()),
+            in_flight: InFlight::default(),
+            #[cfg(feature = "compression")]
+            compress_min_size: None,
+            #[cfg(feature = "compression")]
+            compress_types: Vec::new(),
+            #[cfg(feature = "integrity")]
+            verify_integrity: false,
+        }
+    }
+
+    /// Initializes with an owned index (see `from_owned`) and an alternate asset store
+    pub fn with_owned_store(index: Vec<u8>, store: S) -> Self {
+        let mut assets = Self::with_store(&[], store);
+        assets.index = Cow::Owned(index);
+        assets
+    }
+
+    /// Initializes with the index stored in the KV key `index_key`, such as one published
+    /// by kv-sync (see `SyncConfig::publish_index`), so new deploys of the assets don't
+    /// require a new worker bundle. Fails if the key is missing or the index is invalid.
+    pub async fn load_from_kv(store: S, index_key: &str) -> Result<Self, Error> {
+        let blob = store.get_value(index_key).await?;
+        // validate now, rather than on the first lookup
+        decode_index(&blob)?;
+        let mut assets = Self::with_owned_store(blob.to_vec(), store);
+        assets.index_key = Some(index_key.to_string());
+        Ok(assets)
+    }
+
+    /// Reads the index again from the KV key it was loaded from (see `load_from_kv`),
+    /// and uses it for later lookups, so assets published by a later sync are served
+    /// without redeploying the worker. Returns true if the index changed.
+    /// If the key is missing or the index is invalid, returns an error and keeps the
+    /// current index. Fails if the index wasn't loaded with `load_from_kv`.
+    pub async fn refresh_index(&mut self) -> Result<bool, Error> {
+        let key = match &self.index_key {
+            Some(key) => key,
+            None => {
+                return Err(Error::Message(
+                    "refresh_index requires an index loaded with load_from_kv".to_string(),
+                ))
+            }
+        };
+        let blob = self.store.get_value(key).await?;
+        if blob.as_ref() == self.index.as_ref() {
+            return Ok(false);
         }
+        decode_index(&blob)?;
+        self.index = Cow::Owned(blob.to_vec());
+        self.map = Once::new();
+        self.mount_keys = Once::new();
+        self.folded = Once::new();
+        self.versioned = Once::new();
+        Ok(true)
+    }
+
+    /// Enables gzip compression by `get_asset_encoded` of assets at least `min_size` bytes,
+    /// for clients that accept it, or, with the `brotli` feature, brotli compression
+    /// for clients that accept brotli. File types that are already compressed
+    /// (images, fonts, archives, etc.) are not compressed again.
+    /// Assets with precompressed variants (see `SyncConfig::precompress`) are served
+    /// from those instead.
+    #[cfg(feature = "compression")]
+    pub fn with_compression(mut self, min_size: usize) -> Self {
+        self.compress_min_size = Some(min_size);
+        self
+    }
+
+    /// Returns true if compression is enabled (see `with_compression`)
+    pub(crate) fn compresses(&self) -> bool {
+        #[cfg(feature = "compression")]
+        return self.compress_min_size.is_some();
+        #[cfg(not(feature = "compression"))]
+        false
+    }
+
+    /// Limits compression (see `with_compression`) to assets with one of the media types,
+    /// e.g. `&["text/", "application/javascript", "application/json", "image/svg+xml"]`.
+    /// A type ending in '/' matches all of its subtypes. Assets without a content type
+    /// are not compressed.
+    #[cfg(feature = "compression")]
+    pub fn with_compression_types(mut self, types: &[&str]) -> Self {
+        self.compress_types = types.iter().map(|t| t.to_string()).collect();
+        self
+    }
+
+    /// Adds a redirect or rewrite rule (see `RedirectRule`), applied by `resolve` and
+    /// `handle_request` before the rules in the index (see `SyncConfig::redirects`),
+    /// in the order added
+    pub fn with_redirect_rule(mut self, rule: RedirectRule) -> Self {
+        self.redirect_rules.push(rule);
+        self
+    }
+
+    /// Adds a rewrite rule: requests for paths matching `from` are served the asset at `to`,
+    /// e.g. `with_rewrite("/blog/:post", "/blog/:post/index.html")` for clean URLs.
+    /// See `with_redirect_rule`.
+    pub fn with_rewrite(self, from: &str, to: &str) -> Self {
+        self.with_redirect_rule(RedirectRule {
+            from: from.to_string(),
+            to: to.to_string(),
+            status: 200,
+        })
+    }
+
+    /// Serves the asset at `path` as the body of `handle_request` responses with the
+    /// error status, e.g. `with_error_document(404, "404.html")`, keeping the status.
+    /// This takes precedence over error documents set at sync time
+    /// (see `SyncConfig::error_documents`).
+    pub fn with_error_document(mut self, status: u16, path: &str) -> Self {
+        self.error_documents.retain(|(s, _)| *s != status);
+        self.error_documents.push((status, path.to_string()));
+        self
+    }
+
+    /// Sets security headers added to all responses from `handle_request`,
+    /// e.g. `HeaderPolicy::recommended().with_csp("default-src 'self'")`
+    pub fn with_header_policy(mut self, policy: HeaderPolicy) -> Self {
+        self.header_policy = Some(policy);
+        self
+    }
+
+    /// Enables CORS in `handle_request`: responses to requests with an allowed `Origin`
+    /// get `Access-Control-Allow-Origin`, and preflight (OPTIONS) requests
+    /// get the allowed methods, headers, and max age
+    pub fn with_cors(mut self, cors: CorsPolicy) -> Self {
+        self.cors = Some(cors);
+        self
+    }
+
+    /// Sets `Cache-Control` rules for asset responses from `handle_request`, e.g.
+    /// `CachePolicy::new().with_rule("*.css", "max-age=31536000, immutable")`
+    pub fn with_cache_policy(mut self, policy: CachePolicy) -> Self {
+        self.cache_policy = Some(policy);
+        self
+    }
+
+    /// Sets how request paths are matched against the index, e.g.
+    /// `LookupOptions::new().with_case_insensitive()`
+    pub fn with_lookup_options(mut self, options: LookupOptions) -> Self {
+        self.lookup_options = options;
+        self.folded = Once::new();
+        self
+    }
+
+    /// Keeps values read from KV in memory, within the limits of `config`, evicting the
+    /// least recently used, so repeated requests for an asset don't read KV again.
+    /// The cache belongs to this `KVAssets`, so keep it (e.g., in a `thread_local`)
+    /// to share the cache between requests. With `AssetCacheConfig::stale_after`,
+    /// stale entries are served, then read again in the background by the spawner set
+    /// with `with_refresh_spawner`. Without a spawner, nothing runs in the background:
+    /// stale entries are only refreshed when `revalidate` is called.
+    pub fn with_asset_cache(mut self, config: AssetCacheConfig) -> Self {
+        self.asset_cache = Some(Shared::new(Lock::new(AssetCache::new(config))));
+        self
+    }
+
+    /// Refreshes stale entries of the asset cache (see `AssetCacheConfig::stale_after`)
+    /// in the background: when a stale entry is served, a future that reads it again
+    /// is passed to `spawn`, which runs it after the response is sent, e.g. with the
+    /// worker's `waitUntil`, `wasm_bindgen_futures::spawn_local`, or `tokio::spawn`
+    /// (with the `thread-safe` feature, which makes the future `Send`).
+    /// Entries whose values are no longer in KV are dropped, and, if the read fails,
+    /// the stale entry is served and read again on its next use.
+    pub fn with_refresh_spawner<F>(mut self, spawn: F) -> Self
+    where
+        S: Clone + MaybeSendSync + 'static,
+        F: Fn(RefreshFuture) + MaybeSendSync + 'static,
+    {
+        self.refresher = Some(Box::new(move |store, cache, md, verify| {
+            let (store, cache) = (store.clone(), cache.clone());
+            spawn(Box::pin(async move {
+                // a failed refresh is retried when the entry is next served
+                let _ = refresh_entry(&store, &cache, &md, verify).await;
+            }))
+        }));
+        self
+    }
+
+    /// Enables checking assets read from KV against the SHA-256 digest in the index.
+    /// If the content doesn't match, it's read once more (in case of a transient error),
+    /// then `get_asset` returns `Error::IntegrityMismatch`. Precompressed variants are
+    /// checked against their own digests. Assets and variants without a digest
+    /// (indexes from older versions of kv-sync) aren't checked.
+    #[cfg(feature = "integrity")]
+    pub fn with_integrity_check(mut self) -> Self {
+        self.verify_integrity = true;
+        self
+    }
+
+    /// Sets a callback invoked each time `get_asset` serves an asset,
+    /// for example, to record analytics
+    pub fn on_hit<F: Fn(&str, &AssetMetadata) + MaybeSendSync + 'static>(mut self, f: F) -> Self {
+        self.on_hit = Some(Box::new(f));
+        self
+    }
+
+    /// Sets a callback invoked each time `get_asset` doesn't find the path in the index
+    pub fn on_miss<F: Fn(&str) + MaybeSendSync + 'static>(mut self, f: F) -> Self {
+        self.on_miss = Some(Box::new(f));
+        self
+    }
+
+    /// Sets a callback invoked each time `get_asset` returns an error
+    pub fn on_error<F: Fn(&str, &Error) + MaybeSendSync + 'static>(mut self, f: F) -> Self {
+        self.on_error = Some(Box::new(f));
+        self
+    }
+
+    /// Sets a callback that receives an `AccessRecord`, with the path, status, size,
+    /// and latency, for every `get_asset` and `handle_request` (including `get_response`),
+    /// e.g. to send structured access logs to Logpush or analytics
+    pub fn with_access_logger<F: Fn(AccessRecord) + MaybeSendSync + 'static>(
+        mut self,
+        f: F,
+    ) -> Self {
+        self.access_logger = Some(Box::new(f));
+        self
+    }
+
+    /// Adds a fallback rule: an ordered list of path prefixes tried when `lookup_key`
+    /// doesn't find a path. If the path begins with one of the prefixes, the prefix
+    /// is replaced by each of the prefixes that follow it, in order, until one is found.
+    /// For example, with prefixes `["de/", "en/", ""]`, a lookup of `de/page.html`
+    /// falls back to `en/page.html`, then `page.html`.
+    /// Rules are evaluated against the index only, so fallbacks don't cost extra KV reads.
+    /// Rules are tried in the order they were added.
+    pub fn with_fallback<T: ToString>(mut self, prefixes: &[T]) -> Self {
+        self.fallbacks
+            .push(prefixes.iter().map(|p| p.to_string()).collect());
+        self
+    }
+
+    /// Resolves directory paths, like Workers Sites: `/`, `docs/`, and `docs`
+    /// (if `docs` isn't an asset) are looked up as the first of `names`
+    /// in the directory, e.g. `&["index.html", "index.htm"]`.
+    pub fn with_directory_index<T: ToString>(mut self, names: &[T]) -> Self {
+        self.directory_index = names.iter().map(|n| n.to_string()).collect();
+        self
+    }
+
+    /// Sets how `/about` and `/about/` are resolved, so both forms serve the same asset
+    /// or, with `TrailingSlash::RedirectToCanonical`, one redirects to the other
+    /// (see `resolve`). Directory indexes are set with `with_directory_index`.
+    pub fn with_trailing_slash(mut self, policy: TrailingSlash) -> Self {
+        self.trailing_slash = policy;
+        self
+    }
+
+    /// Serves a single-page app: paths that aren't in the index, after fallback rules,
+    /// are looked up as `entry` (e.g. `index.html`), so the app can route them.
+    /// This includes paths that look like files, such as a missing `/app.js`.
+    pub fn with_spa_fallback<T: ToString>(mut self, entry: T) -> Self {
+        let entry = entry.to_string();
+        self.spa_fallback = Some(entry.strip_prefix('/').unwrap_or(&entry).to_string());
+        self
+    }
+
+    /// Adds another index, such as a separately deployed docs bundle, to be served
+    /// from the same store. Indexes are merged in the order they were added;
+    /// `precedence` determines whether this index's entries replace entries for the
+    /// same path in the indexes before it.
+    pub fn with_index(mut self, index: &'ah [u8], precedence: Precedence) -> Self {
+        self.more_indexes.push((index, precedence));
+        self
+    }
+
+    /// Mounts another index, such as a separately built docs site, at a URL prefix,
+    /// e.g. `/docs`, so `/docs/a.html` is `a.html` in that index. Paths are dispatched
+    /// by the longest matching prefix: paths under a mount are only found in its index,
+    /// not in the main index or mounts with shorter prefixes. Redirects and header rules
+    /// stored in the mounted index apply to paths under the prefix.
+    /// The mounted assets are read from the handler's store.
+    pub fn with_mount(self, prefix: &str, index: &'ah [u8]) -> Self {
+        self.mount(prefix, index, None)
+    }
+
+    /// Mounts an index whose assets are in another store, such as a different
+    /// KV namespace (see `with_mount`)
+    pub fn with_mount_store(self, prefix: &str, index: &'ah [u8], store: S) -> Self {
+        self.mount(prefix, index, Some(store))
+    }
+
+    fn mount(mut self, prefix: &str, index: &'ah [u8], store: Option<S>) -> Self {
+        let prefix = match prefix.trim_matches('/') {
+            "" => String::new(),
+            prefix => format!("{}/", prefix),
+        };
+        self.mounts.push(Mount {
+            prefix,
+            index,
+            store,
+        });
+        self
     }
 
     // Lazily deserialize map, so we don't bother doing so
     // when handling urls that aren't for static assets
     fn ensure_map(&self) -> Result<(), Error> {
-        let mut map = self.map.borrow_mut();
-        if (*map).is_none() {
-            *map = Some(
-                bincode::deserialize(self.index)
-                    .map_err(|e| Error::DeserializeAssets(e.to_string()))?,
-            );
+        if self.map.get().is_some() {
+            return Ok(());
+        }
+        // mount keys are set first, so they're ready for any thread that sees the map
+        self.ensure_mount_keys()?;
+        let key = self.cache_key();
+        if let Some(key) = key.as_ref() {
+            let found = INDEX_CACHE.with(|cache| {
+                cache
+                    .borrow()
+                    .iter()
+                    .find(|(k, _)| k == key)
+                    .map(|(_, index)| index.clone())
+            });
+            if let Some(found) = found {
+                // if another thread set it first, it's the same index
+                let _ = self.map.set(found);
+                return Ok(());
+            }
+        }
+        let mut merged = decode_index(&self.index)?;
+        for (index, precedence) in self.more_indexes.iter() {
+            for (path, md) in decode_index(index)?.into_iter() {
+                match precedence {
+                    Precedence::Higher => {
+                        merged.insert(path, md);
+                    }
+                    Precedence::Lower => {
+                        merged.entry(path).or_insert(md);
+                    }
+                }
+            }
+        }
+        // shorter prefixes first, so longer prefixes replace their paths
+        for mount in self.mounts_by_length() {
+            merged.retain(|path, _| !path.starts_with(&mount.prefix));
+            for (path, mut md) in decode_index(mount.index)?.into_iter() {
+                if is_reserved(&path) {
+                    merge_mounted_rules(&mut merged, &mount.prefix, &path, md);
+                    continue;
+                }
+                for (_, variant) in md.languages.iter_mut() {
+                    *variant = format!("{}{}", mount.prefix, variant);
+                }
+                merged.insert(format!("{}{}", mount.prefix, path), md);
+            }
         }
+        let merged = Shared::new(merged);
+        if let Some(key) = key {
+            INDEX_CACHE.with(|cache| cache.borrow_mut().push((key, merged.clone())));
+        }
+        let _ = self.map.set(merged);
         Ok(())
     }
 
+    /// Returns the merged index, deserializing it on first use
+    pub(crate) fn index_map(&self) -> Result<&AssetIndex, Error> {
+        self.ensure_map()?;
+        Ok(self.map.get().expect("index map"))
+    }
+
+    fn mounts_by_length(&self) -> Vec<&Mount<'ah, S>> {
+        let mut mounts: Vec<&Mount<'ah, S>> = self.mounts.iter().collect();
+        mounts.sort_by_key(|m| m.prefix.len());
+        mounts
+    }
+
+    // Maps the KV keys of assets in mounts with their own store to the mount
+    fn ensure_mount_keys(&self) -> Result<(), Error> {
+        if self.mount_keys.get().is_some() {
+            return Ok(());
+        }
+        let mut mount_keys = HashMap::new();
+        for (n, mount) in self.mounts.iter().enumerate() {
+            if mount.store.is_none() {
+                continue;
+            }
+            for md in decode_index(mount.index)?.into_values() {
+                for variant in md.encoded.iter() {
+                    mount_keys.insert(variant.path.clone(), n);
+                }
+                mount_keys.insert(md.path, n);
+            }
+        }
+        let _ = self.mount_keys.set(mount_keys);
+        Ok(())
+    }
+
+    /// Returns the store of the asset with the KV key
+    pub(crate) fn store_for(&self, key: &str) -> &S {
+        let mount = self
+            .mount_keys
+            .get()
+            .and_then(|mount_keys| mount_keys.get(key).copied());
+        match mount.and_then(|n| self.mounts[n].store.as_ref()) {
+            Some(store) => store,
+            None => &self.store,
+        }
+    }
+
+    // Identifies the indexes of a cached handler by their addresses, which don't change
+    // for 'static data
+    fn cache_key(&self) -> Option<IndexCacheKey> {
+        match (&self.index, self.cached) {
+            (Cow::Borrowed(index), true) => Some(
+                std::iter::once((
+                    index.as_ptr() as usize,
+                    index.len(),
+                    Precedence::Higher,
+                    None,
+                ))
+                .chain(
+                    self.more_indexes
+                        .iter()
+                        .map(|(index, p)| (index.as_ptr() as usize, index.len(), *p, None)),
+                )
+                .chain(self.mounts.iter().map(|m| {
+                    (
+                        m.index.as_ptr() as usize,
+                        m.index.len(),
+                        Precedence::Higher,
+                        Some(m.prefix.clone()),
+                    )
+                }))
+                .collect(),
+            ),
+            _ => None,
+        }
+    }
+
     /// all-in-one method to get the asset from KV
-    pub async fn get_asset(&self, key: &str) -> Result<Option<bytes::Bytes>, Error> {
-        match self.lookup_key(key) {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(path = %key.key_str()))
+    )]
+    pub async fn get_asset<K: ToAssetKey + ?Sized>(
+        &self,
+        key: &K,
+    ) -> Result<Option<bytes::Bytes>, Error> {
+        let started = clock::now_millis();
+        let result = self.get_asset_timed(key, &mut ServerTiming::new()).await;
+        self.log_get(key.key_str(), started, &result);
+        result
+    }
+
+    /// Same as `get_asset`, and adds the duration of the index lookup ("index")
+    /// and KV fetch ("kv") to `timing`. Timings are recorded even if an error is returned.
+    /// Use `timing.header_value()` for the `Server-Timing` response header.
+    pub async fn get_asset_timed<K: ToAssetKey + ?Sized>(
+        &self,
+        key: &K,
+        timing: &mut ServerTiming,
+    ) -> Result<Option<bytes::Bytes>, Error> {
+        // the key is validated by lookup_key, so hooks see validation errors
+        let result = self.fetch_asset_hooked(key.key_str(), timing).await;
+        result.map(|found| found.map(|(_, doc)| doc))
+    }
+
+    /// Fetches the asset and its metadata, and invokes the hooks.
+    /// Protected assets return `Error::SignatureRequired`.
+    pub(crate) async fn fetch_asset_hooked(
+        &self,
+        key: &str,
+        timing: &mut ServerTiming,
+    ) -> Result<Option<(AssetMetadata, bytes::Bytes)>, Error> {
+        self.fetch_hooked(key, timing, false).await
+    }
+
+    /// Fetches the asset and its metadata, including protected assets if
+    /// `allow_protected`, and invokes the hooks
+    pub(crate) async fn fetch_hooked(
+        &self,
+        key: &str,
+        timing: &mut ServerTiming,
+        allow_protected: bool,
+    ) -> Result<Option<(AssetMetadata, bytes::Bytes)>, Error> {
+        let result = self.fetch_asset(key, timing, allow_protected).await;
+        self.run_hooks(
+            key,
+            result
+                .as_ref()
+                .map(|found| found.as_ref().map(|(md, _)| md)),
+        );
+        result
+    }
+
+    /// Invokes the hit, miss, or error hook for the result of a fetch
+    pub(crate) fn run_hooks(&self, key: &str, result: Result<Option<&AssetMetadata>, &Error>) {
+        match result {
             Ok(Some(md)) => {
-                let doc = self.kv.get_kv_value(&md.path).await?;
-                Ok(Some(doc))
+                if let Some(on_hit) = &self.on_hit {
+                    on_hit(key, md);
+                }
+            }
+            Ok(None) => {
+                if let Some(on_miss) = &self.on_miss {
+                    on_miss(key);
+                }
+            }
+            Err(e) => {
+                if let Some(on_error) = &self.on_error {
+                    on_error(key, e);
+                }
+            }
+        }
+    }
+
+    /// Gets the asset from KV, in the best precompressed variant the client's
+    /// `Accept-Encoding` header allows (preferring brotli to gzip), if the asset has any.
+    /// Otherwise the asset is compressed if compression is enabled (see `with_compression`)
+    /// and the client accepts gzip.
+    /// The returned `content_encoding` should be used for the `Content-Encoding` response header.
+    pub async fn get_asset_encoded<K: ToAssetKey + ?Sized>(
+        &self,
+        key: &K,
+        accept_encoding: Option<&str>,
+    ) -> Result<Option<EncodedAsset>, Error> {
+        let found = self
+            .fetch_encoded_hooked(key.key_str(), accept_encoding, &mut ServerTiming::new())
+            .await?;
+        Ok(found.map(|(_, encoded)| encoded))
+    }
+
+    /// Fetches the asset encoded for the client (see `get_asset_encoded`), with its
+    /// metadata, and invokes the hooks
+    pub(crate) async fn fetch_encoded_hooked(
+        &self,
+        key: &str,
+        accept_encoding: Option<&str>,
+        timing: &mut ServerTiming,
+    ) -> Result<Option<(AssetMetadata, EncodedAsset)>, Error> {
+        if let Some(accept) = accept_encoding {
+            let timer = Timer::start();
+            // inline assets are served from the index, uncompressed
+            if let Ok(Some(md)) = self.lookup_key(key) {
+                if let (None, false, Some((coding, variant))) = (
+                    &md.inline,
+                    md.protected,
+                    choose_variant(&md.encoded, accept),
+                ) {
+                    timer.stop(timing, "index");
+                    let variant_md = AssetMetadata {
+                        path: variant.path.clone(),
+                        size: variant.size,
+                        chunks: variant.chunks,
+                        sha256: match md.encoded_sha256.len() == md.encoded.len() {
+                            true => md
+                                .encoded
+                                .iter()
+                                .position(|v| v == variant)
+                                .map(|n| md.encoded_sha256[n]),
+                            false => None,
+                        },
+                        ..Default::default()
+                    };
+                    let timer = Timer::start();
+                    let result = self.read_cached(&variant_md).await;
+                    timer.stop(timing, "kv");
+                    self.run_hooks(key, result.as_ref().map(|_| Some(&md)));
+                    let encoded = EncodedAsset {
+                        body: result?,
+                        content_encoding: Some(coding),
+                    };
+                    return Ok(Some((md, encoded)));
+                }
+            }
+        }
+        let found = match self.fetch_asset_hooked(key, timing).await? {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        let (md, body) = found;
+        let encoded = self.encode_body(key, md.content_type.as_deref(), body, accept_encoding)?;
+        Ok(Some((md, encoded)))
+    }
+
+    /// Compresses the body if compression is enabled and accepted by the client
+    pub(crate) fn encode_body(
+        &self,
+        key: &str,
+        content_type: Option<&str>,
+        body: bytes::Bytes,
+        accept_encoding: Option<&str>,
+    ) -> Result<EncodedAsset, Error> {
+        #[cfg(feature = "compression")]
+        if let (Some(min_size), Some(accept)) = (self.compress_min_size, accept_encoding) {
+            let compressible = match (self.compress_types.is_empty(), content_type) {
+                (true, _) => !crate::encoding::is_compressed_type(key),
+                (false, Some(content_type)) => {
+                    crate::encoding::media_type_matches(content_type, &self.compress_types)
+                }
+                (false, None) => false,
+            };
+            if body.len() >= min_size && compressible {
+                #[cfg(feature = "brotli")]
+                if crate::encoding::accepts(accept, "br") {
+                    return Ok(EncodedAsset {
+                        body: crate::encoding::brotli(&body)?.into(),
+                        content_encoding: Some("br"),
+                    });
+                }
+                if crate::encoding::accepts(accept, "gzip") {
+                    return Ok(EncodedAsset {
+                        body: crate::encoding::gzip(&body)?.into(),
+                        content_encoding: Some("gzip"),
+                    });
+                }
+            }
+        }
+        #[cfg(not(feature = "compression"))]
+        let _ = (key, content_type, accept_encoding);
+        Ok(EncodedAsset {
+            body,
+            content_encoding: None,
+        })
+    }
+
+    async fn fetch_asset(
+        &self,
+        key: &str,
+        timing: &mut ServerTiming,
+        allow_protected: bool,
+    ) -> Result<Option<(AssetMetadata, bytes::Bytes)>, Error> {
+        let timer = Timer::start();
+        let md = self.lookup_key(key);
+        timer.stop(timing, "index");
+        match md {
+            Ok(Some(md)) if md.protected && !allow_protected => {
+                Err(Error::SignatureRequired(md.path))
+            }
+            Ok(Some(md)) if md.inline.is_some() => {
+                let doc = bytes::Bytes::from(md.inline.clone().unwrap());
+                Ok(Some((md, doc)))
+            }
+            Ok(Some(md)) => {
+                let timer = Timer::start();
+                let doc = self.read_cached(&md).await;
+                timer.stop(timing, "kv");
+                Ok(Some((md, doc?)))
             }
             Ok(None) => Ok(None),
             Err(e) => Err(e),
         }
     }
 
+    /// Reads the asset from the asset cache, if enabled and the asset is cached
+    /// (see `with_asset_cache`), or the store. Concurrent reads of an asset share
+    /// one read of the store.
+    async fn read_cached(&self, md: &AssetMetadata) -> Result<bytes::Bytes, Error> {
+        let store = self.store_for(&md.path);
+        let read = self.in_flight.read(
+            &md.path,
+            read_verified(store, md, self.verifies_integrity()),
+        );
+        let cache = match &self.asset_cache {
+            Some(cache) => cache,
+            None => return read.await,
+        };
+        let cached = cache.borrow_mut().get(&md.path);
+        if let Some(doc) = cached {
+            if cache.borrow_mut().start_refresh(&md.path) {
+                match &self.refresher {
+                    Some(refresh) => refresh(store, cache, md.clone(), self.verifies_integrity()),
+                    None => self.stale.borrow_mut().push(md.clone()),
+                }
+            }
+            return Ok(doc);
+        }
+        let doc = read.await?;
+        cache.borrow_mut().insert(&md.path, doc.clone());
+        Ok(doc)
+    }
+
+    /// Reads the stale entries of the asset cache (see `AssetCacheConfig::stale_after`),
+    /// which were served from the cache, again from KV, so they stay cached. Entries whose
+    /// values are no longer in KV are dropped. Without a spawner (see
+    /// `with_refresh_spawner`), stale entries aren't refreshed until this is called:
+    /// run it after sending the response, e.g. in the worker's `waitUntil`, so clients
+    /// don't wait for the reads. With a spawner, there's nothing to refresh.
+    /// Returns the number of entries refreshed, or the first error other than a missing key,
+    /// after trying every entry.
+    pub async fn revalidate(&self) -> Result<usize, Error> {
+        let cache = match &self.asset_cache {
+            Some(cache) => cache,
+            None => return Ok(0),
+        };
+        let stale = std::mem::take(&mut *self.stale.borrow_mut());
+        let mut refreshed = 0;
+        let mut first_error = None;
+        for md in stale.iter() {
+            let store = self.store_for(&md.path);
+            match refresh_entry(store, cache, md, self.verifies_integrity()).await {
+                Ok(true) => refreshed += 1,
+                Ok(false) => {}
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(refreshed),
+        }
+    }
+
+    // Whether assets read from KV are checked against their digests (see `with_integrity_check`)
+    fn verifies_integrity(&self) -> bool {
+        #[cfg(feature = "integrity")]
+        return self.verify_integrity;
+        #[cfg(not(feature = "integrity"))]
+        false
+    }
+
+    /// Reads bytes `start` through `end` (inclusive) of a chunked asset from the chunks that
+    /// contain them, or, if the asset is in the asset cache, from the cache
+    pub(crate) async fn read_chunk_range(
+        &self,
+        md: &AssetMetadata,
+        start: u64,
+        end: u64,
+    ) -> Result<bytes::Bytes, Error> {
+        let cached = match &self.asset_cache {
+            Some(cache) => cache.borrow_mut().get(&md.path),
+            None => None,
+        };
+        if let Some(doc) = cached {
+            return Ok(doc.slice(start as usize..=end as usize));
+        }
+        let (first, last) = ((start / CHUNK_SIZE) as u32, (end / CHUNK_SIZE) as u32);
+        let offset = first as u64 * CHUNK_SIZE;
+        let mut doc = bytes::BytesMut::with_capacity((end - offset + 1) as usize);
+        let store = self.store_for(&md.path);
+        for n in first..=last {
+            let chunk = store.get_value(&chunk_key(&md.path, n)).await?;
+            // every chunk but the last is full
+            if chunk.len() as u64 != CHUNK_SIZE.min(md.size - n as u64 * CHUNK_SIZE) {
+                return Err(chunk_size_mismatch(md, offset + doc.len() as u64));
+            }
+            doc.extend_from_slice(&chunk);
+        }
+        Ok(doc
+            .freeze()
+            .slice((start - offset) as usize..=(end - offset) as usize))
+    }
+
     /// Finds the path in the map, returning the "key"
     /// This lookup should reliably and quickly determine whether asset is in KV,
     /// as it doesn't require querying KV yet.
     /// Removes leading / if present
-    /// Returns Ok(None) if Not found
-    pub fn lookup_key(&self, path: &'_ str) -> Result<Option<AssetMetadata>, Error> {
-        // remove leading '/' if present
-        let path = path.strip_prefix('/').unwrap_or(path);
-        if path.is_empty() {
-            return Err(Error::EmptyKey);
+    /// Returns Ok(None) if Not found, or if the asset's expiration time has passed
+    /// If the path is not found, it's looked up as a directory (see `with_directory_index`),
+    /// then any fallback rules (see `with_fallback`) are applied,
+    /// and then the single-page app fallback, if set (see `with_spa_fallback`).
+    /// The path is percent-decoded (see `LookupOptions::percent_decode`), and paths with
+    /// `..` segments or NUL bytes are rejected with `Error::MalformedPath`. Paths are matched as set by `with_lookup_options`.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(path = %path.key_str()))
+    )]
+    pub fn lookup_key<K: ToAssetKey + ?Sized>(
+        &self,
+        path: &K,
+    ) -> Result<Option<AssetMetadata>, Error> {
+        let path = match AssetKey::new(&self.lookup_options.normalize(path.key_str())?) {
+            Ok(path) => path,
+            // the root directory
+            Err(Error::EmptyKey) if !self.directory_index.is_empty() => {
+                return match (
+                    self.lookup_chain(&self.directory_index)?,
+                    &self.spa_fallback,
+                ) {
+                    (None, Some(entry)) => self.lookup_chain(&[entry]),
+                    (found, _) => Ok(found),
+                };
+            }
+            Err(e) => return Err(e),
+        };
+        let path = path.as_str();
+        let map = self.index_map()?;
+        let file = match self.trailing_slash {
+            TrailingSlash::None => path,
+            _ => path.trim_end_matches('/'),
+        };
+        if self.trailing_slash != TrailingSlash::Add {
+            if let Some(md) = self.find(map, file) {
+                return Ok(Some(md));
+            }
         }
-        self.ensure_map()?;
-        let map = self.map.borrow();
-        let md = map.as_ref().unwrap().get(path).cloned();
-        Ok(md)
+        let dir = path.trim_end_matches('/');
+        for name in self.directory_index.iter() {
+            if let Some(md) = self.find(map, &format!("{}/{}", dir, name)) {
+                return Ok(Some(md));
+            }
+        }
+        if self.trailing_slash == TrailingSlash::Add {
+            if let Some(md) = self.find(map, file) {
+                return Ok(Some(md));
+            }
+        }
+        if let Some(md) = self
+            .find_versioned(map, file)
+            .and_then(|path| find(map, path))
+        {
+            return Ok(Some(md));
+        }
+        for prefixes in self.fallbacks.iter() {
+            // find the first prefix that matches, then try the ones after it
+            let start = prefixes
+                .iter()
+                .position(|p| !p.is_empty() && path.starts_with(p.as_str()));
+            if let Some(pos) = start {
+                let rest = &path[prefixes[pos].len()..];
+                for prefix in prefixes[pos + 1..].iter() {
+                    if let Some(md) = self.find(map, &format!("{}{}", prefix, rest)) {
+                        return Ok(Some(md));
+                    }
+                }
+            }
+        }
+        Ok(self
+            .spa_fallback
+            .as_ref()
+            .and_then(|entry| self.find(map, entry)))
+    }
+
+    /// Looks up each path in order, returning metadata for the first one in the index.
+    /// Leading '/' is removed from each path. Fallback rules are not applied.
+    /// Returns Ok(None) if none of the paths are found.
+    pub fn lookup_chain<K: ToAssetKey>(&self, paths: &[K]) -> Result<Option<AssetMetadata>, Error> {
+        let map = self.index_map()?;
+        for path in paths.iter() {
+            if let Some(md) = self.find(map, path.to_asset_key()?.as_str()) {
+                return Ok(Some(md));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the paths and metadata of assets whose paths start with `prefix`
+    /// (leading '/' removed), sorted by path, e.g. to build a sitemap without reading KV.
+    /// An empty prefix lists every asset. Expired entries are left out.
+    pub fn list_keys(&self, prefix: &str) -> Result<Vec<(String, AssetMetadata)>, Error> {
+        let prefix = prefix.strip_prefix('/').unwrap_or(prefix);
+        self.list_matching(|path| path.starts_with(prefix))
+    }
+
+    /// Returns the paths and metadata of assets whose paths match the glob pattern,
+    /// e.g. `blog/**/*.html`, sorted by path. `*` doesn't match '/', and patterns without
+    /// '/' match file names in any directory. Expired entries are left out.
+    pub fn glob(&self, pattern: &str) -> Result<Vec<(String, AssetMetadata)>, Error> {
+        self.list_matching(|path| glob_match(pattern, path))
+    }
+
+    fn list_matching<F: Fn(&str) -> bool>(
+        &self,
+        matches: F,
+    ) -> Result<Vec<(String, AssetMetadata)>, Error> {
+        let map = self.index_map()?;
+        let now = clock::now_secs();
+        let mut found: Vec<(String, AssetMetadata)> = map
+            .iter()
+            .filter(|(path, md)| matches(path) && !md.is_expired(now) && !is_reserved(path))
+            .map(|(path, md)| (path.clone(), md.clone()))
+            .collect();
+        found.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(found)
+    }
+
+    /// Returns the path, in the form in the index, if it differs from the requested form
+    /// (see `TrailingSlash::RedirectToCanonical`): with a trailing '/' for directories,
+    /// and without for assets
+    pub(crate) fn canonical_path(&self, path: &str) -> Result<Option<String>, Error> {
+        if self.trailing_slash != TrailingSlash::RedirectToCanonical {
+            return Ok(None);
+        }
+        let normalized = self.lookup_options.normalize(path)?;
+        let key = normalized.strip_prefix('/').unwrap_or(&normalized);
+        let file = key.trim_end_matches('/');
+        if file.is_empty() {
+            return Ok(None);
+        }
+        let map = self.index_map()?;
+        let is_dir = if self.find(map, file).is_some() {
+            false
+        } else if self
+            .directory_index
+            .iter()
+            .any(|name| self.find(map, &format!("{}/{}", file, name)).is_some())
+        {
+            true
+        } else {
+            return Ok(None);
+        };
+        // keep the requested path's encoding
+        let path = format!("/{}", path.strip_prefix('/').unwrap_or(path));
+        Ok(match (is_dir, path.ends_with('/')) {
+            (true, false) => Some(format!("{}/", path)),
+            (false, true) => Some(path.trim_end_matches('/').to_string()),
+            _ => None,
+        })
+    }
+
+    /// Returns the redirect rules added with `with_redirect_rule`, then those in the index
+    /// (see `resolve`)
+    pub(crate) fn redirect_rules(&self) -> Result<Vec<RedirectRule>, Error> {
+        let mut rules = self.redirect_rules.clone();
+        if let Some(md) = self.reserved_entry(REDIRECTS_PATH)? {
+            rules.extend(md.redirects);
+        }
+        Ok(rules)
+    }
+
+    /// Returns the header rules in the index applied by `handle_request`
+    pub(crate) fn response_header_rules(&self) -> Result<Vec<HeaderRule>, Error> {
+        Ok(self
+            .reserved_entry(HEADERS_PATH)?
+            .map(|md| md.header_rules)
+            .unwrap_or_default())
+    }
+
+    fn reserved_entry(&self, path: &str) -> Result<Option<AssetMetadata>, Error> {
+        Ok(self.index_map()?.get(path).cloned())
+    }
+
+    /// Returns the metadata for the path, or, if lookup options fold paths, for the
+    /// index path with the same folded form
+    fn find(&self, map: &AssetIndex, path: &str) -> Option<AssetMetadata> {
+        if let Some(md) = find(map, path) {
+            return Some(md);
+        }
+        if !self.lookup_options.folds() {
+            return None;
+        }
+        let folded = self
+            .folded
+            .get_or_init(|| self.lookup_options.fold_index(map));
+        find(map, folded.get(&self.lookup_options.fold(path))?)
     }
 
     /// Lookup asset in worker kV storage.
@@ -120,148 +1259,216 @@ impl<'ah> KVAssets<'ah> {
     /// - the asset was deleted from KV
     /// - the value timed out via TTL
     /// - the index is out of date
-    pub async fn get_kv_value(&self, key: &str) -> Result<bytes::Bytes, Error> {
-        self.kv.get_kv_value(key).await
+    pub async fn get_kv_value<K: ToAssetKey + ?Sized>(
+        &self,
+        key: &K,
+    ) -> Result<bytes::Bytes, Error> {
+        self.store.get_value(key.to_asset_key()?.as_str()).await
     }
 
-    /// Store a value in KV. Optionally, set expiration TTL, number of seconds in future
-    /// when content should be automatically deleted. TTL must be at least 60.
-    pub async fn put_kv_value<T: Into<reqwest::Body>>(
+    /// Lookup asset in worker KV storage, returning the value as a stream
+    /// (see `AssetStore::get_stream`)
+    pub fn get_kv_stream<K: ToAssetKey + ?Sized>(&self, key: &K) -> Result<ByteStream<'_>, Error> {
+        Ok(self.store.get_stream(key.to_asset_key()?.as_str()))
+    }
+
+    /// Gets the asset at the path, like `get_asset`, but returns its body as a stream, so large
+    /// assets can be sent to the client without holding them in memory. Chunked assets are
+    /// read one chunk at a time, and the stream ends with an error if they don't add up to
+    /// the asset's size. If integrity checks are enabled (see `with_integrity_check`), the
+    /// stream ends with `Error::IntegrityMismatch` if the content doesn't match the digest;
+    /// the parts before it have already been sent, so the response should be aborted.
+    /// The asset cache is not used.
+    /// Returns Ok(None) if the asset is not in the index.
+    pub fn get_asset_stream<K: ToAssetKey + ?Sized>(
         &self,
-        key: &str,
-        val: T,
-        expiration_ttl: Option<u64>,
-    ) -> Result<(), Error> {
-        self.kv.put_kv_value(key, val, expiration_ttl).await
+        path: &K,
+    ) -> Result<Option<(AssetMetadata, ByteStream<'_>)>, Error> {
+        let md = match self.lookup_key(path)? {
+            Some(md) if md.protected => return Err(Error::SignatureRequired(md.path)),
+            Some(md) => md,
+            None => return Ok(None),
+        };
+        let body: ByteStream<'_> = if let Some(inline) = &md.inline {
+            let doc = bytes::Bytes::from(inline.clone());
+            Box::pin(stream::once(async move { Ok(doc) }))
+        } else if md.chunks > 0 {
+            let store = self.store_for(&md.path);
+            let path = md.path.clone();
+            let read = Rc::new(Cell::new(0u64));
+            let counted = read.clone();
+            let expected = md.clone();
+            // after the last chunk, fail if the chunks don't add up to the asset's size
+            let check = stream::once(async move {
+                (read.get() != expected.size)
+                    .then(|| Err(chunk_size_mismatch(&expected, read.get())))
+            })
+            .filter_map(future::ready);
+            Box::pin(
+                stream::iter(0..md.chunks)
+                    .map(move |n| store.get_stream(&chunk_key(&path, n)))
+                    .flatten()
+                    .inspect(move |part| {
+                        if let Ok(part) = part {
+                            counted.set(counted.get() + part.len() as u64);
+                        }
+                    })
+                    .chain(check),
+            )
+        } else {
+            self.store_for(&md.path).get_stream(&md.path)
+        };
+        #[cfg(feature = "integrity")]
+        if let (true, Some(digest), None) = (self.verify_integrity, md.sha256, &md.inline) {
+            return Ok(Some((md.clone(), verify_stream(body, digest, md.path))));
+        }
+        Ok(Some((md, body)))
     }
 }
 
-impl KV {
-    /// Lookup asset in worker kV storage.
-    /// If the key passed had been obtained from lookup_key, but the value was not found,
-    /// then one of the following occurred:
-    /// - the asset was deleted from KV
-    /// - the value timed out via TTL
-    /// - the index is out of date
-    pub async fn get_kv_value(&self, key: &str) -> Result<bytes::Bytes, Error> {
-        let url = format!(
-            "{}/accounts/{}/storage/kv/namespaces/{}/values/{}",
-            CLOUDFLARE_KV_ENDPOINT, &self.account_id, &self.namespace_id, key
-        );
-        let client = reqwest::Client::new();
-        let response = client
-            .get(&url)
-            .header("Authorization", format!("Bearer {}", self.auth_token))
-            .send()
-            .await
-            .map_err(|e| Error::KVHttp(e.to_string(), String::new()))?;
-        match response.status().is_success() {
-            false => Err(Error::KVKeyNotFound(
-                key.to_string(),
-                response.status().as_u16(),
-            )),
-            true => Ok(response
-                .bytes()
-                .await
-                .map_err(|e| Error::KVHttp(e.to_string(), String::new()))?),
+/// Hashes the parts of the stream, and, after the last part, fails with
+/// `Error::IntegrityMismatch` if the content doesn't match the digest
+#[cfg(feature = "integrity")]
+fn verify_stream(body: ByteStream<'_>, digest: [u8; 32], path: String) -> ByteStream<'_> {
+    use sha2::{Digest, Sha256};
+
+    let hasher = Rc::new(RefCell::new(Sha256::new()));
+    let hashing = hasher.clone();
+    let check = stream::once(async move {
+        let hasher = std::mem::take(&mut *hasher.borrow_mut());
+        (hasher.finalize().as_slice() != digest).then_some(Err(Error::IntegrityMismatch(path)))
+    })
+    .filter_map(future::ready);
+    Box::pin(
+        body.inspect(move |part| {
+            if let Ok(part) = part {
+                hashing.borrow_mut().update(part);
+            }
+        })
+        .chain(check),
+    )
+}
+
+/// Reads the asset from the store, checking its digest if `verify` is set
+async fn read_verified<S: AssetStore>(
+    store: &S,
+    md: &AssetMetadata,
+    verify: bool,
+) -> Result<bytes::Bytes, Error> {
+    let doc = read_value(store, md).await?;
+    #[cfg(feature = "integrity")]
+    if let (true, Some(digest)) = (verify, md.sha256.as_ref()) {
+        use sha2::{Digest, Sha256};
+        if Sha256::digest(&doc).as_slice() != digest {
+            let doc = read_value(store, md).await?;
+            if Sha256::digest(&doc).as_slice() != digest {
+                return Err(Error::IntegrityMismatch(md.path.clone()));
+            }
+            return Ok(doc);
         }
     }
+    #[cfg(not(feature = "integrity"))]
+    let _ = verify;
+    Ok(doc)
+}
 
-    /// Delete the key at path.
-    pub async fn delete_kv_value(&self, key: &str) -> Result<(), Error> {
-        let url = format!(
-            "{}/accounts/{}/storage/kv/namespaces/{}/values/{}",
-            CLOUDFLARE_KV_ENDPOINT, &self.account_id, &self.namespace_id, key,
-        );
-        let client = reqwest::Client::new();
-        let resp = client
-            .delete(&url)
-            .header("Authorization", format!("Bearer {}", self.auth_token))
-            .send()
-            .await
-            .map_err(|e| Error::KVHttp(e.to_string(), String::new()))?;
-        let status = resp.status();
-        let bytes = resp
-            .bytes()
-            .await
-            .map_err(|e| Error::KVHttp(e.to_string(), String::new()))?;
-        if !status.is_success() {
-            return Err(Error::KVHttpStatus(
-                status.as_u16(),
-                String::from_utf8_lossy(&bytes).to_string(),
-            ));
+async fn read_value<S: AssetStore>(store: &S, md: &AssetMetadata) -> Result<bytes::Bytes, Error> {
+    if md.chunks > 0 {
+        get_chunks(store, md).await
+    } else {
+        store.get_value(&md.path).await
+    }
+}
+
+/// Reads the chunks of a chunked asset, in order, and concatenates them
+async fn get_chunks<S: AssetStore>(store: &S, md: &AssetMetadata) -> Result<bytes::Bytes, Error> {
+    let mut doc = bytes::BytesMut::with_capacity(md.size as usize);
+    for n in 0..md.chunks {
+        doc.extend_from_slice(&store.get_value(&chunk_key(&md.path, n)).await?);
+    }
+    if doc.len() as u64 != md.size {
+        return Err(chunk_size_mismatch(md, doc.len() as u64));
+    }
+    Ok(doc.freeze())
+}
+
+/// Reads a stale asset cache entry again, replacing it, or dropping it if its value is
+/// no longer in KV. Returns true if it was replaced.
+async fn refresh_entry<S: AssetStore>(
+    store: &S,
+    cache: &Lock<AssetCache>,
+    md: &AssetMetadata,
+    verify: bool,
+) -> Result<bool, Error> {
+    match read_verified(store, md, verify).await {
+        Ok(doc) => {
+            cache.borrow_mut().insert(&md.path, doc);
+            Ok(true)
+        }
+        Err(Error::KVKeyNotFound(_, _)) => {
+            cache.borrow_mut().remove(&md.path);
+            Ok(false)
+        }
+        Err(e) => {
+            cache.borrow_mut().end_refresh(&md.path);
+            Err(e)
         }
-        Ok(())
     }
+}
 
-    /// Store a value in KV. Optionally, set expiration TTL, number of seconds in future
-    /// when content should be automatically deleted. TTL must be at least 60.
-    pub async fn put_kv_value<T: Into<reqwest::Body>>(
-        &self,
-        key: &str,
-        val: T,
-        expiration_ttl: Option<u64>,
-    ) -> Result<(), Error> {
-        let url = format!(
-            "{}/accounts/{}/storage/kv/namespaces/{}/values/{}{}",
-            CLOUDFLARE_KV_ENDPOINT,
-            &self.account_id,
-            &self.namespace_id,
-            key,
-            match expiration_ttl {
-                Some(ttl) => {
-                    if ttl < 60 {
-                        return Err(Error::TTLTooShort);
-                    }
-                    format!("?expiration_ttl={}", ttl)
-                }
-                None => String::from(""),
-            }
-        );
+/// Error for a chunked asset whose chunks don't add up to its size in the index
+fn chunk_size_mismatch(md: &AssetMetadata, read: u64) -> Error {
+    Error::Message(format!(
+        "asset {} has {} bytes in {} chunks, expected {}",
+        md.path, read, md.chunks, md.size
+    ))
+}
 
-        let client = reqwest::Client::new();
-        let resp = client
-            .put(&url)
-            .header("Authorization", format!("Bearer {}", self.auth_token))
-            .body(val)
-            .send()
-            .await
-            .map_err(|e| Error::KVHttp(e.to_string(), String::new()))?;
-        let status = resp.status();
-        let bytes = resp
-            .bytes()
-            .await
-            .map_err(|e| Error::KVHttp(e.to_string(), String::new()))?;
-        if !status.is_success() {
-            return Err(Error::KVHttpStatus(
-                status.as_u16(),
-                String::from_utf8_lossy(&bytes).to_string(),
-            ));
-        }
-        let resp: WriteKVResponse = match serde_json::from_slice(&bytes) {
-            Ok(wr) => Ok(wr),
-            Err(e) => Err(Error::KVHttp(
-                e.to_string(),
-                String::from_utf8_lossy(&bytes).to_string(),
-            )),
-        }?;
-        if resp.success {
-            Ok(())
-        } else {
-            Err(Error::Message(format!(
-                "writing key {}: errors:{:?} messages:{:?}",
-                key, resp.errors, resp.messages
-            )))
+/// Returns true for index entries that hold settings, such as redirect rules,
+/// rather than assets. Their paths start with NUL, so they can't be requested.
+pub(crate) fn is_reserved(path: &str) -> bool {
+    path.starts_with('\0')
+}
+
+/// Adds the redirect and header rules of a mounted index's reserved entry to those of
+/// the merged index, with the mount's prefix applied to their paths. Other settings,
+/// such as error documents, apply to the whole site, so only the main index's are used.
+fn merge_mounted_rules(merged: &mut AssetIndex, prefix: &str, path: &str, md: AssetMetadata) {
+    let entry = || AssetMetadata::default();
+    match path {
+        REDIRECTS_PATH => {
+            let rules = md.redirects.into_iter().map(|rule| RedirectRule {
+                from: format!("/{}{}", prefix, rule.from.trim_start_matches('/')),
+                to: match rule.to.starts_with('/') {
+                    true => format!("/{}{}", prefix, &rule.to[1..]),
+                    false => rule.to,
+                },
+                status: rule.status,
+            });
+            let rules_entry = merged.entry(path.to_string()).or_insert_with(entry);
+            rules_entry.redirects.extend(rules);
+        }
+        HEADERS_PATH => {
+            let rules = md.header_rules.into_iter().map(|rule| HeaderRule {
+                // patterns without '/' match file names in any directory of the mount
+                pattern: match rule.pattern.contains('/') {
+                    true => format!("/{}{}", prefix, rule.pattern.trim_start_matches('/')),
+                    false => format!("/{}**/{}", prefix, rule.pattern),
+                },
+                headers: rule.headers,
+            });
+            let rules_entry = merged.entry(path.to_string()).or_insert_with(entry);
+            rules_entry.header_rules.extend(rules);
         }
+        _ => {}
     }
 }
 
-#[derive(Debug, Deserialize)]
-#[allow(clippy::upper_case_acronyms)]
-struct WriteKVResponse {
-    success: bool,
-    errors: Vec<serde_json::Value>,
-    messages: Vec<serde_json::Value>,
+/// Returns the metadata for the path, unless it's missing or expired
+fn find(map: &AssetIndex, path: &str) -> Option<AssetMetadata> {
+    map.get(path)
+        .filter(|md| !md.is_expired(clock::now_secs()))
+        .cloned()
 }
 
 /// Tests manifest lookup function (does not invoke cloudflare api)
@@ -271,22 +1478,25 @@ fn test_lookup() {
         path: "a/b.txt".to_string(),
         modified: 10000,
         size: 10,
+        ..Default::default()
     };
     let md_b = AssetMetadata {
         path: "b".to_string(),
         modified: 20000,
         size: 20,
+        ..Default::default()
     };
     let md_c = AssetMetadata {
         path: "c.json".to_string(),
         modified: 30000,
         size: 30,
+        ..Default::default()
     };
     let mut index = AssetIndex::new();
     index.insert("a/b".to_string(), md_ab.clone());
     index.insert("b".to_string(), md_b.clone());
     index.insert("c.json".to_string(), md_c.clone());
-    let blob = bincode::serialize(&index).expect("serialize-index");
+    let blob = crate::encode_index(&index).expect("serialize-index");
 
     let kv = KVAssets::init(&blob, "123", "namespace", "token");
 
@@ -303,4 +1513,790 @@ fn test_lookup() {
 
     // ensure_map
     assert!(kv.ensure_map().is_ok());
+
+    // inexact matches
+    assert_eq!(kv.lookup_key("C.JSON").unwrap(), None);
+    assert_eq!(kv.lookup_key("%61/b").unwrap().as_ref(), Some(&md_ab));
+    assert!(matches!(kv.lookup_key("a%2"), Err(Error::MalformedPath(_))));
+    assert!(matches!(
+        kv.lookup_key("a/../b"),
+        Err(Error::MalformedPath(_))
+    ));
+    let kv = kv.with_lookup_options(LookupOptions::new().with_case_insensitive());
+    assert_eq!(kv.lookup_key("/C.JSON").unwrap().as_ref(), Some(&md_c));
+    assert_eq!(kv.lookup_key("%61/B").unwrap().as_ref(), Some(&md_ab));
+
+    // listing
+    let paths = |found: Vec<(String, AssetMetadata)>| {
+        found.into_iter().map(|(path, _)| path).collect::<Vec<_>>()
+    };
+    assert_eq!(paths(kv.list_keys("").unwrap()), ["a/b", "b", "c.json"]);
+    assert_eq!(paths(kv.list_keys("/a/").unwrap()), ["a/b"]);
+    assert_eq!(paths(kv.glob("*.json").unwrap()), ["c.json"]);
+    assert_eq!(kv.glob("a/*").unwrap(), vec![("a/b".to_string(), md_ab)]);
+}
+
+/// Tests that entries past their expiration time are treated as not found
+#[test]
+fn test_lookup_expired() {
+    let now = clock::now_secs();
+    let md_old = AssetMetadata {
+        path: "old.abc123.txt".to_string(),
+        modified: 10000,
+        size: 10,
+        expires: Some(now - 1),
+        ..Default::default()
+    };
+    let md_new = AssetMetadata {
+        path: "new.def456.txt".to_string(),
+        modified: 10000,
+        size: 10,
+        expires: Some(now + 3600),
+        ..Default::default()
+    };
+    let mut index = AssetIndex::new();
+    index.insert("old.txt".to_string(), md_old);
+    index.insert("new.txt".to_string(), md_new.clone());
+    let blob = crate::encode_index(&index).expect("serialize-index");
+
+    let kv = KVAssets::init(&blob, "123", "namespace", "token");
+    assert_eq!(kv.lookup_key("old.txt").unwrap(), None);
+    assert_eq!(kv.lookup_key("new.txt").unwrap(), Some(md_new));
+}
+
+/// Tests fallback rules and lookup chains
+#[test]
+fn test_fallback() {
+    let md = |path: &str| AssetMetadata {
+        path: path.to_string(),
+        ..Default::default()
+    };
+    let mut index = AssetIndex::new();
+    index.insert("de/page.html".to_string(), md("de/page.1.html"));
+    index.insert("en/page.html".to_string(), md("en/page.2.html"));
+    index.insert("en/about.html".to_string(), md("en/about.3.html"));
+    index.insert("contact.html".to_string(), md("contact.4.html"));
+    let blob = crate::encode_index(&index).expect("serialize-index");
+
+    let kv = KVAssets::init(&blob, "123", "namespace", "token").with_fallback(&["de/", "en/", ""]);
+    assert_eq!(
+        kv.lookup_key("/de/page.html").unwrap(),
+        Some(md("de/page.1.html"))
+    );
+    assert_eq!(
+        kv.lookup_key("/de/about.html").unwrap(),
+        Some(md("en/about.3.html"))
+    );
+    assert_eq!(
+        kv.lookup_key("/de/contact.html").unwrap(),
+        Some(md("contact.4.html"))
+    );
+    assert_eq!(
+        kv.lookup_key("/en/contact.html").unwrap(),
+        Some(md("contact.4.html"))
+    );
+    assert_eq!(kv.lookup_key("/de/missing.html").unwrap(), None);
+    assert_eq!(kv.lookup_key("/fr/page.html").unwrap(), None);
+
+    assert_eq!(
+        kv.lookup_chain(&["fr/page.html", "/en/page.html", "page.html"])
+            .unwrap(),
+        Some(md("en/page.2.html"))
+    );
+    assert_eq!(kv.lookup_chain(&["fr/page.html"]).unwrap(), None);
+    assert!(matches!(kv.lookup_key("/"), Err(Error::EmptyKey)));
+
+    let kv = kv.with_directory_index(&["index.htm", "page.html"]);
+    assert_eq!(kv.lookup_key("/en/").unwrap(), Some(md("en/page.2.html")));
+    assert_eq!(kv.lookup_key("/de").unwrap(), Some(md("de/page.1.html")));
+    assert_eq!(kv.lookup_key("/").unwrap(), None);
+    assert_eq!(kv.lookup_key("/fr/").unwrap(), None);
+
+    let kv = kv.with_spa_fallback("/contact.html");
+    assert_eq!(
+        kv.lookup_key("/app/settings").unwrap(),
+        Some(md("contact.4.html"))
+    );
+    assert_eq!(
+        kv.lookup_key("/de/about.html").unwrap(),
+        Some(md("en/about.3.html"))
+    );
+    assert_eq!(kv.lookup_key("/").unwrap(), Some(md("contact.4.html")));
+    assert_eq!(kv.lookup_chain(&["fr/page.html"]).unwrap(), None);
+}
+
+/// Tests trailing slash policies
+#[test]
+fn test_trailing_slash() {
+    use crate::{Redirect, Resolved};
+
+    let md = |path: &str| AssetMetadata {
+        path: path.to_string(),
+        ..Default::default()
+    };
+    let mut index = AssetIndex::new();
+    index.insert("about".to_string(), md("about.1.html"));
+    index.insert("about/index.html".to_string(), md("about/index.2.html"));
+    index.insert("docs/index.html".to_string(), md("docs/index.3.html"));
+    let blob = crate::encode_index(&index).expect("serialize-index");
+
+    let kv =
+        KVAssets::init(&blob, "123", "namespace", "token").with_directory_index(&["index.html"]);
+    assert_eq!(kv.lookup_key("/about").unwrap(), Some(md("about.1.html")));
+    assert_eq!(
+        kv.lookup_key("/about/").unwrap(),
+        Some(md("about/index.2.html"))
+    );
+
+    let kv = kv.with_trailing_slash(TrailingSlash::Strip);
+    assert_eq!(kv.lookup_key("/about/").unwrap(), Some(md("about.1.html")));
+    assert_eq!(
+        kv.lookup_key("/docs").unwrap(),
+        Some(md("docs/index.3.html"))
+    );
+
+    let kv = kv.with_trailing_slash(TrailingSlash::Add);
+    assert_eq!(
+        kv.lookup_key("/about").unwrap(),
+        Some(md("about/index.2.html"))
+    );
+
+    let kv = kv.with_trailing_slash(TrailingSlash::RedirectToCanonical);
+    let redirect = |location: &str| {
+        Some(Resolved::Redirect(Redirect {
+            location: location.to_string(),
+            status: 301,
+        }))
+    };
+    assert_eq!(kv.resolve("/about/").unwrap(), redirect("/about"));
+    assert_eq!(kv.resolve("/docs").unwrap(), redirect("/docs/"));
+    assert_eq!(
+        kv.resolve("/about").unwrap(),
+        Some(Resolved::Asset(md("about.1.html")))
+    );
+    assert_eq!(
+        kv.resolve("/docs/").unwrap(),
+        Some(Resolved::Asset(md("docs/index.3.html")))
+    );
+    assert_eq!(kv.resolve("/missing/").unwrap(), None);
+}
+
+/// Tests merging of multiple indexes
+#[test]
+fn test_with_index() {
+    let md = |path: &str| AssetMetadata {
+        path: path.to_string(),
+        ..Default::default()
+    };
+    let index = |entries: &[(&str, &str)]| {
+        let index: AssetIndex = entries
+            .iter()
+            .map(|(path, key)| (path.to_string(), md(key)))
+            .collect();
+        crate::encode_index(&index).expect("serialize-index")
+    };
+    let app = index(&[("index.html", "index.1.html"), ("app.js", "app.1.js")]);
+    let docs = index(&[
+        ("index.html", "index.2.html"),
+        ("docs/a.html", "docs/a.2.html"),
+    ]);
+    let hotfix = index(&[("app.js", "app.3.js")]);
+
+    let kv = KVAssets::init(&app, "123", "namespace", "token")
+        .with_index(&docs, Precedence::Lower)
+        .with_index(&hotfix, Precedence::Higher);
+    assert_eq!(
+        kv.lookup_key("index.html").unwrap(),
+        Some(md("index.1.html"))
+    );
+    assert_eq!(
+        kv.lookup_key("docs/a.html").unwrap(),
+        Some(md("docs/a.2.html"))
+    );
+    assert_eq!(kv.lookup_key("app.js").unwrap(), Some(md("app.3.js")));
+
+    // an invalid index is an error
+    let kv =
+        KVAssets::init(&app, "123", "namespace", "token").with_index(b"xyz", Precedence::Lower);
+    assert!(kv.lookup_key("index.html").is_err());
+}
+
+/// Tests dispatch to mounted indexes by longest prefix
+#[test]
+fn test_mount() {
+    let md = |path: &str| AssetMetadata {
+        path: path.to_string(),
+        ..Default::default()
+    };
+    let index = |entries: &[(&str, &str)]| {
+        let index: AssetIndex = entries
+            .iter()
+            .map(|(path, key)| (path.to_string(), md(key)))
+            .collect();
+        crate::encode_index(&index).expect("serialize-index")
+    };
+    let app = index(&[
+        ("index.html", "index.1.html"),
+        ("docs/old.html", "old.1.html"),
+    ]);
+    let docs = index(&[("index.html", "index.2.html"), ("api/a.html", "a.2.html")]);
+    let api = index(&[("b.html", "b.3.html")]);
+
+    let kv = KVAssets::init(&app, "123", "namespace", "token")
+        .with_mount("/docs/api", &api)
+        .with_mount("/docs", &docs);
+    assert_eq!(
+        kv.lookup_key("/index.html").unwrap(),
+        Some(md("index.1.html"))
+    );
+    assert_eq!(
+        kv.lookup_key("/docs/index.html").unwrap(),
+        Some(md("index.2.html"))
+    );
+    assert_eq!(kv.lookup_key("/docs/old.html").unwrap(), None);
+    assert_eq!(kv.lookup_key("/docs/api/a.html").unwrap(), None);
+    assert_eq!(
+        kv.lookup_key("/docs/api/b.html").unwrap(),
+        Some(md("b.3.html"))
+    );
+    assert_eq!(kv.list_keys("docs/").unwrap().len(), 2);
+}
+
+/// Tests that the redirects and headers of a mounted index apply under its prefix
+#[test]
+fn test_mount_rules() {
+    use crate::{policy::headers_for, Redirect, Resolved};
+
+    let mut docs = AssetIndex::new();
+    docs.insert(
+        "a.html".to_string(),
+        AssetMetadata {
+            path: "a.1.html".to_string(),
+            ..Default::default()
+        },
+    );
+    docs.insert(
+        REDIRECTS_PATH.to_string(),
+        AssetMetadata {
+            redirects: vec![RedirectRule {
+                from: "/old".to_string(),
+                to: "/a.html".to_string(),
+                status: 301,
+            }],
+            ..Default::default()
+        },
+    );
+    docs.insert(
+        HEADERS_PATH.to_string(),
+        AssetMetadata {
+            header_rules: vec![HeaderRule {
+                pattern: "*.html".to_string(),
+                headers: [("X-Docs".to_string(), "1".to_string())].into(),
+            }],
+            ..Default::default()
+        },
+    );
+    let docs = crate::encode_index(&docs).expect("serialize-index");
+    let app = crate::encode_index(&AssetIndex::new()).expect("serialize-index");
+
+    let kv = KVAssets::init(&app, "123", "namespace", "token").with_mount("/docs", &docs);
+    assert_eq!(kv.list_keys("docs/").unwrap().len(), 1);
+    assert_eq!(
+        kv.resolve("/docs/old").unwrap(),
+        Some(Resolved::Redirect(Redirect {
+            location: "/docs/a.html".to_string(),
+            status: 301,
+        }))
+    );
+    let rules = kv.response_header_rules().unwrap();
+    assert_eq!(rules[0].pattern, "/docs/**/*.html");
+    assert_eq!(headers_for(&rules, "docs/a.html").len(), 1);
+    assert!(headers_for(&rules, "a.html").is_empty());
+}
+
+/// Tests reading mounted assets from their own store (does not invoke cloudflare api)
+#[cfg(all(test, not(target_arch = "wasm32")))]
+#[tokio::test]
+async fn test_mount_store() {
+    let dir = std::env::temp_dir().join(format!("kv-assets-mount-{}", std::process::id()));
+    let main = crate::LocalKV::open(dir.join("main")).unwrap();
+    let other = crate::LocalKV::open(dir.join("other")).unwrap();
+    main.put("a.1.txt", b"main", None).unwrap();
+    other.put("a.2.txt", b"other", None).unwrap();
+    let mut index = AssetIndex::new();
+    index.insert(
+        "a.txt".to_string(),
+        AssetMetadata {
+            path: "a.1.txt".to_string(),
+            size: 4,
+            ..Default::default()
+        },
+    );
+    let blob = crate::encode_index(&index).expect("serialize-index");
+    let mut mounted = index.clone();
+    mounted.get_mut("a.txt").unwrap().path = "a.2.txt".to_string();
+    let mounted = crate::encode_index(&mounted).expect("serialize-index");
+
+    let kv = KVAssets::with_store(&blob, main).with_mount_store("/b", &mounted, other);
+    assert_eq!(
+        kv.get_asset("a.txt").await.unwrap().unwrap().as_ref(),
+        b"main"
+    );
+    assert_eq!(
+        kv.get_asset("b/a.txt").await.unwrap().unwrap().as_ref(),
+        b"other"
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Tests that handlers created with `cached` share the parsed index
+#[test]
+fn test_cached_index() {
+    let mut index = AssetIndex::new();
+    index.insert(
+        "a.txt".to_string(),
+        AssetMetadata {
+            path: "a.1.txt".to_string(),
+            ..Default::default()
+        },
+    );
+    let blob: &'static [u8] = Box::leak(
+        crate::encode_index(&index)
+            .expect("serialize-index")
+            .into_boxed_slice(),
+    );
+    let shared = |kv: &KVAssets<'static, KV>| {
+        kv.lookup_key("a.txt").unwrap().unwrap();
+        kv.map.get().unwrap().clone()
+    };
+
+    let first = KVAssets::cached(blob, init_kv("123", "namespace", "token"));
+    let second = KVAssets::cached(blob, init_kv("123", "namespace", "token"));
+    assert!(Shared::ptr_eq(&shared(&first), &shared(&second)));
+    let uncached = KVAssets::init(blob, "123", "namespace", "token");
+    assert!(!Shared::ptr_eq(&shared(&first), &shared(&uncached)));
+    let merged = KVAssets::cached(blob, init_kv("123", "namespace", "token"))
+        .with_index(blob, Precedence::Lower);
+    assert!(!Shared::ptr_eq(&shared(&first), &shared(&merged)));
+}
+
+/// Tests that handlers can be shared between threads (does not invoke cloudflare api)
+#[cfg(all(test, feature = "thread-safe", not(target_arch = "wasm32")))]
+#[test]
+fn test_thread_safe() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<KVAssets<'static>>();
+    assert_send_sync::<KVAssets<'static, crate::LocalKV>>();
+
+    let mut index = AssetIndex::new();
+    index.insert(
+        "a.txt".to_string(),
+        AssetMetadata {
+            path: "a.1.txt".to_string(),
+            ..Default::default()
+        },
+    );
+    let blob = crate::encode_index(&index).expect("serialize-index");
+    let kv = KVAssets::init(&blob, "123", "namespace", "token");
+    std::thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| assert!(kv.lookup_key("a.txt").unwrap().is_some()));
+        }
+    });
+}
+
+/// Tests handlers with an index obtained at runtime (does not invoke cloudflare api)
+#[cfg(all(test, not(target_arch = "wasm32")))]
+#[tokio::test]
+async fn test_from_owned() {
+    let mut index = AssetIndex::new();
+    index.insert(
+        "a.txt".to_string(),
+        AssetMetadata {
+            path: "a.1.txt".to_string(),
+            inline: Some(b"hello".to_vec()),
+            ..Default::default()
+        },
+    );
+    // the index is dropped by the caller, so the handler must own it
+    let owned = || -> KVAssets<'static> {
+        let blob = crate::encode_index(&index).expect("serialize-index");
+        KVAssets::from_owned(blob, init_kv("123", "namespace", "token"))
+    };
+    let kv = owned();
+    assert_eq!(kv.lookup_key("a.txt").unwrap().unwrap().path, "a.1.txt");
+    assert_eq!(
+        kv.get_asset("a.txt").await.unwrap().unwrap().as_ref(),
+        b"hello"
+    );
+}
+
+/// Tests that serving hooks are invoked (does not invoke cloudflare api)
+#[cfg(all(test, not(target_arch = "wasm32")))]
+#[tokio::test]
+async fn test_hooks() {
+    use std::sync::{Arc, Mutex};
+
+    let blob = crate::encode_index(&AssetIndex::new()).expect("serialize-index");
+    let misses = Arc::new(Mutex::new(Vec::new()));
+    let errors = Arc::new(Mutex::new(0));
+    let (m, e) = (misses.clone(), errors.clone());
+    let kv = KVAssets::init(&blob, "123", "namespace", "token")
+        .on_hit(|_, _| panic!("unexpected hit"))
+        .on_miss(move |path| m.lock().unwrap().push(path.to_string()))
+        .on_error(move |_, _| *e.lock().unwrap() += 1);
+
+    assert_eq!(kv.get_asset("/missing.txt").await.unwrap(), None);
+    assert!(kv.get_asset("/").await.is_err());
+    assert_eq!(*misses.lock().unwrap(), vec!["/missing.txt".to_string()]);
+    assert_eq!(*errors.lock().unwrap(), 1);
+}
+
+/// Tests that inline assets are served from the index (does not invoke cloudflare api)
+#[cfg(all(test, not(target_arch = "wasm32")))]
+#[tokio::test]
+async fn test_inline() {
+    let mut index = AssetIndex::new();
+    index.insert(
+        "robots.txt".to_string(),
+        AssetMetadata {
+            path: "robots.abc123.txt".to_string(),
+            size: 9,
+            inline: Some(b"User-agent: *".to_vec()),
+            ..Default::default()
+        },
+    );
+    let blob = crate::encode_index(&index).expect("serialize-index");
+    // the account and namespace are invalid, so this would fail if it read KV
+    let kv = KVAssets::init(&blob, "123", "namespace", "token");
+    assert_eq!(
+        kv.get_asset("/robots.txt").await.unwrap().unwrap().as_ref(),
+        b"User-agent: *"
+    );
+}
+
+/// Tests that chunked assets are reassembled (does not invoke cloudflare api)
+#[cfg(all(test, not(target_arch = "wasm32")))]
+#[tokio::test]
+async fn test_chunked() {
+    let dir = std::env::temp_dir().join(format!("kv-assets-chunked-{}", std::process::id()));
+    let store = crate::LocalKV::open(&dir).unwrap();
+    store
+        .put(&chunk_key("big.abc123.bin", 0), b"0123", None)
+        .unwrap();
+    store
+        .put(&chunk_key("big.abc123.bin", 1), b"45", None)
+        .unwrap();
+    let mut index = AssetIndex::new();
+    let md = AssetMetadata {
+        path: "big.abc123.bin".to_string(),
+        size: 6,
+        chunks: 2,
+        ..Default::default()
+    };
+    index.insert("big.bin".to_string(), md.clone());
+    index.insert("short.bin".to_string(), AssetMetadata { size: 7, ..md });
+    let blob = crate::encode_index(&index).expect("serialize-index");
+
+    let kv = KVAssets::with_store(&blob, store);
+    assert_eq!(
+        kv.get_asset("/big.bin").await.unwrap().unwrap().as_ref(),
+        b"012345"
+    );
+    assert!(kv.get_asset("/short.bin").await.is_err());
+
+    let (_, body) = kv.get_asset_stream("/big.bin").unwrap().unwrap();
+    let parts: Vec<bytes::Bytes> = body.map(|part| part.unwrap()).collect().await;
+    assert_eq!(parts, vec![&b"0123"[..], &b"45"[..]]);
+    assert!(kv.get_asset_stream("/missing.bin").unwrap().is_none());
+    let (_, body) = kv.get_asset_stream("/short.bin").unwrap().unwrap();
+    let parts: Vec<Result<bytes::Bytes, Error>> = body.collect().await;
+    assert!(matches!(parts.last(), Some(Err(Error::Message(_)))));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Tests that cached assets are served without reading the store
+#[cfg(all(test, not(target_arch = "wasm32")))]
+#[tokio::test]
+async fn test_asset_cache() {
+    let dir = std::env::temp_dir().join(format!("kv-assets-cache-{}", std::process::id()));
+    let store = crate::LocalKV::open(&dir).unwrap();
+    store.put("a.abc.txt", b"hello", None).unwrap();
+    let mut index = AssetIndex::new();
+    index.insert(
+        "a.txt".to_string(),
+        AssetMetadata {
+            path: "a.abc.txt".to_string(),
+            size: 5,
+            ..Default::default()
+        },
+    );
+    let blob = crate::encode_index(&index).expect("serialize-index");
+
+    let kv = KVAssets::with_store(&blob, crate::LocalKV::open(&dir).unwrap()).with_asset_cache(
+        AssetCacheConfig {
+            max_entries: 10,
+            max_bytes: 1000,
+            ttl: None,
+            stale_after: None,
+        },
+    );
+    let stale = KVAssets::with_store(&blob, crate::LocalKV::open(&dir).unwrap()).with_asset_cache(
+        AssetCacheConfig {
+            max_entries: 10,
+            max_bytes: 1000,
+            ttl: None,
+            stale_after: Some(0),
+        },
+    );
+    assert_eq!(
+        kv.get_asset("a.txt").await.unwrap().unwrap().as_ref(),
+        b"hello"
+    );
+    assert!(stale.get_asset("a.txt").await.unwrap().is_some());
+    assert!(stale.get_asset("a.txt").await.unwrap().is_some());
+    assert_eq!(stale.revalidate().await.unwrap(), 1);
+    assert_eq!(stale.revalidate().await.unwrap(), 0);
+    // with a spawner, serving a stale entry spawns one read of it
+    let spawned = Shared::new(Lock::new(Vec::new()));
+    let queue = spawned.clone();
+    let background = KVAssets::with_store(&blob, crate::LocalKV::open(&dir).unwrap())
+        .with_asset_cache(AssetCacheConfig {
+            max_entries: 10,
+            max_bytes: 1000,
+            ttl: None,
+            stale_after: Some(0),
+        })
+        .with_refresh_spawner(move |refresh| queue.borrow_mut().push(refresh));
+    for _ in 0..3 {
+        assert!(background.get_asset("a.txt").await.unwrap().is_some());
+    }
+    assert_eq!(spawned.borrow_mut().len(), 1);
+    assert_eq!(background.revalidate().await.unwrap(), 0);
+    let refresh = spawned.borrow_mut().pop().unwrap();
+    refresh.await;
+    store.delete("a.abc.txt").unwrap();
+    assert_eq!(
+        kv.get_asset("a.txt").await.unwrap().unwrap().as_ref(),
+        b"hello"
+    );
+    // the stale copy is served, then dropped when it's not found in KV
+    assert!(stale.get_asset("a.txt").await.unwrap().is_some());
+    assert_eq!(stale.revalidate().await.unwrap(), 0);
+    assert!(stale.get_asset("a.txt").await.is_err());
+    assert!(background.get_asset("a.txt").await.unwrap().is_some());
+    let refresh = spawned.borrow_mut().pop().unwrap();
+    refresh.await;
+    assert!(background.get_asset("a.txt").await.is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Tests compression on the fly (does not invoke cloudflare api)
+#[cfg(all(test, feature = "compression", not(target_arch = "wasm32")))]
+#[tokio::test]
+async fn test_compression() {
+    let md = |content_type: &str| AssetMetadata {
+        path: "x".to_string(),
+        content_type: Some(content_type.to_string()),
+        inline: Some("hello ".repeat(100).into_bytes()),
+        ..Default::default()
+    };
+    let mut index = AssetIndex::new();
+    index.insert("app.js".to_string(), md("application/javascript"));
+    index.insert("data.csv".to_string(), md("text/csv"));
+    let blob = crate::encode_index(&index).expect("serialize-index");
+    let kv = KVAssets::init(&blob, "123", "namespace", "token")
+        .with_compression(100)
+        .with_compression_types(&["text/"]);
+    let coding = |path: &'static str, accept: &'static str| {
+        let kv = &kv;
+        async move {
+            kv.get_asset_encoded(path, Some(accept))
+                .await
+                .unwrap()
+                .unwrap()
+                .content_encoding
+        }
+    };
+    assert_eq!(coding("data.csv", "gzip").await, Some("gzip"));
+    #[cfg(feature = "brotli")]
+    assert_eq!(coding("data.csv", "gzip, br").await, Some("br"));
+    assert_eq!(coding("data.csv", "identity").await, None);
+    assert_eq!(coding("app.js", "gzip").await, None);
+}
+
+/// Tests that precompressed variants are chosen by Accept-Encoding
+#[cfg(all(test, not(target_arch = "wasm32")))]
+#[tokio::test]
+async fn test_precompressed() {
+    let dir = std::env::temp_dir().join(format!("kv-assets-encoded-{}", std::process::id()));
+    let store = crate::LocalKV::open(&dir).unwrap();
+    store.put("app.abc.js", b"plain", None).unwrap();
+    store.put("app.js.def.br", b"brotli", None).unwrap();
+    let mut index = AssetIndex::new();
+    index.insert(
+        "app.js".to_string(),
+        AssetMetadata {
+            path: "app.abc.js".to_string(),
+            size: 5,
+            encoded: vec![EncodedVariant {
+                encoding: "br".to_string(),
+                path: "app.js.def.br".to_string(),
+                size: 6,
+                chunks: 0,
+            }],
+            ..Default::default()
+        },
+    );
+    let blob = crate::encode_index(&index).expect("serialize-index");
+
+    let kv = KVAssets::with_store(&blob, store).with_cors(CorsPolicy::new(&["https://a.org"]));
+    let encoded = kv
+        .get_asset_encoded("app.js", Some("gzip, br"))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(encoded.content_encoding, Some("br"));
+    assert_eq!(encoded.body.as_ref(), b"brotli");
+    let encoded = kv
+        .get_asset_encoded("app.js", Some("gzip"))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(encoded.content_encoding, None);
+    assert_eq!(encoded.body.as_ref(), b"plain");
+    assert_eq!(
+        kv.get_asset_encoded("x.js", Some("br")).await.unwrap(),
+        None
+    );
+
+    let headers = [("Accept-Encoding", "br"), ("Origin", "https://a.org")];
+    let resp = kv.handle_request("GET", "/app.js", &headers).await.unwrap();
+    assert_eq!(resp.header("vary"), Some("Accept-Encoding, Origin"));
+    assert_eq!(resp.variant.encoding.as_deref(), Some("br"));
+    assert_eq!(
+        resp.variant.cache_key("https://a.org/app.js?v=1"),
+        "https://a.org/app.js?v=1&variant=br"
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Tests loading the index from the store (does not invoke cloudflare api)
+#[cfg(all(test, not(target_arch = "wasm32")))]
+#[tokio::test]
+async fn test_load_from_kv() {
+    let dir = std::env::temp_dir().join(format!("kv-assets-load-{}", std::process::id()));
+    let store = crate::LocalKV::open(&dir).unwrap();
+    let mut index = AssetIndex::new();
+    index.insert(
+        "robots.txt".to_string(),
+        AssetMetadata {
+            path: "robots.abc123.txt".to_string(),
+            size: 13,
+            inline: Some(b"User-agent: *".to_vec()),
+            ..Default::default()
+        },
+    );
+    let blob = crate::encode_index(&index).expect("serialize-index");
+    store.put("_index", &blob, None).unwrap();
+    store.put("_bad", b"xyz", None).unwrap();
+
+    let kv = KVAssets::load_from_kv(store, "_index").await.unwrap();
+    assert_eq!(
+        kv.get_asset("/robots.txt").await.unwrap().unwrap().as_ref(),
+        b"User-agent: *"
+    );
+    let mut index = AssetIndex::new();
+    index.insert(
+        "humans.txt".to_string(),
+        AssetMetadata {
+            path: "humans.def456.txt".to_string(),
+            inline: Some(b"Team".to_vec()),
+            ..Default::default()
+        },
+    );
+    let blob = crate::encode_index(&index).expect("serialize-index");
+    let mut kv = kv;
+    assert!(!kv.refresh_index().await.unwrap());
+    crate::LocalKV::open(&dir)
+        .unwrap()
+        .put("_index", &blob, None)
+        .unwrap();
+    assert!(kv.refresh_index().await.unwrap());
+    assert!(kv.get_asset("/robots.txt").await.unwrap().is_none());
+    assert!(kv.get_asset("/humans.txt").await.unwrap().is_some());
+    let mut embedded = KVAssets::embedded(&blob);
+    assert!(embedded.refresh_index().await.is_err());
+
+    let store = crate::LocalKV::open(&dir).unwrap();
+    assert!(KVAssets::load_from_kv(store, "_bad").await.is_err());
+    let store = crate::LocalKV::open(&dir).unwrap();
+    assert!(KVAssets::load_from_kv(store, "_missing").await.is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Tests that content not matching the digest is rejected (does not invoke cloudflare api)
+#[cfg(all(test, feature = "integrity", not(target_arch = "wasm32")))]
+#[tokio::test]
+async fn test_integrity_check() {
+    use sha2::{Digest, Sha256};
+
+    let dir = std::env::temp_dir().join(format!("kv-assets-integrity-{}", std::process::id()));
+    let store = crate::LocalKV::open(&dir).unwrap();
+    store.put("a.1.txt", b"good", None).unwrap();
+    store.put("b.2.txt", b"truncat", None).unwrap();
+    store.put("a.1.txt.gz", b"not gzip", None).unwrap();
+    let md = |key: &str, content: &[u8]| AssetMetadata {
+        path: key.to_string(),
+        size: content.len() as u64,
+        sha256: Some(Sha256::digest(content).into()),
+        ..Default::default()
+    };
+    let mut index = AssetIndex::new();
+    index.insert("a.txt".to_string(), md("a.1.txt", b"good"));
+    index.insert("b.txt".to_string(), md("b.2.txt", b"truncated"));
+    let mut encoded = md("a.1.txt", b"good");
+    encoded.encoded = vec![crate::EncodedVariant {
+        encoding: "gzip".to_string(),
+        path: "a.1.txt.gz".to_string(),
+        size: 8,
+        chunks: 0,
+    }];
+    encoded.encoded_sha256 = vec![Sha256::digest(b"gzipped").into()];
+    index.insert("c.txt".to_string(), encoded);
+    let blob = crate::encode_index(&index).expect("serialize-index");
+
+    let kv = KVAssets::with_store(&blob, store).with_integrity_check();
+    // a precompressed variant is checked against its own digest
+    assert!(matches!(
+        kv.get_asset_encoded("c.txt", Some("gzip")).await,
+        Err(Error::IntegrityMismatch(path)) if path == "a.1.txt.gz"
+    ));
+    assert_eq!(
+        kv.get_asset("a.txt").await.unwrap().unwrap().as_ref(),
+        b"good"
+    );
+    assert!(matches!(
+        kv.get_asset("b.txt").await,
+        Err(Error::IntegrityMismatch(_))
+    ));
+    let read = |path: &'static str| {
+        let kv = &kv;
+        async move {
+            let (_, body) = kv.get_asset_stream(path).unwrap().unwrap();
+            body.collect::<Vec<Result<bytes::Bytes, Error>>>().await
+        }
+    };
+    assert!(read("a.txt").await.iter().all(|part| part.is_ok()));
+    assert!(matches!(
+        read("b.txt").await.last(),
+        Some(Err(Error::IntegrityMismatch(_)))
+    ));
+
+    std::fs::remove_dir_all(&dir).unwrap();
 }
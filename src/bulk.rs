@@ -0,0 +1,709 @@
+#![cfg(not(target_arch = "wasm32"))]
+//! Bulk upload and delete for kv-sync, with partial failure handling.
+//!
+//! Cloudflare's bulk endpoints accept or reject a batch as a whole. When a batch fails,
+//! it is split in half and each half is retried, so a few bad keys (or a transient error)
+//! don't fail the entire sync. Keys that still fail on their own are returned
+//! as `KeyFailure`s. Errors that affect every request (authentication,
+//! missing namespace) abort the operation.
+//!
+//! Every uploaded key gets KV metadata with the upload time (`UploadMetadata`), so prune
+//! can spare keys uploaded recently by another sync (see `SyncConfig::prune_grace`).
+//! Asset keys also get their content type, digest, and cache control, for the dashboard
+//! and other tools that read the namespace.
+//!
+//! Batches are sent from several threads. The number of requests in flight adapts
+//! to the API (AIMD): it grows by about one per round of fast responses, and halves
+//! when the API responds with 429 (rate limited), in which case the request is retried
+//! after a pause rather than split.
+
+use crate::{DeployRecord, Error};
+use cloudflare::{
+    endpoints::workerskv::{
+        delete_bulk::DeleteBulk, list_namespace_keys::ListNamespaceKeysParams,
+        write_bulk::KeyValuePair,
+    },
+    framework::{
+        apiclient::ApiClient,
+        auth::Credentials,
+        endpoint::{Endpoint, Method},
+        response::{ApiFailure, ApiResult},
+        Environment, HttpApiClient, HttpApiClientConfig,
+    },
+};
+use indicatif::ProgressBar;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant},
+};
+use wrangler::{kv::bulk::BATCH_KEY_MAX, settings::global_user::GlobalUser};
+
+// Keep batches well under the API's 100MB limit
+const BATCH_MAX_BYTES: usize = 50 * 1024 * 1024;
+// Deletes are sent in batches smaller than the API's limit (BATCH_KEY_MAX),
+// so several can be in flight, and a failing batch affects fewer keys
+const DELETE_BATCH_SIZE: usize = 1000;
+// Requests in flight at the start of an upload or delete
+const INITIAL_CONCURRENCY: f64 = 2.0;
+// Maximum number of requests in flight
+const MAX_CONCURRENCY: usize = 8;
+// A response slower than this multiple of the fastest one so far doesn't increase concurrency
+const SLOW_RESPONSE_FACTOR: u32 = 2;
+// Times a rate-limited (429) request is retried
+const THROTTLE_RETRIES: u32 = 5;
+// Pause before retrying a rate-limited request, doubled on each retry
+const THROTTLE_PAUSE: Duration = Duration::from_secs(1);
+
+/// A key that could not be uploaded or deleted, and the reason
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyFailure {
+    /// KV key
+    pub key: String,
+    /// Error from the last attempt
+    pub error: String,
+}
+
+/// KV metadata stored with each uploaded key
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct UploadMetadata {
+    /// Upload time, in UTC seconds since EPOCH
+    pub(crate) uploaded: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) content_type: Option<String>,
+    /// Hex SHA-256 digest of the asset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) cache_control: Option<String>,
+    /// Set for the key of a deploy record (see `SyncConfig::record_history`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) deploy: Option<DeployRecord>,
+}
+
+impl UploadMetadata {
+    /// Returns the upload time from a key's metadata, or None if the key
+    /// wasn't uploaded by this version of kv-sync
+    pub(crate) fn uploaded(metadata: Option<&serde_json::Value>) -> Option<u64> {
+        metadata?.get("uploaded")?.as_u64()
+    }
+}
+
+/// Key in a namespace listing
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct ListedKey {
+    pub(crate) name: String,
+    /// Expiration, in UTC seconds since EPOCH
+    #[serde(default)]
+    pub(crate) expiration: Option<u64>,
+    #[serde(default)]
+    pub(crate) metadata: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(transparent)]
+struct ListedKeys(Vec<ListedKey>);
+
+impl ApiResult for ListedKeys {}
+
+/// List keys, with their metadata (which cloudflare's `ListNamespaceKeys` doesn't return)
+struct ListKeys<'a> {
+    account_identifier: &'a str,
+    namespace_identifier: &'a str,
+    params: ListNamespaceKeysParams,
+}
+
+impl<'a> Endpoint<ListedKeys, ListNamespaceKeysParams> for ListKeys<'a> {
+    fn method(&self) -> Method {
+        Method::Get
+    }
+    fn path(&self) -> String {
+        format!(
+            "accounts/{}/storage/kv/namespaces/{}/keys",
+            self.account_identifier, self.namespace_identifier
+        )
+    }
+    fn query(&self) -> Option<ListNamespaceKeysParams> {
+        Some(self.params.clone())
+    }
+}
+
+/// Write key-value pairs in bulk, with metadata for each
+/// (which cloudflare's `WriteBulk` doesn't support)
+struct WriteBulk<'a> {
+    account_identifier: &'a str,
+    namespace_identifier: &'a str,
+    pairs: &'a [KeyValuePair],
+    /// Metadata of keys that have more than the upload time
+    metadata: &'a HashMap<String, UploadMetadata>,
+    uploaded: u64,
+}
+
+#[derive(Serialize)]
+struct PairWithMetadata<'a> {
+    #[serde(flatten)]
+    pair: &'a KeyValuePair,
+    metadata: UploadMetadata,
+}
+
+impl<'a> Endpoint<(), (), Vec<PairWithMetadata<'a>>> for WriteBulk<'a> {
+    fn method(&self) -> Method {
+        Method::Put
+    }
+    fn path(&self) -> String {
+        format!(
+            "accounts/{}/storage/kv/namespaces/{}/bulk",
+            self.account_identifier, self.namespace_identifier
+        )
+    }
+    fn body(&self) -> Option<Vec<PairWithMetadata<'a>>> {
+        Some(
+            self.pairs
+                .iter()
+                .map(|pair| PairWithMetadata {
+                    pair,
+                    metadata: UploadMetadata {
+                        uploaded: self.uploaded,
+                        ..self.metadata.get(&pair.key).cloned().unwrap_or_default()
+                    },
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Client for the bulk KV endpoints of one namespace,
+/// plus the key listing and value reads used by adopt
+pub(crate) struct BulkClient {
+    client: HttpApiClient,
+    user: GlobalUser,
+    account_id: String,
+    namespace_id: String,
+}
+
+impl BulkClient {
+    pub(crate) fn new(
+        user: &GlobalUser,
+        account_id: &str,
+        namespace_id: &str,
+    ) -> Result<Self, Error> {
+        // bulk operations can take a while if payloads are large
+        let config = HttpApiClientConfig {
+            http_timeout: Duration::from_secs(5 * 60),
+            ..Default::default()
+        };
+        let client = HttpApiClient::new(
+            Credentials::from(user.to_owned()),
+            config,
+            Environment::Production,
+        )?;
+        Ok(Self {
+            client,
+            user: user.to_owned(),
+            account_id: account_id.to_string(),
+            namespace_id: namespace_id.to_string(),
+        })
+    }
+
+    /// Uploads key-value pairs, with their metadata, returning the keys that failed
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(keys = pairs.len()))
+    )]
+    pub(crate) fn put(
+        &self,
+        pairs: &[KeyValuePair],
+        metadata: &HashMap<String, UploadMetadata>,
+        progress_bar: &Option<ProgressBar>,
+    ) -> Result<Vec<KeyFailure>, Error> {
+        send_parallel(
+            batch_pairs(pairs),
+            &|p: &KeyValuePair| p.key.as_str(),
+            &|b: &[KeyValuePair]| {
+                self.client
+                    .request(&WriteBulk {
+                        account_identifier: &self.account_id,
+                        namespace_identifier: &self.namespace_id,
+                        pairs: b,
+                        metadata,
+                        uploaded: crate::clock::now_secs(),
+                    })
+                    .map(|_| ())
+                    .map_err(BulkError::from)
+            },
+            progress_bar,
+        )
+    }
+
+    /// Deletes keys, returning the keys that failed
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(keys = keys.len()))
+    )]
+    pub(crate) fn delete(
+        &self,
+        keys: Vec<String>,
+        progress_bar: &Option<ProgressBar>,
+    ) -> Result<Vec<KeyFailure>, Error> {
+        send_parallel(
+            keys.chunks(DELETE_BATCH_SIZE).collect(),
+            &|k: &String| k.as_str(),
+            &|b: &[String]| {
+                self.client
+                    .request(&DeleteBulk {
+                        account_identifier: &self.account_id,
+                        namespace_identifier: &self.namespace_id,
+                        bulk_keys: b.to_vec(),
+                    })
+                    .map(|_| ())
+                    .map_err(BulkError::from)
+            },
+            progress_bar,
+        )
+    }
+
+    /// Lists all keys in the namespace, with their expiration and metadata
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(namespace = %self.namespace_id))
+    )]
+    pub(crate) fn list_keys(&self) -> Result<Vec<ListedKey>, Error> {
+        let mut keys = Vec::new();
+        let mut cursor = None;
+        loop {
+            let response = self
+                .client
+                .request(&ListKeys {
+                    account_identifier: &self.account_id,
+                    namespace_identifier: &self.namespace_id,
+                    params: ListNamespaceKeysParams {
+                        limit: None,
+                        cursor: cursor.take(),
+                        prefix: None,
+                    },
+                })
+                .map_err(|e| {
+                    let e = BulkError::from(e);
+                    Error::KVHttpStatus(e.status.unwrap_or_default(), e.message)
+                })?;
+            keys.extend(response.result.0);
+            cursor = response
+                .result_info
+                .as_ref()
+                .and_then(|info| info["cursor"].as_str())
+                .filter(|c| !c.is_empty())
+                .map(String::from);
+            if cursor.is_none() {
+                return Ok(keys);
+            }
+        }
+    }
+
+    /// Reads a value. If the key doesn't exist, returns `Error::KVKeyNotFound`
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self)))]
+    pub(crate) fn get_value(&self, key: &str) -> Result<Vec<u8>, Error> {
+        let url = format!(
+            "https://api.cloudflare.com/client/v4/accounts/{}/storage/kv/namespaces/{}/values/{}",
+            self.account_id,
+            self.namespace_id,
+            crate::key::uri_encode(key, false)
+        );
+        let request = reqwest::blocking::Client::new().get(&url);
+        let request = match &self.user {
+            GlobalUser::TokenAuth { api_token } => request.bearer_auth(api_token),
+            GlobalUser::GlobalKeyAuth { email, api_key } => request
+                .header("X-Auth-Email", email)
+                .header("X-Auth-Key", api_key),
+        };
+        let response = request
+            .send()
+            .map_err(|e| Error::KVHttp(key.to_string(), e.to_string()))?;
+        match response.status().as_u16() {
+            200 => Ok(response
+                .bytes()
+                .map_err(|e| Error::KVHttp(key.to_string(), e.to_string()))?
+                .to_vec()),
+            404 => Err(Error::KVKeyNotFound(key.to_string(), 404)),
+            status => Err(Error::KVHttpStatus(
+                status,
+                response.text().unwrap_or_default(),
+            )),
+        }
+    }
+}
+
+/// Failed bulk request
+pub(crate) struct BulkError {
+    /// http status, if a response was received
+    status: Option<u16>,
+    message: String,
+}
+
+impl From<ApiFailure> for BulkError {
+    fn from(e: ApiFailure) -> Self {
+        match e {
+            ApiFailure::Error(status, api_errors) => BulkError {
+                status: Some(status.as_u16()),
+                message: api_errors
+                    .errors
+                    .iter()
+                    .map(|e| format!("Code {}: {}", e.code, e.message))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            },
+            ApiFailure::Invalid(e) => BulkError {
+                status: None,
+                message: e.to_string(),
+            },
+        }
+    }
+}
+
+impl BulkError {
+    // Errors that would fail every request, so there's no point retrying
+    fn is_fatal(&self) -> bool {
+        matches!(self.status, Some(401) | Some(403) | Some(404))
+    }
+
+    fn is_throttled(&self) -> bool {
+        self.status == Some(429)
+    }
+}
+
+/// Limits the number of requests in flight, adjusting the limit
+/// with additive increase / multiplicative decrease
+struct Aimd {
+    state: Mutex<AimdState>,
+    released: Condvar,
+    max: usize,
+}
+
+struct AimdState {
+    limit: f64,
+    in_flight: usize,
+    fastest: Option<Duration>,
+}
+
+impl Aimd {
+    fn new(initial: f64, max: usize) -> Self {
+        Self {
+            state: Mutex::new(AimdState {
+                limit: initial.min(max as f64),
+                in_flight: 0,
+                fastest: None,
+            }),
+            released: Condvar::new(),
+            max,
+        }
+    }
+
+    /// Waits until another request may be sent
+    fn acquire(&self) {
+        let mut state = self.state.lock().unwrap();
+        while state.in_flight >= state.limit as usize {
+            state = self.released.wait(state).unwrap();
+        }
+        state.in_flight += 1;
+    }
+
+    /// Ends a request, adjusting the limit: a fast response adds 1/limit
+    /// (about one more request per round), and a 429 halves it
+    fn release(&self, latency: Option<Duration>, throttled: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.in_flight -= 1;
+        if throttled {
+            state.limit = (state.limit / 2.0).max(1.0);
+        } else if let Some(latency) = latency {
+            let fastest = state.fastest.map_or(latency, |f| f.min(latency));
+            state.fastest = Some(fastest);
+            if latency <= fastest * SLOW_RESPONSE_FACTOR {
+                state.limit = (state.limit + 1.0 / state.limit).min(self.max as f64);
+            }
+        }
+        self.released.notify_all();
+    }
+
+    /// Sends a request when allowed, retrying after a pause if it's rate limited
+    fn send<T, F>(&self, batch: &[T], send: &F) -> Result<(), BulkError>
+    where
+        F: Fn(&[T]) -> Result<(), BulkError>,
+    {
+        let mut pause = THROTTLE_PAUSE;
+        let mut retries = 0;
+        loop {
+            self.acquire();
+            let start = Instant::now();
+            let result = send(batch);
+            match result {
+                Err(e) if e.is_throttled() => {
+                    self.release(None, true);
+                    if retries == THROTTLE_RETRIES {
+                        return Err(e);
+                    }
+                    retries += 1;
+                    std::thread::sleep(pause);
+                    pause *= 2;
+                }
+                Ok(()) => {
+                    self.release(Some(start.elapsed()), false);
+                    return Ok(());
+                }
+                Err(e) => {
+                    self.release(None, false);
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+/// Sends the batch. If it fails, splits it in half and retries each half,
+/// until the failing keys are isolated.
+fn send_bisecting<T, K, F>(
+    batch: &[T],
+    key: &K,
+    send: &F,
+    failures: &mut Vec<KeyFailure>,
+    progress_bar: &Option<ProgressBar>,
+) -> Result<(), Error>
+where
+    K: Fn(&T) -> &str,
+    F: Fn(&[T]) -> Result<(), BulkError>,
+{
+    if batch.is_empty() {
+        return Ok(());
+    }
+    match send(batch) {
+        Ok(()) => {}
+        Err(e) if e.is_fatal() => {
+            return Err(Error::KVHttpStatus(e.status.unwrap_or_default(), e.message))
+        }
+        // still rate limited after retries: splitting would only add requests
+        Err(e) if batch.len() == 1 || e.is_throttled() => {
+            failures.extend(batch.iter().map(|item| KeyFailure {
+                key: key(item).to_string(),
+                error: e.message.clone(),
+            }))
+        }
+        Err(_) => {
+            let (left, right) = batch.split_at(batch.len() / 2);
+            send_bisecting(left, key, send, failures, progress_bar)?;
+            return send_bisecting(right, key, send, failures, progress_bar);
+        }
+    }
+    if let Some(pb) = progress_bar {
+        pb.inc(batch.len() as u64);
+    }
+    Ok(())
+}
+
+/// Sends the batches from up to `MAX_CONCURRENCY` threads, each batch with `send_bisecting`,
+/// with the number of requests in flight limited by `Aimd`. A fatal error stops
+/// the remaining batches and is returned. Failures are sorted by key.
+fn send_parallel<T, K, F>(
+    batches: Vec<&[T]>,
+    key: &K,
+    send: &F,
+    progress_bar: &Option<ProgressBar>,
+) -> Result<Vec<KeyFailure>, Error>
+where
+    T: Sync,
+    K: Fn(&T) -> &str + Sync,
+    F: Fn(&[T]) -> Result<(), BulkError> + Sync,
+{
+    let aimd = Aimd::new(INITIAL_CONCURRENCY, MAX_CONCURRENCY);
+    let send = |b: &[T]| aimd.send(b, send);
+    let threads = MAX_CONCURRENCY.min(batches.len());
+    let batches = Mutex::new(batches.into_iter());
+    let failures = Mutex::new(Vec::new());
+    let fatal: Mutex<Option<Error>> = Mutex::new(None);
+    std::thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| loop {
+                if fatal.lock().unwrap().is_some() {
+                    break;
+                }
+                let batch = match batches.lock().unwrap().next() {
+                    Some(batch) => batch,
+                    None => break,
+                };
+                let mut batch_failures = Vec::new();
+                match send_bisecting(batch, key, &send, &mut batch_failures, progress_bar) {
+                    Ok(()) => failures.lock().unwrap().extend(batch_failures),
+                    Err(e) => {
+                        fatal.lock().unwrap().get_or_insert(e);
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    if let Some(e) = fatal.into_inner().unwrap() {
+        return Err(e);
+    }
+    let mut failures = failures.into_inner().unwrap();
+    failures.sort_by(|a: &KeyFailure, b| a.key.cmp(&b.key));
+    Ok(failures)
+}
+
+/// Splits pairs into batches within the API's limits on key count and payload size
+fn batch_pairs(pairs: &[KeyValuePair]) -> Vec<&[KeyValuePair]> {
+    let mut batches = Vec::new();
+    let mut start = 0;
+    let mut batch_bytes = 0;
+    for (i, pair) in pairs.iter().enumerate() {
+        let size = pair.key.len() + pair.value.len();
+        if i > start && (i - start >= BATCH_KEY_MAX || batch_bytes + size > BATCH_MAX_BYTES) {
+            batches.push(&pairs[start..i]);
+            start = i;
+            batch_bytes = 0;
+        }
+        batch_bytes += size;
+    }
+    if start < pairs.len() {
+        batches.push(&pairs[start..]);
+    }
+    batches
+}
+
+#[test]
+fn test_send_bisecting() {
+    use std::cell::Cell;
+
+    let keys: Vec<String> = (0..20).map(|n| format!("key{}", n)).collect();
+    let requests = Cell::new(0);
+    let send = |b: &[String]| {
+        requests.set(requests.get() + 1);
+        if b.iter().any(|k| k == "key3" || k == "key17") {
+            Err(BulkError {
+                status: Some(400),
+                message: "bad key".into(),
+            })
+        } else {
+            Ok(())
+        }
+    };
+    let mut failures = Vec::new();
+    send_bisecting(&keys, &|k: &String| k.as_str(), &send, &mut failures, &None).unwrap();
+    assert_eq!(
+        failures.iter().map(|f| f.key.as_str()).collect::<Vec<_>>(),
+        vec!["key3", "key17"]
+    );
+    assert!(requests.get() < keys.len());
+
+    // fatal errors aren't retried
+    requests.set(0);
+    let send = |_: &[String]| {
+        requests.set(requests.get() + 1);
+        Err(BulkError {
+            status: Some(403),
+            message: "forbidden".into(),
+        })
+    };
+    let mut failures = Vec::new();
+    let result = send_bisecting(&keys, &|k: &String| k.as_str(), &send, &mut failures, &None);
+    assert!(matches!(result, Err(Error::KVHttpStatus(403, _))));
+    assert_eq!(requests.get(), 1);
+}
+
+#[test]
+fn test_send_parallel() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let keys: Vec<String> = (0..95).map(|n| format!("key{:02}", n)).collect();
+    let sent = AtomicUsize::new(0);
+    let send = |b: &[String]| {
+        if b.iter().any(|k| k == "key07" || k == "key63") {
+            return Err(BulkError {
+                status: Some(400),
+                message: "bad key".into(),
+            });
+        }
+        sent.fetch_add(b.len(), Ordering::SeqCst);
+        Ok(())
+    };
+    let batches = || keys.chunks(10).collect::<Vec<_>>();
+    let failures = send_parallel(batches(), &|k: &String| k.as_str(), &send, &None).unwrap();
+    assert_eq!(
+        failures.iter().map(|f| f.key.as_str()).collect::<Vec<_>>(),
+        vec!["key07", "key63"]
+    );
+    assert_eq!(sent.load(Ordering::SeqCst), 93);
+
+    // a fatal error stops the remaining batches
+    let send = |_: &[String]| {
+        Err(BulkError {
+            status: Some(401),
+            message: "unauthorized".into(),
+        })
+    };
+    let result = send_parallel(batches(), &|k: &String| k.as_str(), &send, &None);
+    assert!(matches!(result, Err(Error::KVHttpStatus(401, _))));
+}
+
+#[test]
+fn test_aimd() {
+    let aimd = Aimd::new(2.0, 4);
+    let limit = || aimd.state.lock().unwrap().limit;
+    let fast = Some(Duration::from_millis(100));
+    // each round of fast responses adds about one request
+    for _ in 0..6 {
+        aimd.acquire();
+        aimd.release(fast, false);
+    }
+    assert_eq!(limit(), 4.0);
+    aimd.acquire();
+    aimd.release(fast, false);
+    assert_eq!(limit(), 4.0, "limited to max");
+    // slow responses don't add
+    aimd.acquire();
+    aimd.release(Some(Duration::from_secs(1)), false);
+    assert_eq!(limit(), 4.0);
+    // 429 halves, to a minimum of 1
+    aimd.acquire();
+    aimd.release(None, true);
+    assert_eq!(limit(), 2.0);
+    for _ in 0..3 {
+        aimd.acquire();
+        aimd.release(None, true);
+    }
+    assert_eq!(limit(), 1.0);
+}
+
+#[test]
+fn test_upload_metadata() {
+    let pairs = vec![KeyValuePair {
+        key: "a.123.txt".into(),
+        value: "YQ==".into(),
+        expiration: None,
+        expiration_ttl: None,
+        base64: Some(true),
+    }];
+    let mut metadata = HashMap::new();
+    metadata.insert(
+        "a.123.txt".to_string(),
+        UploadMetadata {
+            content_type: Some("text/plain".into()),
+            ..Default::default()
+        },
+    );
+    let write = WriteBulk {
+        account_identifier: "account",
+        namespace_identifier: "ns",
+        pairs: &pairs,
+        metadata: &metadata,
+        uploaded: 1000,
+    };
+    let body = serde_json::to_value(write.body().unwrap()).unwrap();
+    assert_eq!(
+        body,
+        serde_json::json!([{"key": "a.123.txt", "value": "YQ==", "base64": true,
+            "metadata": {"uploaded": 1000, "content_type": "text/plain"}}])
+    );
+    assert_eq!(
+        UploadMetadata::uploaded(Some(&body[0]["metadata"])),
+        Some(1000)
+    );
+    assert_eq!(UploadMetadata::uploaded(None), None);
+}
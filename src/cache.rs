@@ -0,0 +1,182 @@
+//! Bounded in-memory cache of values read from KV (see `KVAssets::with_asset_cache`).
+//!
+//! Entries are keyed by KV key, which includes a hash of the contents, so a cached value
+//! is never out of date; the TTL only limits how long unused memory is held. Entries past
+//! the staleness window are served while they're read again (see
+//! `KVAssets::with_refresh_spawner` and `KVAssets::revalidate`), so hot assets stay
+//! cached instead of expiring, and values removed from KV are dropped.
+
+use crate::clock;
+use std::collections::HashMap;
+
+/// Limits of the asset cache
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AssetCacheConfig {
+    /// Maximum number of values
+    pub max_entries: usize,
+    /// Maximum total size of the values, in bytes. Larger values aren't cached.
+    pub max_bytes: usize,
+    /// Seconds after which an entry is dropped, or None to keep entries until evicted
+    pub ttl: Option<u64>,
+    /// Seconds after which an entry is stale: it's still served, and read again in the
+    /// background (see `KVAssets::with_refresh_spawner`), or queued for
+    /// `KVAssets::revalidate`, or None to never refresh entries
+    pub stale_after: Option<u64>,
+}
+
+struct Entry {
+    body: bytes::Bytes,
+    /// When the entry was added, in UTC seconds since EPOCH
+    added: u64,
+    /// Value of `AssetCache::clock` when the entry was last used
+    used: u64,
+    /// Whether the entry is being read again (see `AssetCache::start_refresh`)
+    refreshing: bool,
+}
+
+/// Least-recently-used cache of KV values
+pub(crate) struct AssetCache {
+    config: AssetCacheConfig,
+    entries: HashMap<String, Entry>,
+    bytes: usize,
+    clock: u64,
+}
+
+impl AssetCache {
+    pub(crate) fn new(config: AssetCacheConfig) -> Self {
+        Self {
+            config,
+            entries: HashMap::new(),
+            bytes: 0,
+            clock: 0,
+        }
+    }
+
+    /// Returns the cached value of the key, unless it has expired
+    pub(crate) fn get(&mut self, key: &str) -> Option<bytes::Bytes> {
+        self.get_at(key, clock::now_secs())
+    }
+
+    fn get_at(&mut self, key: &str, now: u64) -> Option<bytes::Bytes> {
+        let expired = match (self.entries.get(key), self.config.ttl) {
+            (None, _) => return None,
+            (Some(entry), Some(ttl)) => entry.added + ttl <= now,
+            (Some(_), None) => false,
+        };
+        if expired {
+            self.remove(key);
+            return None;
+        }
+        self.clock += 1;
+        let entry = self.entries.get_mut(key)?;
+        entry.used = self.clock;
+        Some(entry.body.clone())
+    }
+
+    /// Returns true if the key's entry is older than the staleness window and isn't
+    /// already being refreshed, marking it as being refreshed. The mark is cleared when
+    /// the entry is replaced or removed, or by `end_refresh` if the refresh fails.
+    pub(crate) fn start_refresh(&mut self, key: &str) -> bool {
+        self.start_refresh_at(key, clock::now_secs())
+    }
+
+    fn start_refresh_at(&mut self, key: &str, now: u64) -> bool {
+        if !self.is_stale_at(key, now) {
+            return false;
+        }
+        match self.entries.get_mut(key) {
+            Some(entry) if !entry.refreshing => {
+                entry.refreshing = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Clears the mark set by `start_refresh`, so the entry is refreshed again when it's next used
+    pub(crate) fn end_refresh(&mut self, key: &str) {
+        if let Some(entry) = self.entries.get_mut(key) {
+            entry.refreshing = false;
+        }
+    }
+
+    fn is_stale_at(&self, key: &str, now: u64) -> bool {
+        match (self.entries.get(key), self.config.stale_after) {
+            (Some(entry), Some(stale_after)) => entry.added + stale_after <= now,
+            _ => false,
+        }
+    }
+
+    /// Adds the value, evicting the least recently used entries to make room
+    pub(crate) fn insert(&mut self, key: &str, body: bytes::Bytes) {
+        self.insert_at(key, body, clock::now_secs())
+    }
+
+    fn insert_at(&mut self, key: &str, body: bytes::Bytes, now: u64) {
+        if self.config.max_entries == 0 || body.len() > self.config.max_bytes {
+            return;
+        }
+        self.remove(key);
+        while self.entries.len() >= self.config.max_entries
+            || self.bytes + body.len() > self.config.max_bytes
+        {
+            let oldest = match self.entries.iter().min_by_key(|(_, e)| e.used) {
+                Some((key, _)) => key.clone(),
+                None => break,
+            };
+            self.remove(&oldest);
+        }
+        self.clock += 1;
+        self.bytes += body.len();
+        self.entries.insert(
+            key.to_string(),
+            Entry {
+                body,
+                added: now,
+                used: self.clock,
+                refreshing: false,
+            },
+        );
+    }
+
+    pub(crate) fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.bytes -= entry.body.len();
+        }
+    }
+}
+
+#[test]
+fn test_asset_cache() {
+    let mut cache = AssetCache::new(AssetCacheConfig {
+        max_entries: 2,
+        max_bytes: 10,
+        ttl: Some(60),
+        stale_after: Some(30),
+    });
+    let value = |s: &'static str| bytes::Bytes::from_static(s.as_bytes());
+    cache.insert_at("a", value("aaaa"), 100);
+    cache.insert_at("b", value("bbbb"), 100);
+    assert_eq!(cache.get_at("a", 100), Some(value("aaaa")));
+    // evicts b, the least recently used
+    cache.insert_at("c", value("cc"), 100);
+    assert_eq!(cache.get_at("b", 100), None);
+    assert_eq!(cache.get_at("c", 100), Some(value("cc")));
+    // too large for the cache
+    cache.insert_at("d", value("ddddddddddd"), 100);
+    assert_eq!(cache.get_at("d", 100), None);
+    // evicts a to fit in max_bytes
+    cache.insert_at("e", value("eeeeeeee"), 100);
+    assert_eq!(cache.get_at("a", 100), None);
+    assert_eq!(cache.bytes, 10);
+    assert!(!cache.is_stale_at("e", 129));
+    assert!(cache.is_stale_at("e", 130));
+    // refreshed once until the refresh ends
+    assert!(cache.start_refresh_at("e", 130));
+    assert!(!cache.start_refresh_at("e", 130));
+    cache.end_refresh("e");
+    assert!(cache.start_refresh_at("e", 130));
+    assert_eq!(cache.get_at("e", 159), Some(value("eeeeeeee")));
+    assert_eq!(cache.get_at("e", 160), None);
+    assert_eq!(cache.bytes, 2);
+}
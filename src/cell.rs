@@ -0,0 +1,57 @@
+//! Interior mutability for `KVAssets`: single-threaded cells by default, or, with the
+//! `thread-safe` feature, their thread-safe counterparts, so the handler is `Send + Sync`
+//! and can be shared with `Arc` by tasks on a multi-threaded runtime.
+
+#[cfg(feature = "thread-safe")]
+pub(crate) use std::sync::{Arc as Shared, OnceLock as Once};
+#[cfg(not(feature = "thread-safe"))]
+pub(crate) use std::{cell::OnceCell as Once, rc::Rc as Shared};
+
+/// Mutable value: a `RefCell`, or, with the `thread-safe` feature, a `Mutex`
+pub(crate) struct Lock<T> {
+    #[cfg(not(feature = "thread-safe"))]
+    inner: std::cell::RefCell<T>,
+    #[cfg(feature = "thread-safe")]
+    inner: std::sync::Mutex<T>,
+}
+
+impl<T: Default> Default for Lock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T> Lock<T> {
+    pub(crate) fn new(value: T) -> Self {
+        Self {
+            inner: value.into(),
+        }
+    }
+
+    /// Borrows the value. The borrow must end before the value is borrowed again.
+    #[cfg(not(feature = "thread-safe"))]
+    pub(crate) fn borrow_mut(&self) -> std::cell::RefMut<'_, T> {
+        self.inner.borrow_mut()
+    }
+
+    /// Locks the value. The lock must be released before the value is locked again.
+    #[cfg(feature = "thread-safe")]
+    pub(crate) fn borrow_mut(&self) -> std::sync::MutexGuard<'_, T> {
+        // a panic while locked doesn't leave the caches inconsistent
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Bound of the serving hooks (see `KVAssets::on_hit`): `Send + Sync` with the
+/// `thread-safe` feature, otherwise implemented by every type
+#[cfg(not(feature = "thread-safe"))]
+pub trait MaybeSendSync {}
+#[cfg(not(feature = "thread-safe"))]
+impl<T: ?Sized> MaybeSendSync for T {}
+
+/// Bound of the serving hooks (see `KVAssets::on_hit`): `Send + Sync` with the
+/// `thread-safe` feature, otherwise implemented by every type
+#[cfg(feature = "thread-safe")]
+pub trait MaybeSendSync: Send + Sync {}
+#[cfg(feature = "thread-safe")]
+impl<T: Send + Sync + ?Sized> MaybeSendSync for T {}
@@ -0,0 +1,56 @@
+//! Chunked storage for assets larger than the KV value size limit (25 MiB).
+//!
+//! kv-sync splits files larger than `CHUNK_SIZE` into numbered chunks, each stored under
+//! its own key (`chunk_key`), and records the number of chunks in `AssetMetadata::chunks`.
+//! The asset's own key is not written. `get_asset` reads the chunks in order and
+//! concatenates them.
+
+/// Maximum size of a KV value, and the size of every chunk except the last
+pub const CHUNK_SIZE: u64 = 25 * 1024 * 1024;
+
+const CHUNK_SEPARATOR: &str = ".chunk";
+
+/// Returns the KV key of chunk `n` (starting at 0) of the asset stored at `key`
+pub fn chunk_key(key: &str, n: u32) -> String {
+    format!("{}{}{}", key, CHUNK_SEPARATOR, n)
+}
+
+/// Returns the number of chunks for a value of `size` bytes, or 0 if it fits in one KV value
+#[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+pub(crate) fn chunk_count(size: u64) -> u32 {
+    if size <= CHUNK_SIZE {
+        0
+    } else {
+        size.div_ceil(CHUNK_SIZE) as u32
+    }
+}
+
+/// Splits a chunk key into the asset key and chunk number, or returns None
+/// if it isn't a chunk key
+#[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+pub(crate) fn split_chunk_key(key: &str) -> Option<(&str, u32)> {
+    let pos = key.rfind(CHUNK_SEPARATOR)?;
+    let n = &key[pos + CHUNK_SEPARATOR.len()..];
+    if n.is_empty() || !n.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some((&key[..pos], n.parse().ok()?))
+}
+
+#[test]
+fn test_chunks() {
+    assert_eq!(chunk_count(0), 0);
+    assert_eq!(chunk_count(CHUNK_SIZE), 0);
+    assert_eq!(chunk_count(CHUNK_SIZE + 1), 2);
+    assert_eq!(chunk_count(3 * CHUNK_SIZE), 3);
+    assert_eq!(
+        chunk_key("video.0123456789.mp4", 2),
+        "video.0123456789.mp4.chunk2"
+    );
+    assert_eq!(
+        split_chunk_key("video.0123456789.mp4.chunk12"),
+        Some(("video.0123456789.mp4", 12))
+    );
+    assert_eq!(split_chunk_key("video.0123456789.mp4"), None);
+    assert_eq!(split_chunk_key("a.chunkx"), None);
+}
@@ -0,0 +1,91 @@
+//! Request coalescing: concurrent reads of the same KV key share one request,
+//! so a hot asset on a busy worker isn't fetched once per request.
+
+use crate::{cell::Lock, Error};
+use futures_channel::oneshot;
+use futures_util::future::{FutureExt, Shared};
+use std::{collections::HashMap, future::Future};
+
+type Pending = Shared<oneshot::Receiver<Result<bytes::Bytes, Error>>>;
+
+/// Reads in progress, by key
+#[derive(Default)]
+pub(crate) struct InFlight {
+    pending: Lock<HashMap<String, Pending>>,
+}
+
+impl InFlight {
+    /// Returns the result of the read of `key` in progress, if there is one, or runs `read`
+    /// and shares its result with reads of `key` that start before it finishes
+    pub(crate) async fn read<F>(&self, key: &str, read: F) -> Result<bytes::Bytes, Error>
+    where
+        F: Future<Output = Result<bytes::Bytes, Error>>,
+    {
+        let pending = self.pending.borrow_mut().get(key).cloned();
+        if let Some(pending) = pending {
+            match pending.await {
+                Ok(result) => return result,
+                // the first read was cancelled, so this one reads the key itself
+                Err(oneshot::Canceled) => return read.await,
+            }
+        }
+        let (tx, rx) = oneshot::channel();
+        self.pending
+            .borrow_mut()
+            .insert(key.to_string(), rx.shared());
+        let _done = Done {
+            in_flight: self,
+            key,
+        };
+        let result = read.await;
+        let _ = tx.send(result.clone());
+        result
+    }
+}
+
+/// Removes the key when its read finishes or is cancelled
+struct Done<'f> {
+    in_flight: &'f InFlight,
+    key: &'f str,
+}
+
+impl Drop for Done<'_> {
+    fn drop(&mut self) {
+        self.in_flight.pending.borrow_mut().remove(self.key);
+    }
+}
+
+/// Tests that concurrent reads of a key share one read (does not invoke cloudflare api)
+#[cfg(all(test, not(target_arch = "wasm32")))]
+#[tokio::test]
+async fn test_coalesce() {
+    use std::cell::Cell;
+
+    let in_flight = InFlight::default();
+    let reads = Cell::new(0);
+    let read = |value: &'static [u8]| {
+        let reads = &reads;
+        async move {
+            reads.set(reads.get() + 1);
+            tokio::task::yield_now().await;
+            Ok(bytes::Bytes::from_static(value))
+        }
+    };
+    let (a, b) = futures_util::future::join(
+        in_flight.read("a", read(b"1")),
+        in_flight.read("a", read(b"2")),
+    )
+    .await;
+    assert_eq!(
+        (a.unwrap(), b.unwrap()),
+        (b"1".as_ref().into(), b"1".as_ref().into())
+    );
+    assert_eq!(reads.get(), 1);
+
+    // a read after the first one finishes reads again
+    assert_eq!(
+        in_flight.read("a", read(b"3")).await.unwrap().as_ref(),
+        b"3"
+    );
+    assert_eq!(reads.get(), 2);
+}
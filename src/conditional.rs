@@ -0,0 +1,161 @@
+//! Conditional requests with `ETag` and `If-None-Match`, or `Last-Modified` and
+//! `If-Modified-Since`, so clients that already have an asset get a 304 response
+//! without the asset being read from KV.
+//!
+//! An asset's ETag is derived from the SHA-256 digest recorded at sync time, or, for
+//! indexes without digests, from its KV key, which contains a hash of the contents.
+
+use crate::{clock, AssetMetadata, AssetStore, Error, KVAssets, ServerTiming, ToAssetKey};
+
+/// Result of `KVAssets::get_asset_conditional`
+#[derive(Clone, Debug, PartialEq)]
+pub enum Conditional {
+    /// The client's copy is current. Respond 304 with the ETag
+    NotModified {
+        /// Value for the `ETag` response header
+        etag: String,
+    },
+    /// The asset, which doesn't match the client's copy, if any
+    Modified {
+        /// Asset contents
+        body: bytes::Bytes,
+        /// Value for the `ETag` response header
+        etag: String,
+    },
+}
+
+impl AssetMetadata {
+    /// Returns the asset's ETag, including the quotes
+    pub fn etag(&self) -> String {
+        match &self.sha256 {
+            Some(digest) => {
+                let hex: String = digest[..16].iter().map(|b| format!("{:02x}", b)).collect();
+                format!("\"{}\"", hex)
+            }
+            None => {
+                let key: String = self
+                    .path
+                    .chars()
+                    .map(|c| match c {
+                        '!' | '#'..='~' => c,
+                        _ => '_',
+                    })
+                    .collect();
+                format!("\"{}\"", key)
+            }
+        }
+    }
+
+    /// Returns the modified time as an http date, for the `Last-Modified` header,
+    /// or None if it's unknown
+    pub fn last_modified(&self) -> Option<String> {
+        match self.modified {
+            0 => None,
+            modified => Some(clock::http_date(modified)),
+        }
+    }
+
+    /// Returns false if the asset hasn't changed since the time in an `If-Modified-Since`
+    /// header. Returns true if the header isn't a valid http date, or the asset's
+    /// modified time is unknown.
+    pub fn modified_since(&self, if_modified_since: &str) -> bool {
+        match clock::parse_http_date(if_modified_since) {
+            Some(since) if self.modified > 0 => self.modified > since,
+            _ => true,
+        }
+    }
+}
+
+/// Returns true if the `If-None-Match` header value matches the ETag.
+/// Uses the weak comparison, so `W/` prefixes are ignored.
+pub fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    if_none_match
+        .split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+impl<'ah, S: AssetStore> KVAssets<'ah, S> {
+    /// Returns the ETag of the asset at `path`, or None if it's not in the index
+    pub fn etag_for<K: ToAssetKey + ?Sized>(&self, path: &K) -> Result<Option<String>, Error> {
+        Ok(self.lookup_key(path)?.map(|md| md.etag()))
+    }
+
+    /// Gets the asset, unless `if_none_match` (the request's `If-None-Match` header)
+    /// matches its ETag, in which case `Conditional::NotModified` is returned without
+    /// reading KV. Returns Ok(None) if the asset is not in the index.
+    pub async fn get_asset_conditional<K: ToAssetKey + ?Sized>(
+        &self,
+        key: &K,
+        if_none_match: Option<&str>,
+    ) -> Result<Option<Conditional>, Error> {
+        if let Some(if_none_match) = if_none_match {
+            if let Some(etag) = self.etag_for(key)? {
+                if etag_matches(if_none_match, &etag) {
+                    return Ok(Some(Conditional::NotModified { etag }));
+                }
+            }
+        }
+        let found = self
+            .fetch_asset_hooked(key.key_str(), &mut ServerTiming::new())
+            .await?;
+        Ok(found.map(|(md, body)| Conditional::Modified {
+            body,
+            etag: md.etag(),
+        }))
+    }
+
+    /// Gets the asset, unless it hasn't changed since `if_modified_since` (the request's
+    /// `If-Modified-Since` header), in which case `Conditional::NotModified` is returned
+    /// without reading KV. Returns Ok(None) if the asset is not in the index.
+    pub async fn get_asset_if_modified_since<K: ToAssetKey + ?Sized>(
+        &self,
+        key: &K,
+        if_modified_since: Option<&str>,
+    ) -> Result<Option<Conditional>, Error> {
+        if let Some(since) = if_modified_since {
+            if let Some(md) = self.lookup_key(key)? {
+                if !md.modified_since(since) {
+                    return Ok(Some(Conditional::NotModified { etag: md.etag() }));
+                }
+            }
+        }
+        self.get_asset_conditional(key, None).await
+    }
+}
+
+#[test]
+fn test_etag() {
+    let md = AssetMetadata {
+        path: "css/site.f25c32ee58.css".to_string(),
+        ..Default::default()
+    };
+    assert_eq!(md.etag(), "\"css/site.f25c32ee58.css\"");
+    let md = AssetMetadata {
+        sha256: Some([0xab; 32]),
+        ..md
+    };
+    let etag = md.etag();
+    assert_eq!(etag, format!("\"{}\"", "ab".repeat(16)));
+
+    assert!(etag_matches(&etag, &etag));
+    assert!(etag_matches(&format!("\"x\", W/{}", etag), &etag));
+    assert!(etag_matches("*", &etag));
+    assert!(etag_matches(&etag, &format!("W/{}", etag)));
+    assert!(!etag_matches("\"x\"", &etag));
+
+    assert_eq!(md.last_modified(), None);
+    assert!(md.modified_since("Sun, 06 Nov 1994 08:49:37 GMT"));
+    let md = AssetMetadata {
+        modified: 784_111_777,
+        ..md
+    };
+    assert_eq!(
+        md.last_modified().as_deref(),
+        Some("Sun, 06 Nov 1994 08:49:37 GMT")
+    );
+    assert!(!md.modified_since("Sun, 06 Nov 1994 08:49:37 GMT"));
+    assert!(md.modified_since("Sun, 06 Nov 1994 08:49:36 GMT"));
+    assert!(md.modified_since("yesterday"));
+}
@@ -0,0 +1,183 @@
+#![cfg(not(target_arch = "wasm32"))]
+//! Cloudflare credentials for kv-sync.
+//!
+//! If a profile is selected (`SyncConfig::profile` or `KV_ASSETS_PROFILE`), its API token
+//! is used. Otherwise, credentials are read from `CF_API_TOKEN` (or `CF_API_KEY` and
+//! `CF_EMAIL`) if set. Otherwise, with the `keyring` feature, from the API token saved
+//! in the OS credential store (macOS Keychain, Windows Credential Manager, or the Secret
+//! Service on linux) with `save_token`, and finally from wrangler's config file,
+//! which `login` creates.
+//!
+//! Profiles are read from `~/.config/kv-assets/credentials`, a toml file with a table
+//! for each profile:
+//!
+//! ```toml
+//! [staging]
+//! account_id = "..."
+//! api_token = "..."
+//! namespace_id = "..."
+//! ```
+//!
+//! All fields are optional. A profile's account and namespace replace the ones
+//! from wrangler.toml.
+
+use crate::Error;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use wrangler::settings::global_user::GlobalUser;
+
+/// Environment variable with the name of the profile to use, if none is configured
+pub const PROFILE_ENV_VAR: &str = "KV_ASSETS_PROFILE";
+
+/// Named account, API token, and namespace in the profiles file
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    /// Cloudflare account id
+    pub account_id: Option<String>,
+    /// API token
+    pub api_token: Option<String>,
+    /// KV namespace to sync to, instead of the site's namespace from wrangler.toml
+    pub namespace_id: Option<String>,
+}
+
+/// Returns the path of the profiles file, `~/.config/kv-assets/credentials`
+pub fn profiles_path() -> Result<PathBuf, Error> {
+    let home = std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .ok_or_else(|| Error::Message("can't find the home directory".to_string()))?;
+    Ok(PathBuf::from(home)
+        .join(".config")
+        .join("kv-assets")
+        .join("credentials"))
+}
+
+/// Loads the named profile from the profiles file
+pub fn load_profile(name: &str) -> Result<Profile, Error> {
+    let path = profiles_path()?;
+    let text = std::fs::read_to_string(&path)
+        .map_err(|e| Error::IO(format!("reading {}: {}", path.display(), e)))?;
+    let mut profiles = parse_profiles(&text)
+        .map_err(|e| Error::Message(format!("invalid profiles {}: {}", path.display(), e)))?;
+    profiles.remove(name).ok_or_else(|| {
+        Error::Message(format!(
+            "profile '{}' not found in {}",
+            name,
+            path.display()
+        ))
+    })
+}
+
+fn parse_profiles(text: &str) -> Result<BTreeMap<String, Profile>, toml::de::Error> {
+    toml::from_str(text)
+}
+
+/// Returns the profile with the name, or the one named in `KV_ASSETS_PROFILE`,
+/// or None if neither is set
+pub(crate) fn selected_profile(name: Option<&str>) -> Result<Option<Profile>, Error> {
+    match name {
+        Some(name) => load_profile(name).map(Some),
+        None => match std::env::var(PROFILE_ENV_VAR) {
+            Ok(name) if !name.is_empty() => load_profile(&name).map(Some),
+            _ => Ok(None),
+        },
+    }
+}
+
+/// Environment variables with credentials, which take precedence over saved credentials
+#[cfg(feature = "keyring")]
+const AUTH_ENV_VARS: [&str; 3] = ["CF_API_TOKEN", "CF_API_KEY", "CF_EMAIL"];
+
+#[cfg(feature = "keyring")]
+const KEYRING_SERVICE: &str = "kv-sync";
+#[cfg(feature = "keyring")]
+const KEYRING_USER: &str = "cloudflare-api-token";
+
+/// Logs in to Cloudflare in the browser (the same flow as `wrangler login`), and saves
+/// the API token in wrangler's config file, where later syncs find it.
+/// Asks for confirmation before opening the browser, and waits for the token
+/// for up to 500 seconds.
+pub fn login() -> Result<(), Error> {
+    wrangler::login::run()?;
+    Ok(())
+}
+
+/// Returns the credentials for Cloudflare api calls. If the credential store
+/// isn't available (e.g., no Secret Service on a CI runner), it's skipped.
+pub(crate) fn global_user(profile: Option<&Profile>) -> Result<GlobalUser, Error> {
+    if let Some(api_token) = profile.and_then(|p| p.api_token.clone()) {
+        return Ok(GlobalUser::TokenAuth { api_token });
+    }
+    #[cfg(feature = "keyring")]
+    if !AUTH_ENV_VARS.iter().any(|var| std::env::var(var).is_ok()) {
+        if let Ok(Some(api_token)) = load_token() {
+            return Ok(GlobalUser::TokenAuth { api_token });
+        }
+    }
+    Ok(GlobalUser::new()?)
+}
+
+#[cfg(feature = "keyring")]
+fn entry() -> Result<keyring::Entry, Error> {
+    keyring::Entry::new(KEYRING_SERVICE, KEYRING_USER).map_err(keyring_error)
+}
+
+#[cfg(feature = "keyring")]
+fn keyring_error(e: keyring::Error) -> Error {
+    Error::Message(format!("credential store: {}", e))
+}
+
+/// Saves the API token in the OS credential store, replacing any saved token
+#[cfg(feature = "keyring")]
+pub fn save_token(token: &str) -> Result<(), Error> {
+    entry()?.set_password(token).map_err(keyring_error)
+}
+
+/// Returns the API token saved in the OS credential store, if any
+#[cfg(feature = "keyring")]
+pub fn load_token() -> Result<Option<String>, Error> {
+    match entry()?.get_password() {
+        Ok(token) => Ok(Some(token)),
+        Err(keyring::Error::NoEntry) => Ok(None),
+        Err(e) => Err(keyring_error(e)),
+    }
+}
+
+/// Removes the API token from the OS credential store.
+/// Returns false if there wasn't one.
+#[cfg(feature = "keyring")]
+pub fn delete_token() -> Result<bool, Error> {
+    match entry()?.delete_credential() {
+        Ok(()) => Ok(true),
+        Err(keyring::Error::NoEntry) => Ok(false),
+        Err(e) => Err(keyring_error(e)),
+    }
+}
+
+#[test]
+fn test_parse_profiles() {
+    let profiles = parse_profiles(
+        r#"
+        [default]
+        api_token = "abc"
+
+        [staging]
+        account_id = "123"
+        api_token = "def"
+        namespace_id = "456"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(
+        profiles["default"],
+        Profile {
+            api_token: Some("abc".to_string()),
+            ..Default::default()
+        }
+    );
+    assert_eq!(profiles["staging"].namespace_id.as_deref(), Some("456"));
+    // misspelled fields aren't ignored
+    assert!(parse_profiles("[staging]\ntoken = \"abc\"").is_err());
+    assert!(parse_profiles("[staging]\naccount_id = 1").is_err());
+}
@@ -0,0 +1,90 @@
+//! Serving assets entirely from memory, without KV.
+//!
+//! `kv-sync embed` (see `embed_assets`) writes an index with the contents of every asset
+//! embedded, which the worker includes with `include_bytes!` and serves with
+//! `KVAssets::embedded`. Suitable for small sites, since the whole site is part of
+//! the worker bundle.
+
+use crate::{AssetStore, Error, KVAssets};
+
+/// Asset store for indexes with all contents embedded. It holds no values:
+/// reads return `Error::KVKeyNotFound`, and writes fail.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Embedded;
+
+#[cfg_attr(target_arch = "wasm32", async_trait::async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait::async_trait)]
+impl AssetStore for Embedded {
+    async fn get_value(&self, key: &str) -> Result<bytes::Bytes, Error> {
+        Err(Error::KVKeyNotFound(key.to_string(), 404))
+    }
+
+    async fn put_value(
+        &self,
+        key: &str,
+        _value: bytes::Bytes,
+        _expiration_ttl: Option<u64>,
+    ) -> Result<(), Error> {
+        Err(Error::Message(format!(
+            "can't write {}: embedded assets are read-only",
+            key
+        )))
+    }
+
+    async fn delete_value(&self, key: &str) -> Result<(), Error> {
+        Err(Error::Message(format!(
+            "can't delete {}: embedded assets are read-only",
+            key
+        )))
+    }
+}
+
+impl<'ah> KVAssets<'ah, Embedded> {
+    /// Initialize with an index generated by `kv-sync embed`, serving all assets from memory
+    pub fn embedded(bundle: &'ah [u8]) -> Self {
+        Self::with_store(bundle, Embedded)
+    }
+}
+
+/// Tests serving from an embedded bundle (does not invoke cloudflare api)
+#[cfg(all(test, not(target_arch = "wasm32")))]
+#[tokio::test]
+async fn test_embedded() {
+    use crate::{AssetIndex, AssetMetadata};
+
+    let mut index = AssetIndex::new();
+    index.insert(
+        "index.html".to_string(),
+        AssetMetadata {
+            path: "index.abc123.html".to_string(),
+            size: 7,
+            inline: Some(b"<html/>".to_vec()),
+            ..Default::default()
+        },
+    );
+    index.insert(
+        "big.bin".to_string(),
+        AssetMetadata {
+            path: "big.def456.bin".to_string(),
+            size: 100,
+            ..Default::default()
+        },
+    );
+    let bundle = crate::encode_index(&index).expect("serialize-index");
+    let assets = KVAssets::embedded(&bundle);
+    assert_eq!(
+        assets
+            .get_asset("/index.html")
+            .await
+            .unwrap()
+            .unwrap()
+            .as_ref(),
+        b"<html/>"
+    );
+    assert_eq!(assets.get_asset("/missing").await.unwrap(), None);
+    // not embedded
+    assert!(matches!(
+        assets.get_asset("/big.bin").await,
+        Err(Error::KVKeyNotFound(_, 404))
+    ));
+}
@@ -0,0 +1,198 @@
+//! Content-Encoding negotiation, precompressed variants, and on-the-fly compression
+
+use serde::{Deserialize, Serialize};
+
+// Extensions of file types that are already compressed, and aren't worth compressing again
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "avif", "br", "bz2", "gif", "gz", "jpeg", "jpg", "mp3", "mp4", "ogg", "pdf", "png",
+    "webm", "webp", "woff", "woff2", "xz", "zip", "zst",
+];
+
+/// Asset body, with the value for the `Content-Encoding` response header
+/// if the body is compressed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EncodedAsset {
+    /// Asset contents, encoded as indicated by `content_encoding`
+    pub body: bytes::Bytes,
+    /// Content-Encoding of body, or None if it is not compressed
+    pub content_encoding: Option<&'static str>,
+}
+
+/// Codings of precompressed variants, in order of preference, with their file extensions
+pub(crate) const PRECOMPRESSED: &[(&str, &str)] = &[("br", "br"), ("gzip", "gz")];
+
+/// Compressed copy of an asset, uploaded by kv-sync (see `SyncConfig::precompress`).
+/// Stored in the index, so it can't gain fields (see `AssetMetadata`).
+#[derive(Clone, Debug, Default, PartialEq, Eq, Ord, PartialOrd, Serialize, Deserialize)]
+pub struct EncodedVariant {
+    /// Content-Encoding of the variant: "br" or "gzip"
+    pub encoding: String,
+    /// KV key of the variant
+    pub path: String,
+    /// Size of the compressed contents
+    pub size: u64,
+    /// Number of chunks, or 0 if it's stored under `path` (see `chunk_key`)
+    pub chunks: u32,
+}
+
+/// Returns the most preferred variant acceptable to the client, with its coding
+pub(crate) fn choose_variant<'v>(
+    variants: &'v [EncodedVariant],
+    accept_encoding: &str,
+) -> Option<(&'static str, &'v EncodedVariant)> {
+    PRECOMPRESSED
+        .iter()
+        .filter(|(coding, _)| accepts(accept_encoding, coding))
+        .find_map(|(coding, _)| {
+            variants
+                .iter()
+                .find(|v| v.encoding == *coding)
+                .map(|v| (*coding, v))
+        })
+}
+
+/// Returns true if the value of an `Accept-Encoding` request header allows the coding.
+/// A coding with `q=0` is not acceptable; `*` matches codings not otherwise listed.
+pub(crate) fn accepts(accept_encoding: &str, coding: &str) -> bool {
+    let mut wildcard = false;
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or_default().trim();
+        let q_zero = parts.any(|p| {
+            let p = p.trim();
+            p.starts_with("q=") && p[2..].trim().parse::<f32>().is_ok_and(|q| q <= 0.0)
+        });
+        if name.eq_ignore_ascii_case(coding) {
+            return !q_zero;
+        }
+        if name == "*" {
+            wildcard = !q_zero;
+        }
+    }
+    wildcard
+}
+
+/// Returns true if the path's extension indicates a file type that is already compressed
+#[cfg_attr(
+    all(target_arch = "wasm32", not(feature = "compression")),
+    allow(dead_code)
+)]
+pub(crate) fn is_compressed_type(path: &str) -> bool {
+    match path.rsplit_once('.') {
+        Some((_, ext)) => COMPRESSED_EXTENSIONS
+            .iter()
+            .any(|c| c.eq_ignore_ascii_case(ext)),
+        None => false,
+    }
+}
+
+/// Returns true if the media type is one of `types`: a type ending in '/', such as
+/// `text/`, matches every subtype; others match exactly. Parameters are ignored.
+#[cfg(feature = "compression")]
+pub(crate) fn media_type_matches(content_type: &str, types: &[String]) -> bool {
+    let media_type = content_type.split(';').next().unwrap_or_default().trim();
+    types.iter().any(|t| match t.ends_with('/') {
+        true => media_type
+            .get(..t.len())
+            .is_some_and(|start| start.eq_ignore_ascii_case(t)),
+        false => media_type.eq_ignore_ascii_case(t),
+    })
+}
+
+/// Compress data with brotli, at a quality fast enough for compressing responses
+#[cfg(feature = "brotli")]
+pub(crate) fn brotli(data: &[u8]) -> Result<Vec<u8>, crate::Error> {
+    use std::io::Write as _;
+
+    let mut out = Vec::with_capacity(data.len() / 2);
+    {
+        let mut writer = brotli::CompressorWriter::new(&mut out, 4096, 5, 22);
+        writer
+            .write_all(data)
+            .map_err(|e| crate::Error::Message(format!("brotli error: {}", e)))?;
+    }
+    Ok(out)
+}
+
+/// Compress data with gzip
+#[cfg(feature = "compression")]
+pub(crate) fn gzip(data: &[u8]) -> Result<Vec<u8>, crate::Error> {
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write as _;
+
+    let mut encoder = GzEncoder::new(Vec::with_capacity(data.len() / 2), Compression::default());
+    encoder
+        .write_all(data)
+        .map_err(|e| crate::Error::Message(format!("gzip error: {}", e)))?;
+    encoder
+        .finish()
+        .map_err(|e| crate::Error::Message(format!("gzip error: {}", e)))
+}
+
+#[test]
+fn test_accepts() {
+    assert!(accepts("gzip, deflate, br", "gzip"));
+    assert!(accepts("deflate, GZIP;q=0.5", "gzip"));
+    assert!(!accepts("deflate, br", "gzip"));
+    assert!(!accepts("gzip;q=0, *", "gzip"));
+    assert!(accepts("br, *", "gzip"));
+    assert!(!accepts("*;q=0", "gzip"));
+    assert!(!accepts("", "gzip"));
+
+    assert!(is_compressed_type("images/logo.PNG"));
+    assert!(!is_compressed_type("app.js"));
+    assert!(!is_compressed_type("README"));
+
+    let variant = |encoding: &str| EncodedVariant {
+        encoding: encoding.to_string(),
+        ..Default::default()
+    };
+    let variants = [variant("gzip"), variant("br")];
+    let chosen = |accept| choose_variant(&variants, accept).map(|(coding, _)| coding);
+    assert_eq!(chosen("gzip, deflate, br"), Some("br"));
+    assert_eq!(chosen("gzip"), Some("gzip"));
+    assert_eq!(chosen("br;q=0, gzip"), Some("gzip"));
+    assert_eq!(chosen("identity"), None);
+    assert_eq!(choose_variant(&variants[..1], "br"), None);
+}
+
+#[cfg(feature = "compression")]
+#[test]
+fn test_gzip() {
+    use std::io::Read as _;
+
+    let data = "hello ".repeat(100);
+    let compressed = gzip(data.as_bytes()).unwrap();
+    assert!(compressed.len() < data.len());
+    let mut decoded = String::new();
+    flate2::read::GzDecoder::new(&compressed[..])
+        .read_to_string(&mut decoded)
+        .unwrap();
+    assert_eq!(decoded, data);
+}
+
+#[cfg(feature = "compression")]
+#[test]
+fn test_media_type_matches() {
+    let types = vec!["text/".to_string(), "application/json".to_string()];
+    assert!(media_type_matches("text/html; charset=utf-8", &types));
+    assert!(media_type_matches("Application/JSON", &types));
+    assert!(!media_type_matches("application/json-seq", &types));
+    assert!(!media_type_matches("image/svg+xml", &types));
+    assert!(!media_type_matches("tex", &types));
+}
+
+#[cfg(feature = "brotli")]
+#[test]
+fn test_brotli() {
+    use std::io::Read as _;
+
+    let data = "hello ".repeat(100);
+    let compressed = brotli(data.as_bytes()).unwrap();
+    assert!(compressed.len() < data.len());
+    let mut decoded = String::new();
+    brotli::Decompressor::new(&compressed[..], 4096)
+        .read_to_string(&mut decoded)
+        .unwrap();
+    assert_eq!(decoded, data);
+}
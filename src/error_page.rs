@@ -0,0 +1,117 @@
+//! Error documents: assets served as the body of error responses from `handle_request`,
+//! such as `404.html` for 404 and `error.html` for 500, set when building the handler
+//! (see `KVAssets::with_error_document`) or at sync time (see `SyncConfig::error_documents`).
+//!
+//! Documents set at sync time are stored in the index, on an entry at
+//! `ERROR_DOCUMENTS_PATH`, which can't be requested since paths can't contain control
+//! characters.
+
+use crate::{AssetResponse, AssetStore, Error, KVAssets};
+
+/// Index path of the entry with the error documents set at sync time
+pub(crate) const ERROR_DOCUMENTS_PATH: &str = "\0errors";
+
+/// Returns an error if the status isn't an error status (400 to 599) or the path is empty
+#[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+pub(crate) fn check_error_document(status: u16, path: &str) -> Result<(), Error> {
+    if !(400..=599).contains(&status) || path.trim_start_matches('/').is_empty() {
+        return Err(Error::Message(format!(
+            "invalid error document {} -> {:?}",
+            status, path
+        )));
+    }
+    Ok(())
+}
+
+impl<'ah, S: AssetStore> KVAssets<'ah, S> {
+    /// Returns the path of the error document for the status, from `with_error_document`,
+    /// or, if not set there, from the index
+    pub(crate) fn error_document(&self, status: u16) -> Result<Option<String>, Error> {
+        if let Some((_, path)) = self.error_documents.iter().find(|(s, _)| *s == status) {
+            return Ok(Some(path.clone()));
+        }
+        let map = self.index_map()?;
+        Ok(map.get(ERROR_DOCUMENTS_PATH).and_then(|md| {
+            md.error_documents
+                .iter()
+                .find(|(s, _)| *s == status)
+                .map(|(_, path)| path.clone())
+        }))
+    }
+
+    /// Replaces the body and content type of an error response with those of the
+    /// error document for its status, if there is one and it can be read
+    pub(crate) async fn with_error_body(
+        &self,
+        method: &str,
+        mut response: AssetResponse,
+    ) -> AssetResponse {
+        let path = match self.error_document(response.status) {
+            Ok(Some(path)) => path,
+            _ => return response,
+        };
+        let document = match self.respond(method, &path, &[]).await {
+            Ok(document) if document.status == 200 => document,
+            _ => return response,
+        };
+        response
+            .headers
+            .retain(|(name, _)| !name.eq_ignore_ascii_case("content-length"));
+        for name in ["Content-Type", "Content-Length"] {
+            if let Some(value) = document.header(name) {
+                response.headers.push((name.to_string(), value.to_string()));
+            }
+        }
+        response.body = document.body;
+        response
+    }
+}
+
+/// Tests error documents (does not invoke cloudflare api)
+#[cfg(all(test, not(target_arch = "wasm32")))]
+#[tokio::test]
+async fn test_error_documents() {
+    use crate::{AssetIndex, AssetMetadata};
+
+    let md = |body: &[u8]| AssetMetadata {
+        path: "x".to_string(),
+        size: body.len() as u64,
+        content_type: Some("text/html".to_string()),
+        inline: Some(body.to_vec()),
+        protected: body == b"secret",
+        ..Default::default()
+    };
+    let mut index = AssetIndex::new();
+    index.insert("404.html".to_string(), md(b"not found"));
+    index.insert("403.html".to_string(), md(b"forbidden"));
+    index.insert("private.txt".to_string(), md(b"secret"));
+    index.insert(
+        ERROR_DOCUMENTS_PATH.to_string(),
+        AssetMetadata {
+            error_documents: vec![(404, "missing.html".to_string())],
+            ..Default::default()
+        },
+    );
+    let blob = crate::encode_index(&index).expect("serialize-index");
+    let kv = KVAssets::init(&blob, "123", "namespace", "token");
+
+    // the document in the index is missing, so the response is unchanged
+    let resp = kv.handle_request("GET", "/nope", &[]).await.unwrap();
+    assert_eq!((resp.status, resp.body.len()), (404, 0));
+
+    let kv = kv
+        .with_error_document(404, "/404.html")
+        .with_error_document(403, "403.html");
+    let resp = kv.handle_request("GET", "/nope", &[]).await.unwrap();
+    assert_eq!(resp.status, 404);
+    assert_eq!(resp.body.as_ref(), b"not found");
+    assert_eq!(resp.header("content-type"), Some("text/html"));
+    let resp = kv.handle_request("GET", "/private.txt", &[]).await.unwrap();
+    assert_eq!(
+        (resp.status, resp.body.as_ref()),
+        (403, b"forbidden".as_ref())
+    );
+    let resp = kv.handle_request("HEAD", "/nope", &[]).await.unwrap();
+    assert_eq!((resp.status, resp.body.len()), (404, 0));
+    assert_eq!(resp.header("content-length"), Some("9"));
+}
@@ -0,0 +1,70 @@
+//! Glob patterns for matching asset paths in sync-time and serving rules.
+//!
+//! `*` matches any characters except '/', `**` matches any characters including '/',
+//! and `?` matches one character other than '/'. Patterns without a '/' match
+//! the file name in any directory, so `*.css` matches `css/site.css`, and patterns
+//! starting with '/' match from the root, so `/robots.txt` only matches `robots.txt`.
+
+/// Returns true if the asset path (without leading '/') matches the pattern
+pub(crate) fn glob_match(pattern: &str, path: &str) -> bool {
+    let (anchored, pattern) = match pattern.strip_prefix('/') {
+        Some(pattern) => (true, pattern),
+        None => (false, pattern),
+    };
+    if anchored || pattern.contains('/') {
+        match_bytes(pattern.as_bytes(), path.as_bytes())
+    } else {
+        let name = path.rsplit('/').next().unwrap_or(path);
+        match_bytes(pattern.as_bytes(), name.as_bytes())
+    }
+}
+
+fn match_bytes(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some(b'*') if pattern.get(1) == Some(&b'*') => {
+            let rest = &pattern[2..];
+            // "**/" also matches no directories
+            if rest.first() == Some(&b'/') && match_bytes(&rest[1..], text) {
+                return true;
+            }
+            (0..=text.len()).any(|i| match_bytes(rest, &text[i..]))
+        }
+        Some(b'*') => {
+            let rest = &pattern[1..];
+            for i in 0..=text.len() {
+                if match_bytes(rest, &text[i..]) {
+                    return true;
+                }
+                if text.get(i) == Some(&b'/') {
+                    break;
+                }
+            }
+            false
+        }
+        Some(b'?') => {
+            matches!(text.first(), Some(c) if *c != b'/') && match_bytes(&pattern[1..], &text[1..])
+        }
+        Some(c) => text.first() == Some(c) && match_bytes(&pattern[1..], &text[1..]),
+    }
+}
+
+#[test]
+fn test_glob_match() {
+    assert!(glob_match("*.css", "site.css"));
+    assert!(glob_match("*.css", "css/site.css"));
+    assert!(!glob_match("*.css", "site.css.map"));
+    assert!(glob_match("downloads/*", "downloads/a.zip"));
+    assert!(glob_match("/downloads/*", "downloads/a.zip"));
+    assert!(!glob_match("downloads/*", "downloads/old/a.zip"));
+    assert!(glob_match("downloads/**", "downloads/old/a.zip"));
+    assert!(glob_match("**/*.html", "index.html"));
+    assert!(glob_match("**/*.html", "docs/api/index.html"));
+    assert!(glob_match("img/?.png", "img/a.png"));
+    assert!(!glob_match("img/?.png", "img/ab.png"));
+    assert!(glob_match("robots.txt", "robots.txt"));
+    assert!(glob_match("robots.txt", "a/robots.txt"));
+    assert!(!glob_match("/robots.txt", "a/robots.txt"));
+    assert!(glob_match("/**", "a/robots.txt"));
+    assert!(!glob_match("docs/*.html", "index.html"));
+}
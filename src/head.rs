@@ -0,0 +1,87 @@
+//! Metadata-only lookups, for answering HEAD requests without reading the asset.
+
+use crate::{chunk_key, AssetStore, Error, KVAssets, ToAssetKey};
+use futures_util::StreamExt;
+
+/// Metadata of an asset from the index (see `KVAssets::head`)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AssetHead {
+    /// Size of the asset
+    pub size: u64,
+    /// Last modified time, in UTC seconds since EPOCH, or 0 if it's unknown
+    pub modified: u64,
+    /// Media type for the `Content-Type` header, if known
+    pub content_type: Option<String>,
+    /// Value for the `ETag` header, including the quotes
+    pub etag: String,
+}
+
+impl<'ah, S: AssetStore> KVAssets<'ah, S> {
+    /// Returns the size, modified time, content type, and ETag of the asset at `path`,
+    /// from the index. If `verify` is true, also checks that the asset's value is in KV,
+    /// by reading the start of the value (or its first chunk); otherwise, KV isn't read.
+    /// Assets embedded in the index are always present.
+    /// Returns Ok(None) if the asset is not in the index, or, if verified, not in KV.
+    pub async fn head<K: ToAssetKey + ?Sized>(
+        &self,
+        path: &K,
+        verify: bool,
+    ) -> Result<Option<AssetHead>, Error> {
+        let md = match self.lookup_key(path)? {
+            Some(md) if md.protected => return Err(Error::SignatureRequired(md.path)),
+            Some(md) => md,
+            None => return Ok(None),
+        };
+        if verify && md.inline.is_none() {
+            let key = match md.chunks {
+                0 => md.path.clone(),
+                _ => chunk_key(&md.path, 0),
+            };
+            // the stream is dropped after its first part, so the rest isn't read
+            match self.store_for(&md.path).get_stream(&key).next().await {
+                Some(Err(Error::KVKeyNotFound(_, _))) => return Ok(None),
+                Some(Err(e)) => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(Some(AssetHead {
+            size: md.size,
+            modified: md.modified,
+            etag: md.etag(),
+            content_type: md.content_type,
+        }))
+    }
+}
+
+/// Tests metadata-only lookups (does not invoke cloudflare api)
+#[cfg(all(test, not(target_arch = "wasm32")))]
+#[tokio::test]
+async fn test_head() {
+    use crate::{AssetIndex, AssetMetadata};
+
+    let dir = std::env::temp_dir().join(format!("kv-assets-head-{}", std::process::id()));
+    let store = crate::LocalKV::open(&dir).unwrap();
+    store.put("a.1.css", b"body{}", None).unwrap();
+    let md = |path: &str| AssetMetadata {
+        path: path.to_string(),
+        size: 6,
+        modified: 1000,
+        content_type: Some("text/css".to_string()),
+        ..Default::default()
+    };
+    let mut index = AssetIndex::new();
+    index.insert("a.css".to_string(), md("a.1.css"));
+    index.insert("b.css".to_string(), md("b.2.css"));
+    let blob = crate::encode_index(&index).expect("serialize-index");
+
+    let kv = KVAssets::with_store(&blob, store);
+    let head = kv.head("/a.css", true).await.unwrap().unwrap();
+    assert_eq!(head.size, 6);
+    assert_eq!(head.content_type.as_deref(), Some("text/css"));
+    assert_eq!(head.etag, md("a.1.css").etag());
+    assert!(kv.head("/b.css", false).await.unwrap().is_some());
+    assert!(kv.head("/b.css", true).await.unwrap().is_none());
+    assert!(kv.head("/c.css", false).await.unwrap().is_none());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
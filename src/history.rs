@@ -0,0 +1,81 @@
+#![cfg(not(target_arch = "wasm32"))]
+//! Records of past deploys, kept in the namespace.
+//!
+//! When `SyncConfig::record_history` is set, each successful sync stores its index under
+//! a key starting with `HISTORY_PREFIX`, with a `DeployRecord` in the key's metadata,
+//! so `deploy_history` can list deploys from the key listing alone, and `deployed_index`
+//! can read the index of any of them to compare it with another. History keys are never
+//! pruned.
+
+use crate::{decode_index, upload::open_destination, AssetIndex, Error, SyncConfig};
+use serde::{Deserialize, Serialize};
+
+/// Prefix of the KV keys of deploy records
+pub const HISTORY_PREFIX: &str = "__kv-sync/history/";
+
+/// A deploy made by kv-sync
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeployRecord {
+    /// KV key with the deployed index
+    #[serde(skip)]
+    pub key: String,
+    /// When the sync finished, in UTC seconds since EPOCH
+    pub deployed: u64,
+    /// Hex SHA-256 digest of the index
+    pub index_sha256: String,
+    /// Number of assets in the index
+    pub assets: usize,
+    /// Number of keys uploaded
+    pub uploaded: usize,
+    /// Number of stale keys deleted
+    pub deleted: usize,
+    /// User that ran the sync, from `USER` or `USERNAME`
+    pub deployer: Option<String>,
+}
+
+/// Returns the key of the record for a deploy at `deployed`.
+/// Times are zero-padded, so keys sort in the order of deploys.
+pub(crate) fn history_key(deployed: u64) -> String {
+    format!("{}{:020}", HISTORY_PREFIX, deployed)
+}
+
+/// Returns the name of the user running the sync
+pub(crate) fn deployer() -> Option<String> {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .ok()
+        .filter(|user| !user.is_empty())
+}
+
+/// Lists the deploys recorded in the destination of `args` (the local kv dir,
+/// or the site's namespace), most recent first
+pub fn deploy_history(args: SyncConfig) -> Result<Vec<DeployRecord>, Error> {
+    let dest = open_destination(&args)?;
+    let mut records: Vec<DeployRecord> = dest
+        .list_metadata()?
+        .into_iter()
+        .filter(|(key, _)| key.starts_with(HISTORY_PREFIX))
+        .filter_map(|(key, metadata)| {
+            let record = metadata?.get("deploy").cloned()?;
+            let record: DeployRecord = serde_json::from_value(record).ok()?;
+            Some(DeployRecord { key, ..record })
+        })
+        .collect();
+    records.sort_by(|a, b| b.key.cmp(&a.key));
+    Ok(records)
+}
+
+/// Reads the index that was deployed by `record`
+pub fn deployed_index(args: SyncConfig, record: &DeployRecord) -> Result<AssetIndex, Error> {
+    let blob = open_destination(&args)?.get(&record.key)?;
+    decode_index(&blob)
+}
+
+#[test]
+fn test_history_key() {
+    assert_eq!(
+        history_key(1_700_000_000),
+        "__kv-sync/history/00000000001700000000"
+    );
+    assert!(history_key(999) < history_key(1000));
+}
@@ -0,0 +1,257 @@
+//! Serialized form of the asset index.
+//!
+//! The index is generated by kv-sync and compiled into the worker, so a worker
+//! may be reading an index written by a newer (or older) version of kv-sync, for example
+//! during a rolling upgrade. Since bincode is not self-describing, the index is written
+//! in a small container format that tolerates schema changes:
+//!
+//! - a header: the magic bytes `KVAI` followed by the format version (u16, little-endian)
+//! - the bincode-serialized list of `(key, entry)` pairs, where each entry is the
+//!   bincode-serialized `AssetMetadata`, kept as a separate byte array
+//!
+//! Because each entry has its own length, a reader ignores any trailing fields it doesn't
+//! know about (written by a newer kv-sync), and fills in fields missing from the end of an
+//! entry (written by an older kv-sync) with zero bytes. For this to work, new fields must
+//! only be appended to the end of `AssetMetadata`, and their bincode encoding of all zeros
+//! must be their default value (`None`, `0`, `false`, empty string or collection).
+//!
+//! `INDEX_FORMAT_VERSION` is incremented only for changes that older readers can't handle.
+//! Indexes without a header (from kv-assets 0.2) are still readable.
+
+use crate::{AssetIndex, AssetMetadata, Error};
+use serde::Deserialize;
+
+/// Current version of the index container format
+pub const INDEX_FORMAT_VERSION: u16 = 1;
+
+const INDEX_MAGIC: &[u8; 4] = b"KVAI";
+const HEADER_LEN: usize = INDEX_MAGIC.len() + 2;
+
+// Zero bytes appended to each entry when decoding, providing defaults for fields
+// that were added after the entry was written. 256 bytes covers at least 32 new fields.
+const ENTRY_PADDING: [u8; 256] = [0u8; 256];
+
+/// Asset metadata as written by kv-assets 0.2, before the index had a header
+#[derive(Deserialize)]
+struct LegacyMetadata {
+    path: String,
+    modified: u64,
+    size: u64,
+}
+
+/// Serializes the index into the versioned container format
+pub fn encode_index(index: &AssetIndex) -> Result<Vec<u8>, Error> {
+    let mut entries = Vec::with_capacity(index.len());
+    for (key, md) in index.iter() {
+        let entry = bincode::serialize(md).map_err(|e| Error::SerializeAssets(e.to_string()))?;
+        entries.push((key, entry));
+    }
+    // sort for a deterministic result, so unchanged assets produce an identical file
+    entries.sort();
+    let mut blob = Vec::with_capacity(HEADER_LEN);
+    blob.extend_from_slice(INDEX_MAGIC);
+    blob.extend_from_slice(&INDEX_FORMAT_VERSION.to_le_bytes());
+    bincode::serialize_into(&mut blob, &entries)
+        .map_err(|e| Error::SerializeAssets(e.to_string()))?;
+    Ok(blob)
+}
+
+/// Deserializes an index written by any version of kv-sync
+pub fn decode_index(blob: &[u8]) -> Result<AssetIndex, Error> {
+    if blob.len() < HEADER_LEN || !blob.starts_with(INDEX_MAGIC) {
+        return decode_legacy(blob);
+    }
+    let version = u16::from_le_bytes([blob[4], blob[5]]);
+    if version > INDEX_FORMAT_VERSION {
+        return Err(Error::DeserializeAssets(format!(
+            "unsupported index format version {} (this version of kv-assets reads up to {})",
+            version, INDEX_FORMAT_VERSION
+        )));
+    }
+    let entries: Vec<(String, Vec<u8>)> = bincode::deserialize(&blob[HEADER_LEN..])
+        .map_err(|e| Error::DeserializeAssets(e.to_string()))?;
+    let mut index = AssetIndex::with_capacity(entries.len());
+    for (key, entry) in entries.into_iter() {
+        let md = decode_entry(&entry)
+            .map_err(|e| Error::DeserializeAssets(format!("entry {}: {}", key, e)))?;
+        index.insert(key, md);
+    }
+    Ok(index)
+}
+
+/// Returns the container format version of an index blob,
+/// or 0 for indexes without a header (from kv-assets 0.2)
+pub fn index_format_version(blob: &[u8]) -> u16 {
+    if blob.len() < HEADER_LEN || !blob.starts_with(INDEX_MAGIC) {
+        0
+    } else {
+        u16::from_le_bytes([blob[4], blob[5]])
+    }
+}
+
+/// Rewrites the index file at `from` in the current format, at `to` (which may be the same
+/// file), so deployments can upgrade without regenerating the index from the asset files.
+/// Returns the format version of the original file.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn migrate_index(from: &std::path::Path, to: &std::path::Path) -> Result<u16, Error> {
+    let blob =
+        std::fs::read(from).map_err(|e| Error::IO(format!("reading {}: {}", from.display(), e)))?;
+    let index = decode_index(&blob)?;
+    std::fs::write(to, encode_index(&index)?)
+        .map_err(|e| Error::IO(format!("writing {}: {}", to.display(), e)))?;
+    Ok(index_format_version(&blob))
+}
+
+/// Decode one entry, ignoring unknown trailing fields and defaulting missing ones
+fn decode_entry(entry: &[u8]) -> Result<AssetMetadata, bincode::Error> {
+    use std::io::Read as _;
+    let mut md: AssetMetadata = bincode::deserialize_from(entry.chain(&ENTRY_PADDING[..]))?;
+    // digests that don't pair up with the variants can't be trusted, so the variants
+    // are served unverified
+    if md.encoded_sha256.len() != md.encoded.len() {
+        md.encoded_sha256.clear();
+    }
+    Ok(md)
+}
+
+fn decode_legacy(blob: &[u8]) -> Result<AssetIndex, Error> {
+    let legacy: std::collections::HashMap<String, LegacyMetadata> =
+        bincode::deserialize(blob).map_err(|e| Error::DeserializeAssets(e.to_string()))?;
+    Ok(legacy
+        .into_iter()
+        .map(|(k, md)| {
+            (
+                k,
+                AssetMetadata {
+                    path: md.path,
+                    modified: md.modified,
+                    size: md.size,
+                    ..Default::default()
+                },
+            )
+        })
+        .collect())
+}
+
+/// Indexes written by earlier versions of kv-sync, in format version 1, can be read
+#[test]
+fn test_index_fixtures() {
+    // the first writer of format version 1: path, modified, size, and expires
+    let blob = hex::decode(concat!(
+        "4b564149010001000000000000000500000000000000622e74787431000000000000001000000000",
+        "000000622e616263646566303132332e74787400105e5f0000000014000000000000000100f15365",
+        "00000000",
+    ))
+    .unwrap();
+    let index = decode_index(&blob).unwrap();
+    let md = index.get("b.txt").unwrap();
+    assert_eq!(md.path, "b.abcdef0123.txt");
+    assert_eq!((md.size, md.expires), (20, Some(1_700_000_000)));
+    assert_eq!(md.sha256, None);
+
+    // a writer from before `encoded_sha256`, with a precompressed variant
+    let blob = hex::decode(concat!(
+        "4b564149010001000000000000000600000000000000612e68746d6cc80000000000000011000000",
+        "00000000612e313233343536373839302e68746d6c00105e5f000000000600000000000000000000",
+        "00000000000000000000000107070707070707070707070707070707070707070707070707070707",
+        "07070707010900000000000000746578742f68746d6c01000000000000000400000000000000677a",
+        "69701400000000000000612e313233343536373839302e68746d6c2e677a04000000000000000000",
+        "000000000000000000000000000000000000000000000000000000000000000000000000",
+    ))
+    .unwrap();
+    let index = decode_index(&blob).unwrap();
+    let md = index.get("a.html").unwrap();
+    assert_eq!(md.sha256, Some([7; 32]));
+    assert_eq!(md.content_type.as_deref(), Some("text/html"));
+    assert_eq!(md.encoded[0].path, "a.1234567890.html.gz");
+    assert!(md.encoded_sha256.is_empty());
+    assert!(md.error_documents.is_empty());
+
+    // digests out of step with the variants are dropped
+    let mut index = AssetIndex::new();
+    let mut md = md.clone();
+    md.encoded_sha256 = vec![[1; 32], [2; 32]];
+    index.insert("a.html".to_string(), md);
+    let index = decode_index(&encode_index(&index).unwrap()).unwrap();
+    assert!(index["a.html"].encoded_sha256.is_empty());
+}
+
+/// Indexes written by older or newer kv-sync versions can be read
+#[test]
+fn test_index_compatibility() {
+    #[derive(serde::Serialize)]
+    struct V0 {
+        path: String,
+        modified: u64,
+        size: u64,
+    }
+    let header = |blob: &mut Vec<u8>| {
+        blob.extend_from_slice(INDEX_MAGIC);
+        blob.extend_from_slice(&INDEX_FORMAT_VERSION.to_le_bytes());
+    };
+
+    // kv-assets 0.2: no header
+    let mut legacy = std::collections::HashMap::new();
+    legacy.insert(
+        "a.txt".to_string(),
+        V0 {
+            path: "a.123.txt".into(),
+            modified: 10,
+            size: 20,
+        },
+    );
+    let blob = bincode::serialize(&legacy).unwrap();
+    assert_eq!(index_format_version(&blob), 0);
+    let index = decode_index(&blob).unwrap();
+    assert_eq!(index.get("a.txt").unwrap().path, "a.123.txt");
+    assert_eq!(index.get("a.txt").unwrap().expires, None);
+
+    // older writer: fields missing from the end of each entry
+    let v0 = V0 {
+        path: "b.456.txt".into(),
+        modified: 10,
+        size: 20,
+    };
+    let mut blob = Vec::new();
+    header(&mut blob);
+    bincode::serialize_into(
+        &mut blob,
+        &vec![("b.txt".to_string(), bincode::serialize(&v0).unwrap())],
+    )
+    .unwrap();
+    let index = decode_index(&blob).unwrap();
+    assert_eq!(index.get("b.txt").unwrap().size, 20);
+    assert_eq!(index.get("b.txt").unwrap().expires, None);
+
+    // newer writer: unknown fields at the end of each entry
+    let future = (
+        AssetMetadata {
+            path: "c.789.txt".into(),
+            modified: 10,
+            size: 20,
+            expires: Some(99),
+            ..Default::default()
+        },
+        "not yet invented".to_string(),
+    );
+    let mut blob = Vec::new();
+    header(&mut blob);
+    bincode::serialize_into(
+        &mut blob,
+        &vec![("c.txt".to_string(), bincode::serialize(&future).unwrap())],
+    )
+    .unwrap();
+    let index = decode_index(&blob).unwrap();
+    assert_eq!(index.get("c.txt").unwrap().path, "c.789.txt");
+    assert_eq!(index.get("c.txt").unwrap().expires, Some(99));
+
+    // round trip
+    let encoded = encode_index(&index).unwrap();
+    assert_eq!(decode_index(&encoded).unwrap(), index);
+    assert_eq!(index_format_version(&encoded), INDEX_FORMAT_VERSION);
+
+    // incompatible future version is rejected
+    let mut blob = encoded;
+    blob[4] = 0xff;
+    assert!(decode_index(&blob).is_err());
+}
@@ -0,0 +1,165 @@
+//! Validated keys for index lookups and KV values.
+//!
+//! Lookup and KV methods accept `&str`, `String`, or `AssetKey` (anything implementing
+//! `ToAssetKey`). Strings are validated when the method is called, so a bad key is
+//! reported as `Error::EmptyKey` or `Error::InvalidKey` before any request is made.
+//! Construct an `AssetKey` once to validate a key ahead of time, or to reuse it.
+
+use crate::Error;
+use std::{convert::TryFrom, fmt};
+
+/// Maximum length of a KV key, in bytes
+pub const KEY_MAX_LEN: usize = 512;
+
+/// Asset path or KV key that is valid for lookups and KV requests:
+/// not empty, at most `KEY_MAX_LEN` bytes, not `.` or `..`, and without control characters.
+/// A leading '/' is removed, so `/index.html` and `index.html` are the same key,
+/// and keys starting with `//` are invalid.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct AssetKey(String);
+
+impl AssetKey {
+    /// Validates and normalizes the key
+    pub fn new(key: &str) -> Result<Self, Error> {
+        let key = key.strip_prefix('/').unwrap_or(key);
+        if key.is_empty() {
+            return Err(Error::EmptyKey);
+        }
+        let problem = if key.len() > KEY_MAX_LEN {
+            Some("longer than 512 bytes")
+        } else if key.starts_with('/') {
+            Some("starts with //")
+        } else if key == "." || key == ".." {
+            Some("not allowed by KV")
+        } else if key.chars().any(char::is_control) {
+            Some("contains control characters")
+        } else {
+            None
+        };
+        match problem {
+            Some(problem) => Err(Error::InvalidKey(format!("{:?} {}", key, problem))),
+            None => Ok(Self(key.to_string())),
+        }
+    }
+
+    /// Returns the key as a string
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Percent-encodes all but unreserved characters (and '/', if keep_slash)
+pub(crate) fn uri_encode(s: &str, keep_slash: bool) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric()
+            || matches!(b, b'-' | b'_' | b'.' | b'~')
+            || (keep_slash && b == b'/')
+        {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+impl fmt::Display for AssetKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl AsRef<str> for AssetKey {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::str::FromStr for AssetKey {
+    type Err = Error;
+
+    fn from_str(key: &str) -> Result<Self, Error> {
+        Self::new(key)
+    }
+}
+
+impl TryFrom<&str> for AssetKey {
+    type Error = Error;
+
+    fn try_from(key: &str) -> Result<Self, Error> {
+        Self::new(key)
+    }
+}
+
+impl TryFrom<String> for AssetKey {
+    type Error = Error;
+
+    fn try_from(key: String) -> Result<Self, Error> {
+        Self::new(&key)
+    }
+}
+
+/// Types accepted as keys by lookup and KV methods
+pub trait ToAssetKey {
+    /// Returns the key as given, before validation. Serving hooks receive this.
+    fn key_str(&self) -> &str;
+
+    /// Returns the validated key
+    fn to_asset_key(&self) -> Result<AssetKey, Error> {
+        AssetKey::new(self.key_str())
+    }
+}
+
+impl ToAssetKey for AssetKey {
+    fn key_str(&self) -> &str {
+        &self.0
+    }
+
+    fn to_asset_key(&self) -> Result<AssetKey, Error> {
+        Ok(self.clone())
+    }
+}
+
+impl ToAssetKey for str {
+    fn key_str(&self) -> &str {
+        self
+    }
+}
+
+impl ToAssetKey for String {
+    fn key_str(&self) -> &str {
+        self
+    }
+}
+
+impl<T: ToAssetKey + ?Sized> ToAssetKey for &T {
+    fn key_str(&self) -> &str {
+        (**self).key_str()
+    }
+
+    fn to_asset_key(&self) -> Result<AssetKey, Error> {
+        (**self).to_asset_key()
+    }
+}
+
+#[test]
+fn test_asset_key() {
+    assert_eq!(AssetKey::new("/a/b.txt").unwrap().as_str(), "a/b.txt");
+    assert_eq!(
+        "a/b.txt".to_asset_key().unwrap(),
+        String::from("/a/b.txt").to_asset_key().unwrap()
+    );
+    assert!(matches!(AssetKey::new("/"), Err(Error::EmptyKey)));
+    assert!(matches!(AssetKey::new(".."), Err(Error::InvalidKey(_))));
+    assert!(matches!(AssetKey::new("//a"), Err(Error::InvalidKey(_))));
+    assert!(matches!(
+        AssetKey::new("a\nb.txt"),
+        Err(Error::InvalidKey(_))
+    ));
+    assert!(AssetKey::new(&"x".repeat(KEY_MAX_LEN)).is_ok());
+    assert!(matches!(
+        AssetKey::new(&"x".repeat(KEY_MAX_LEN + 1)),
+        Err(Error::InvalidKey(_))
+    ));
+}
@@ -0,0 +1,1746 @@
+use crate::{
+    api_error::{api_error, ApiErrorDetail, CloudflareApiError},
+    key::uri_encode,
+    retry::{self, RetryPolicy},
+    throttle::Throttle,
+    trace::KvRequest,
+    AssetKey, AssetStore, ByteStream, Error, ToAssetKey, CHUNK_SIZE,
+};
+use futures_util::{stream, Stream, StreamExt, TryStreamExt};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+const CLOUDFLARE_KV_ENDPOINT: &str = "https://api.cloudflare.com/client/v4";
+// Maximum number of keys in one bulk write
+const BULK_KEY_MAX: usize = 10_000;
+// Keep bulk writes well under the API's 100MB limit
+const BULK_MAX_BYTES: usize = 50 * 1024 * 1024;
+// Keys per page when listing (the API's maximum)
+const LIST_PAGE_SIZE: u32 = 1000;
+/// Maximum size of a key's metadata, serialized as JSON
+pub(crate) const METADATA_MAX_BYTES: usize = 1024;
+// Size of the reads from a stream uploaded with `put_kv_stream`
+#[cfg(not(target_arch = "wasm32"))]
+const STREAM_BUF_SIZE: usize = 64 * 1024;
+// Time allowed for a response to a KV request
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+// Time allowed to connect to the KV api (not in a worker)
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// The API's rate limit
+const DEFAULT_RATE_LIMIT: (u32, Duration) = (1200, Duration::from_secs(300));
+
+/// Workers KV Parameters
+#[allow(clippy::upper_case_acronyms)]
+pub struct KV {
+    endpoint: String,
+    account_id: String,
+    namespace_id: String,
+    auth: Auth,
+    // shared by all requests, so connections are reused
+    client: OnceLock<reqwest::Client>,
+    retry: RetryPolicy,
+    timeout: Duration,
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    connect_timeout: Duration,
+    // shared with clients from `with_retry`, which use the same credentials
+    throttle: Arc<Throttle>,
+    hooks: Arc<Hooks>,
+}
+
+/// Middleware applied to every request to the API before it's sent, e.g. to add headers
+/// (see `KVBuilder::with_request_hook`)
+pub type RequestHook =
+    Box<dyn Fn(reqwest::RequestBuilder) -> reqwest::RequestBuilder + Send + Sync>;
+/// Callback invoked with every response from the API, and the time it took,
+/// e.g. to record metrics (see `KVBuilder::with_response_hook`)
+pub type ResponseHook = Box<dyn Fn(&reqwest::Response, Duration) + Send + Sync>;
+
+#[derive(Default)]
+struct Hooks {
+    request: Vec<RequestHook>,
+    response: Vec<ResponseHook>,
+}
+
+/// Clones share the http client, rate limit, and hooks (see `KV::with_retry`)
+impl Clone for KV {
+    fn clone(&self) -> Self {
+        self.with_retry(self.retry.clone())
+    }
+}
+
+/// Initialize KV parameters. `auth` is an API token, or another `Auth`.
+pub fn init_kv<T: ToString, A: Into<Auth>>(account: T, namespace: T, auth: A) -> KV {
+    KVBuilder::new(account, namespace, auth).build()
+}
+
+/// Credentials for the Cloudflare API
+#[derive(Clone)]
+pub enum Auth {
+    /// API token, sent as a Bearer token. Strings convert to this.
+    Token(String),
+    /// Legacy global API key, with the account's email address
+    ApiKey {
+        /// Email address of the Cloudflare account
+        email: String,
+        /// Global API key
+        key: String,
+    },
+    /// Cloudflare Access service token, for an API endpoint behind Cloudflare Access
+    ServiceToken {
+        /// Client id of the service token
+        client_id: String,
+        /// Client secret of the service token
+        client_secret: String,
+    },
+}
+
+impl From<String> for Auth {
+    fn from(token: String) -> Self {
+        Auth::Token(token)
+    }
+}
+
+impl From<&str> for Auth {
+    fn from(token: &str) -> Self {
+        Auth::Token(token.to_string())
+    }
+}
+
+impl From<&String> for Auth {
+    fn from(token: &String) -> Self {
+        Auth::Token(token.clone())
+    }
+}
+
+impl Auth {
+    /// Adds the credentials to a request
+    fn apply(&self, builder: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match self {
+            Auth::Token(token) => builder.header("Authorization", format!("Bearer {}", token)),
+            Auth::ApiKey { email, key } => builder
+                .header("X-Auth-Email", email)
+                .header("X-Auth-Key", key),
+            Auth::ServiceToken {
+                client_id,
+                client_secret,
+            } => builder
+                .header("CF-Access-Client-Id", client_id)
+                .header("CF-Access-Client-Secret", client_secret),
+        }
+    }
+}
+
+/// Builds a `KV` client with optional settings (see `KV::builder`)
+#[allow(clippy::upper_case_acronyms)]
+pub struct KVBuilder {
+    endpoint: String,
+    account_id: String,
+    namespace_id: String,
+    auth: Auth,
+    client: Option<reqwest::Client>,
+    retry: RetryPolicy,
+    timeout: Duration,
+    connect_timeout: Duration,
+    rate_limit: (u32, Duration),
+    hooks: Hooks,
+}
+
+impl KVBuilder {
+    /// Builder for the namespace, with default settings
+    pub fn new<T: ToString, A: Into<Auth>>(account: T, namespace: T, auth: A) -> Self {
+        Self {
+            endpoint: CLOUDFLARE_KV_ENDPOINT.to_string(),
+            account_id: account.to_string(),
+            namespace_id: namespace.to_string(),
+            auth: auth.into(),
+            client: None,
+            retry: RetryPolicy::default(),
+            timeout: DEFAULT_TIMEOUT,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            rate_limit: DEFAULT_RATE_LIMIT,
+            hooks: Hooks::default(),
+        }
+    }
+
+    /// Sends requests with `client`, e.g., one shared with the rest of the application,
+    /// or configured with a proxy. By default, a client is created on first use.
+    pub fn with_client(mut self, client: reqwest::Client) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Retries requests that fail with 429, a 5xx status, or a network error, with `policy`.
+    /// By default, requests are retried with `RetryPolicy::default()`.
+    pub fn with_retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = policy;
+        self
+    }
+
+    /// Fails requests with `Error::Timeout` if the response doesn't arrive within
+    /// `timeout` (10 seconds by default), so a worker can fall back to cached content.
+    /// Timed out requests are retried, so the total time is longer with retries.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Fails requests with `Error::Timeout` if a connection isn't made within `timeout`
+    /// (5 seconds by default). Not used with `with_client`, or in a worker,
+    /// where connections are made by the runtime.
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Paces requests to at most `requests` per `period`, by default the API's limit of
+    /// 1200 per 5 minutes, so bulk operations slow down instead of failing with 429s.
+    /// Requests also wait when the API's rate limit headers report the limit was reached.
+    /// 0 requests disables throttling.
+    pub fn with_rate_limit(mut self, requests: u32, period: Duration) -> Self {
+        self.rate_limit = (requests, period);
+        self
+    }
+
+    /// Sends requests to `url` instead of `https://api.cloudflare.com/client/v4`,
+    /// e.g. a local mock server in tests, or a gateway that forwards to the API
+    pub fn with_endpoint<T: ToString>(mut self, url: T) -> Self {
+        self.endpoint = url.to_string().trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Adds middleware that can change each request to the API, e.g. to add a header
+    /// with a trace id. Hooks run in the order they were added, after the credentials
+    /// are set, and again for each retry.
+    pub fn with_request_hook<F>(mut self, f: F) -> Self
+    where
+        F: Fn(reqwest::RequestBuilder) -> reqwest::RequestBuilder + Send + Sync + 'static,
+    {
+        self.hooks.request.push(Box::new(f));
+        self
+    }
+
+    /// Adds a callback invoked with each response from the API, including error statuses
+    /// and responses that are retried, and the time it took. Requests that fail
+    /// without a response aren't reported.
+    pub fn with_response_hook<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&reqwest::Response, Duration) + Send + Sync + 'static,
+    {
+        self.hooks.response.push(Box::new(f));
+        self
+    }
+
+    /// Returns the KV client
+    pub fn build(self) -> KV {
+        let client = OnceLock::new();
+        if let Some(c) = self.client {
+            let _ = client.set(c);
+        }
+        KV {
+            endpoint: self.endpoint,
+            account_id: self.account_id,
+            namespace_id: self.namespace_id,
+            auth: self.auth,
+            client,
+            retry: self.retry,
+            timeout: self.timeout,
+            connect_timeout: self.connect_timeout,
+            throttle: Arc::new(Throttle::new(self.rate_limit.0, self.rate_limit.1)),
+            hooks: Arc::new(self.hooks),
+        }
+    }
+}
+
+/// Worker variable with the Cloudflare account id (see `KV::from_worker_env`)
+pub const ACCOUNT_ID_VAR: &str = "KV_ACCOUNT_ID";
+/// Worker variable with the id of the namespace holding the assets
+pub const NAMESPACE_ID_VAR: &str = "KV_NAMESPACE_ID";
+/// Worker secret with an API token that can read the namespace
+pub const API_TOKEN_VAR: &str = "KV_API_TOKEN";
+
+impl KV {
+    /// Initializes KV parameters from the worker's `env` object (the second parameter of
+    /// a module worker's `fetch` handler), using the vars `KV_ACCOUNT_ID` and
+    /// `KV_NAMESPACE_ID`, and the secret `KV_API_TOKEN`.
+    /// Values are read with the REST API, so a KV namespace binding can't be used
+    /// in place of `KV_NAMESPACE_ID`.
+    #[cfg(target_arch = "wasm32")]
+    pub fn from_worker_env(env: &js_sys::wasm_bindgen::JsValue) -> Result<Self, Error> {
+        Self::from_vars(|name| {
+            js_sys::Reflect::get(env, &name.into())
+                .ok()
+                .and_then(|value| value.as_string())
+        })
+    }
+
+    // Reads the parameters with `get`, so the lookup can be tested without a worker
+    #[cfg_attr(not(target_arch = "wasm32"), allow(dead_code))]
+    pub(crate) fn from_vars<F: Fn(&str) -> Option<String>>(get: F) -> Result<Self, Error> {
+        let var = |name: &str| match get(name) {
+            Some(value) if !value.is_empty() => Ok(value),
+            _ => Err(Error::MissingEnvVar(name.to_string())),
+        };
+        Ok(init_kv(
+            var(ACCOUNT_ID_VAR)?,
+            var(NAMESPACE_ID_VAR)?,
+            var(API_TOKEN_VAR)?,
+        ))
+    }
+
+    /// Builder for a KV client with optional settings, such as a shared `reqwest::Client`
+    pub fn builder<T: ToString, A: Into<Auth>>(account: T, namespace: T, auth: A) -> KVBuilder {
+        KVBuilder::new(account, namespace, auth)
+    }
+
+    /// The http client, created on first use unless one was given to the builder
+    fn client(&self) -> &reqwest::Client {
+        self.client.get_or_init(|| {
+            #[cfg(not(target_arch = "wasm32"))]
+            let builder = reqwest::Client::builder().connect_timeout(self.connect_timeout);
+            #[cfg(target_arch = "wasm32")]
+            let builder = reqwest::Client::builder();
+            builder.build().unwrap_or_else(|_| reqwest::Client::new())
+        })
+    }
+
+    /// Returns a client for the same namespace, sharing the http client, that retries
+    /// with `policy`, e.g. `kv.with_retry(RetryPolicy::none()).get_kv_value(key)`
+    /// to fail fast on one call
+    pub fn with_retry(&self, policy: RetryPolicy) -> KV {
+        let mut kv = KVBuilder::new(&self.account_id, &self.namespace_id, self.auth.clone())
+            .with_client(self.client().clone())
+            .with_retry(policy)
+            .with_timeout(self.timeout)
+            .with_endpoint(&self.endpoint)
+            .build();
+        kv.throttle = self.throttle.clone();
+        kv.hooks = self.hooks.clone();
+        kv
+    }
+
+    /// Sends the request, retrying it according to the retry policy. Requests with
+    /// a streamed body, including multipart forms, can't be copied, so they are sent once
+    /// (see `send_rebuilt`).
+    async fn send(&self, builder: reqwest::RequestBuilder) -> Result<reqwest::Response, Error> {
+        let template = builder.try_clone();
+        self.send_each(builder, move || {
+            template.as_ref().and_then(|b| b.try_clone())
+        })
+        .await
+    }
+
+    /// Sends the request built by `build`, building it again for each retry, so requests
+    /// whose body can't be copied, such as multipart forms, are retried
+    async fn send_rebuilt<F>(&self, build: F) -> Result<reqwest::Response, Error>
+    where
+        F: Fn() -> reqwest::RequestBuilder,
+    {
+        self.send_each(build(), || Some(build())).await
+    }
+
+    /// Sends `builder`, then, while the response or error is retryable and the retry
+    /// policy allows, the requests from `next`, until it returns `None`
+    async fn send_each<F>(
+        &self,
+        mut builder: reqwest::RequestBuilder,
+        mut next: F,
+    ) -> Result<reqwest::Response, Error>
+    where
+        F: FnMut() -> Option<reqwest::RequestBuilder>,
+    {
+        let mut attempt = 1;
+        loop {
+            let wait = self.throttle.acquire();
+            if !wait.is_zero() {
+                retry::sleep(wait).await;
+            }
+            let prepared = self
+                .hooks
+                .request
+                .iter()
+                .fold(self.auth.apply(builder), |builder, hook| hook(builder));
+            let started = crate::clock::now_millis();
+            let result = self.attempt(prepared).await;
+            if let Some(Ok(resp)) = &result {
+                self.throttle.observe(resp.status(), resp.headers());
+                let elapsed = (crate::clock::now_millis() - started).max(0.0);
+                for hook in self.hooks.response.iter() {
+                    hook(resp, Duration::from_secs_f64(elapsed / 1000.0));
+                }
+            }
+            let retry_after = match &result {
+                Some(Ok(resp)) if retry::retry_status(resp.status()) => {
+                    retry::retry_after(resp.headers())
+                }
+                Some(Err(e)) if retry::retry_error(e) => None,
+                None => None,
+                _ => return self.response(result),
+            };
+            if attempt >= self.retry.max_attempts {
+                return self.response(result);
+            }
+            builder = match next() {
+                Some(retry) => retry,
+                None => return self.response(result),
+            };
+            retry::sleep(self.retry.delay(attempt, retry_after, retry::random())).await;
+            attempt += 1;
+        }
+    }
+
+    /// Sends the request once, returning `None` if it timed out without a response
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn attempt(
+        &self,
+        builder: reqwest::RequestBuilder,
+    ) -> Option<Result<reqwest::Response, reqwest::Error>> {
+        Some(builder.timeout(self.timeout).send().await)
+    }
+
+    /// Sends the request once, returning `None` if it timed out without a response.
+    /// reqwest has no timeouts in a worker, so the request races a timer.
+    #[cfg(target_arch = "wasm32")]
+    async fn attempt(
+        &self,
+        builder: reqwest::RequestBuilder,
+    ) -> Option<Result<reqwest::Response, reqwest::Error>> {
+        use futures_util::future::{select, Either};
+        let send = Box::pin(builder.send());
+        let timer = Box::pin(retry::sleep(self.timeout));
+        match select(send, timer).await {
+            Either::Left((result, _)) => Some(result),
+            Either::Right(_) => None,
+        }
+    }
+
+    /// Response of the last attempt, or its error
+    fn response(
+        &self,
+        result: Option<Result<reqwest::Response, reqwest::Error>>,
+    ) -> Result<reqwest::Response, Error> {
+        match result {
+            Some(result) => result.map_err(http_error),
+            None => Err(Error::Timeout(format!(
+                "no response in {}ms",
+                self.timeout.as_millis()
+            ))),
+        }
+    }
+
+    /// Lookup asset in worker kV storage.
+    /// Returns `Error::KVKeyNotFound` only if the key is missing (404), and an API error,
+    /// such as `Error::Api`, for other failures, e.g. an invalid token.
+    /// If the key passed had been obtained from lookup_key, but the value was not found,
+    /// then one of the following occurred:
+    /// - the asset was deleted from KV
+    /// - the value timed out via TTL
+    /// - the index is out of date
+    pub async fn get_kv_value<K: ToAssetKey + ?Sized>(
+        &self,
+        key: &K,
+    ) -> Result<bytes::Bytes, Error> {
+        self.get_kv_value_with_options(key, &GetOptions::default())
+            .await
+    }
+
+    /// Lookup asset in worker kV storage, with `GetOptions`, such as a cache TTL
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(key = %key.key_str(), status = tracing::field::Empty, bytes = tracing::field::Empty, latency_ms = tracing::field::Empty))
+    )]
+    pub async fn get_kv_value_with_options<K: ToAssetKey + ?Sized>(
+        &self,
+        key: &K,
+        options: &GetOptions,
+    ) -> Result<bytes::Bytes, Error> {
+        let key = key.to_asset_key()?;
+        let url = format!("{}{}", self.key_url("values", &key), options.query()?);
+        let request = KvRequest::start();
+        let response = self.send(self.client().get(&url)).await?;
+        match response.status().is_success() {
+            false => {
+                request.finish(response.status().as_u16(), None);
+                Err(read_error(&key, response).await)
+            }
+            true => {
+                let status = response.status().as_u16();
+                let body = response.bytes().await.map_err(http_error)?;
+                request.finish(status, Some(body.len()));
+                Ok(body)
+            }
+        }
+    }
+
+    /// Reads several values, with up to `concurrency` requests in flight, e.g. fragments
+    /// of a page. Returns each key with its result, in the order of `keys`, so a missing
+    /// key (`Error::KVKeyNotFound`) doesn't prevent reading the others.
+    pub async fn get_many(
+        &self,
+        keys: &[&str],
+        concurrency: usize,
+    ) -> Vec<(String, Result<bytes::Bytes, Error>)> {
+        stream::iter(keys.iter())
+            .map(|key| async move { (key.to_string(), self.get_kv_value(*key).await) })
+            .buffered(concurrency.max(1))
+            .collect()
+            .await
+    }
+
+    /// Reads a JSON value and deserializes it, e.g. configuration stored with `put_json`
+    pub async fn get_json<T: DeserializeOwned, K: ToAssetKey + ?Sized>(
+        &self,
+        key: &K,
+    ) -> Result<T, Error> {
+        let bytes = self.get_kv_value(key).await?;
+        parse_json(key.key_str(), &bytes)
+    }
+
+    /// Serializes the value as JSON and stores it, with an optional `Expiration`
+    pub async fn put_json<T: Serialize + ?Sized, K: ToAssetKey + ?Sized, E: Into<Expiration>>(
+        &self,
+        key: &K,
+        value: &T,
+        expiration: E,
+    ) -> Result<(), Error> {
+        let json = serde_json::to_vec(value).map_err(|e| {
+            Error::Message(format!(
+                "serializing JSON value of {}: {}",
+                key.key_str(),
+                e
+            ))
+        })?;
+        self.put_kv_value(key, json, expiration, None).await
+    }
+
+    /// Lookup a value and the metadata stored with it (see `put_kv_value`).
+    /// The metadata is `None` if the key was stored without metadata.
+    /// Returns `Error::KVKeyNotFound` if the key is missing.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(key = %key.key_str(), status = tracing::field::Empty, latency_ms = tracing::field::Empty))
+    )]
+    pub async fn get_kv_value_with_metadata<K: ToAssetKey + ?Sized>(
+        &self,
+        key: &K,
+    ) -> Result<(bytes::Bytes, Option<serde_json::Value>), Error> {
+        let key = key.to_asset_key()?;
+        futures_util::future::try_join(self.get_kv_value(&key), self.get_kv_metadata(&key)).await
+    }
+
+    /// Requests the metadata of a key, or `None` if it has none
+    async fn get_kv_metadata(&self, key: &AssetKey) -> Result<Option<serde_json::Value>, Error> {
+        let url = self.key_url("metadata", key);
+        let request = KvRequest::start();
+        let resp = self.send(self.client().get(&url)).await?;
+        let status = resp.status();
+        request.finish(status.as_u16(), None);
+        if !status.is_success() {
+            return Err(read_error(key, resp).await);
+        }
+        let bytes = resp.bytes().await.map_err(http_error)?;
+        parse_metadata(&bytes)
+    }
+
+    /// Returns the value for the key as a stream of bytes, so large values, such as videos
+    /// or archives, can be sent to the client without holding the whole value in memory.
+    /// Returns `Error::KVKeyNotFound` if the key is missing.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(key = %key.key_str(), status = tracing::field::Empty, latency_ms = tracing::field::Empty))
+    )]
+    pub async fn get_kv_stream<K: ToAssetKey + ?Sized>(
+        &self,
+        key: &K,
+    ) -> Result<impl Stream<Item = Result<bytes::Bytes, Error>>, Error> {
+        let key = key.to_asset_key()?;
+        let url = self.key_url("values", &key);
+        let request = KvRequest::start();
+        let response = self.send(self.client().get(&url)).await?;
+        request.finish(response.status().as_u16(), None);
+        if !response.status().is_success() {
+            return Err(read_error(&key, response).await);
+        }
+        Ok(response.bytes_stream().map_err(http_error))
+    }
+
+    /// Delete the key at path.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(key = %key.key_str(), status = tracing::field::Empty, latency_ms = tracing::field::Empty))
+    )]
+    pub async fn delete_kv_value<K: ToAssetKey + ?Sized>(&self, key: &K) -> Result<(), Error> {
+        let key = key.to_asset_key()?;
+        let url = self.key_url("values", &key);
+        let request = KvRequest::start();
+        let resp = self.send(self.client().delete(&url)).await?;
+        let status = resp.status();
+        request.finish(status.as_u16(), None);
+        let bytes = resp.bytes().await.map_err(http_error)?;
+        if !status.is_success() {
+            return Err(api_error(status.as_u16(), &bytes));
+        }
+        Ok(())
+    }
+
+    /// Store a value in KV. Optionally, set an `Expiration`, a TTL or absolute time
+    /// when content should be automatically deleted, at least 60 seconds in future.
+    /// Values larger than `CHUNK_SIZE` are rejected with `Error::ValueTooLarge`;
+    /// store them in chunks (see `chunk_key`).
+    /// Optional JSON metadata, of up to 1024 bytes, is stored with the value
+    /// and can be read with `get_kv_value_with_metadata`.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(key = %key.key_str(), status = tracing::field::Empty, latency_ms = tracing::field::Empty))
+    )]
+    pub async fn put_kv_value<
+        K: ToAssetKey + ?Sized,
+        T: Into<reqwest::Body>,
+        E: Into<Expiration>,
+    >(
+        &self,
+        key: &K,
+        val: T,
+        expiration: E,
+        metadata: Option<serde_json::Value>,
+    ) -> Result<(), Error> {
+        let key = key.to_asset_key()?;
+        self.put_body(&key, val.into(), expiration.into(), metadata, None)
+            .await
+    }
+
+    /// Stores a value if the key doesn't exist, e.g. a deployment marker or a lock-like key,
+    /// returning whether it was written. Existence is checked by reading the key's
+    /// metadata before writing, so this is not atomic: a writer that checks at the same
+    /// time may also find the key absent, and both write, and KV's eventual consistency
+    /// means a key written recently elsewhere may not be seen for up to a minute.
+    /// Use it to avoid repeating work, not for mutual exclusion.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(key = %key.key_str(), status = tracing::field::Empty, latency_ms = tracing::field::Empty))
+    )]
+    pub async fn put_kv_value_if_absent<K: ToAssetKey + ?Sized, T: Into<reqwest::Body>>(
+        &self,
+        key: &K,
+        val: T,
+    ) -> Result<bool, Error> {
+        let key = key.to_asset_key()?;
+        match self.get_kv_metadata(&key).await {
+            Ok(_) => Ok(false),
+            Err(Error::KVKeyNotFound(..)) => {
+                self.put_body(&key, val.into(), Expiration::Never, None, None)
+                    .await?;
+                Ok(true)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Streams `len` bytes from `reader` to a KV value, without holding the value
+    /// in memory, e.g. to upload a large file. `len` must be at most `CHUNK_SIZE`.
+    /// A streamed upload can't be repeated, so it isn't retried.
+    #[cfg(not(target_arch = "wasm32"))]
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(key = %key.key_str(), status = tracing::field::Empty, latency_ms = tracing::field::Empty))
+    )]
+    pub async fn put_kv_stream<K, R, E>(
+        &self,
+        key: &K,
+        reader: R,
+        len: u64,
+        expiration: E,
+    ) -> Result<(), Error>
+    where
+        K: ToAssetKey + ?Sized,
+        R: tokio::io::AsyncRead + Unpin + Send + Sync + 'static,
+        E: Into<Expiration>,
+    {
+        use tokio::io::AsyncReadExt;
+
+        let key = key.to_asset_key()?;
+        if len > CHUNK_SIZE {
+            return Err(Error::ValueTooLarge(key.to_string(), len));
+        }
+        let chunks = stream::try_unfold(reader, |mut reader| async move {
+            let mut buf = vec![0u8; STREAM_BUF_SIZE];
+            let n = reader.read(&mut buf).await?;
+            buf.truncate(n);
+            Ok::<_, std::io::Error>((n > 0).then(|| (bytes::Bytes::from(buf), reader)))
+        });
+        let body = reqwest::Body::wrap_stream(chunks);
+        self.put_body(&key, body, expiration.into(), None, Some(len))
+            .await
+    }
+
+    /// Writes a value, with its length if it's streamed
+    async fn put_body(
+        &self,
+        key: &AssetKey,
+        val: reqwest::Body,
+        expiration: Expiration,
+        metadata: Option<serde_json::Value>,
+        len: Option<u64>,
+    ) -> Result<(), Error> {
+        let expiration = expiration.query(crate::clock::now_secs())?;
+        let metadata = match metadata {
+            Some(metadata) => {
+                let json = metadata.to_string();
+                if json.len() > METADATA_MAX_BYTES {
+                    return Err(Error::Message(format!(
+                        "metadata for {} has {} bytes, more than the KV limit of {}",
+                        key,
+                        json.len(),
+                        METADATA_MAX_BYTES
+                    )));
+                }
+                Some(json)
+            }
+            None => None,
+        };
+        let url = format!("{}{}", self.key_url("values", key), expiration);
+
+        if let Some(size) = val.as_bytes().map(|b| b.len() as u64) {
+            if size > CHUNK_SIZE {
+                return Err(Error::ValueTooLarge(key.to_string(), size));
+            }
+        }
+        let request = KvRequest::start();
+        let resp = match val.as_bytes().map(bytes::Bytes::copy_from_slice) {
+            // a value in memory is sent again if the request is retried
+            Some(value) => {
+                self.send_rebuilt(|| self.put_request(&url, value.clone().into(), &metadata, None))
+                    .await?
+            }
+            None => {
+                self.send(self.put_request(&url, val, &metadata, len))
+                    .await?
+            }
+        };
+        let status = resp.status();
+        request.finish(status.as_u16(), None);
+        let bytes = resp.bytes().await.map_err(http_error)?;
+        if !status.is_success() {
+            return Err(api_error(status.as_u16(), &bytes));
+        }
+        let resp: WriteKVResponse = match serde_json::from_slice(&bytes) {
+            Ok(wr) => Ok(wr),
+            Err(e) => Err(Error::KVHttp(
+                e.to_string(),
+                String::from_utf8_lossy(&bytes).to_string(),
+            )),
+        }?;
+        match resp.success {
+            true => Ok(()),
+            false => Err(Error::Api(CloudflareApiError {
+                status: status.as_u16(),
+                errors: resp.errors,
+            })),
+        }
+    }
+
+    /// Request writing a value, with its length if it's streamed.
+    /// Metadata is sent with the value as a multipart form.
+    fn put_request(
+        &self,
+        url: &str,
+        val: reqwest::Body,
+        metadata: &Option<String>,
+        len: Option<u64>,
+    ) -> reqwest::RequestBuilder {
+        let builder = match len {
+            Some(len) => self
+                .client()
+                .put(url)
+                .header(reqwest::header::CONTENT_LENGTH, len),
+            None => self.client().put(url),
+        };
+        match metadata {
+            Some(metadata) => {
+                let part = match val.as_bytes() {
+                    Some(bytes) => reqwest::multipart::Part::bytes(bytes.to_vec()),
+                    None => reqwest::multipart::Part::stream(val),
+                };
+                builder.multipart(
+                    reqwest::multipart::Form::new()
+                        .part("value", part)
+                        .text("metadata", metadata.clone()),
+                )
+            }
+            None => builder.body(val),
+        }
+    }
+
+    /// Stores many values with the bulk API, in as few requests as the API's limits allow
+    /// (10,000 keys per request), which is much faster than calling `put_kv_value`
+    /// for each of many small values. Batches are written in order, and writing stops
+    /// at the first batch that fails, so earlier batches may already be stored.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(keys = items.len(), status = tracing::field::Empty, latency_ms = tracing::field::Empty))
+    )]
+    pub async fn put_bulk(&self, items: Vec<KVPair>) -> Result<(), Error> {
+        for item in items.iter() {
+            item.key.as_str().to_asset_key()?;
+            if matches!(item.expiration_ttl, Some(ttl) if ttl < 60) {
+                return Err(Error::TTLTooShort);
+            }
+            if item.value.len() as u64 > CHUNK_SIZE {
+                return Err(Error::ValueTooLarge(
+                    item.key.clone(),
+                    item.value.len() as u64,
+                ));
+            }
+        }
+        for batch in batch_kv_pairs(&items) {
+            self.bulk_request(self.client().put(self.bulk_url()).json(batch))
+                .await
+                .map_err(|e| {
+                    Error::Message(format!(
+                        "bulk write of {} keys from {}: {}",
+                        batch.len(),
+                        batch[0].key,
+                        e
+                    ))
+                })?;
+        }
+        Ok(())
+    }
+
+    /// Deletes many keys with the bulk API, in batches of up to 10,000 keys.
+    /// Every batch is attempted; if any fail, the error lists each failed batch,
+    /// and keys in the other batches are deleted.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(keys = keys.len(), status = tracing::field::Empty, latency_ms = tracing::field::Empty))
+    )]
+    pub async fn delete_bulk(&self, keys: Vec<String>) -> Result<(), Error> {
+        for key in keys.iter() {
+            key.as_str().to_asset_key()?;
+        }
+        let mut failures = Vec::new();
+        for batch in keys.chunks(BULK_KEY_MAX) {
+            if let Err(e) = self
+                .bulk_request(self.client().delete(self.bulk_url()).json(batch))
+                .await
+            {
+                failures.push(format!("{} keys from {}: {}", batch.len(), batch[0], e));
+            }
+        }
+        match failures.is_empty() {
+            true => Ok(()),
+            false => Err(Error::Message(format!(
+                "bulk delete failed for {} of {} batches: {}",
+                failures.len(),
+                keys.chunks(BULK_KEY_MAX).len(),
+                failures.join("; ")
+            ))),
+        }
+    }
+
+    /// Copies a value, with its metadata, to the `dest` namespace, e.g. to promote content
+    /// from a preview namespace to production. The value is streamed from one namespace
+    /// to the other (in a worker, it's read into memory), and the copy doesn't expire.
+    /// Returns `Error::KVKeyNotFound` if the key is missing.
+    pub async fn copy_key<K: ToAssetKey + ?Sized>(&self, dest: &KV, key: &K) -> Result<(), Error> {
+        let key = key.to_asset_key()?;
+        self.copy_value(dest, &key, Expiration::Never).await
+    }
+
+    /// Moves a value, with its metadata, to the `dest` namespace: copies it with
+    /// `copy_key`, then deletes it from this namespace
+    pub async fn move_key<K: ToAssetKey + ?Sized>(&self, dest: &KV, key: &K) -> Result<(), Error> {
+        let key = key.to_asset_key()?;
+        self.copy_value(dest, &key, Expiration::Never).await?;
+        self.delete_kv_value(&key).await
+    }
+
+    /// Copies the keys starting with `prefix` to the `dest` namespace, with their metadata
+    /// and expirations, with up to `concurrency` copies in flight. Keys that expire within
+    /// a minute aren't copied. Every key is attempted; if any fail, the error lists each
+    /// failed key. Returns the number of keys copied.
+    pub async fn copy_prefix(
+        &self,
+        dest: &KV,
+        prefix: &str,
+        concurrency: usize,
+    ) -> Result<u64, Error> {
+        let keys: Vec<KVKey> = self.list_keys(Some(prefix)).try_collect().await?;
+        let min_expiration = crate::clock::now_secs() + EXPIRATION_MIN_SECS;
+        // map_or rather than is_none_or, which needs Rust 1.82
+        #[allow(clippy::unnecessary_map_or)]
+        let results: Vec<(String, Result<bool, Error>)> = stream::iter(keys)
+            .filter(|key| {
+                futures_util::future::ready(key.expiration.map_or(true, |e| e >= min_expiration))
+            })
+            .map(|key| async move {
+                let expiration = match key.expiration {
+                    Some(secs) => Expiration::At(std::time::UNIX_EPOCH + Duration::from_secs(secs)),
+                    None => Expiration::Never,
+                };
+                let result = match key.name.as_str().to_asset_key() {
+                    Ok(asset_key) => self.copy_value(dest, &asset_key, expiration).await,
+                    Err(e) => Err(e),
+                };
+                // a key may expire while it's being copied
+                let result = match result {
+                    Ok(()) => Ok(true),
+                    Err(Error::ExpirationTooSoon(_)) | Err(Error::KVKeyNotFound(..))
+                        if key.expiration.is_some() =>
+                    {
+                        Ok(false)
+                    }
+                    Err(e) => Err(e),
+                };
+                (key.name, result)
+            })
+            .buffer_unordered(concurrency.max(1))
+            .collect()
+            .await;
+        let copied = results
+            .iter()
+            .filter(|(_, r)| matches!(r, Ok(true)))
+            .count();
+        let failures: Vec<String> = results
+            .iter()
+            .filter_map(|(key, r)| r.as_ref().err().map(|e| format!("{}: {}", key, e)))
+            .collect();
+        match failures.is_empty() {
+            true => Ok(copied as u64),
+            false => Err(Error::Message(format!(
+                "copy failed for {} of {} keys: {}",
+                failures.len(),
+                results.len(),
+                failures.join("; ")
+            ))),
+        }
+    }
+
+    /// Copies a value and its metadata to `dest`, with the expiration
+    async fn copy_value(
+        &self,
+        dest: &KV,
+        key: &AssetKey,
+        expiration: Expiration,
+    ) -> Result<(), Error> {
+        let metadata = self.get_kv_metadata(key).await?;
+        let url = self.key_url("values", key);
+        let request = KvRequest::start();
+        let response = self.send(self.client().get(&url)).await?;
+        request.finish(response.status().as_u16(), None);
+        if !response.status().is_success() {
+            return Err(read_error(key, response).await);
+        }
+        // with metadata, the value is one part of a multipart body
+        let len = response.content_length().filter(|_| metadata.is_none());
+        let body = value_body(response).await?;
+        dest.put_body(key, body, expiration, metadata, len).await
+    }
+
+    /// Lists the keys in the namespace, optionally only those starting with `prefix`,
+    /// in lexicographic order. Pages of keys are requested as the stream is read.
+    pub fn list_keys(&self, prefix: Option<&str>) -> impl Stream<Item = Result<KVKey, Error>> + '_ {
+        let prefix = prefix.map(|p| p.to_string());
+        // state is the cursor of the next page, None after the last page
+        stream::try_unfold(Some(String::new()), move |cursor| {
+            let prefix = prefix.clone();
+            async move {
+                let cursor = match cursor {
+                    Some(cursor) => cursor,
+                    None => return Ok(None),
+                };
+                let (keys, next) = self.list_page(prefix.as_deref(), &cursor).await?;
+                Ok::<_, Error>(Some((stream::iter(keys.into_iter().map(Ok)), next)))
+            }
+        })
+        .try_flatten()
+    }
+
+    /// Requests one page of keys, returning the keys and the cursor of the next page
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(status = tracing::field::Empty, bytes = tracing::field::Empty, latency_ms = tracing::field::Empty))
+    )]
+    async fn list_page(
+        &self,
+        prefix: Option<&str>,
+        cursor: &str,
+    ) -> Result<(Vec<KVKey>, Option<String>), Error> {
+        let url = format!(
+            "{}/accounts/{}/storage/kv/namespaces/{}/keys",
+            &self.endpoint, &self.account_id, &self.namespace_id
+        );
+        let mut query = vec![("limit", LIST_PAGE_SIZE.to_string())];
+        if let Some(prefix) = prefix {
+            query.push(("prefix", prefix.to_string()));
+        }
+        if !cursor.is_empty() {
+            query.push(("cursor", cursor.to_string()));
+        }
+        let request = KvRequest::start();
+        let resp = self.send(self.client().get(&url).query(&query)).await?;
+        let status = resp.status();
+        let bytes = resp.bytes().await.map_err(http_error)?;
+        request.finish(status.as_u16(), Some(bytes.len()));
+        if !status.is_success() {
+            return Err(api_error(status.as_u16(), &bytes));
+        }
+        parse_key_page(&bytes)
+    }
+
+    /// Url of a key's value or metadata. Key names are percent-encoded, including '/',
+    /// as the API requires.
+    fn key_url(&self, kind: &str, key: &AssetKey) -> String {
+        format!(
+            "{}/accounts/{}/storage/kv/namespaces/{}/{}/{}",
+            &self.endpoint,
+            &self.account_id,
+            &self.namespace_id,
+            kind,
+            uri_encode(key.as_str(), false)
+        )
+    }
+
+    fn bulk_url(&self) -> String {
+        format!(
+            "{}/accounts/{}/storage/kv/namespaces/{}/bulk",
+            &self.endpoint, &self.account_id, &self.namespace_id
+        )
+    }
+
+    /// Sends a bulk write or delete, and checks the response
+    async fn bulk_request(&self, builder: reqwest::RequestBuilder) -> Result<(), Error> {
+        let request = KvRequest::start();
+        let resp = self.send(builder).await?;
+        let status = resp.status();
+        request.finish(status.as_u16(), None);
+        let bytes = resp.bytes().await.map_err(http_error)?;
+        if !status.is_success() {
+            return Err(api_error(status.as_u16(), &bytes));
+        }
+        let resp: WriteKVResponse = serde_json::from_slice(&bytes).map_err(|e| {
+            Error::KVHttp(e.to_string(), String::from_utf8_lossy(&bytes).to_string())
+        })?;
+        match resp.success {
+            true => Ok(()),
+            false => Err(Error::Api(CloudflareApiError {
+                status: status.as_u16(),
+                errors: resp.errors,
+            })),
+        }
+    }
+}
+
+// Minimum seconds until a value expires
+const EXPIRATION_MIN_SECS: u64 = 60;
+
+/// When a value stored with `KV::put_kv_value` expires.
+/// `Option<u64>` converts to a TTL, or `Never` if it's `None`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Expiration {
+    /// Number of seconds in future, at least 60
+    Ttl(u64),
+    /// Absolute time, at least 60 seconds in future
+    At(std::time::SystemTime),
+    /// The value doesn't expire
+    Never,
+}
+
+impl From<Option<u64>> for Expiration {
+    fn from(ttl: Option<u64>) -> Self {
+        match ttl {
+            Some(ttl) => Expiration::Ttl(ttl),
+            None => Expiration::Never,
+        }
+    }
+}
+
+impl Expiration {
+    /// Returns the query string for a value written at `now` (UTC seconds since EPOCH)
+    fn query(&self, now: u64) -> Result<String, Error> {
+        match self {
+            Expiration::Ttl(ttl) if *ttl < EXPIRATION_MIN_SECS => Err(Error::TTLTooShort),
+            Expiration::Ttl(ttl) => Ok(format!("?expiration_ttl={}", ttl)),
+            Expiration::At(at) => {
+                let secs = at
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0);
+                if secs < now + EXPIRATION_MIN_SECS {
+                    return Err(Error::ExpirationTooSoon(secs));
+                }
+                Ok(format!("?expiration={}", secs))
+            }
+            Expiration::Never => Ok(String::new()),
+        }
+    }
+}
+
+/// Error for a failed read: `Error::KVKeyNotFound` if the key doesn't exist (404),
+/// otherwise an API error, so an expired token or an outage isn't mistaken for
+/// a missing key
+async fn read_error(key: &AssetKey, response: reqwest::Response) -> Error {
+    let status = response.status().as_u16();
+    if status == 404 {
+        return Error::KVKeyNotFound(key.to_string(), status);
+    }
+    match response.bytes().await {
+        Ok(body) => api_error(status, &body),
+        Err(e) => http_error(e),
+    }
+}
+
+/// Body with a response's value, to write it elsewhere: streamed, so the value isn't
+/// held in memory
+#[cfg(not(target_arch = "wasm32"))]
+async fn value_body(response: reqwest::Response) -> Result<reqwest::Body, Error> {
+    Ok(reqwest::Body::wrap_stream(response.bytes_stream()))
+}
+
+/// Body with a response's value, to write it elsewhere. In a worker, request bodies
+/// can't be streamed, so the value is read into memory.
+#[cfg(target_arch = "wasm32")]
+async fn value_body(response: reqwest::Response) -> Result<reqwest::Body, Error> {
+    Ok(response.bytes().await.map_err(http_error)?.into())
+}
+
+/// Maps a reqwest error to `Error::Timeout` or `Error::KVHttp`
+fn http_error(e: reqwest::Error) -> Error {
+    match e.is_timeout() {
+        true => Error::Timeout(e.to_string()),
+        false => Error::KVHttp(e.to_string(), String::new()),
+    }
+}
+
+/// Deserializes a JSON value read from KV
+fn parse_json<T: DeserializeOwned>(key: &str, bytes: &[u8]) -> Result<T, Error> {
+    serde_json::from_slice(bytes)
+        .map_err(|e| Error::Message(format!("parsing JSON value of {}: {}", key, e)))
+}
+
+/// Options for reading a value (see `KV::get_kv_value_with_options`)
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GetOptions {
+    /// Seconds a value read from KV may be cached at the edge, at least 60.
+    /// A longer TTL gives more cache hits on hot keys, and slower visibility of updates.
+    pub cache_ttl: Option<u64>,
+}
+
+impl GetOptions {
+    /// Sets the cache TTL, in seconds
+    pub fn with_cache_ttl(mut self, cache_ttl: u64) -> Self {
+        self.cache_ttl = Some(cache_ttl);
+        self
+    }
+
+    fn query(&self) -> Result<String, Error> {
+        match self.cache_ttl {
+            Some(ttl) if ttl < EXPIRATION_MIN_SECS => Err(Error::TTLTooShort),
+            Some(ttl) => Ok(format!("?cache_ttl={}", ttl)),
+            None => Ok(String::new()),
+        }
+    }
+}
+
+/// Key and value for a bulk write (see `KV::put_bulk`)
+#[derive(Clone, Debug, Serialize)]
+#[allow(clippy::upper_case_acronyms)]
+pub struct KVPair {
+    /// Key to write
+    pub key: String,
+    /// Value, either text, or, if `base64` is set, base64-encoded bytes
+    pub value: String,
+    /// Number of seconds in future when the value should be deleted (at least 60)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expiration_ttl: Option<u64>,
+    /// Whether `value` is base64-encoded binary data
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub base64: bool,
+}
+
+impl KVPair {
+    /// Pair with a text value that doesn't expire
+    pub fn new<K: Into<String>, V: Into<String>>(key: K, value: V) -> Self {
+        Self {
+            key: key.into(),
+            value: value.into(),
+            expiration_ttl: None,
+            base64: false,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct MetadataResponse {
+    success: bool,
+    #[serde(default)]
+    errors: Vec<ApiErrorDetail>,
+    #[serde(default)]
+    result: Option<serde_json::Value>,
+}
+
+/// Parses the response to a metadata request; keys without metadata have a null result
+fn parse_metadata(bytes: &[u8]) -> Result<Option<serde_json::Value>, Error> {
+    let resp: MetadataResponse = serde_json::from_slice(bytes)
+        .map_err(|e| Error::KVHttp(e.to_string(), String::from_utf8_lossy(bytes).to_string()))?;
+    match resp.success {
+        true => Ok(resp.result.filter(|value| !value.is_null())),
+        false => Err(Error::Message(format!(
+            "reading metadata: errors:{:?}",
+            resp.errors
+        ))),
+    }
+}
+
+/// Key in a namespace listing (see `KV::list_keys`)
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
+pub struct KVKey {
+    /// Key name
+    pub name: String,
+    /// When the key expires, in seconds since the Unix epoch
+    #[serde(default)]
+    pub expiration: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ListKeysResponse {
+    success: bool,
+    #[serde(default)]
+    errors: Vec<ApiErrorDetail>,
+    #[serde(default)]
+    result: Vec<KVKey>,
+    #[serde(default)]
+    result_info: Option<ListKeysInfo>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ListKeysInfo {
+    #[serde(default)]
+    cursor: Option<String>,
+}
+
+/// Parses a page of the key listing into its keys and the cursor of the next page,
+/// which is empty or missing after the last page
+fn parse_key_page(bytes: &[u8]) -> Result<(Vec<KVKey>, Option<String>), Error> {
+    let resp: ListKeysResponse = serde_json::from_slice(bytes)
+        .map_err(|e| Error::KVHttp(e.to_string(), String::from_utf8_lossy(bytes).to_string()))?;
+    if !resp.success {
+        return Err(Error::Message(format!(
+            "listing keys: errors:{:?}",
+            resp.errors
+        )));
+    }
+    let cursor = resp
+        .result_info
+        .and_then(|info| info.cursor)
+        .filter(|cursor| !cursor.is_empty());
+    Ok((resp.result, cursor))
+}
+
+/// Splits pairs into batches within the API's limits on key count and payload size
+fn batch_kv_pairs(pairs: &[KVPair]) -> Vec<&[KVPair]> {
+    let mut batches = Vec::new();
+    let mut start = 0;
+    let mut batch_bytes = 0;
+    for (i, pair) in pairs.iter().enumerate() {
+        let size = pair.key.len() + pair.value.len();
+        if i > start && (i - start >= BULK_KEY_MAX || batch_bytes + size > BULK_MAX_BYTES) {
+            batches.push(&pairs[start..i]);
+            start = i;
+            batch_bytes = 0;
+        }
+        batch_bytes += size;
+    }
+    if start < pairs.len() {
+        batches.push(&pairs[start..]);
+    }
+    batches
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(clippy::upper_case_acronyms)]
+struct WriteKVResponse {
+    success: bool,
+    #[serde(default)]
+    errors: Vec<ApiErrorDetail>,
+}
+
+#[cfg_attr(target_arch = "wasm32", async_trait::async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait::async_trait)]
+impl AssetStore for KV {
+    async fn get_value(&self, key: &str) -> Result<bytes::Bytes, Error> {
+        self.get_kv_value(key).await
+    }
+
+    fn get_stream(&self, key: &str) -> ByteStream<'_> {
+        let key = key.to_string();
+        Box::pin(stream::once(async move { self.get_kv_stream(&key).await }).try_flatten())
+    }
+
+    async fn put_value(
+        &self,
+        key: &str,
+        value: bytes::Bytes,
+        expiration_ttl: Option<u64>,
+    ) -> Result<(), Error> {
+        self.put_kv_value(key, value, expiration_ttl, None).await
+    }
+
+    async fn delete_value(&self, key: &str) -> Result<(), Error> {
+        self.delete_kv_value(key).await
+    }
+}
+
+#[test]
+fn test_from_vars() {
+    let vars = |name: &str| match name {
+        ACCOUNT_ID_VAR => Some("123".to_string()),
+        NAMESPACE_ID_VAR => Some("namespace".to_string()),
+        API_TOKEN_VAR => Some(String::new()),
+        _ => None,
+    };
+    assert!(matches!(
+        KV::from_vars(vars),
+        Err(Error::MissingEnvVar(name)) if name == API_TOKEN_VAR
+    ));
+    let kv = KV::from_vars(|name| match name {
+        API_TOKEN_VAR => Some("token".to_string()),
+        _ => vars(name),
+    })
+    .unwrap();
+    assert_eq!(kv.namespace_id, "namespace");
+    assert!(matches!(kv.auth, Auth::Token(token) if token == "token"));
+}
+
+/// Tests that values over the KV limit are rejected (does not invoke cloudflare api)
+#[cfg(all(test, not(target_arch = "wasm32")))]
+#[tokio::test]
+async fn test_value_too_large() {
+    let kv = init_kv("123", "namespace", "token");
+    let value = vec![0u8; CHUNK_SIZE as usize + 1];
+    assert!(matches!(
+        kv.put_kv_value("big.bin", value, None, None).await,
+        Err(Error::ValueTooLarge(key, size)) if key == "big.bin" && size == CHUNK_SIZE + 1
+    ));
+}
+
+#[test]
+fn test_batch_kv_pairs() {
+    let pairs: Vec<KVPair> = (0..BULK_KEY_MAX + 1)
+        .map(|n| KVPair::new(format!("key{}", n), "x"))
+        .collect();
+    let batches = batch_kv_pairs(&pairs);
+    assert_eq!(batches.len(), 2);
+    assert_eq!(batches[0].len(), BULK_KEY_MAX);
+    assert_eq!(batches[1][0].key, format!("key{}", BULK_KEY_MAX));
+
+    let big = "x".repeat(BULK_MAX_BYTES / 2);
+    let pairs = vec![
+        KVPair::new("a", big.as_str()),
+        KVPair::new("b", big.as_str()),
+    ];
+    assert_eq!(batch_kv_pairs(&pairs).len(), 2);
+    assert!(batch_kv_pairs(&[]).is_empty());
+
+    let json = serde_json::to_string(&KVPair::new("a", "1")).unwrap();
+    assert_eq!(json, r#"{"key":"a","value":"1"}"#);
+}
+
+/// Tests that bulk requests check keys before sending (does not invoke cloudflare api)
+#[cfg(all(test, not(target_arch = "wasm32")))]
+#[tokio::test]
+async fn test_bulk_invalid_key() {
+    let kv = init_kv("123", "namespace", "token");
+    let keys = vec!["a.txt".to_string(), "..".to_string()];
+    assert!(matches!(
+        kv.delete_bulk(keys).await,
+        Err(Error::InvalidKey(_))
+    ));
+    let mut pair = KVPair::new("a.txt", "1");
+    pair.expiration_ttl = Some(10);
+    assert!(matches!(
+        kv.put_bulk(vec![pair]).await,
+        Err(Error::TTLTooShort)
+    ));
+}
+
+#[test]
+fn test_parse_key_page() {
+    let page = br#"{"success":true,"errors":[],"messages":[],
+        "result":[{"name":"a.txt"},{"name":"b.txt","expiration":1700000000}],
+        "result_info":{"count":2,"cursor":"next"}}"#;
+    let (keys, cursor) = parse_key_page(page).unwrap();
+    assert_eq!(keys.len(), 2);
+    assert_eq!(keys[1].name, "b.txt");
+    assert_eq!(keys[1].expiration, Some(1700000000));
+    assert_eq!(cursor.as_deref(), Some("next"));
+
+    let last = br#"{"success":true,"errors":[],"result":[],"result_info":{"count":0,"cursor":""}}"#;
+    assert_eq!(parse_key_page(last).unwrap(), (Vec::new(), None));
+    let failed = br#"{"success":false,"errors":[{"code":10000}],"result":null}"#;
+    assert!(parse_key_page(failed).is_err());
+}
+
+#[test]
+fn test_parse_metadata() {
+    let with = br#"{"success":true,"errors":[],"result":{"content-type":"text/plain"}}"#;
+    assert_eq!(
+        parse_metadata(with).unwrap(),
+        Some(serde_json::json!({"content-type": "text/plain"}))
+    );
+    let without = br#"{"success":true,"errors":[],"result":null}"#;
+    assert_eq!(parse_metadata(without).unwrap(), None);
+}
+
+#[test]
+fn test_expiration() {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let now = 1_700_000_000;
+    assert_eq!(Expiration::from(None).query(now).unwrap(), "");
+    assert_eq!(
+        Expiration::from(Some(120)).query(now).unwrap(),
+        "?expiration_ttl=120"
+    );
+    assert!(matches!(
+        Expiration::Ttl(59).query(now),
+        Err(Error::TTLTooShort)
+    ));
+    let at = UNIX_EPOCH + Duration::from_secs(now + 3600);
+    assert_eq!(
+        Expiration::At(at).query(now).unwrap(),
+        format!("?expiration={}", now + 3600)
+    );
+    let soon = UNIX_EPOCH + Duration::from_secs(now + 30);
+    assert!(matches!(
+        Expiration::At(soon).query(now),
+        Err(Error::ExpirationTooSoon(_))
+    ));
+}
+
+#[test]
+fn test_builder() {
+    let client = reqwest::Client::new();
+    let kv = KV::builder("123", "namespace", "token")
+        .with_client(client)
+        .build();
+    assert!(kv.client.get().is_some());
+    let kv = init_kv("123", "namespace", "token");
+    assert!(kv.client.get().is_none());
+    let _ = kv.client();
+    assert!(kv.client.get().is_some());
+    assert_eq!(kv.retry, RetryPolicy::default());
+    let once = kv.with_retry(RetryPolicy::none());
+    assert_eq!(once.retry.max_attempts, 1);
+    assert_eq!(once.namespace_id, "namespace");
+    assert_eq!(once.timeout, DEFAULT_TIMEOUT);
+    assert!(Arc::ptr_eq(&once.throttle, &kv.throttle));
+
+    let kv = KV::builder("123", "namespace", "token")
+        .with_timeout(Duration::from_millis(500))
+        .build();
+    assert!(matches!(
+        kv.response(None),
+        Err(Error::Timeout(msg)) if msg.contains("500ms")
+    ));
+}
+
+#[test]
+fn test_auth() {
+    let headers = |auth: Auth| {
+        let request = auth
+            .apply(reqwest::Client::new().get("https://example.com"))
+            .build()
+            .unwrap();
+        request.headers().clone()
+    };
+    assert_eq!(headers("token".into())["Authorization"], "Bearer token");
+    let key = headers(Auth::ApiKey {
+        email: "me@example.com".to_string(),
+        key: "key".to_string(),
+    });
+    assert_eq!(key["X-Auth-Email"], "me@example.com");
+    assert_eq!(key["X-Auth-Key"], "key");
+    assert!(key.get("Authorization").is_none());
+    let service = headers(Auth::ServiceToken {
+        client_id: "id".to_string(),
+        client_secret: "secret".to_string(),
+    });
+    assert_eq!(service["CF-Access-Client-Id"], "id");
+    assert_eq!(service["CF-Access-Client-Secret"], "secret");
+}
+
+#[test]
+fn test_key_url() {
+    let kv = init_kv("123", "namespace", "token");
+    let url = |key: &str| kv.key_url("values", &AssetKey::new(key).unwrap());
+    assert!(url("index.html").ends_with("/namespaces/namespace/values/index.html"));
+    assert!(url("css/app.css").ends_with("/values/css%2Fapp.css"));
+    assert!(url("a b?c#d").ends_with("/values/a%20b%3Fc%23d"));
+    assert!(url("café/100%.txt").ends_with("/values/caf%C3%A9%2F100%25.txt"));
+    assert!(kv
+        .key_url("metadata", &AssetKey::new("x+y").unwrap())
+        .ends_with("/metadata/x%2By"));
+}
+
+/// Tests that streamed values are checked and read (does not invoke cloudflare api)
+#[cfg(all(test, not(target_arch = "wasm32")))]
+#[tokio::test]
+async fn test_put_kv_stream() {
+    let kv = init_kv("123", "namespace", "token");
+    assert!(matches!(
+        kv.put_kv_stream("big.bin", &b""[..], CHUNK_SIZE + 1, None).await,
+        Err(Error::ValueTooLarge(_, size)) if size == CHUNK_SIZE + 1
+    ));
+    assert!(matches!(
+        kv.put_kv_stream("..", &b""[..], 0, None).await,
+        Err(Error::InvalidKey(_))
+    ));
+}
+
+#[test]
+fn test_get_options() {
+    assert_eq!(GetOptions::default().query().unwrap(), "");
+    let options = GetOptions::default().with_cache_ttl(300);
+    assert_eq!(options.query().unwrap(), "?cache_ttl=300");
+    assert!(matches!(
+        GetOptions::default().with_cache_ttl(30).query(),
+        Err(Error::TTLTooShort)
+    ));
+}
+
+#[test]
+fn test_parse_json() {
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Config {
+        name: String,
+        limit: u32,
+    }
+    let config: Config = parse_json("config.json", br#"{"name":"site","limit":5}"#).unwrap();
+    assert_eq!(
+        config,
+        Config {
+            name: "site".to_string(),
+            limit: 5
+        }
+    );
+    assert!(matches!(
+        parse_json::<Config>("config.json", b"{"),
+        Err(Error::Message(msg)) if msg.contains("config.json")
+    ));
+}
+
+/// Tests that a write with metadata, sent as a multipart form, is retried
+/// (does not invoke cloudflare api)
+#[cfg(all(test, not(target_arch = "wasm32")))]
+#[tokio::test]
+async fn test_put_metadata_retry() {
+    let (url, requests) = mock_server(vec![
+        "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        concat!(
+            "HTTP/1.1 200 OK\r\nContent-Length: 42\r\nConnection: close\r\n\r\n",
+            r#"{"success":true,"errors":[],"result":null}"#
+        ),
+    ]);
+    let kv = KV::builder("123", "namespace", "token")
+        .with_endpoint(url)
+        .with_retry(RetryPolicy {
+            base_delay: Duration::from_millis(1),
+            ..Default::default()
+        })
+        .build();
+    let metadata = serde_json::json!({"content-type": "text/html"});
+    kv.put_kv_value("index.html", "<html>", None, Some(metadata))
+        .await
+        .unwrap();
+    let requests = requests.lock().unwrap();
+    assert_eq!(requests.len(), 2);
+    assert!(requests.iter().all(|r| r.starts_with("PUT ")));
+}
+
+/// Tests copying keys, skipping keys about to expire (does not invoke cloudflare api)
+#[cfg(all(test, not(target_arch = "wasm32")))]
+#[tokio::test]
+async fn test_copy_prefix() {
+    let list = concat!(
+        "HTTP/1.1 200 OK\r\nContent-Length: 131\r\nConnection: close\r\n\r\n",
+        r#"{"success":true,"errors":[],"result":[{"name":"preview/a.html"},"#,
+        r#"{"name":"preview/old","expiration":1}],"result_info":{"cursor":""}}"#
+    );
+    let not_found = "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+    let (url, requests) = mock_server(vec![list, not_found, not_found]);
+    let source = KV::builder("123", "preview", "token")
+        .with_endpoint(url)
+        .build();
+    let dest = KV::builder("123", "production", "token")
+        .with_endpoint("http://127.0.0.1:9")
+        .build();
+    // the expiring key is skipped, and the missing key fails before anything is written
+    let err = source.copy_prefix(&dest, "preview/", 4).await.unwrap_err();
+    assert!(err.to_string().contains("1 of 1 keys: preview/a.html"));
+    assert!(matches!(
+        source.copy_key(&dest, "gone.html").await,
+        Err(Error::KVKeyNotFound(_, 404))
+    ));
+    let requests = requests.lock().unwrap();
+    assert!(requests[1].contains("/metadata/preview%2Fa.html"));
+    assert_eq!(requests.len(), 3);
+}
+
+/// Tests that an existing key isn't overwritten (does not invoke cloudflare api)
+#[cfg(all(test, not(target_arch = "wasm32")))]
+#[tokio::test]
+async fn test_put_if_absent() {
+    let (url, requests) = mock_server(vec![
+        concat!(
+            "HTTP/1.1 200 OK\r\nContent-Length: 42\r\nConnection: close\r\n\r\n",
+            r#"{"success":true,"errors":[],"result":null}"#
+        ),
+        "HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+    ]);
+    let kv = KV::builder("123", "namespace", "token")
+        .with_endpoint(url)
+        .build();
+    assert!(!kv.put_kv_value_if_absent("deploy/abc", "1").await.unwrap());
+    // an error other than a missing key doesn't write
+    assert!(matches!(
+        kv.put_kv_value_if_absent("deploy/abc", "1").await,
+        Err(Error::KVHttpStatus(403, _))
+    ));
+    let requests = requests.lock().unwrap();
+    assert!(requests.iter().all(|r| r.starts_with("GET ")));
+    assert_eq!(requests.len(), 2);
+}
+
+/// Serves the responses in order, one per connection, and returns the server's url
+/// and the request lines it received
+#[cfg(all(test, not(target_arch = "wasm32")))]
+fn mock_server(
+    responses: Vec<&'static str>,
+) -> (String, std::sync::Arc<std::sync::Mutex<Vec<String>>>) {
+    use std::io::{BufRead, BufReader, Read, Write};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/client/v4/", listener.local_addr().unwrap());
+    let requests = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let received = requests.clone();
+    std::thread::spawn(move || {
+        for response in responses {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            received.lock().unwrap().push(line.trim_end().to_string());
+            // skip the headers, and the body, so the connection closes cleanly
+            let mut len = 0;
+            while reader.read_line(&mut line).unwrap() > 2 {
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        len = value.trim().parse().unwrap();
+                    }
+                }
+                line.clear();
+            }
+            reader.read_exact(&mut vec![0; len]).unwrap();
+            stream.write_all(response.as_bytes()).unwrap();
+        }
+    });
+    (url, requests)
+}
+
+/// Tests requests to another endpoint, with a retry (does not invoke cloudflare api)
+#[cfg(all(test, not(target_arch = "wasm32")))]
+#[tokio::test]
+async fn test_endpoint() {
+    let (url, requests) = mock_server(vec![
+        "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        "HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello",
+    ]);
+    let kv = KV::builder("123", "namespace", "token")
+        .with_endpoint(url)
+        .with_retry(RetryPolicy {
+            base_delay: Duration::from_millis(1),
+            ..Default::default()
+        })
+        .build();
+    assert_eq!(kv.get_kv_value("a b/c.txt").await.unwrap(), "hello");
+    let requests = requests.lock().unwrap();
+    assert_eq!(requests.len(), 2);
+    assert_eq!(
+        requests[1],
+        "GET /client/v4/accounts/123/storage/kv/namespaces/namespace/values/a%20b%2Fc.txt HTTP/1.1"
+    );
+}
+
+/// Tests that only 404 is reported as a missing key (does not invoke cloudflare api)
+#[cfg(all(test, not(target_arch = "wasm32")))]
+#[tokio::test]
+async fn test_read_errors() {
+    let (url, _) = mock_server(vec![
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        concat!(
+            "HTTP/1.1 403 Forbidden\r\nContent-Length: 65\r\nConnection: close\r\n\r\n",
+            r#"{"success":false,"errors":[{"code":10000,"message":"no access"}]}"#
+        ),
+    ]);
+    let kv = KV::builder("123", "namespace", "token")
+        .with_endpoint(url)
+        .build();
+    assert!(matches!(
+        kv.get_kv_value("missing.txt").await,
+        Err(Error::KVKeyNotFound(key, 404)) if key == "missing.txt"
+    ));
+    assert!(matches!(
+        kv.get_kv_value("index.html").await,
+        Err(Error::Api(e)) if e.is_auth_error()
+    ));
+}
+
+/// Tests request and response hooks (does not invoke cloudflare api)
+#[cfg(all(test, not(target_arch = "wasm32")))]
+#[tokio::test]
+async fn test_hooks() {
+    use std::sync::Mutex;
+
+    let (url, _) = mock_server(vec![concat!(
+        "HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n",
+        "\r\nhello"
+    )]);
+    let statuses = Arc::new(Mutex::new(Vec::new()));
+    let recorded = statuses.clone();
+    let kv = KV::builder("123", "namespace", "token")
+        .with_endpoint(url)
+        .with_request_hook(|builder| builder.header("X-Trace-Id", "abc"))
+        .with_response_hook(move |resp, _| recorded.lock().unwrap().push(resp.status().as_u16()))
+        .build();
+    assert_eq!(kv.get_kv_value("a.txt").await.unwrap(), "hello");
+    assert_eq!(*statuses.lock().unwrap(), vec![200]);
+
+    let request = kv.hooks.request[0](reqwest::Client::new().get("https://example.com"))
+        .build()
+        .unwrap();
+    assert_eq!(request.headers()["X-Trace-Id"], "abc");
+}
+
+/// Tests reading several keys (does not invoke cloudflare api)
+#[cfg(all(test, not(target_arch = "wasm32")))]
+#[tokio::test]
+async fn test_get_many() {
+    let (url, _) = mock_server(vec![
+        "HTTP/1.1 200 OK\r\nContent-Length: 1\r\nConnection: close\r\n\r\na",
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        "HTTP/1.1 200 OK\r\nContent-Length: 1\r\nConnection: close\r\n\r\nc",
+    ]);
+    let kv = KV::builder("123", "namespace", "token")
+        .with_endpoint(url)
+        .build();
+    let results = kv.get_many(&["a.html", "b.html", "c.html"], 1).await;
+    let keys: Vec<&str> = results.iter().map(|(key, _)| key.as_str()).collect();
+    assert_eq!(keys, vec!["a.html", "b.html", "c.html"]);
+    assert_eq!(results[0].1.as_ref().unwrap(), "a");
+    assert!(matches!(results[1].1, Err(Error::KVKeyNotFound(_, 404))));
+    assert_eq!(results[2].1.as_ref().unwrap(), "c");
+}
@@ -0,0 +1,196 @@
+//! Language variants of assets, such as `index.en.html` and `index.de.html`, grouped at sync
+//! time (see `SyncConfig::languages`) and chosen with the `Accept-Language` request header
+//! (see `KVAssets::get_asset_negotiated`).
+//!
+//! The variants are listed on the index entry for the path without the language suffix,
+//! `index.html`, in the order of `SyncConfig::languages`. Language ranges are matched with
+//! the lookup scheme of RFC 4647, in order of quality: a range matches a variant with the
+//! same tag, then a more specific tag (`de` matches `de-CH`), then the range is shortened
+//! (`de-CH-1996` to `de-CH` to `de`). If no range matches, the first variant is the default.
+
+use crate::{AssetStore, Error, KVAssets, ToAssetKey};
+
+/// Returns the path without its language suffix, and the language, if the suffix before
+/// the file extension is one of `languages`, ignoring case: `docs/guide.pt-BR.html` is
+/// `docs/guide.html` in `pt-BR`.
+pub fn split_language<'l>(path: &str, languages: &'l [String]) -> Option<(String, &'l str)> {
+    let (dir, name) = match path.rsplit_once('/') {
+        Some((dir, name)) => (&path[..=dir.len()], name),
+        None => ("", path),
+    };
+    let (rest, ext) = name.rsplit_once('.')?;
+    let (stem, suffix) = rest.rsplit_once('.')?;
+    if stem.is_empty() {
+        return None;
+    }
+    let language = languages.iter().find(|l| l.eq_ignore_ascii_case(suffix))?;
+    Some((format!("{}{}.{}", dir, stem, ext), language.as_str()))
+}
+
+/// Returns the position of the language in `available` that best matches the value of an
+/// `Accept-Language` request header, or None if none match
+pub(crate) fn choose_language<'t, I>(available: I, accept_language: &str) -> Option<usize>
+where
+    I: Iterator<Item = &'t str> + Clone,
+{
+    let mut ranges: Vec<(&str, f32)> = accept_language
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let range = parts.next().unwrap_or_default().trim();
+            let q = parts
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+            (!range.is_empty() && q > 0.0).then_some((range, q))
+        })
+        .collect();
+    // stable, so ranges with equal quality keep their order
+    ranges.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    for (range, _) in ranges {
+        if range == "*" {
+            return Some(0);
+        }
+        let mut range = range;
+        loop {
+            let exact = available
+                .clone()
+                .position(|tag| tag.eq_ignore_ascii_case(range));
+            let prefix = || {
+                available.clone().position(|tag| {
+                    tag.get(..range.len())
+                        .is_some_and(|start| start.eq_ignore_ascii_case(range))
+                        && tag[range.len()..].starts_with('-')
+                })
+            };
+            if let Some(pos) = exact.or_else(prefix) {
+                return Some(pos);
+            }
+            match range.rsplit_once('-') {
+                // a single-character subtag is dropped with the subtag after it
+                Some((rest, _)) => {
+                    range = match rest.rsplit_once('-') {
+                        Some((shorter, last)) if last.len() == 1 => shorter,
+                        _ => rest,
+                    }
+                }
+                None => break,
+            }
+        }
+    }
+    None
+}
+
+impl<'ah, S: AssetStore> KVAssets<'ah, S> {
+    /// Gets the asset at `path` in the language that best matches `accept_language`, the
+    /// value of the `Accept-Language` request header, if the asset has language variants
+    /// (see `SyncConfig::languages`); otherwise, gets the asset at `path`.
+    /// Returns Ok(None) if the asset is not in the index.
+    pub async fn get_asset_negotiated<K: ToAssetKey + ?Sized>(
+        &self,
+        path: &K,
+        accept_language: &str,
+    ) -> Result<Option<bytes::Bytes>, Error> {
+        match self.negotiate_language(path.key_str(), accept_language)? {
+            Some((_, variant)) => self.get_asset(variant.as_str()).await,
+            None => self.get_asset(path).await,
+        }
+    }
+
+    /// Returns the language and index path of the variant of the asset at `path` that best
+    /// matches `accept_language`, or the first variant if none match.
+    /// Returns Ok(None) if the asset has no language variants.
+    pub(crate) fn negotiate_language(
+        &self,
+        path: &str,
+        accept_language: &str,
+    ) -> Result<Option<(String, String)>, Error> {
+        let languages = match self.lookup_key(path)? {
+            Some(md) if !md.languages.is_empty() => md.languages,
+            _ => return Ok(None),
+        };
+        let tags = languages.iter().map(|(tag, _)| tag.as_str());
+        let pos = choose_language(tags, accept_language).unwrap_or(0);
+        Ok(languages.into_iter().nth(pos))
+    }
+}
+
+#[test]
+fn test_split_language() {
+    let languages = vec!["en".to_string(), "pt-BR".to_string()];
+    assert_eq!(
+        split_language("index.en.html", &languages),
+        Some(("index.html".to_string(), "en"))
+    );
+    assert_eq!(
+        split_language("docs/guide.pt-br.html", &languages),
+        Some(("docs/guide.html".to_string(), "pt-BR"))
+    );
+    assert_eq!(split_language("app.min.js", &languages), None);
+    assert_eq!(split_language("en.html", &languages), None);
+    assert_eq!(split_language("docs.en/index.html", &languages), None);
+}
+
+#[test]
+fn test_choose_language() {
+    let tags = ["en", "de", "de-CH", "pt-BR"];
+    let choose = |accept: &str| choose_language(tags.iter().copied(), accept);
+    assert_eq!(choose("de"), Some(1));
+    assert_eq!(choose("fr, de;q=0.5, en;q=0.8"), Some(0));
+    assert_eq!(choose("DE-ch"), Some(2));
+    assert_eq!(choose("de-CH-1996"), Some(2));
+    assert_eq!(choose("de-AT"), Some(1));
+    assert_eq!(choose("pt"), Some(3));
+    assert_eq!(choose("en-x-private"), Some(0));
+    assert_eq!(choose("fr, *;q=0.1"), Some(0));
+    assert_eq!(choose("de;q=0, fr"), None);
+    assert_eq!(choose(""), None);
+}
+
+/// Tests language negotiation (does not invoke cloudflare api)
+#[cfg(all(test, not(target_arch = "wasm32")))]
+#[tokio::test]
+async fn test_get_asset_negotiated() {
+    use crate::{AssetIndex, AssetMetadata};
+
+    let md = |path: &str, body: &[u8]| AssetMetadata {
+        path: path.to_string(),
+        inline: Some(body.to_vec()),
+        ..Default::default()
+    };
+    let mut index = AssetIndex::new();
+    index.insert("index.en.html".to_string(), md("index.en.1.html", b"hello"));
+    index.insert("index.de.html".to_string(), md("index.de.2.html", b"hallo"));
+    index.insert(
+        "index.html".to_string(),
+        AssetMetadata {
+            languages: vec![
+                ("en".to_string(), "index.en.html".to_string()),
+                ("de".to_string(), "index.de.html".to_string()),
+            ],
+            ..md("index.en.1.html", b"hello")
+        },
+    );
+    let blob = crate::encode_index(&index).expect("serialize-index");
+
+    let kv = KVAssets::init(&blob, "123", "namespace", "token");
+    let get = |accept: &'static str| {
+        let kv = &kv;
+        async move {
+            kv.get_asset_negotiated("/index.html", accept)
+                .await
+                .unwrap()
+                .unwrap()
+        }
+    };
+    assert_eq!(get("de-DE, en;q=0.5").await.as_ref(), b"hallo");
+    assert_eq!(get("fr").await.as_ref(), b"hello");
+
+    let resp = kv
+        .handle_request("GET", "/index.html", &[("Accept-Language", "de")])
+        .await
+        .unwrap();
+    assert_eq!(resp.body.as_ref(), b"hallo");
+    assert_eq!(resp.header("content-language"), Some("de"));
+    assert_eq!(resp.header("vary"), Some("Accept-Language"));
+    assert_eq!(resp.variant.language.as_deref(), Some("de"));
+}
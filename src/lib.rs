@@ -1,11 +1,109 @@
+mod access;
+mod adopt;
+mod api_error;
 mod assets;
+mod bulk;
+mod cache;
+mod cell;
+mod chunk;
+mod clock;
+mod coalesce;
+mod conditional;
+mod credentials;
+mod embedded;
+mod encoding;
+mod error_page;
+mod glob;
+mod head;
+mod history;
+mod index;
+mod key;
+mod kv;
+mod language;
+mod listing;
+mod local;
+mod lookup;
+mod plan;
+mod policy;
+mod queue;
+mod range;
+mod redirect;
+mod response;
+mod retry;
+mod s3;
+mod signed;
+mod source;
+mod sri;
+mod stats;
+mod store;
+mod template;
+mod throttle;
+mod timing;
+mod trace;
 mod upload;
-
-pub use assets::{init_kv, AssetIndex, AssetMetadata, KVAssets, KV};
+mod versioned;
+
+pub use access::{AccessLogger, AccessRecord};
+pub use api_error::{ApiErrorDetail, CloudflareApiError};
+pub use assets::{
+    AssetIndex, AssetMetadata, ErrorHook, HitHook, KVAssets, MissHook, Precedence, RefreshFuture,
+};
+pub use cache::AssetCacheConfig;
+pub use cell::MaybeSendSync;
+pub use chunk::{chunk_key, CHUNK_SIZE};
+pub use conditional::{etag_matches, Conditional};
+pub use embedded::Embedded;
+pub use encoding::{EncodedAsset, EncodedVariant};
+pub use head::AssetHead;
+pub use index::{decode_index, encode_index, index_format_version, INDEX_FORMAT_VERSION};
+pub use key::{AssetKey, ToAssetKey, KEY_MAX_LEN};
+pub use kv::{
+    init_kv, Auth, Expiration, GetOptions, KVBuilder, KVKey, KVPair, RequestHook, ResponseHook,
+    ACCOUNT_ID_VAR, API_TOKEN_VAR, KV, NAMESPACE_ID_VAR,
+};
+pub use language::split_language;
+pub use listing::{DirectoryEntry, ListingFormat};
+pub use lookup::{LookupOptions, TrailingSlash};
+pub use policy::{parse_headers, CachePolicy, CorsPolicy, FrameOptions, HeaderPolicy, HeaderRule};
+pub use range::AssetRange;
+pub use redirect::{parse_redirects, Redirect, RedirectRule, Resolved};
+pub use response::{AssetResponse, Variant, ALLOWED_METHODS};
+pub use retry::RetryPolicy;
+#[cfg(feature = "signed-urls")]
+pub use signed::sign_path;
+pub use stats::NamespaceStats;
+pub use store::{AssetStore, ByteStream};
+pub use timing::ServerTiming;
+pub use versioned::IMMUTABLE_CACHE_CONTROL;
 
 // for non-wasm, export asset builders that depend on std::fs and wrangler libs
 #[cfg(not(target_arch = "wasm32"))]
-pub use upload::{sync_assets, SyncConfig};
+pub use adopt::{adopt_index, AdoptReport};
+#[cfg(not(target_arch = "wasm32"))]
+pub use bulk::KeyFailure;
+#[cfg(all(feature = "keyring", not(target_arch = "wasm32")))]
+pub use credentials::{delete_token, load_token, save_token};
+#[cfg(not(target_arch = "wasm32"))]
+pub use credentials::{load_profile, login, profiles_path, Profile, PROFILE_ENV_VAR};
+#[cfg(not(target_arch = "wasm32"))]
+pub use history::{deploy_history, deployed_index, DeployRecord, HISTORY_PREFIX};
+#[cfg(not(target_arch = "wasm32"))]
+pub use index::migrate_index;
+#[cfg(not(target_arch = "wasm32"))]
+pub use local::LocalKV;
+#[cfg(not(target_arch = "wasm32"))]
+pub use plan::{IndexDiff, PlanDestination, SyncPlan, PLAN_FORMAT_VERSION};
+#[cfg(not(target_arch = "wasm32"))]
+pub use s3::{import_s3, S3Credentials, S3Source};
+#[cfg(not(target_arch = "wasm32"))]
+pub use stats::namespace_stats;
+#[cfg(not(target_arch = "wasm32"))]
+pub use template::{builtin_template_vars, DEPLOY_TIME_VAR, GIT_SHA_VAR};
+#[cfg(not(target_arch = "wasm32"))]
+pub use upload::{
+    apply_plan, embed_assets, load_header_rules, load_headers_file, load_redirects, plan_sync,
+    retry_queued, sync_assets, ReplicaReport, SyncConfig, SyncReport,
+};
 
 use thiserror::Error as ThisError;
 #[derive(Clone, Debug, ThisError)]
@@ -18,6 +116,12 @@ pub enum Error {
     #[allow(clippy::upper_case_acronyms)]
     KVHttpStatus(u16, String),
 
+    #[error("Cloudflare API error: {0}")]
+    Api(CloudflareApiError),
+
+    #[error("KV request timed out: {0}")]
+    Timeout(String),
+
     #[error("KV Key {0} not found. status={1}")]
     #[allow(clippy::upper_case_acronyms)]
     KVKeyNotFound(String, u16),
@@ -25,9 +129,24 @@ pub enum Error {
     #[error("Deserializing assets:{0}")]
     DeserializeAssets(String),
 
+    #[error("Serializing assets:{0}")]
+    SerializeAssets(String),
+
     #[error("Empty key passed to lookup")]
     EmptyKey,
 
+    #[error("Invalid key: {0}")]
+    InvalidKey(String),
+
+    #[error("Malformed request path: {0}")]
+    MalformedPath(String),
+
+    #[error("Asset {0} is protected and requires a signed URL")]
+    SignatureRequired(String),
+
+    #[error("Invalid URL signature: {0}")]
+    SignatureInvalid(String),
+
     #[cfg(not(target_arch = "wasm32"))]
     #[error("Wangler error: {0}")]
     Wrangler(String),
@@ -46,13 +165,28 @@ pub enum Error {
     CreateDir(String),
 
     #[cfg(not(target_arch = "wasm32"))]
-    #[error("Invalid asset path: not a directory: {0}")]
+    #[error("Invalid asset path: not a directory or archive: {0}")]
     InvalidAssetPath(String),
 
     #[cfg(not(target_arch = "wasm32"))]
     #[error("Missing config file {0}")]
     MissingWranglerFile(String),
 
+    #[error("Range not satisfiable for asset of {0} bytes")]
+    RangeNotSatisfiable(u64),
+
+    #[error("Missing worker variable or secret {0}")]
+    MissingEnvVar(String),
+
+    #[error("Integrity check failed for {0}: content doesn't match the index")]
+    IntegrityMismatch(String),
+
+    #[error("Value for {0} has {1} bytes, more than the KV limit of 25 MiB")]
+    ValueTooLarge(String, u64),
+
+    #[error("Expiration {0} is too soon. Must be at least 60 seconds in the future")]
+    ExpirationTooSoon(u64),
+
     #[error("TTL to short. Must be at least 60 seconds")]
     #[allow(clippy::upper_case_acronyms)]
     TTLTooShort,
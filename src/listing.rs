@@ -0,0 +1,239 @@
+//! Directory listings generated from the index (see `KVAssets::with_directory_listing`).
+
+use crate::{AssetStore, Error, KVAssets};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Format of a directory listing
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ListingFormat {
+    /// Html page with a link for each entry
+    Html,
+    /// Json array of `DirectoryEntry`
+    Json,
+}
+
+impl ListingFormat {
+    /// Returns the value for the `Content-Type` header
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ListingFormat::Html => "text/html; charset=utf-8",
+            ListingFormat::Json => "application/json",
+        }
+    }
+}
+
+/// File or subdirectory in a directory listing
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct DirectoryEntry {
+    /// File name, or subdirectory name with a trailing '/'
+    pub name: String,
+    /// Size of the file, or 0 for a subdirectory
+    pub size: u64,
+    /// Last modified time of the file, in UTC seconds since EPOCH, or 0 if unknown
+    pub modified: u64,
+}
+
+impl<'ah, S: AssetStore> KVAssets<'ah, S> {
+    /// Allows directory listings (see `render_directory`) of directories under the
+    /// prefixes, e.g. `["downloads/"]`, or `[""]` for every directory.
+    /// `handle_request` answers GET requests for listable directories without an index
+    /// document with an html listing.
+    pub fn with_directory_listing<T: ToString>(mut self, prefixes: &[T]) -> Self {
+        self.listable = prefixes
+            .iter()
+            .map(|p| {
+                let p = p.to_string();
+                p.strip_prefix('/').map(str::to_string).unwrap_or(p)
+            })
+            .collect();
+        self
+    }
+
+    /// Returns the files and subdirectories directly in the directory at `path`, sorted
+    /// by name, or None if the directory isn't listable (see `with_directory_listing`)
+    /// or has no assets
+    pub fn list_directory(&self, path: &str) -> Result<Option<Vec<DirectoryEntry>>, Error> {
+        let dir = path.trim_matches('/');
+        let dir = match dir {
+            "" => String::new(),
+            dir => format!("{}/", dir),
+        };
+        if !self.listable.iter().any(|p| dir.starts_with(p.as_str())) {
+            return Ok(None);
+        }
+        let mut entries: BTreeMap<String, DirectoryEntry> = BTreeMap::new();
+        for (path, md) in self.list_keys(&dir)? {
+            let rest = &path[dir.len()..];
+            let entry = match rest.split_once('/') {
+                Some((subdir, _)) => DirectoryEntry {
+                    name: format!("{}/", subdir),
+                    size: 0,
+                    modified: 0,
+                },
+                None => DirectoryEntry {
+                    name: rest.to_string(),
+                    size: md.size,
+                    modified: md.modified,
+                },
+            };
+            entries.insert(entry.name.clone(), entry);
+        }
+        if entries.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(entries.into_values().collect()))
+    }
+
+    /// Renders a listing of the directory at `path` (see `list_directory`), or returns None
+    /// if it isn't listable or has no assets
+    pub fn render_directory(
+        &self,
+        path: &str,
+        format: ListingFormat,
+    ) -> Result<Option<String>, Error> {
+        let entries = match self.list_directory(path)? {
+            Some(entries) => entries,
+            None => return Ok(None),
+        };
+        let body = match format {
+            ListingFormat::Json => serde_json::to_string(&entries)
+                .map_err(|e| Error::Message(format!("json error: {}", e)))?,
+            ListingFormat::Html => {
+                let title = html_escape(&format!("/{}", path.trim_matches('/')));
+                let mut html = format!(
+                    "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Index of {0}</title></head>\n<body><h1>Index of {0}</h1>\n<ul>\n",
+                    title
+                );
+                if !path.trim_matches('/').is_empty() {
+                    html.push_str("<li><a href=\"../\">../</a></li>\n");
+                }
+                for entry in entries.iter() {
+                    html.push_str(&format!(
+                        "<li><a href=\"{}\">{}</a></li>\n",
+                        html_escape(&percent_encode(&entry.name)),
+                        html_escape(&entry.name)
+                    ));
+                }
+                html.push_str("</ul></body></html>\n");
+                html
+            }
+        };
+        Ok(Some(body))
+    }
+}
+
+fn html_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Percent-encodes a relative link, keeping '/'
+fn percent_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                out.push(b as char)
+            }
+            b => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+#[test]
+fn test_directory_listing() {
+    use crate::{AssetIndex, AssetMetadata};
+
+    let mut index = AssetIndex::new();
+    for (path, size) in [
+        ("index.html", 1),
+        ("files/a b.txt", 2),
+        ("files/<x>.txt", 3),
+        ("files/old/c.txt", 4),
+    ] {
+        let md = AssetMetadata {
+            path: path.to_string(),
+            size,
+            ..Default::default()
+        };
+        index.insert(path.to_string(), md);
+    }
+    let blob = crate::encode_index(&index).expect("serialize-index");
+
+    let kv = KVAssets::init(&blob, "123", "namespace", "token");
+    assert_eq!(kv.list_directory("/files/").unwrap(), None);
+
+    let kv = kv.with_directory_listing(&["/files/"]);
+    assert_eq!(kv.list_directory("/").unwrap(), None);
+    assert_eq!(kv.list_directory("/files/empty").unwrap(), None);
+    let names: Vec<String> = kv
+        .list_directory("/files")
+        .unwrap()
+        .unwrap()
+        .into_iter()
+        .map(|e| e.name)
+        .collect();
+    assert_eq!(names, ["<x>.txt", "a b.txt", "old/"]);
+
+    let html = kv
+        .render_directory("files/", ListingFormat::Html)
+        .unwrap()
+        .unwrap();
+    assert!(html.contains("<a href=\"a%20b.txt\">a b.txt</a>"));
+    assert!(html.contains("<a href=\"%3Cx%3E.txt\">&lt;x&gt;.txt</a>"));
+    assert!(html.contains("<a href=\"old/\">old/</a>"));
+    let json = kv
+        .render_directory("files/old", ListingFormat::Json)
+        .unwrap()
+        .unwrap();
+    assert_eq!(json, r#"[{"name":"c.txt","size":4,"modified":0}]"#);
+}
+
+/// Tests listings from handle_request (does not invoke cloudflare api)
+#[cfg(all(test, not(target_arch = "wasm32")))]
+#[tokio::test]
+async fn test_listing_response() {
+    use crate::{AssetIndex, AssetMetadata};
+
+    let mut index = AssetIndex::new();
+    index.insert(
+        "files/a.txt".to_string(),
+        AssetMetadata {
+            path: "files/a.abc.txt".to_string(),
+            inline: Some(b"a".to_vec()),
+            ..Default::default()
+        },
+    );
+    let blob = crate::encode_index(&index).expect("serialize-index");
+
+    let kv = KVAssets::init(&blob, "123", "namespace", "token").with_directory_listing(&[""]);
+    let resp = kv.handle_request("GET", "/files/", &[]).await.unwrap();
+    assert_eq!(resp.status, 200);
+    assert_eq!(
+        resp.header("content-type"),
+        Some("text/html; charset=utf-8")
+    );
+    assert_eq!(
+        kv.handle_request("GET", "/files", &[])
+            .await
+            .unwrap()
+            .status,
+        404
+    );
+    assert_eq!(
+        kv.handle_request("GET", "/", &[]).await.unwrap().status,
+        200
+    );
+}
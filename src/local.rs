@@ -0,0 +1,253 @@
+#![cfg(not(target_arch = "wasm32"))]
+//! Directory-backed emulation of Workers KV, for offline development.
+//!
+//! Values are stored in `<dir>/values`, one file per key, and expiration and metadata
+//! (if any) in `<dir>/meta` as json. File names are the key, with any characters other
+//! than letters, digits, '-', '_', and '.' percent-encoded, so keys containing '/' are stored
+//! as a single file. Expired keys behave as if they were deleted, as they do in KV.
+
+use crate::{clock, AssetStore, Error, ToAssetKey};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// Local, persistent emulation of a Workers KV namespace
+#[derive(Clone)]
+pub struct LocalKV {
+    dir: PathBuf,
+}
+
+/// Expiration and user metadata stored alongside a value
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct LocalMeta {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expiration: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    metadata: Option<serde_json::Value>,
+}
+
+impl LocalKV {
+    /// Opens the store in the directory, creating it if it doesn't exist
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self, Error> {
+        let dir = dir.as_ref().to_path_buf();
+        for sub in ["values", "meta"].iter() {
+            std::fs::create_dir_all(dir.join(sub)).map_err(|e| {
+                Error::IO(format!("creating local kv dir {}: {}", dir.display(), e))
+            })?;
+        }
+        Ok(Self { dir })
+    }
+
+    /// Returns the value and its metadata (if any) for the key.
+    /// If the key is missing or expired, returns `Error::KVKeyNotFound`.
+    pub fn get_with_metadata<K: ToAssetKey + ?Sized>(
+        &self,
+        key: &K,
+    ) -> Result<(bytes::Bytes, Option<serde_json::Value>), Error> {
+        let key = key.to_asset_key()?;
+        let key = key.as_str();
+        let meta = self.read_meta(key)?;
+        if matches!(meta.expiration, Some(exp) if exp <= clock::now_secs()) {
+            self.delete(key)?;
+            return Err(Error::KVKeyNotFound(key.to_string(), 404));
+        }
+        match std::fs::read(self.value_path(key)) {
+            Ok(value) => Ok((value.into(), meta.metadata)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Err(Error::KVKeyNotFound(key.to_string(), 404))
+            }
+            Err(e) => Err(Error::IO(format!("reading local kv key {}: {}", key, e))),
+        }
+    }
+
+    /// Returns the value for the key.
+    /// If the key is missing or expired, returns `Error::KVKeyNotFound`.
+    pub fn get<K: ToAssetKey + ?Sized>(&self, key: &K) -> Result<bytes::Bytes, Error> {
+        self.get_with_metadata(key).map(|(value, _)| value)
+    }
+
+    /// Stores a value, with optional expiration TTL (at least 60 seconds) and json metadata
+    pub fn put_with_metadata<K: ToAssetKey + ?Sized>(
+        &self,
+        key: &K,
+        value: &[u8],
+        expiration_ttl: Option<u64>,
+        metadata: Option<serde_json::Value>,
+    ) -> Result<(), Error> {
+        let key = key.to_asset_key()?;
+        let key = key.as_str();
+        if matches!(expiration_ttl, Some(ttl) if ttl < 60) {
+            return Err(Error::TTLTooShort);
+        }
+        std::fs::write(self.value_path(key), value)
+            .map_err(|e| Error::IO(format!("writing local kv key {}: {}", key, e)))?;
+        let meta = LocalMeta {
+            expiration: expiration_ttl.map(|ttl| clock::now_secs() + ttl),
+            metadata,
+        };
+        let meta_path = self.meta_path(key);
+        if meta.expiration.is_none() && meta.metadata.is_none() {
+            remove_if_exists(&meta_path)
+        } else {
+            let json = serde_json::to_vec(&meta)
+                .map_err(|e| Error::Message(format!("json serialization error: {}", e)))?;
+            std::fs::write(&meta_path, json)
+                .map_err(|e| Error::IO(format!("writing {}: {}", meta_path.display(), e)))
+        }
+    }
+
+    /// Stores a value, with optional expiration TTL (at least 60 seconds)
+    pub fn put<K: ToAssetKey + ?Sized>(
+        &self,
+        key: &K,
+        value: &[u8],
+        expiration_ttl: Option<u64>,
+    ) -> Result<(), Error> {
+        self.put_with_metadata(key, value, expiration_ttl, None)
+    }
+
+    /// Deletes the key. Deleting a key that doesn't exist is not an error.
+    pub fn delete<K: ToAssetKey + ?Sized>(&self, key: &K) -> Result<(), Error> {
+        let key = key.to_asset_key()?;
+        let key = key.as_str();
+        remove_if_exists(&self.value_path(key))?;
+        remove_if_exists(&self.meta_path(key))
+    }
+
+    /// Returns all unexpired keys, in sorted order
+    pub fn keys(&self) -> Result<Vec<String>, Error> {
+        let values = self.dir.join("values");
+        let entries = std::fs::read_dir(&values)
+            .map_err(|e| Error::IO(format!("reading {}: {}", values.display(), e)))?;
+        let now = clock::now_secs();
+        let mut keys = Vec::new();
+        for entry in entries {
+            let entry = entry.map_err(|e| Error::IO(e.to_string()))?;
+            if let Some(key) = entry.file_name().to_str().and_then(decode_key) {
+                if !matches!(self.read_meta(&key)?.expiration, Some(exp) if exp <= now) {
+                    keys.push(key);
+                }
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    /// Returns the metadata for the key, without reading the value
+    pub(crate) fn metadata(&self, key: &str) -> Result<Option<serde_json::Value>, Error> {
+        Ok(self.read_meta(key)?.metadata)
+    }
+
+    fn value_path(&self, key: &str) -> PathBuf {
+        self.dir.join("values").join(encode_key(key))
+    }
+
+    fn meta_path(&self, key: &str) -> PathBuf {
+        self.dir
+            .join("meta")
+            .join(format!("{}.json", encode_key(key)))
+    }
+
+    fn read_meta(&self, key: &str) -> Result<LocalMeta, Error> {
+        let path = self.meta_path(key);
+        match std::fs::read(&path) {
+            Ok(json) => serde_json::from_slice(&json)
+                .map_err(|e| Error::IO(format!("parsing {}: {}", path.display(), e))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(LocalMeta::default()),
+            Err(e) => Err(Error::IO(format!("reading {}: {}", path.display(), e))),
+        }
+    }
+}
+
+// The trait methods use blocking file i/o, which is fine for development servers and tests
+#[async_trait::async_trait]
+impl AssetStore for LocalKV {
+    async fn get_value(&self, key: &str) -> Result<bytes::Bytes, Error> {
+        self.get(key)
+    }
+
+    async fn put_value(
+        &self,
+        key: &str,
+        value: bytes::Bytes,
+        expiration_ttl: Option<u64>,
+    ) -> Result<(), Error> {
+        self.put(key, &value, expiration_ttl)
+    }
+
+    async fn delete_value(&self, key: &str) -> Result<(), Error> {
+        self.delete(key)
+    }
+}
+
+fn remove_if_exists(path: &Path) -> Result<(), Error> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(Error::IO(format!("removing {}: {}", path.display(), e)))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Converts a key to a file name
+fn encode_key(key: &str) -> String {
+    let mut name = String::with_capacity(key.len());
+    for (i, b) in key.bytes().enumerate() {
+        // a leading '.' is encoded so keys can't be "." or ".."
+        if b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || (b == b'.' && i > 0) {
+            name.push(b as char);
+        } else {
+            name.push_str(&format!("%{:02X}", b));
+        }
+    }
+    name
+}
+
+/// Converts a file name back to a key. Returns None if the name isn't a valid encoding.
+fn decode_key(name: &str) -> Option<String> {
+    let bytes = name.as_bytes();
+    let mut key = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = name.get(i + 1..i + 3)?;
+            key.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            key.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(key).ok()
+}
+
+#[test]
+fn test_local_kv() {
+    let dir = std::env::temp_dir().join(format!("kv-assets-local-{}", std::process::id()));
+    let kv = LocalKV::open(&dir).unwrap();
+
+    let key = "css/site.1234 ü.css";
+    assert_eq!(decode_key(&encode_key(key)).unwrap(), key);
+    assert_eq!(encode_key(".."), "%2E.");
+
+    kv.put(key, b"body{}", None).unwrap();
+    kv.put_with_metadata("b", b"xyz", Some(3600), Some(serde_json::json!({"v": 1})))
+        .unwrap();
+    assert_eq!(kv.get(key).unwrap().as_ref(), b"body{}");
+    let (value, meta) = kv.get_with_metadata("b").unwrap();
+    assert_eq!(value.as_ref(), b"xyz");
+    assert_eq!(meta, Some(serde_json::json!({"v": 1})));
+    assert_eq!(kv.keys().unwrap(), vec!["b".to_string(), key.to_string()]);
+    assert!(matches!(
+        kv.put("c", b"", Some(10)),
+        Err(Error::TTLTooShort)
+    ));
+
+    // reopening sees the same data
+    let kv = LocalKV::open(&dir).unwrap();
+    kv.delete("b").unwrap();
+    kv.delete("b").unwrap();
+    assert!(matches!(kv.get("b"), Err(Error::KVKeyNotFound(_, 404))));
+    assert_eq!(kv.keys().unwrap(), vec![key.to_string()]);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
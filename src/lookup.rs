@@ -0,0 +1,223 @@
+//! Normalization of request paths before they're looked up in the index.
+//!
+//! Paths are percent-decoded, unless disabled, and paths with `..` segments or NUL bytes
+//! are rejected with `Error::MalformedPath`. Case folding and Unicode normalization are
+//! applied to both the request path and the index paths, so `/About.HTML` finds
+//! `about.html`. An exact match is always tried first.
+
+use crate::{AssetIndex, Error};
+use std::collections::HashMap;
+
+/// How request paths are matched against the index (see `KVAssets::with_lookup_options`).
+/// The default matches decoded paths exactly.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LookupOptions {
+    /// Decode `%XX` escapes in request paths, e.g. `caf%C3%A9.txt` (on by default).
+    /// Disable it for indexes with paths that contain a literal '%'.
+    pub percent_decode: bool,
+    /// Ignore case when no path matches exactly
+    pub case_insensitive: bool,
+    /// Compare paths in Unicode normalization form C (NFC), so names with
+    /// decomposed accents match
+    #[cfg(feature = "unicode")]
+    pub normalize_unicode: bool,
+}
+
+/// How paths with and without a trailing '/', such as `/about` and `/about/`, are resolved
+/// (see `KVAssets::with_trailing_slash`)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TrailingSlash {
+    /// Paths are looked up as requested
+    #[default]
+    None,
+    /// A trailing '/' is removed, so `/about/` serves the asset `about`, if there is one,
+    /// before looking for a directory index
+    Strip,
+    /// Paths are looked up as directories first, so `/about` serves the directory index
+    /// of `about/`, if there is one, before looking for the asset `about`
+    Add,
+    /// Like `Strip`, but requests for the other form of a path are redirected to the
+    /// form in the index: `/about/` to `/about` for assets, and `/about` to `/about/`
+    /// for directories
+    RedirectToCanonical,
+}
+
+impl Default for LookupOptions {
+    fn default() -> Self {
+        Self {
+            percent_decode: true,
+            case_insensitive: false,
+            #[cfg(feature = "unicode")]
+            normalize_unicode: false,
+        }
+    }
+}
+
+impl LookupOptions {
+    /// Creates options that match decoded paths exactly
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Enables percent-decoding of request paths (the default)
+    pub fn with_percent_decode(mut self) -> Self {
+        self.percent_decode = true;
+        self
+    }
+
+    /// Disables percent-decoding, so paths are matched as requested
+    pub fn without_percent_decode(mut self) -> Self {
+        self.percent_decode = false;
+        self
+    }
+
+    /// Enables case-insensitive matching
+    pub fn with_case_insensitive(mut self) -> Self {
+        self.case_insensitive = true;
+        self
+    }
+
+    /// Enables matching in Unicode normalization form C
+    #[cfg(feature = "unicode")]
+    pub fn with_normalize_unicode(mut self) -> Self {
+        self.normalize_unicode = true;
+        self
+    }
+
+    /// Returns the request path, decoded and normalized. Rejects malformed escapes,
+    /// `..` segments, and NUL bytes (after decoding) with `Error::MalformedPath`.
+    pub(crate) fn normalize(&self, path: &str) -> Result<String, Error> {
+        let path = match self.percent_decode {
+            true => percent_decode(path)?,
+            false => path.to_string(),
+        };
+        if path.contains('\0') || path.split(['/', '\\']).any(|segment| segment == "..") {
+            return Err(Error::MalformedPath(format!("{:?}", path)));
+        }
+        #[cfg(feature = "unicode")]
+        if self.normalize_unicode {
+            use unicode_normalization::UnicodeNormalization;
+            return Ok(path.nfc().collect());
+        }
+        Ok(path)
+    }
+
+    /// Returns true if paths are compared after folding (see `fold`)
+    pub(crate) fn folds(&self) -> bool {
+        #[cfg(feature = "unicode")]
+        if self.normalize_unicode {
+            return true;
+        }
+        self.case_insensitive
+    }
+
+    /// Returns the form of the path used for inexact matches
+    pub(crate) fn fold(&self, path: &str) -> String {
+        let path = match self.case_insensitive {
+            true => path.to_lowercase(),
+            false => path.to_string(),
+        };
+        #[cfg(feature = "unicode")]
+        if self.normalize_unicode {
+            use unicode_normalization::UnicodeNormalization;
+            return path.nfc().collect();
+        }
+        path
+    }
+
+    /// Maps the folded form of each index path to the path. If several paths fold to
+    /// the same form, the first in sorted order is used.
+    pub(crate) fn fold_index(&self, map: &AssetIndex) -> HashMap<String, String> {
+        let mut sorted: Vec<&String> = map.keys().collect();
+        sorted.sort();
+        let mut folded = HashMap::new();
+        for path in sorted.into_iter() {
+            folded
+                .entry(self.fold(path))
+                .or_insert_with(|| path.to_string());
+        }
+        folded
+    }
+}
+
+/// Decodes `%XX` escapes. Returns `Error::MalformedPath` if an escape is malformed
+/// or the result isn't UTF-8.
+pub(crate) fn percent_decode(path: &str) -> Result<String, Error> {
+    if !path.contains('%') {
+        return Ok(path.to_string());
+    }
+    let invalid = || Error::MalformedPath(format!("{:?} has invalid percent-encoding", path));
+    let bytes = path.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3).ok_or_else(invalid)?;
+            let hex = std::str::from_utf8(hex).map_err(|_| invalid())?;
+            out.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).map_err(|_| invalid())
+}
+
+#[test]
+fn test_lookup_options() {
+    assert_eq!(
+        percent_decode("a%20b/caf%C3%A9.txt").unwrap(),
+        "a b/café.txt"
+    );
+    assert_eq!(percent_decode("100%25").unwrap(), "100%");
+    assert!(matches!(
+        percent_decode("a%2"),
+        Err(Error::MalformedPath(_))
+    ));
+    assert!(matches!(
+        percent_decode("a%zz"),
+        Err(Error::MalformedPath(_))
+    ));
+    assert!(matches!(
+        percent_decode("%FF"),
+        Err(Error::MalformedPath(_))
+    ));
+
+    let exact = LookupOptions::new();
+    assert_eq!(exact.normalize("a%20b").unwrap(), "a b");
+    assert_eq!(exact.normalize("a..b/c.").unwrap(), "a..b/c.");
+    for path in ["../x", "a/%2E%2E/x", "a/..", "a%00b", "a\\..\\b"] {
+        assert!(matches!(
+            exact.normalize(path),
+            Err(Error::MalformedPath(_))
+        ));
+    }
+    assert!(!exact.folds());
+    let literal = LookupOptions::new().without_percent_decode();
+    assert_eq!(literal.normalize("100%.txt").unwrap(), "100%.txt");
+    assert!(matches!(
+        literal.normalize("a/../b"),
+        Err(Error::MalformedPath(_))
+    ));
+    let options = LookupOptions::new().with_case_insensitive();
+    assert_eq!(options.normalize("A%20B").unwrap(), "A B");
+    assert_eq!(options.fold("About.HTML"), "about.html");
+
+    let mut index = AssetIndex::new();
+    index.insert("About.html".to_string(), Default::default());
+    index.insert("about.html".to_string(), Default::default());
+    let folded = options.fold_index(&index);
+    assert_eq!(folded.len(), 1);
+    assert_eq!(folded["about.html"], "About.html");
+}
+
+#[cfg(feature = "unicode")]
+#[test]
+fn test_normalize_unicode() {
+    let options = LookupOptions::new().with_normalize_unicode();
+    // "é" as 'e' and a combining accent
+    assert_eq!(options.normalize("cafe\u{301}").unwrap(), "caf\u{e9}");
+    assert_eq!(options.fold("caf\u{e9}"), "caf\u{e9}");
+    assert!(options.folds());
+}
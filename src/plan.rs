@@ -0,0 +1,151 @@
+#![cfg(not(target_arch = "wasm32"))]
+//! Sync plans, for a two-phase (plan, then apply) deploy.
+//!
+//! A plan lists the KV keys to upload and delete, and the index that will be written.
+//! Keys include a hash of the file contents, so applying a plan uploads exactly the
+//! contents that were planned; if the asset files changed since, `apply_plan` fails.
+
+use crate::{AssetIndex, Error};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+/// Format version of plan files
+pub const PLAN_FORMAT_VERSION: u32 = 1;
+
+/// Changes to be made by a sync
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SyncPlan {
+    /// Plan file format version
+    pub version: u32,
+    /// When the plan was created (unix seconds)
+    pub created: u64,
+    /// Where the assets will be synced to
+    pub destination: PlanDestination,
+    /// Keys to upload
+    pub uploads: Vec<String>,
+    /// Stale keys to delete. Empty unless the plan was created with prune
+    pub deletes: Vec<String>,
+    /// Number of stale keys that will be left in place, because prune was not enabled
+    pub deferred_prune: usize,
+    /// Expiration of uploaded keys (unix seconds), if a TTL was set
+    pub expires: Option<u64>,
+    /// Changes to the index, compared with the index file at planning time
+    pub index_diff: IndexDiff,
+    /// The index that will be written
+    pub index: AssetIndex,
+    /// Template variables, used again by `apply_plan`
+    #[serde(default)]
+    pub template_vars: BTreeMap<String, String>,
+}
+
+/// Destination of a planned sync
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlanDestination {
+    /// Workers KV namespace
+    Remote {
+        account_id: String,
+        namespace_id: String,
+    },
+    /// `LocalKV` directory
+    Local { dir: PathBuf },
+}
+
+/// Paths added, changed, or removed from the index
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexDiff {
+    pub added: Vec<String>,
+    pub changed: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl IndexDiff {
+    /// Compares two indexes. Results are sorted by path.
+    pub fn between(old: &AssetIndex, new: &AssetIndex) -> Self {
+        let mut diff = IndexDiff::default();
+        for (path, md) in new.iter() {
+            match old.get(path) {
+                None => diff.added.push(path.clone()),
+                Some(old_md) if old_md != md => diff.changed.push(path.clone()),
+                _ => {}
+            }
+        }
+        diff.removed = old
+            .keys()
+            .filter(|path| !new.contains_key(*path))
+            .cloned()
+            .collect();
+        diff.added.sort();
+        diff.changed.sort();
+        diff.removed.sort();
+        diff
+    }
+
+    /// Returns true if the index is unchanged
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.changed.is_empty() && self.removed.is_empty()
+    }
+}
+
+impl SyncPlan {
+    /// Returns true if applying the plan would make no changes
+    pub fn is_empty(&self) -> bool {
+        self.uploads.is_empty() && self.deletes.is_empty() && self.index_diff.is_empty()
+    }
+
+    /// Writes the plan as json
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let json = serde_json::to_vec_pretty(self)
+            .map_err(|e| Error::Message(format!("json serialization error: {}", e)))?;
+        std::fs::write(path, json)
+            .map_err(|e| Error::IO(format!("writing plan {}: {}", path.display(), e)))
+    }
+
+    /// Reads a plan written by `save`
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let json = std::fs::read(path)
+            .map_err(|e| Error::IO(format!("reading plan {}: {}", path.display(), e)))?;
+        let plan: SyncPlan = serde_json::from_slice(&json)
+            .map_err(|e| Error::Message(format!("invalid plan {}: {}", path.display(), e)))?;
+        if plan.version != PLAN_FORMAT_VERSION {
+            return Err(Error::Message(format!(
+                "unsupported plan version {} in {}",
+                plan.version,
+                path.display()
+            )));
+        }
+        Ok(plan)
+    }
+}
+
+#[test]
+fn test_index_diff() {
+    use crate::AssetMetadata;
+
+    let md = |key: &str| AssetMetadata {
+        path: key.to_string(),
+        ..Default::default()
+    };
+    let old: AssetIndex = vec![
+        ("a".to_string(), md("a.1")),
+        ("b".to_string(), md("b.1")),
+        ("c".to_string(), md("c.1")),
+    ]
+    .into_iter()
+    .collect();
+    let new: AssetIndex = vec![
+        ("a".to_string(), md("a.1")),
+        ("b".to_string(), md("b.2")),
+        ("d".to_string(), md("d.1")),
+    ]
+    .into_iter()
+    .collect();
+    let diff = IndexDiff::between(&old, &new);
+    assert_eq!(diff.added, vec!["d"]);
+    assert_eq!(diff.changed, vec!["b"]);
+    assert_eq!(diff.removed, vec!["c"]);
+    assert!(IndexDiff::between(&new, &new).is_empty());
+}
@@ -0,0 +1,469 @@
+//! Security, CORS, and caching headers added to responses by `handle_request`.
+
+use crate::{glob::glob_match, Error};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Index path of the entry holding the header rules applied by `handle_request`
+/// (see `SyncConfig::response_header_rules`)
+pub(crate) const HEADERS_PATH: &str = "\0headers";
+
+/// Value of the `X-Frame-Options` header
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameOptions {
+    /// Page can't be displayed in a frame
+    Deny,
+    /// Page can only be displayed in a frame on the same origin
+    SameOrigin,
+}
+
+/// Security headers for responses (see `KVAssets::with_header_policy`).
+/// Start with `HeaderPolicy::recommended()`, or `default()` (no headers),
+/// and adjust with the `with_*` methods.
+/// Headers set for an asset at sync time (`AssetMetadata.headers`) take precedence.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HeaderPolicy {
+    /// `Strict-Transport-Security` max-age in seconds
+    pub hsts_max_age: Option<u64>,
+    /// Add `includeSubDomains` to `Strict-Transport-Security`
+    pub hsts_include_subdomains: bool,
+    /// Add `preload` to `Strict-Transport-Security`
+    pub hsts_preload: bool,
+    /// Send `X-Content-Type-Options: nosniff`
+    pub nosniff: bool,
+    /// `X-Frame-Options`
+    pub frame_options: Option<FrameOptions>,
+    /// `Content-Security-Policy`
+    pub content_security_policy: Option<String>,
+    /// `Referrer-Policy`
+    pub referrer_policy: Option<String>,
+}
+
+impl HeaderPolicy {
+    /// HSTS for one year including subdomains, nosniff, `X-Frame-Options: DENY`,
+    /// and `Referrer-Policy: strict-origin-when-cross-origin`. No CSP, since it depends
+    /// on the site; add one with `with_csp`.
+    pub fn recommended() -> Self {
+        Self {
+            hsts_max_age: Some(365 * 24 * 3600),
+            hsts_include_subdomains: true,
+            hsts_preload: false,
+            nosniff: true,
+            frame_options: Some(FrameOptions::Deny),
+            content_security_policy: None,
+            referrer_policy: Some("strict-origin-when-cross-origin".to_string()),
+        }
+    }
+
+    /// Sets `Strict-Transport-Security`
+    pub fn with_hsts(mut self, max_age: u64, include_subdomains: bool) -> Self {
+        self.hsts_max_age = Some(max_age);
+        self.hsts_include_subdomains = include_subdomains;
+        self
+    }
+
+    /// Sets `X-Frame-Options`
+    pub fn with_frame_options(mut self, frame_options: FrameOptions) -> Self {
+        self.frame_options = Some(frame_options);
+        self
+    }
+
+    /// Sets `Content-Security-Policy`, e.g. `default-src 'self'`
+    pub fn with_csp<T: ToString>(mut self, policy: T) -> Self {
+        self.content_security_policy = Some(policy.to_string());
+        self
+    }
+
+    /// Sets `Referrer-Policy`
+    pub fn with_referrer_policy<T: ToString>(mut self, policy: T) -> Self {
+        self.referrer_policy = Some(policy.to_string());
+        self
+    }
+
+    /// Returns the headers for the policy
+    pub fn headers(&self) -> Vec<(String, String)> {
+        let mut headers = Vec::new();
+        if let Some(max_age) = self.hsts_max_age {
+            let mut value = format!("max-age={}", max_age);
+            if self.hsts_include_subdomains {
+                value.push_str("; includeSubDomains");
+            }
+            if self.hsts_preload {
+                value.push_str("; preload");
+            }
+            headers.push(("Strict-Transport-Security".to_string(), value));
+        }
+        if self.nosniff {
+            headers.push(("X-Content-Type-Options".to_string(), "nosniff".to_string()));
+        }
+        if let Some(frame_options) = self.frame_options {
+            let value = match frame_options {
+                FrameOptions::Deny => "DENY",
+                FrameOptions::SameOrigin => "SAMEORIGIN",
+            };
+            headers.push(("X-Frame-Options".to_string(), value.to_string()));
+        }
+        if let Some(csp) = &self.content_security_policy {
+            headers.push(("Content-Security-Policy".to_string(), csp.clone()));
+        }
+        if let Some(referrer_policy) = &self.referrer_policy {
+            headers.push(("Referrer-Policy".to_string(), referrer_policy.clone()));
+        }
+        headers
+    }
+}
+
+/// Cross-origin resource sharing (see `KVAssets::with_cors`), so fonts, json, and other
+/// assets can be used by pages on other origins
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CorsPolicy {
+    /// Origins allowed to read assets, e.g. `https://example.com`, or `*` for any origin
+    pub allowed_origins: Vec<String>,
+    /// Methods allowed in preflight responses. default: GET, HEAD, OPTIONS
+    pub allowed_methods: Vec<String>,
+    /// Request headers allowed in preflight responses. default: none
+    pub allowed_headers: Vec<String>,
+    /// How long (seconds) browsers may cache a preflight response. default: None
+    pub max_age: Option<u64>,
+    /// Glob patterns of the asset paths that get CORS headers (see `glob.rs`),
+    /// e.g. `*.woff2`, or empty for all paths. default: empty
+    pub paths: Vec<String>,
+}
+
+impl CorsPolicy {
+    /// Allows the origins, e.g. `["https://example.com"]`
+    pub fn new<T: ToString>(origins: &[T]) -> Self {
+        Self {
+            allowed_origins: origins.iter().map(|o| o.to_string()).collect(),
+            allowed_methods: ["GET", "HEAD", "OPTIONS"]
+                .iter()
+                .map(|m| m.to_string())
+                .collect(),
+            allowed_headers: Vec::new(),
+            max_age: None,
+            paths: Vec::new(),
+        }
+    }
+
+    /// Allows any origin (`Access-Control-Allow-Origin: *`)
+    pub fn any_origin() -> Self {
+        Self::new(&["*"])
+    }
+
+    /// Sets the methods allowed in preflight responses
+    pub fn with_methods<T: ToString>(mut self, methods: &[T]) -> Self {
+        self.allowed_methods = methods.iter().map(|m| m.to_string()).collect();
+        self
+    }
+
+    /// Sets the request headers allowed in preflight responses
+    pub fn with_headers<T: ToString>(mut self, headers: &[T]) -> Self {
+        self.allowed_headers = headers.iter().map(|h| h.to_string()).collect();
+        self
+    }
+
+    /// Sets how long browsers may cache preflight responses
+    pub fn with_max_age(mut self, seconds: u64) -> Self {
+        self.max_age = Some(seconds);
+        self
+    }
+
+    /// Limits CORS headers to assets matching one of the glob patterns,
+    /// e.g. `["*.woff2", "*.json", "*.wasm"]`
+    pub fn with_paths<T: ToString>(mut self, patterns: &[T]) -> Self {
+        self.paths = patterns.iter().map(|p| p.to_string()).collect();
+        self
+    }
+
+    /// Returns true if responses for the asset path (without leading '/') get CORS headers
+    pub fn applies_to(&self, path: &str) -> bool {
+        self.paths.is_empty() || self.paths.iter().any(|p| glob_match(p, path))
+    }
+
+    /// Returns the `Access-Control-Allow-Origin` value for the request's origin,
+    /// or None if the origin isn't allowed
+    pub fn allow_origin(&self, origin: &str) -> Option<&str> {
+        if self.allowed_origins.iter().any(|o| o == "*") {
+            Some("*")
+        } else {
+            self.allowed_origins
+                .iter()
+                .find(|o| o.eq_ignore_ascii_case(origin))
+                .map(|o| o.as_str())
+        }
+    }
+
+    /// Returns the CORS headers for a response to a request from `origin`.
+    /// For a preflight request, also includes the allowed methods, headers, and max age.
+    pub fn headers(&self, origin: &str, preflight: bool) -> Vec<(String, String)> {
+        let allowed = match self.allow_origin(origin) {
+            Some(allowed) => allowed,
+            None => return Vec::new(),
+        };
+        let mut headers = vec![(
+            "Access-Control-Allow-Origin".to_string(),
+            allowed.to_string(),
+        )];
+        if allowed != "*" {
+            headers.push(("Vary".to_string(), "Origin".to_string()));
+        }
+        if preflight {
+            headers.push((
+                "Access-Control-Allow-Methods".to_string(),
+                self.allowed_methods.join(", "),
+            ));
+            if !self.allowed_headers.is_empty() {
+                headers.push((
+                    "Access-Control-Allow-Headers".to_string(),
+                    self.allowed_headers.join(", "),
+                ));
+            }
+            if let Some(max_age) = self.max_age {
+                headers.push(("Access-Control-Max-Age".to_string(), max_age.to_string()));
+            }
+        }
+        headers
+    }
+}
+
+/// Response headers for assets matching a glob pattern (see `SyncConfig::header_rules`
+/// and `SyncConfig::response_header_rules`).
+/// Stored in the index, so it can't gain fields (see `AssetMetadata`).
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct HeaderRule {
+    /// Glob pattern for asset paths, e.g. `downloads/**` or `*.pdf`.
+    /// `*` doesn't match '/', and patterns without '/' match file names in any directory.
+    pub pattern: String,
+    /// Header names and values
+    pub headers: BTreeMap<String, String>,
+}
+
+impl HeaderRule {
+    /// Returns the glob pattern for a path in a Netlify `_headers` file, in which `*`
+    /// matches the rest of the path and `:name` matches one segment,
+    /// e.g. `/docs/*` -> `/docs/**`
+    pub fn path_glob(path: &str) -> String {
+        let glob: Vec<String> = path
+            .split('/')
+            .map(|segment| match segment {
+                "*" => "**".to_string(),
+                s if s.starts_with(':') => "*".to_string(),
+                s => s.to_string(),
+            })
+            .collect();
+        glob.join("/")
+    }
+}
+
+/// Parses rules in the format of a Netlify `_headers` file: a path, e.g. `/docs/*`,
+/// followed by indented `Name: value` lines. Blank lines and lines starting with '#'
+/// are ignored. Values of a repeated header are joined with ", ".
+pub fn parse_headers(text: &str) -> Result<Vec<HeaderRule>, Error> {
+    let mut rules: Vec<HeaderRule> = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let invalid = || Error::Message(format!("invalid header on line {}: {}", n + 1, trimmed));
+        if !line.starts_with([' ', '\t']) {
+            if !trimmed.starts_with('/') {
+                return Err(invalid());
+            }
+            rules.push(HeaderRule {
+                pattern: HeaderRule::path_glob(trimmed),
+                headers: BTreeMap::new(),
+            });
+            continue;
+        }
+        let rule = rules.last_mut().ok_or_else(invalid)?;
+        let (name, value) = trimmed.split_once(':').ok_or_else(invalid)?;
+        let (name, value) = (name.trim().to_string(), value.trim());
+        rule.headers
+            .entry(name)
+            .and_modify(|v| {
+                v.push_str(", ");
+                v.push_str(value)
+            })
+            .or_insert_with(|| value.to_string());
+    }
+    Ok(rules)
+}
+
+/// Returns the headers from all rules matching the asset path. If several rules
+/// set a header, the last one wins.
+pub(crate) fn headers_for(rules: &[HeaderRule], path: &str) -> Vec<(String, String)> {
+    let mut headers: Vec<(String, String)> = Vec::new();
+    for rule in rules.iter().filter(|r| glob_match(&r.pattern, path)) {
+        for (name, value) in rule.headers.iter() {
+            headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+            headers.push((name.clone(), value.clone()));
+        }
+    }
+    headers
+}
+
+/// `Cache-Control` values for assets matching glob patterns (see `KVAssets::with_cache_policy`
+/// and `SyncConfig::cache_policy`), e.g. `*.css` -> `max-age=31536000, immutable`.
+/// A rule applies if its pattern matches the requested path or the asset's KV key,
+/// which has the same directory and extension. If several rules match, the last one wins,
+/// so general rules go first. A `Cache-Control` header set for the asset at sync time
+/// takes precedence.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CachePolicy {
+    /// Glob patterns and `Cache-Control` values
+    pub rules: Vec<(String, String)>,
+}
+
+impl CachePolicy {
+    /// Creates a policy with no rules
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a rule, e.g. `with_rule("*.html", "no-cache")`
+    pub fn with_rule<P: ToString, V: ToString>(mut self, pattern: P, value: V) -> Self {
+        self.rules.push((pattern.to_string(), value.to_string()));
+        self
+    }
+
+    /// Returns the `Cache-Control` value of the last rule matching any of the paths
+    /// (without leading '/')
+    pub fn cache_control(&self, paths: &[&str]) -> Option<&str> {
+        self.rules
+            .iter()
+            .rev()
+            .find(|(pattern, _)| paths.iter().any(|path| glob_match(pattern, path)))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+#[test]
+fn test_headers_for() {
+    let rule = |pattern: &str, headers: &[(&str, &str)]| HeaderRule {
+        pattern: pattern.to_string(),
+        headers: headers
+            .iter()
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+            .collect(),
+    };
+    let rules = vec![
+        rule("downloads/**", &[("Content-Disposition", "attachment")]),
+        rule("*.pdf", &[("X-Robots-Tag", "noindex")]),
+        rule("downloads/public/*", &[("content-disposition", "inline")]),
+    ];
+    assert_eq!(
+        headers_for(&rules, "downloads/a.zip"),
+        vec![("Content-Disposition".to_string(), "attachment".to_string())]
+    );
+    assert_eq!(
+        headers_for(&rules, "downloads/public/b.pdf"),
+        vec![
+            ("X-Robots-Tag".to_string(), "noindex".to_string()),
+            ("content-disposition".to_string(), "inline".to_string())
+        ]
+    );
+    assert!(headers_for(&rules, "index.html").is_empty());
+}
+
+#[test]
+fn test_parse_headers() {
+    let rules = parse_headers(
+        "# comment\n/*\n  X-Frame-Options: DENY\n\n/docs/:version/*\n  Link: </a.css>\n  Link: </b.js>\n",
+    )
+    .unwrap();
+    assert_eq!(rules.len(), 2);
+    assert_eq!(rules[0].pattern, "/**");
+    assert_eq!(rules[1].pattern, "/docs/*/**");
+    assert_eq!(rules[1].headers["Link"], "</a.css>, </b.js>");
+    assert_eq!(
+        headers_for(&rules, "docs/v1/index.html"),
+        vec![
+            ("X-Frame-Options".to_string(), "DENY".to_string()),
+            ("Link".to_string(), "</a.css>, </b.js>".to_string())
+        ]
+    );
+    assert!(parse_headers("  X-Frame-Options: DENY").is_err());
+    assert!(parse_headers("/a\n  X-Frame-Options").is_err());
+    assert!(parse_headers("a\n").is_err());
+}
+
+#[test]
+fn test_cache_policy() {
+    let policy = CachePolicy::new()
+        .with_rule("**", "max-age=300")
+        .with_rule("*.css", "max-age=31536000, immutable")
+        .with_rule("*.html", "no-cache");
+    assert_eq!(
+        policy.cache_control(&["css/site.css"]),
+        Some("max-age=31536000, immutable")
+    );
+    assert_eq!(
+        policy.cache_control(&["about", "index.abc123.html"]),
+        Some("no-cache")
+    );
+    assert_eq!(policy.cache_control(&["data.json"]), Some("max-age=300"));
+    assert_eq!(CachePolicy::new().cache_control(&["a.css"]), None);
+}
+
+#[test]
+fn test_header_policy() {
+    assert!(HeaderPolicy::default().headers().is_empty());
+    let policy = HeaderPolicy::recommended()
+        .with_frame_options(FrameOptions::SameOrigin)
+        .with_csp("default-src 'self'");
+    let headers = policy.headers();
+    let get = |name: &str| {
+        headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    };
+    assert_eq!(
+        get("Strict-Transport-Security"),
+        Some("max-age=31536000; includeSubDomains")
+    );
+    assert_eq!(get("X-Content-Type-Options"), Some("nosniff"));
+    assert_eq!(get("X-Frame-Options"), Some("SAMEORIGIN"));
+    assert_eq!(get("Content-Security-Policy"), Some("default-src 'self'"));
+    assert_eq!(
+        get("Referrer-Policy"),
+        Some("strict-origin-when-cross-origin")
+    );
+}
+
+#[test]
+fn test_cors_policy() {
+    let cors = CorsPolicy::new(&["https://example.com"]).with_max_age(600);
+    assert_eq!(
+        cors.headers("https://example.com", false),
+        vec![
+            (
+                "Access-Control-Allow-Origin".to_string(),
+                "https://example.com".to_string()
+            ),
+            ("Vary".to_string(), "Origin".to_string()),
+        ]
+    );
+    assert!(cors.headers("https://evil.com", true).is_empty());
+    let preflight = cors.headers("https://example.com", true);
+    assert!(preflight.contains(&(
+        "Access-Control-Allow-Methods".to_string(),
+        "GET, HEAD, OPTIONS".to_string()
+    )));
+    assert!(preflight.contains(&("Access-Control-Max-Age".to_string(), "600".to_string())));
+
+    assert!(cors.applies_to("fonts/a.woff2"));
+    let fonts = cors.with_paths(&["*.woff2", "/api/*.json"]);
+    assert!(fonts.applies_to("fonts/a.woff2"));
+    assert!(fonts.applies_to("api/v1.json"));
+    assert!(!fonts.applies_to("index.html"));
+    assert!(!fonts.applies_to("docs/api/v1.json"));
+
+    let any = CorsPolicy::any_origin().with_headers(&["Range"]);
+    assert_eq!(any.allow_origin("https://a.org"), Some("*"));
+    assert!(any.headers("https://a.org", true).contains(&(
+        "Access-Control-Allow-Headers".to_string(),
+        "Range".to_string()
+    )));
+}
@@ -0,0 +1,119 @@
+#![cfg(not(target_arch = "wasm32"))]
+//! Persistent queue of uploads and deletes that still failed after retries.
+//!
+//! When `SyncConfig::retry_queue` is set, a sync saves its failed operations to the queue
+//! file, including the values to upload, so `retry_queued` (`kv-sync retry`) can finish
+//! them later, after a transient Cloudflare incident, without re-running the sync
+//! or keeping the asset files around.
+
+use crate::{plan::PlanDestination, Error};
+use cloudflare::endpoints::workerskv::write_bulk::KeyValuePair;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Format version of queue files
+const QUEUE_FORMAT_VERSION: u32 = 1;
+
+/// Operations waiting to be retried, for one destination
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct RetryQueue {
+    version: u32,
+    pub(crate) destination: PlanDestination,
+    /// Pairs to upload, with base64-encoded values
+    pub(crate) uploads: Vec<KeyValuePair>,
+    /// Keys to delete
+    pub(crate) deletes: Vec<String>,
+}
+
+impl RetryQueue {
+    pub(crate) fn new(destination: PlanDestination) -> Self {
+        Self {
+            version: QUEUE_FORMAT_VERSION,
+            destination,
+            uploads: Vec::new(),
+            deletes: Vec::new(),
+        }
+    }
+
+    /// Reads the queue file. Returns None if it doesn't exist.
+    pub(crate) fn load(path: &Path) -> Result<Option<Self>, Error> {
+        let json = match std::fs::read(path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(Error::IO(format!("reading {}: {}", path.display(), e))),
+        };
+        let queue: RetryQueue = serde_json::from_slice(&json)
+            .map_err(|e| Error::Message(format!("invalid queue {}: {}", path.display(), e)))?;
+        if queue.version > QUEUE_FORMAT_VERSION {
+            return Err(Error::Message(format!(
+                "queue {} has unsupported version {}",
+                path.display(),
+                queue.version
+            )));
+        }
+        Ok(Some(queue))
+    }
+
+    /// Writes the queue file, or removes it if the queue is empty
+    pub(crate) fn save(&self, path: &Path) -> Result<(), Error> {
+        if self.is_empty() {
+            return match std::fs::remove_file(path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    Err(Error::IO(format!("removing {}: {}", path.display(), e)))
+                }
+                _ => Ok(()),
+            };
+        }
+        let json = serde_json::to_vec_pretty(self)
+            .map_err(|e| Error::Message(format!("json serialization error: {}", e)))?;
+        std::fs::write(path, json)
+            .map_err(|e| Error::IO(format!("writing {}: {}", path.display(), e)))
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.uploads.is_empty() && self.deletes.is_empty()
+    }
+
+    /// Adds operations. A queued upload or delete of the same key is replaced,
+    /// so the most recent operation for each key wins.
+    pub(crate) fn push(&mut self, uploads: Vec<KeyValuePair>, deletes: Vec<String>) {
+        for pair in uploads.into_iter() {
+            self.deletes.retain(|k| k != &pair.key);
+            self.uploads.retain(|p| p.key != pair.key);
+            self.uploads.push(pair);
+        }
+        for key in deletes.into_iter() {
+            self.uploads.retain(|p| p.key != key);
+            if !self.deletes.contains(&key) {
+                self.deletes.push(key);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_retry_queue() {
+    let pair = |key: &str, value: &str| KeyValuePair {
+        key: key.to_string(),
+        value: value.to_string(),
+        expiration: None,
+        expiration_ttl: None,
+        base64: Some(true),
+    };
+    let path = std::env::temp_dir().join(format!("kv-assets-queue-{}.json", std::process::id()));
+    assert!(RetryQueue::load(&path).unwrap().is_none());
+
+    let mut queue = RetryQueue::new(PlanDestination::Local { dir: "kv".into() });
+    queue.push(vec![pair("a", "MQ=="), pair("b", "Mg==")], vec!["c".into()]);
+    queue.push(vec![pair("c", "Mw==")], vec!["a".into(), "d".into()]);
+    queue.save(&path).unwrap();
+
+    let queue = RetryQueue::load(&path).unwrap().unwrap();
+    let keys: Vec<&str> = queue.uploads.iter().map(|p| p.key.as_str()).collect();
+    assert_eq!(keys, vec!["b", "c"]);
+    assert_eq!(queue.deletes, vec!["a".to_string(), "d".to_string()]);
+
+    // an empty queue removes the file
+    RetryQueue::new(queue.destination).save(&path).unwrap();
+    assert!(!path.exists());
+}
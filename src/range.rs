@@ -0,0 +1,225 @@
+//! Byte ranges of assets, for `Range` requests (e.g. seeking in video or audio).
+//!
+//! KV can't read part of a value, so the asset is read and the range is sliced from it.
+//! For chunked assets (see `chunk.rs`), only the chunks that overlap the range are read.
+
+use crate::{AssetStore, Error, KVAssets, ServerTiming, ToAssetKey};
+
+/// Part of an asset (see `KVAssets::get_asset_range`)
+#[derive(Clone, Debug, PartialEq)]
+pub struct AssetRange {
+    /// Bytes `start` through `end` of the asset
+    pub body: bytes::Bytes,
+    /// Offset of the first byte
+    pub start: u64,
+    /// Offset of the last byte (inclusive, as in `Content-Range`)
+    pub end: u64,
+    /// Size of the whole asset
+    pub total: u64,
+}
+
+impl AssetRange {
+    /// Returns the value for the `Content-Range` response header
+    pub fn content_range(&self) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, self.total)
+    }
+}
+
+/// Range from a `Range` request header
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ByteRange {
+    /// `bytes=start-` or `bytes=start-end`
+    From(u64, Option<u64>),
+    /// `bytes=-len`: the last `len` bytes
+    Suffix(u64),
+}
+
+impl ByteRange {
+    /// Parses a `Range` header with a single byte range. Returns None for other units,
+    /// multiple ranges, or invalid values, which should be answered with the whole asset.
+    pub(crate) fn parse(value: &str) -> Option<Self> {
+        let spec = value.trim().strip_prefix("bytes=")?;
+        if spec.contains(',') {
+            return None;
+        }
+        let (start, end) = spec.split_once('-')?;
+        let (start, end) = (start.trim(), end.trim());
+        if start.is_empty() {
+            return end.parse().ok().map(ByteRange::Suffix);
+        }
+        let start = start.parse().ok()?;
+        if end.is_empty() {
+            return Some(ByteRange::From(start, None));
+        }
+        let end = end.parse().ok()?;
+        if end < start {
+            return None;
+        }
+        Some(ByteRange::From(start, Some(end)))
+    }
+
+    /// Returns the first and last offsets of the range within an asset of `total` bytes,
+    /// or None if the range isn't satisfiable
+    pub(crate) fn resolve(&self, total: u64) -> Option<(u64, u64)> {
+        let (start, end) = match *self {
+            ByteRange::From(start, end) => (start, end.unwrap_or(u64::MAX)),
+            ByteRange::Suffix(0) => return None,
+            ByteRange::Suffix(len) => (total.saturating_sub(len), u64::MAX),
+        };
+        if start >= total || end < start {
+            return None;
+        }
+        Some((start, end.min(total - 1)))
+    }
+}
+
+impl<'ah, S: AssetStore> KVAssets<'ah, S> {
+    /// Gets bytes `start` through `end` (inclusive, or to the end of the asset if None)
+    /// of the asset. Returns Ok(None) if the asset is not in the index, or
+    /// `Error::RangeNotSatisfiable` with the asset's size if `start` is past its end.
+    /// For chunked assets, only the chunks that overlap the range are read, and they
+    /// aren't checked with `with_integrity_check`, which needs the whole asset.
+    pub async fn get_asset_range<K: ToAssetKey + ?Sized>(
+        &self,
+        key: &K,
+        start: u64,
+        end: Option<u64>,
+    ) -> Result<Option<AssetRange>, Error> {
+        let range = ByteRange::From(start, end);
+        // check with the size from the index, so an unsatisfiable range doesn't read KV
+        let md = match self.lookup_key(key)? {
+            Some(md) => md,
+            None => return Ok(None),
+        };
+        let (first, last) = range
+            .resolve(md.size)
+            .ok_or(Error::RangeNotSatisfiable(md.size))?;
+        if md.chunks > 0 && md.inline.is_none() && !md.protected {
+            let result = self.read_chunk_range(&md, first, last).await;
+            self.run_hooks(key.key_str(), result.as_ref().map(|_| Some(&md)));
+            return Ok(Some(AssetRange {
+                body: result?,
+                start: first,
+                end: last,
+                total: md.size,
+            }));
+        }
+        let body = match self
+            .fetch_asset_hooked(key.key_str(), &mut ServerTiming::new())
+            .await?
+        {
+            Some((_, body)) => body,
+            None => return Ok(None),
+        };
+        let total = body.len() as u64;
+        let (start, end) = range
+            .resolve(total)
+            .ok_or(Error::RangeNotSatisfiable(total))?;
+        Ok(Some(AssetRange {
+            body: body.slice(start as usize..=end as usize),
+            start,
+            end,
+            total,
+        }))
+    }
+}
+
+#[test]
+fn test_byte_range() {
+    assert_eq!(
+        ByteRange::parse("bytes=0-99"),
+        Some(ByteRange::From(0, Some(99)))
+    );
+    assert_eq!(
+        ByteRange::parse("bytes=100-"),
+        Some(ByteRange::From(100, None))
+    );
+    assert_eq!(ByteRange::parse("bytes=-20"), Some(ByteRange::Suffix(20)));
+    assert_eq!(ByteRange::parse("bytes=0-1,5-6"), None);
+    assert_eq!(ByteRange::parse("bytes=9-1"), None);
+    assert_eq!(ByteRange::parse("items=0-1"), None);
+
+    assert_eq!(ByteRange::From(0, Some(99)).resolve(50), Some((0, 49)));
+    assert_eq!(ByteRange::From(50, None).resolve(50), None);
+    assert_eq!(ByteRange::Suffix(20).resolve(50), Some((30, 49)));
+    assert_eq!(ByteRange::Suffix(80).resolve(50), Some((0, 49)));
+    assert_eq!(ByteRange::Suffix(0).resolve(50), None);
+}
+
+/// Tests that ranges of chunked assets only read the chunks they need
+/// (does not invoke cloudflare api)
+#[cfg(all(test, not(target_arch = "wasm32")))]
+#[tokio::test]
+async fn test_chunk_range() {
+    use crate::{chunk_key, AssetIndex, AssetMetadata, CHUNK_SIZE};
+    use std::sync::{Arc, Mutex};
+
+    // chunk n is filled with byte n
+    struct Chunks {
+        size: u64,
+        reads: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl AssetStore for Chunks {
+        async fn get_value(&self, key: &str) -> Result<bytes::Bytes, Error> {
+            self.reads.lock().unwrap().push(key.to_string());
+            let n = (0..3)
+                .find(|n| chunk_key("big.bin", *n) == key)
+                .ok_or_else(|| Error::KVKeyNotFound(key.to_string(), 404))?;
+            let len = CHUNK_SIZE.min(self.size - n as u64 * CHUNK_SIZE);
+            Ok(vec![n as u8; len as usize].into())
+        }
+
+        async fn put_value(&self, _: &str, _: bytes::Bytes, _: Option<u64>) -> Result<(), Error> {
+            Err(Error::Message("read-only test store".into()))
+        }
+
+        async fn delete_value(&self, _: &str) -> Result<(), Error> {
+            Err(Error::Message("read-only test store".into()))
+        }
+    }
+
+    let size = 2 * CHUNK_SIZE + 10;
+    let mut index = AssetIndex::new();
+    index.insert(
+        "big.bin".to_string(),
+        AssetMetadata {
+            path: "big.bin".to_string(),
+            size,
+            chunks: 3,
+            ..Default::default()
+        },
+    );
+    let blob = crate::encode_index(&index).expect("serialize-index");
+    let reads = Arc::new(Mutex::new(Vec::new()));
+    let store = Chunks {
+        size,
+        reads: reads.clone(),
+    };
+    let kv = KVAssets::with_store(&blob, store);
+    let take_reads = || std::mem::take(&mut *reads.lock().unwrap());
+
+    let part = kv
+        .get_asset_range("big.bin", 2 * CHUNK_SIZE + 2, None)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(part.body.as_ref(), &[2u8; 8]);
+    assert_eq!(
+        (part.start, part.end, part.total),
+        (2 * CHUNK_SIZE + 2, size - 1, size)
+    );
+    assert_eq!(take_reads(), vec![chunk_key("big.bin", 2)]);
+
+    let part = kv
+        .get_asset_range("big.bin", CHUNK_SIZE - 1, Some(CHUNK_SIZE))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(part.body.as_ref(), &[0, 1]);
+    assert_eq!(
+        take_reads(),
+        vec![chunk_key("big.bin", 0), chunk_key("big.bin", 1)]
+    );
+}
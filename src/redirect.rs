@@ -0,0 +1,316 @@
+//! Netlify-style redirect rules (see `SyncConfig::redirects` and `KVAssets::resolve`).
+//!
+//! Rules are stored in the index, on an entry at `REDIRECTS_PATH`, which can't be requested
+//! because request paths with NUL bytes are rejected, or added to the handler with
+//! `KVAssets::with_redirect_rule`, which are applied first. Paths that are assets take precedence
+//! over rules, and the first matching rule wins. A `from` path may end with `*`, which matches
+//! the rest of the path and is substituted for `:splat` in `to`, and may have `:name`
+//! segments, which match one segment and are substituted for `:name` in `to`.
+//! Rules with status 200 are rewrites: the asset at `to` is served.
+
+use crate::{AssetMetadata, AssetStore, Error, KVAssets, ToAssetKey};
+use serde::{Deserialize, Serialize};
+
+/// Index path of the entry holding the redirect rules
+pub(crate) const REDIRECTS_PATH: &str = "\0redirects";
+
+/// Redirect rule, such as a line of a `_redirects` file: `/blog/* /news/:splat 301`.
+/// Stored in the index, so it can't gain fields (see `AssetMetadata`).
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct RedirectRule {
+    /// Request path, with a leading '/'
+    pub from: String,
+    /// Path or url to redirect to
+    pub to: String,
+    /// Response status: 301, 302, 303, 307, 308, or 200 to rewrite
+    #[serde(default = "default_status")]
+    pub status: u16,
+}
+
+fn default_status() -> u16 {
+    301
+}
+
+/// Redirect for a request (see `KVAssets::resolve`)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Redirect {
+    /// Value for the `Location` header
+    pub location: String,
+    /// Response status
+    pub status: u16,
+}
+
+/// Result of `KVAssets::resolve`
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Resolved {
+    /// Asset to serve, which, for rewrites, is the asset at the rule's target
+    Asset(AssetMetadata),
+    /// Redirect response
+    Redirect(Redirect),
+}
+
+/// Parses rules in the format of a Netlify `_redirects` file: one rule per line, with the
+/// `from` path, `to` path or url, and optional status (default 301), separated by whitespace.
+/// Blank lines and lines starting with '#' are ignored.
+pub fn parse_redirects(text: &str) -> Result<Vec<RedirectRule>, Error> {
+    let mut rules = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = || Error::Message(format!("invalid redirect on line {}: {}", n + 1, line));
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let status = match fields.get(2) {
+            Some(status) => status.parse().map_err(|_| invalid())?,
+            None => default_status(),
+        };
+        if fields.len() > 3 {
+            return Err(invalid());
+        }
+        let rule = RedirectRule {
+            from: fields[0].to_string(),
+            to: fields.get(1).ok_or_else(invalid)?.to_string(),
+            status,
+        };
+        rule.check().map_err(|_| invalid())?;
+        rules.push(rule);
+    }
+    Ok(rules)
+}
+
+impl RedirectRule {
+    /// Checks that `from` is a path and the status is a redirect or rewrite
+    pub(crate) fn check(&self) -> Result<(), Error> {
+        if !self.from.starts_with('/') {
+            return Err(Error::Message(format!(
+                "redirect from {} must start with '/'",
+                self.from
+            )));
+        }
+        if !matches!(self.status, 200 | 301 | 302 | 303 | 307 | 308) {
+            return Err(Error::Message(format!(
+                "redirect from {} has unsupported status {}",
+                self.from, self.status
+            )));
+        }
+        Ok(())
+    }
+
+    /// Returns the target, with placeholders replaced, if the rule matches the path
+    /// (which has a leading '/')
+    fn apply(&self, path: &str) -> Option<String> {
+        let mut params: Vec<(&str, &str)> = Vec::new();
+        let mut rest = path;
+        let mut pattern = self.from.as_str();
+        loop {
+            if pattern == "*" || pattern == "/*" {
+                params.push(("splat", rest.strip_prefix('/').unwrap_or(rest)));
+                break;
+            }
+            let (p, next_pattern) = split_segment(pattern);
+            let (s, next_rest) = split_segment(rest);
+            match p.strip_prefix(':') {
+                Some(name) if !s.is_empty() => params.push((name, s)),
+                _ if p == s => {}
+                _ => return None,
+            }
+            if next_pattern.is_empty() || (next_rest.is_empty() && next_pattern != "/*") {
+                let end = next_pattern.trim_end_matches('/') == next_rest.trim_end_matches('/');
+                if !end {
+                    return None;
+                }
+                break;
+            }
+            pattern = next_pattern;
+            rest = next_rest;
+        }
+        let mut to = self.to.clone();
+        // longer names first, so `:a` doesn't replace part of `:ab`
+        params.sort_by_key(|(name, _)| std::cmp::Reverse(name.len()));
+        for (name, value) in params {
+            to = to.replace(&format!(":{}", name), value);
+        }
+        Some(to)
+    }
+}
+
+/// Splits the first segment, with its leading '/', from the rest of the path
+fn split_segment(path: &str) -> (&str, &str) {
+    let path = path.strip_prefix('/').unwrap_or(path);
+    match path.find('/') {
+        Some(pos) => (&path[..pos], &path[pos..]),
+        None => (path, ""),
+    }
+}
+
+impl<'ah, S: AssetStore> KVAssets<'ah, S> {
+    /// Resolves the request path to an asset or a redirect, using the redirect rules in the
+    /// index (see `SyncConfig::redirects`), and the trailing slash policy (see
+    /// `with_trailing_slash`). Assets at the path take precedence over rules;
+    /// otherwise the first matching rule is used, and if none match, the path is looked up
+    /// with `lookup_key`. Returns Ok(None) if the path isn't found.
+    pub fn resolve<K: ToAssetKey + ?Sized>(&self, path: &K) -> Result<Option<Resolved>, Error> {
+        match self.redirect_for(path.key_str())? {
+            Some(redirect) if redirect.status == 200 => {
+                Ok(self.lookup_key(&redirect.location)?.map(Resolved::Asset))
+            }
+            Some(redirect) => Ok(Some(Resolved::Redirect(redirect))),
+            None => Ok(self.lookup_key(path)?.map(Resolved::Asset)),
+        }
+    }
+
+    /// Returns the redirect to the canonical form of the path (see `with_trailing_slash`),
+    /// or the redirect or rewrite for the path from the first matching rule,
+    /// unless the path is an asset
+    pub(crate) fn redirect_for(&self, path: &str) -> Result<Option<Redirect>, Error> {
+        if let Some(location) = self.canonical_path(path)? {
+            return Ok(Some(Redirect {
+                location,
+                status: 301,
+            }));
+        }
+        let rules = self.redirect_rules()?;
+        if rules.is_empty() {
+            return Ok(None);
+        }
+        let path = self.lookup_options.normalize(path)?;
+        let path = format!("/{}", path.strip_prefix('/').unwrap_or(&path));
+        if let Ok(Some(_)) = self.lookup_chain(&[path.as_str()]) {
+            return Ok(None);
+        }
+        Ok(rules.iter().find_map(|rule| {
+            rule.apply(&path).map(|location| Redirect {
+                location,
+                status: rule.status,
+            })
+        }))
+    }
+}
+
+#[test]
+fn test_parse_redirects() {
+    let rules = parse_redirects(
+        "# comment\n\n/old   /new\n/blog/* /news/:splat 302\n/u/:id https://example.com/:id 308\n",
+    )
+    .unwrap();
+    assert_eq!(rules.len(), 3);
+    assert_eq!(rules[0].status, 301);
+    assert_eq!(rules[1].to, "/news/:splat");
+    assert!(parse_redirects("/a").is_err());
+    assert!(parse_redirects("/a /b 404").is_err());
+    assert!(parse_redirects("a /b").is_err());
+
+    assert_eq!(rules[0].apply("/old").as_deref(), Some("/new"));
+    assert_eq!(rules[0].apply("/old/").as_deref(), Some("/new"));
+    assert_eq!(rules[0].apply("/older"), None);
+    assert_eq!(
+        rules[1].apply("/blog/2021/a.html").as_deref(),
+        Some("/news/2021/a.html")
+    );
+    assert_eq!(rules[1].apply("/blog").as_deref(), Some("/news/"));
+    assert_eq!(
+        rules[2].apply("/u/42").as_deref(),
+        Some("https://example.com/42")
+    );
+    assert_eq!(rules[2].apply("/u/42/x"), None);
+    assert_eq!(rules[2].apply("/u/"), None);
+}
+
+#[test]
+fn test_resolve() {
+    use crate::AssetIndex;
+
+    let md = |path: &str| AssetMetadata {
+        path: path.to_string(),
+        ..Default::default()
+    };
+    let mut index = AssetIndex::new();
+    index.insert("new.html".to_string(), md("new.1.html"));
+    index.insert("blog/kept.html".to_string(), md("kept.1.html"));
+    index.insert(
+        REDIRECTS_PATH.to_string(),
+        AssetMetadata {
+            redirects: parse_redirects("/blog/* /news/:splat\n/old /new.html 200").unwrap(),
+            ..Default::default()
+        },
+    );
+    let blob = crate::encode_index(&index).expect("serialize-index");
+
+    let kv = KVAssets::init(&blob, "123", "namespace", "token");
+    assert_eq!(
+        kv.resolve("/blog/a.html").unwrap(),
+        Some(Resolved::Redirect(Redirect {
+            location: "/news/a.html".to_string(),
+            status: 301
+        }))
+    );
+    assert_eq!(
+        kv.resolve("/blog/kept.html").unwrap(),
+        Some(Resolved::Asset(md("kept.1.html")))
+    );
+    assert_eq!(
+        kv.resolve("/old").unwrap(),
+        Some(Resolved::Asset(md("new.1.html")))
+    );
+    assert_eq!(kv.resolve("/other").unwrap(), None);
+    assert!(kv
+        .list_keys("")
+        .unwrap()
+        .iter()
+        .all(|(p, _)| p != REDIRECTS_PATH));
+}
+
+/// Tests redirects from handle_request (does not invoke cloudflare api)
+#[cfg(all(test, not(target_arch = "wasm32")))]
+#[tokio::test]
+async fn test_redirect_response() {
+    use crate::AssetIndex;
+
+    let mut index = AssetIndex::new();
+    index.insert(
+        "app.html".to_string(),
+        AssetMetadata {
+            path: "app.1.html".to_string(),
+            inline: Some(b"app".to_vec()),
+            ..Default::default()
+        },
+    );
+    index.insert(
+        REDIRECTS_PATH.to_string(),
+        AssetMetadata {
+            redirects: parse_redirects(
+                "/docs/* https://docs.example.com/:splat 302\n/app/* /app.html 200",
+            )
+            .unwrap(),
+            ..Default::default()
+        },
+    );
+    let blob = crate::encode_index(&index).expect("serialize-index");
+
+    let kv = KVAssets::init(&blob, "123", "namespace", "token");
+    let resp = kv.handle_request("GET", "/docs/a/b", &[]).await.unwrap();
+    assert_eq!(resp.status, 302);
+    assert_eq!(
+        resp.header("location"),
+        Some("https://docs.example.com/a/b")
+    );
+    let resp = kv
+        .handle_request("GET", "/app/settings", &[])
+        .await
+        .unwrap();
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.body.as_ref(), b"app");
+
+    // rules added to the handler are applied before those in the index
+    let kv = kv
+        .with_rewrite("/docs/:page", "/app.html")
+        .with_rewrite("/blog/:post", "/:post.html");
+    let resp = kv.handle_request("GET", "/docs/a", &[]).await.unwrap();
+    assert_eq!((resp.status, resp.body.as_ref()), (200, b"app".as_ref()));
+    let resp = kv.handle_request("GET", "/docs/a/b", &[]).await.unwrap();
+    assert_eq!(resp.status, 302);
+    let resp = kv.handle_request("GET", "/blog/app", &[]).await.unwrap();
+    assert_eq!((resp.status, resp.body.as_ref()), (200, b"app".as_ref()));
+}
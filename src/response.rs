@@ -0,0 +1,683 @@
+//! Request handler that answers http requests for assets, independent of the worker framework.
+
+use crate::{
+    clock, etag_matches, policy::headers_for, range::ByteRange, AssetMetadata, AssetStore, Error,
+    KVAssets, ListingFormat, ServerTiming,
+};
+
+/// Methods answered by `handle_request`, for the `Allow` header
+pub const ALLOWED_METHODS: &str = "GET, HEAD, OPTIONS";
+
+/// Http response for an asset request
+#[derive(Clone, Debug, PartialEq)]
+pub struct AssetResponse {
+    /// Http status code
+    pub status: u16,
+    /// Response headers
+    pub headers: Vec<(String, String)>,
+    /// Response body. Empty for HEAD and OPTIONS requests, and errors.
+    pub body: bytes::Bytes,
+    /// Variant of the asset chosen for the request's `Accept-Encoding` and
+    /// `Accept-Language` headers
+    pub variant: Variant,
+}
+
+/// Variant of an asset chosen by `handle_request`, for caching responses: a cache that
+/// ignores the `Vary` header, such as the Cloudflare Cache API (which only honors
+/// `Accept-Encoding`), should store responses under `cache_key`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Variant {
+    /// Content-Encoding of the body, if it's compressed
+    pub encoding: Option<String>,
+    /// Language tag of the language variant, if the asset has language variants
+    pub language: Option<String>,
+    /// Request headers the response depends on, as in its `Vary` header
+    pub vary: Vec<String>,
+}
+
+impl Variant {
+    /// Returns the url with the variant added as a query parameter, if the response
+    /// depends on the encoding or language, for the key of a cached response
+    pub fn cache_key(&self, url: &str) -> String {
+        let mut parts = Vec::new();
+        if let Some(language) = &self.language {
+            parts.push(language.as_str());
+        }
+        if let Some(encoding) = &self.encoding {
+            parts.push(encoding.as_str());
+        }
+        if parts.is_empty() {
+            return url.to_string();
+        }
+        let separator = if url.contains('?') { '&' } else { '?' };
+        format!("{}{}variant={}", url, separator, parts.join("."))
+    }
+}
+
+impl AssetResponse {
+    /// Creates a response with no headers or body
+    pub fn new(status: u16) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: bytes::Bytes::new(),
+            variant: Variant::default(),
+        }
+    }
+
+    /// Adds a header
+    pub fn with_header<N: ToString, V: ToString>(mut self, name: N, value: V) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Returns the value of the first header with the name (case-insensitive)
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Returns the value of the first request header with the name (case-insensitive)
+pub(crate) fn request_header<'r>(headers: &[(&str, &'r str)], name: &str) -> Option<&'r str> {
+    headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, v)| *v)
+}
+
+/// Combines the response's `Vary` headers, such as `Vary: Origin` from CORS, and the
+/// headers its variant depends on, into one `Vary` header, and lists them in the variant
+fn merge_vary(response: &mut AssetResponse) {
+    let mut vary = std::mem::take(&mut response.variant.vary);
+    response.headers.retain(|(name, value)| {
+        if !name.eq_ignore_ascii_case("vary") {
+            return true;
+        }
+        vary.extend(value.split(',').map(|v| v.trim().to_string()));
+        false
+    });
+    let mut merged: Vec<String> = Vec::new();
+    for name in vary {
+        if !name.is_empty() && !merged.iter().any(|m| m.eq_ignore_ascii_case(&name)) {
+            merged.push(name);
+        }
+    }
+    if !merged.is_empty() {
+        response
+            .headers
+            .push(("Vary".to_string(), merged.join(", ")));
+    }
+    response.variant.vary = merged;
+}
+
+impl<'ah, S: AssetStore> KVAssets<'ah, S> {
+    /// Answers an http request for the asset at `path`, so it can be the only handler
+    /// for asset routes:
+    /// - GET returns the asset, in its precompressed variant (see `SyncConfig::precompress`)
+    ///   or compressed if enabled (see `with_compression`), if accepted by the client's
+    ///   `Accept-Encoding` request header, with `Vary: Accept-Encoding` (see
+    ///   `AssetResponse::variant`)
+    /// - GET for a directory path ending in '/' that isn't an asset returns an html listing,
+    ///   if the directory is listable (see `with_directory_listing`)
+    /// - GET with a single range in the `Range` header returns 206 with that part of the
+    ///   asset, or 416 if it starts past the end
+    /// - HEAD returns the headers from the index, without reading KV
+    /// - GET and HEAD return 304, without reading KV, if the `If-None-Match` request header
+    ///   matches the asset's `ETag`, or, without `If-None-Match`, if the asset hasn't
+    ///   changed since the `If-Modified-Since` date
+    /// - GET and HEAD for paths matching a redirect rule in the index (see `resolve`)
+    ///   return the redirect with a `Location` header, or, for rewrites, the target asset
+    /// - GET and HEAD for assets with language variants (see `get_asset_negotiated`)
+    ///   return the variant for the `Accept-Language` request header, with
+    ///   `Content-Language` and `Vary: Accept-Language`
+    /// - OPTIONS returns 204 with the `Allow` header
+    /// - other methods return 405
+    ///
+    /// Responses for assets include the asset's headers from the index (`AssetMetadata.headers`),
+    /// `Content-Type` from `AssetMetadata.content_type` and `Cache-Control` from the
+    /// `CachePolicy` (see `with_cache_policy`) if the headers don't set them,
+    /// `ETag`, and `Last-Modified`.
+    /// Responses include the headers of the header rules in the index matching the path
+    /// (see `SyncConfig::response_header_rules`), then those from the `HeaderPolicy`, if set,
+    /// unless the asset has its own value for the header. If CORS is enabled (see `with_cors`), requests with an
+    /// allowed `Origin` get CORS headers, if the path matches `CorsPolicy::paths`; OPTIONS
+    /// requests with `Access-Control-Request-Method` are answered as preflight requests.
+    /// Paths not in the index return 404, malformed paths (see `lookup_key`) return 400,
+    /// and protected assets (see `get_asset_signed`) return 403.
+    /// Errors reading KV are returned as `Err`, or, if there's an error document for 500,
+    /// answered with 500. Error responses have the body of the error document for their
+    /// status, if there is one (see `with_error_document`).
+    pub async fn handle_request(
+        &self,
+        method: &str,
+        path: &str,
+        request_headers: &[(&str, &str)],
+    ) -> Result<AssetResponse, Error> {
+        let started = clock::now_millis();
+        let result = self.answer(method, path, request_headers).await;
+        self.log_response(method, path, started, &result);
+        result
+    }
+
+    /// Answers the request (see `handle_request`)
+    async fn answer(
+        &self,
+        method: &str,
+        path: &str,
+        request_headers: &[(&str, &str)],
+    ) -> Result<AssetResponse, Error> {
+        let language = match method {
+            "GET" | "HEAD" => self
+                .negotiate_language(
+                    path,
+                    request_header(request_headers, "accept-language").unwrap_or_default(),
+                )
+                .unwrap_or_default(),
+            _ => None,
+        };
+        let target = language
+            .as_ref()
+            .map_or(path, |(_, variant)| variant.as_str());
+        let mut response = match self.respond(method, target, request_headers).await {
+            Err(Error::MalformedPath(_)) => AssetResponse::new(400),
+            Err(Error::SignatureRequired(_)) => AssetResponse::new(403),
+            // with an error document for 500, errors are answered with it
+            Err(e) => match self.error_document(500) {
+                Ok(Some(_)) => AssetResponse::new(500),
+                _ => return Err(e),
+            },
+            Ok(response) => response,
+        };
+        if response.status >= 400 {
+            response = self.with_error_body(method, response).await;
+        }
+        if let Some((tag, _)) = language {
+            response.variant.vary.push("Accept-Language".to_string());
+            if response.status < 400 {
+                response
+                    .headers
+                    .push(("Content-Language".to_string(), tag.clone()));
+                response.variant.language = Some(tag);
+            }
+        }
+        if let (Some(cors), Some(origin)) = (&self.cors, request_header(request_headers, "origin"))
+        {
+            if cors.applies_to(path.strip_prefix('/').unwrap_or(path)) {
+                let preflight = method == "OPTIONS"
+                    && request_header(request_headers, "access-control-request-method").is_some();
+                response.headers.extend(cors.headers(origin, preflight));
+            }
+        }
+        let rules = self.response_header_rules()?;
+        if !rules.is_empty() {
+            let path = self
+                .lookup_options
+                .normalize(path)
+                .unwrap_or_else(|_| path.to_string());
+            let path = path.strip_prefix('/').unwrap_or(&path);
+            for (name, value) in headers_for(&rules, path) {
+                if response.header(&name).is_none() {
+                    response.headers.push((name, value));
+                }
+            }
+        }
+        if let Some(policy) = &self.header_policy {
+            for (name, value) in policy.headers() {
+                if response.header(&name).is_none() {
+                    response.headers.push((name, value));
+                }
+            }
+        }
+        merge_vary(&mut response);
+        Ok(response)
+    }
+
+    /// Answers a GET request for the asset at `path` (see `handle_request`).
+    /// The response has the body, and `Content-Type`, `ETag`, `Last-Modified`, and the
+    /// asset's headers from the index, such as `Cache-Control`, ready to copy into
+    /// the worker framework's response.
+    pub async fn get_response(
+        &self,
+        path: &str,
+        request_headers: &[(&str, &str)],
+    ) -> Result<AssetResponse, Error> {
+        self.handle_request("GET", path, request_headers).await
+    }
+
+    pub(crate) async fn respond(
+        &self,
+        method: &str,
+        path: &str,
+        request_headers: &[(&str, &str)],
+    ) -> Result<AssetResponse, Error> {
+        let rewritten;
+        let path = match method {
+            "GET" | "HEAD" => match self.redirect_for(path) {
+                Ok(Some(redirect)) if redirect.status == 200 => {
+                    rewritten = redirect.location;
+                    rewritten.as_str()
+                }
+                Ok(Some(redirect)) => {
+                    return Ok(AssetResponse::new(redirect.status)
+                        .with_header("Location", redirect.location))
+                }
+                Ok(None) | Err(Error::EmptyKey) | Err(Error::InvalidKey(_)) => path,
+                Err(e) => return Err(e),
+            },
+            _ => path,
+        };
+        if method == "GET" || method == "HEAD" {
+            let md = self.lookup_key(path).ok().flatten();
+            // protected assets are only served by get_asset_signed
+            if md.as_ref().is_some_and(|md| md.protected) {
+                return Ok(AssetResponse::new(403));
+            }
+            if let Some(mut response) = self.not_modified(path, request_headers)? {
+                if method == "GET" && md.is_some_and(|md| self.varies_by_encoding(&md)) {
+                    response.variant.vary.push("Accept-Encoding".to_string());
+                }
+                return Ok(response);
+            }
+        }
+        match method {
+            "GET" => {
+                if let Some(range) =
+                    request_header(request_headers, "range").and_then(ByteRange::parse)
+                {
+                    return self.range_response(path, range).await;
+                }
+                let found = match self
+                    .fetch_encoded_hooked(
+                        path,
+                        request_header(request_headers, "accept-encoding"),
+                        &mut ServerTiming::new(),
+                    )
+                    .await
+                {
+                    Err(Error::EmptyKey) | Err(Error::InvalidKey(_)) => None,
+                    result => result?,
+                };
+                let (md, encoded) = match found {
+                    Some(found) => found,
+                    None => return self.listing_response(path),
+                };
+                let mut response = AssetResponse::new(200)
+                    .with_header("Content-Length", encoded.body.len())
+                    .with_header("Accept-Ranges", "bytes");
+                if self.varies_by_encoding(&md) {
+                    response.variant.vary.push("Accept-Encoding".to_string());
+                }
+                response.variant.encoding = encoded.content_encoding.map(|e| e.to_string());
+                // the encoded body differs from the asset, so its ETag is weak
+                match encoded.content_encoding {
+                    Some(encoding) => {
+                        response = response
+                            .with_header("Content-Encoding", encoding)
+                            .with_header("ETag", format!("W/{}", md.etag()));
+                    }
+                    None => response = response.with_header("ETag", md.etag()),
+                }
+                response.headers.extend(self.asset_headers(path, md));
+                response.body = encoded.body;
+                Ok(response)
+            }
+            "HEAD" => match self.lookup_key(path) {
+                Ok(Some(md)) => {
+                    let mut response = AssetResponse::new(200)
+                        .with_header("Content-Length", md.size)
+                        .with_header("Accept-Ranges", "bytes")
+                        .with_header("ETag", md.etag());
+                    response.headers.extend(self.asset_headers(path, md));
+                    Ok(response)
+                }
+                Ok(None) | Err(Error::EmptyKey) | Err(Error::InvalidKey(_)) => {
+                    Ok(AssetResponse::new(404))
+                }
+                Err(e) => Err(e),
+            },
+            "OPTIONS" => Ok(AssetResponse::new(204).with_header("Allow", ALLOWED_METHODS)),
+            _ => Ok(AssetResponse::new(405).with_header("Allow", ALLOWED_METHODS)),
+        }
+    }
+
+    /// Returns true if the body of a GET response for the asset depends on `Accept-Encoding`:
+    /// if it has precompressed variants, or could be compressed (see `with_compression`)
+    fn varies_by_encoding(&self, md: &AssetMetadata) -> bool {
+        (!md.encoded.is_empty() && md.inline.is_none()) || self.compresses()
+    }
+
+    /// Answers a GET request for a path ending in '/' that isn't an asset with an html
+    /// listing, if the directory is listable (see `with_directory_listing`), or 404
+    fn listing_response(&self, path: &str) -> Result<AssetResponse, Error> {
+        if !path.is_empty() && !path.ends_with('/') {
+            return Ok(AssetResponse::new(404));
+        }
+        match self.render_directory(path, ListingFormat::Html)? {
+            Some(html) => {
+                let mut response = AssetResponse::new(200)
+                    .with_header("Content-Length", html.len())
+                    .with_header("Content-Type", ListingFormat::Html.content_type());
+                response.body = html.into();
+                Ok(response)
+            }
+            None => Ok(AssetResponse::new(404)),
+        }
+    }
+
+    /// Answers a GET request with a `Range` header: 206 with the part of the asset,
+    /// or 416 if the range is past the end of the asset
+    async fn range_response(&self, path: &str, range: ByteRange) -> Result<AssetResponse, Error> {
+        let not_satisfiable = |total: u64| {
+            AssetResponse::new(416).with_header("Content-Range", format!("bytes */{}", total))
+        };
+        let md = match self.lookup_key(path) {
+            Ok(Some(md)) => md,
+            Ok(None) | Err(Error::EmptyKey) | Err(Error::InvalidKey(_)) => {
+                return Ok(AssetResponse::new(404))
+            }
+            Err(e) => return Err(e),
+        };
+        let (start, end) = match range.resolve(md.size) {
+            Some(range) => range,
+            None => return Ok(not_satisfiable(md.size)),
+        };
+        let part = match self.get_asset_range(path, start, Some(end)).await {
+            Ok(Some(part)) => part,
+            Ok(None) => return Ok(AssetResponse::new(404)),
+            Err(Error::RangeNotSatisfiable(total)) => return Ok(not_satisfiable(total)),
+            Err(e) => return Err(e),
+        };
+        let mut response = AssetResponse::new(206)
+            .with_header("Content-Length", part.body.len())
+            .with_header("Content-Range", part.content_range())
+            .with_header("ETag", md.etag());
+        response.headers.extend(self.asset_headers(path, md));
+        response.body = part.body;
+        Ok(response)
+    }
+
+    /// Returns the asset's headers, with `Content-Type` from the index and `Cache-Control`
+    /// from the `CachePolicy` unless the asset's headers set them, and `Last-Modified`
+    /// if the modified time is known
+    fn asset_headers(&self, path: &str, md: AssetMetadata) -> Vec<(String, String)> {
+        let last_modified = md.last_modified();
+        let mut headers = md.headers;
+        let has = |headers: &[(String, String)], name: &str| {
+            headers.iter().any(|(n, _)| n.eq_ignore_ascii_case(name))
+        };
+        if let Some(last_modified) = last_modified {
+            headers.push(("Last-Modified".to_string(), last_modified));
+        }
+        if let Some(content_type) = md.content_type {
+            if !has(&headers, "content-type") {
+                headers.push(("Content-Type".to_string(), content_type));
+            }
+        }
+        let path = path.strip_prefix('/').unwrap_or(path);
+        if let Some(policy) = &self.cache_policy {
+            if let Some(value) = policy.cache_control(&[path, &md.path]) {
+                if !has(&headers, "cache-control") {
+                    headers.push(("Cache-Control".to_string(), value.to_string()));
+                }
+            }
+        }
+        if !has(&headers, "cache-control") && self.is_versioned(path) {
+            headers.push((
+                "Cache-Control".to_string(),
+                crate::IMMUTABLE_CACHE_CONTROL.to_string(),
+            ));
+        }
+        headers
+    }
+
+    /// Returns a 304 response if the request's `If-None-Match` matches the asset's ETag,
+    /// or, without `If-None-Match`, the asset hasn't changed since `If-Modified-Since`
+    fn not_modified(
+        &self,
+        path: &str,
+        request_headers: &[(&str, &str)],
+    ) -> Result<Option<AssetResponse>, Error> {
+        let if_none_match = request_header(request_headers, "if-none-match");
+        let if_modified_since = request_header(request_headers, "if-modified-since");
+        if if_none_match.is_none() && if_modified_since.is_none() {
+            return Ok(None);
+        }
+        let md = match self.lookup_key(path) {
+            Ok(Some(md)) => md,
+            Ok(None) | Err(Error::EmptyKey) | Err(Error::InvalidKey(_)) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let etag = md.etag();
+        // If-Modified-Since is ignored when If-None-Match is present (RFC 7232)
+        let unchanged = match (if_none_match, if_modified_since) {
+            (Some(if_none_match), _) => etag_matches(if_none_match, &etag),
+            (None, Some(since)) => !md.modified_since(since),
+            (None, None) => false,
+        };
+        if !unchanged {
+            return Ok(None);
+        }
+        let mut response = AssetResponse::new(304).with_header("ETag", etag);
+        response.headers.extend(
+            self.asset_headers(path, md)
+                .into_iter()
+                .filter(|(n, _)| !n.eq_ignore_ascii_case("content-type")),
+        );
+        Ok(Some(response))
+    }
+}
+
+/// Tests method handling (does not invoke cloudflare api)
+#[cfg(all(test, not(target_arch = "wasm32")))]
+#[tokio::test]
+async fn test_handle_request() {
+    use crate::AssetIndex;
+
+    let mut index = AssetIndex::new();
+    index.insert(
+        "robots.txt".to_string(),
+        AssetMetadata {
+            path: "robots.abc123.txt".to_string(),
+            size: 13,
+            inline: Some(b"User-agent: *".to_vec()),
+            modified: 784_111_777,
+            headers: vec![("X-Robots-Tag".to_string(), "none".to_string())],
+            content_type: Some("text/plain".to_string()),
+            ..Default::default()
+        },
+    );
+    let blob = crate::encode_index(&index).expect("serialize-index");
+    let kv = KVAssets::init(&blob, "123", "namespace", "token");
+
+    let resp = kv.handle_request("GET", "/robots.txt", &[]).await.unwrap();
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.body.as_ref(), b"User-agent: *");
+    assert_eq!(resp.header("content-length"), Some("13"));
+    assert_eq!(resp.header("x-robots-tag"), Some("none"));
+    assert_eq!(resp.header("content-type"), Some("text/plain"));
+    assert_eq!(
+        resp.header("last-modified"),
+        Some("Sun, 06 Nov 1994 08:49:37 GMT")
+    );
+    assert_eq!(kv.get_response("/robots.txt", &[]).await.unwrap(), resp);
+    assert_eq!(resp.header("cache-control"), None);
+
+    let cached = KVAssets::init(&blob, "123", "namespace", "token").with_cache_policy(
+        crate::CachePolicy::new()
+            .with_rule("*.txt", "max-age=60")
+            .with_rule("robots.*", "no-cache"),
+    );
+    let resp = cached
+        .handle_request("HEAD", "/robots.txt", &[])
+        .await
+        .unwrap();
+    assert_eq!(resp.header("Cache-Control"), Some("no-cache"));
+
+    let resp = kv.handle_request("HEAD", "/robots.txt", &[]).await.unwrap();
+    assert_eq!(resp.status, 200);
+    assert!(resp.body.is_empty());
+    assert_eq!(resp.header("Content-Length"), Some("13"));
+    assert_eq!(resp.header("X-Robots-Tag"), Some("none"));
+    assert_eq!(resp.header("Content-Type"), Some("text/plain"));
+    let etag = resp.header("ETag").unwrap().to_string();
+
+    let resp = kv
+        .handle_request("GET", "/robots.txt", &[("If-None-Match", &etag)])
+        .await
+        .unwrap();
+    assert_eq!(resp.status, 304);
+    assert!(resp.body.is_empty());
+    assert_eq!(resp.header("ETag"), Some(etag.as_str()));
+    let resp = kv
+        .handle_request("GET", "/robots.txt", &[("If-None-Match", "\"x\"")])
+        .await
+        .unwrap();
+    assert_eq!(resp.status, 200);
+
+    let since = [("If-Modified-Since", "Sun, 06 Nov 1994 08:49:37 GMT")];
+    let resp = kv.get_response("/robots.txt", &since).await.unwrap();
+    assert_eq!(resp.status, 304);
+    let earlier = [("If-Modified-Since", "Sat, 05 Nov 1994 08:49:37 GMT")];
+    let resp = kv.get_response("/robots.txt", &earlier).await.unwrap();
+    assert_eq!(resp.status, 200);
+    let resp = kv
+        .get_response("/robots.txt", &[since[0], ("If-None-Match", "\"x\"")])
+        .await
+        .unwrap();
+    assert_eq!(resp.status, 200);
+
+    let resp = kv
+        .handle_request("GET", "/robots.txt", &[("Range", "bytes=-3")])
+        .await
+        .unwrap();
+    assert_eq!(resp.status, 206);
+    assert_eq!(resp.body.as_ref(), b": *");
+    assert_eq!(resp.header("Content-Range"), Some("bytes 10-12/13"));
+    assert_eq!(resp.header("Content-Length"), Some("3"));
+    let resp = kv
+        .handle_request("GET", "/robots.txt", &[("Range", "bytes=13-")])
+        .await
+        .unwrap();
+    assert_eq!(resp.status, 416);
+    assert_eq!(resp.header("Content-Range"), Some("bytes */13"));
+    assert!(matches!(
+        kv.get_asset_range("robots.txt", 20, None).await,
+        Err(Error::RangeNotSatisfiable(13))
+    ));
+    let part = kv.get_asset_range("robots.txt", 0, Some(3)).await.unwrap();
+    assert_eq!(part.unwrap().body.as_ref(), b"User");
+
+    let resp = kv
+        .handle_request("OPTIONS", "/robots.txt", &[])
+        .await
+        .unwrap();
+    assert_eq!(resp.status, 204);
+    assert_eq!(resp.header("Allow"), Some(ALLOWED_METHODS));
+
+    let resp = kv.handle_request("POST", "/robots.txt", &[]).await.unwrap();
+    assert_eq!(resp.status, 405);
+    assert_eq!(resp.header("Allow"), Some(ALLOWED_METHODS));
+
+    assert_eq!(
+        kv.handle_request("GET", "/x", &[]).await.unwrap().status,
+        404
+    );
+    assert_eq!(
+        kv.handle_request("HEAD", "/", &[]).await.unwrap().status,
+        404
+    );
+
+    // cors
+    let kv = KVAssets::init(&blob, "123", "namespace", "token")
+        .with_cors(crate::CorsPolicy::new(&["https://a.org"]).with_max_age(60));
+    let origin = ("Origin", "https://a.org");
+    let resp = kv
+        .handle_request("GET", "/robots.txt", &[origin])
+        .await
+        .unwrap();
+    assert_eq!(
+        resp.header("Access-Control-Allow-Origin"),
+        Some("https://a.org")
+    );
+    let resp = kv
+        .handle_request(
+            "OPTIONS",
+            "/robots.txt",
+            &[origin, ("Access-Control-Request-Method", "GET")],
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status, 204);
+    assert_eq!(resp.header("Access-Control-Max-Age"), Some("60"));
+    let resp = kv
+        .handle_request("GET", "/robots.txt", &[("Origin", "https://b.org")])
+        .await
+        .unwrap();
+    assert_eq!(resp.header("Access-Control-Allow-Origin"), None);
+    let kv = KVAssets::init(&blob, "123", "namespace", "token")
+        .with_cors(crate::CorsPolicy::new(&["https://a.org"]).with_paths(&["*.json"]));
+    let resp = kv
+        .handle_request("GET", "/robots.txt", &[origin])
+        .await
+        .unwrap();
+    assert_eq!(resp.header("Access-Control-Allow-Origin"), None);
+
+    // policy headers are added to all responses
+    let policy = crate::HeaderPolicy {
+        nosniff: true,
+        ..Default::default()
+    };
+    let kv = KVAssets::init(&blob, "123", "namespace", "token")
+        .with_header_policy(policy.clone().with_referrer_policy("no-referrer"));
+    let resp = kv.handle_request("GET", "/robots.txt", &[]).await.unwrap();
+    assert_eq!(resp.header("X-Content-Type-Options"), Some("nosniff"));
+    assert_eq!(resp.header("Referrer-Policy"), Some("no-referrer"));
+    let resp = kv.handle_request("GET", "/x", &[]).await.unwrap();
+    assert_eq!(resp.status, 404);
+    assert_eq!(resp.header("X-Content-Type-Options"), Some("nosniff"));
+}
+
+/// Tests header rules from the index in handle_request (does not invoke cloudflare api)
+#[cfg(all(test, not(target_arch = "wasm32")))]
+#[tokio::test]
+async fn test_response_header_rules() {
+    use crate::{policy::HEADERS_PATH, AssetIndex};
+
+    let mut index = AssetIndex::new();
+    index.insert(
+        "docs/a.html".to_string(),
+        AssetMetadata {
+            path: "docs/a.1.html".to_string(),
+            inline: Some(b"a".to_vec()),
+            headers: vec![("X-Frame-Options".to_string(), "SAMEORIGIN".to_string())],
+            ..Default::default()
+        },
+    );
+    index.insert(
+        HEADERS_PATH.to_string(),
+        AssetMetadata {
+            header_rules: crate::parse_headers(
+                "/*\n  X-Frame-Options: DENY\n/docs/*\n  Content-Security-Policy: default-src 'self'\n",
+            )
+            .unwrap(),
+            ..Default::default()
+        },
+    );
+    let blob = crate::encode_index(&index).expect("serialize-index");
+    let kv = KVAssets::init(&blob, "123", "namespace", "token");
+
+    let resp = kv.handle_request("GET", "/docs/a.html", &[]).await.unwrap();
+    assert_eq!(resp.header("X-Frame-Options"), Some("SAMEORIGIN"));
+    assert_eq!(
+        resp.header("Content-Security-Policy"),
+        Some("default-src 'self'")
+    );
+    let resp = kv.handle_request("GET", "/missing", &[]).await.unwrap();
+    assert_eq!(resp.status, 404);
+    assert_eq!(resp.header("X-Frame-Options"), Some("DENY"));
+    assert_eq!(resp.header("Content-Security-Policy"), None);
+    assert_eq!(kv.list_keys("").unwrap().len(), 1);
+}
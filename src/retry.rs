@@ -0,0 +1,157 @@
+//! Retries of KV requests that fail with 429 (rate limited), a 5xx status, or a transient
+//! network error, with exponential backoff. Every KV API call is a GET, PUT, or DELETE,
+//! which are idempotent, so repeating one is safe.
+
+use std::time::Duration;
+
+/// How KV requests are retried (see `KVBuilder::with_retry` and `KV::with_retry`)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Maximum number of attempts, including the first. 1 disables retries.
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled for each later retry
+    pub base_delay: Duration,
+    /// Longest delay between attempts, unless the response's `Retry-After` is longer
+    pub max_delay: Duration,
+    /// Whether delays are randomized, between half and all of the computed delay,
+    /// so clients that failed together don't retry together
+    pub jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    /// 3 attempts, with delays starting at 100ms
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(5),
+            jitter: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Policy that makes each request once
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the delay before retrying after `attempt` (1 for the first) failed.
+    /// `random` is in `[0, 1)`, and is only used with jitter.
+    pub(crate) fn delay(
+        &self,
+        attempt: u32,
+        retry_after: Option<Duration>,
+        random: f64,
+    ) -> Duration {
+        let backoff = self
+            .base_delay
+            .checked_mul(1 << attempt.saturating_sub(1).min(16))
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+        let backoff = match self.jitter {
+            true => backoff.mul_f64(0.5 + random / 2.0),
+            false => backoff,
+        };
+        match retry_after {
+            Some(retry_after) => retry_after.max(backoff),
+            None => backoff,
+        }
+    }
+}
+
+/// Whether a response with the status should be retried
+pub(crate) fn retry_status(status: reqwest::StatusCode) -> bool {
+    status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Whether a request that failed with the error should be retried
+pub(crate) fn retry_error(e: &reqwest::Error) -> bool {
+    #[cfg(not(target_arch = "wasm32"))]
+    if e.is_connect() {
+        return true;
+    }
+    e.is_timeout() || e.is_request()
+}
+
+/// Delay requested by a `Retry-After` header in seconds (http dates are ignored)
+pub(crate) fn retry_after(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    headers
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+}
+
+/// Random number in `[0, 1)` for jitter
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn random() -> f64 {
+    js_sys::Math::random()
+}
+
+/// Random number in `[0, 1)` for jitter
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn random() -> f64 {
+    use std::hash::{BuildHasher, Hasher};
+    // RandomState is seeded randomly for each process, and differently for each instance
+    let bits = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Waits for the duration
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) async fn sleep(delay: Duration) {
+    tokio::time::sleep(delay).await
+}
+
+/// Waits for the duration, with the worker's `setTimeout`
+#[cfg(target_arch = "wasm32")]
+pub(crate) async fn sleep(delay: Duration) {
+    let promise = js_sys::Promise::new(&mut |resolve, _reject| {
+        let global = js_sys::global();
+        let set_timeout = js_sys::Reflect::get(&global, &"setTimeout".into())
+            .ok()
+            .filter(|f| f.is_function())
+            .map(js_sys::Function::from);
+        let scheduled = set_timeout.is_some_and(|set_timeout| {
+            set_timeout
+                .call2(&global, &resolve, &(delay.as_millis() as f64).into())
+                .is_ok()
+        });
+        if !scheduled {
+            let _ = resolve.call0(&global);
+        }
+    });
+    let _ = wasm_bindgen_futures::JsFuture::from(promise).await;
+}
+
+#[test]
+fn test_delay() {
+    let policy = RetryPolicy {
+        jitter: false,
+        ..Default::default()
+    };
+    assert_eq!(policy.delay(1, None, 0.0), Duration::from_millis(100));
+    assert_eq!(policy.delay(3, None, 0.0), Duration::from_millis(400));
+    assert_eq!(policy.delay(20, None, 0.0), Duration::from_secs(5));
+    assert_eq!(
+        policy.delay(1, Some(Duration::from_secs(2)), 0.0),
+        Duration::from_secs(2)
+    );
+
+    let policy = RetryPolicy::default();
+    assert_eq!(policy.delay(2, None, 0.0), Duration::from_millis(100));
+    assert!(policy.delay(2, None, 0.99) < Duration::from_millis(200));
+    assert!((0.0..1.0).contains(&random()));
+
+    let mut headers = reqwest::header::HeaderMap::new();
+    headers.insert(reqwest::header::RETRY_AFTER, "7".parse().unwrap());
+    assert_eq!(retry_after(&headers), Some(Duration::from_secs(7)));
+    assert!(retry_status(reqwest::StatusCode::SERVICE_UNAVAILABLE));
+    assert!(!retry_status(reqwest::StatusCode::NOT_FOUND));
+}
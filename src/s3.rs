@@ -0,0 +1,307 @@
+#![cfg(not(target_arch = "wasm32"))]
+//! Import assets from an S3-compatible bucket, for migrating sites from S3 static hosting.
+//!
+//! Objects are downloaded into a directory, which is then synced like any other asset dir.
+//! Requests are signed with AWS signature v4 if credentials are provided, and use
+//! path-style urls, so other S3-compatible services (R2, MinIO, etc.) work with `endpoint`.
+
+use crate::{key::uri_encode, Error};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::path::{Component, Path, PathBuf};
+
+/// Bucket to import from
+#[derive(Clone, Debug)]
+pub struct S3Source {
+    /// Bucket name
+    pub bucket: String,
+    /// Only objects with keys starting with this prefix are imported. The prefix
+    /// is removed from the key to get the asset path. default: "" (all objects)
+    pub prefix: String,
+    /// Region. default: "us-east-1"
+    pub region: String,
+    /// Service url, for S3-compatible services. default: None (AWS S3 in region)
+    pub endpoint: Option<String>,
+    /// Credentials for signing requests. default: None (anonymous access)
+    pub credentials: Option<S3Credentials>,
+}
+
+impl Default for S3Source {
+    fn default() -> Self {
+        Self {
+            bucket: String::new(),
+            prefix: String::new(),
+            region: "us-east-1".to_string(),
+            endpoint: None,
+            credentials: None,
+        }
+    }
+}
+
+/// AWS access keys
+#[derive(Clone, Debug)]
+pub struct S3Credentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+}
+
+impl S3Credentials {
+    /// Reads credentials from AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY, and (optional)
+    /// AWS_SESSION_TOKEN. Returns None if the key id or secret is not set.
+    pub fn from_env() -> Option<Self> {
+        Some(Self {
+            access_key_id: std::env::var("AWS_ACCESS_KEY_ID").ok()?,
+            secret_access_key: std::env::var("AWS_SECRET_ACCESS_KEY").ok()?,
+            session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListBucketResult {
+    #[serde(default)]
+    is_truncated: bool,
+    #[serde(default)]
+    contents: Vec<S3Object>,
+    next_continuation_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct S3Object {
+    key: String,
+}
+
+/// Downloads all objects under the source prefix into `dir`, returning the number of files.
+/// Files are streamed to disk, so large buckets don't need to fit in memory.
+pub fn import_s3(source: &S3Source, dir: &Path) -> Result<usize, Error> {
+    let client = reqwest::blocking::Client::new();
+    let mut count = 0;
+    let mut token: Option<String> = None;
+    loop {
+        let mut query = vec![
+            ("list-type", "2".to_string()),
+            ("prefix", source.prefix.clone()),
+        ];
+        if let Some(token) = token.take() {
+            query.push(("continuation-token", token));
+        }
+        let body = source
+            .get(&client, "", &query)?
+            .text()
+            .map_err(http_error)?;
+        let list: ListBucketResult = quick_xml::de::from_str(&body)
+            .map_err(|e| Error::Message(format!("invalid bucket listing: {}", e)))?;
+        for object in list.contents.iter() {
+            // skip "directory" placeholders
+            if object.key.ends_with('/') {
+                continue;
+            }
+            let rel = relative_path(&source.prefix, &object.key)
+                .ok_or_else(|| Error::Message(format!("unsupported object key: {}", object.key)))?;
+            let path = dir.join(rel);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)
+                    .map_err(|e| Error::IO(format!("creating {}: {}", parent.display(), e)))?;
+            }
+            let mut file = std::fs::File::create(&path)
+                .map_err(|e| Error::IO(format!("creating {}: {}", path.display(), e)))?;
+            source
+                .get(&client, &object.key, &[])?
+                .copy_to(&mut file)
+                .map_err(|e| Error::IO(format!("downloading {}: {}", object.key, e)))?;
+            count += 1;
+        }
+        match list.next_continuation_token {
+            Some(next) if list.is_truncated => token = Some(next),
+            _ => break,
+        }
+    }
+    Ok(count)
+}
+
+impl S3Source {
+    /// Sends a GET request for the object key (or the bucket, if key is empty)
+    fn get(
+        &self,
+        client: &reqwest::blocking::Client,
+        key: &str,
+        query: &[(&str, String)],
+    ) -> Result<reqwest::blocking::Response, Error> {
+        let endpoint = match &self.endpoint {
+            Some(endpoint) => endpoint.trim_end_matches('/').to_string(),
+            None => format!("https://s3.{}.amazonaws.com", self.region),
+        };
+        let host = endpoint
+            .split("://")
+            .nth(1)
+            .unwrap_or(&endpoint)
+            .to_string();
+        let uri = format!(
+            "/{}/{}",
+            uri_encode(&self.bucket, false),
+            uri_encode(key, true)
+        );
+        let mut params: Vec<(String, String)> = query
+            .iter()
+            .map(|(k, v)| (uri_encode(k, false), uri_encode(v, false)))
+            .collect();
+        params.sort();
+        let query = params
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>()
+            .join("&");
+        let url = if query.is_empty() {
+            format!("{}{}", endpoint, uri)
+        } else {
+            format!("{}{}?{}", endpoint, uri, query)
+        };
+        let mut request = client.get(&url);
+        if let Some(creds) = &self.credentials {
+            let now = chrono::Utc::now();
+            for (name, value) in sign_v4(
+                creds,
+                &self.region,
+                &host,
+                &uri,
+                &query,
+                &now.format("%Y%m%dT%H%M%SZ").to_string(),
+            ) {
+                request = request.header(name, value);
+            }
+        }
+        let response = request.send().map_err(http_error)?;
+        if !response.status().is_success() {
+            let status = response.status().as_u16();
+            return Err(Error::Message(format!(
+                "S3 GET {} failed: status {}: {}",
+                url,
+                status,
+                response.text().unwrap_or_default()
+            )));
+        }
+        Ok(response)
+    }
+}
+
+fn http_error(e: reqwest::Error) -> Error {
+    Error::Message(format!("S3 request error: {}", e))
+}
+
+/// Returns the headers for an AWS signature v4 GET request.
+/// `uri` and `query` must already be in canonical (encoded and sorted) form.
+fn sign_v4(
+    creds: &S3Credentials,
+    region: &str,
+    host: &str,
+    uri: &str,
+    query: &str,
+    amz_date: &str,
+) -> Vec<(&'static str, String)> {
+    const PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+    let date = &amz_date[..8];
+    let mut headers = vec![
+        ("host", host.to_string()),
+        ("x-amz-content-sha256", PAYLOAD.to_string()),
+        ("x-amz-date", amz_date.to_string()),
+    ];
+    if let Some(token) = &creds.session_token {
+        headers.push(("x-amz-security-token", token.clone()));
+    }
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(k, v)| format!("{}:{}\n", k, v.trim()))
+        .collect();
+    let signed_headers = headers
+        .iter()
+        .map(|(k, _)| *k)
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_request = format!(
+        "GET\n{}\n{}\n{}\n{}\n{}",
+        uri, query, canonical_headers, signed_headers, PAYLOAD
+    );
+    let scope = format!("{}/{}/s3/aws4_request", date, region);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex::encode(Sha256::digest(canonical_request.as_bytes()))
+    );
+    let mut key = hmac(format!("AWS4{}", creds.secret_access_key).as_bytes(), date);
+    for part in [region, "s3", "aws4_request"].iter() {
+        key = hmac(&key, part);
+    }
+    let signature = hex::encode(hmac(&key, &string_to_sign));
+    headers.push((
+        "authorization",
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            creds.access_key_id, scope, signed_headers, signature
+        ),
+    ));
+    // reqwest sets the host header from the url
+    headers.retain(|(k, _)| *k != "host");
+    headers
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac accepts any key length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Returns the path of the object relative to the prefix, or None if it isn't
+/// a safe relative path
+fn relative_path(prefix: &str, key: &str) -> Option<PathBuf> {
+    let rest = key.strip_prefix(prefix)?;
+    // the prefix is a directory, so "site" doesn't match "site2/index.html"
+    if !prefix.is_empty() && !prefix.ends_with('/') && !rest.starts_with('/') {
+        return None;
+    }
+    let rel = rest.trim_start_matches('/');
+    let path = PathBuf::from(rel);
+    if rel.is_empty() || !path.components().all(|c| matches!(c, Component::Normal(_))) {
+        return None;
+    }
+    Some(path)
+}
+
+#[test]
+fn test_s3_paths() {
+    assert_eq!(uri_encode("a b/c~d+e", true), "a%20b/c~d%2Be");
+    assert_eq!(uri_encode("a/b", false), "a%2Fb");
+    assert_eq!(
+        relative_path("site/", "site/css/app.css"),
+        Some(PathBuf::from("css/app.css"))
+    );
+    assert_eq!(
+        relative_path("site", "site/index.html"),
+        Some(PathBuf::from("index.html"))
+    );
+    assert_eq!(relative_path("site/", "site/../etc/passwd"), None);
+    assert_eq!(relative_path("site/", "other/index.html"), None);
+    assert_eq!(relative_path("site", "site2/index.html"), None);
+    assert_eq!(
+        relative_path("", "index.html"),
+        Some(PathBuf::from("index.html"))
+    );
+
+    let list: ListBucketResult = quick_xml::de::from_str(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+        <ListBucketResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+          <Name>b</Name><IsTruncated>true</IsTruncated>
+          <Contents><Key>site/a.html</Key><Size>10</Size></Contents>
+          <Contents><Key>site/b &amp; c.html</Key><Size>20</Size></Contents>
+          <NextContinuationToken>tok</NextContinuationToken>
+        </ListBucketResult>"#,
+    )
+    .unwrap();
+    assert!(list.is_truncated);
+    assert_eq!(list.contents[1].key, "site/b & c.html");
+    assert_eq!(list.next_continuation_token.as_deref(), Some("tok"));
+}
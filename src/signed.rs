@@ -0,0 +1,159 @@
+#![cfg(feature = "signed-urls")]
+//! Signed URLs for protected assets (see `SyncConfig::protected`).
+//!
+//! A signed URL has the query `expires=<time>&signature=<hex>`, where the signature is
+//! the HMAC-SHA256, with a secret shared by the signer and the worker, of the path
+//! (without leading '/') and the expiration time, in UTC seconds since EPOCH.
+
+use crate::{clock, AssetStore, Error, KVAssets, ServerTiming, ToAssetKey};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Returns the query string (without '?') that grants access to the asset at `path`,
+/// as it appears in the URL, until `expires` (UTC seconds since EPOCH)
+pub fn sign_path(path: &str, expires: u64, secret: &[u8]) -> String {
+    let signature: String = mac(path, expires, secret)
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    format!("expires={}&signature={}", expires, signature)
+}
+
+fn mac(path: &str, expires: u64, secret: &[u8]) -> Hmac<Sha256> {
+    // HMAC accepts keys of any length
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("hmac key");
+    mac.update(path.strip_prefix('/').unwrap_or(path).as_bytes());
+    mac.update(b"\n");
+    mac.update(expires.to_string().as_bytes());
+    mac
+}
+
+/// Checks the signature in the query (see `sign_path`) at time `now`
+fn verify(path: &str, query: &str, secret: &[u8], now: u64) -> Result<(), Error> {
+    let param = |name: &str| {
+        query
+            .trim_start_matches('?')
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(n, _)| *n == name)
+            .map(|(_, value)| value)
+    };
+    let invalid = |reason: &str| Error::SignatureInvalid(reason.to_string());
+    let expires: u64 = param("expires")
+        .ok_or_else(|| invalid("missing expires"))?
+        .parse()
+        .map_err(|_| invalid("invalid expires"))?;
+    let signature = param("signature").ok_or_else(|| invalid("missing signature"))?;
+    if expires <= now {
+        return Err(invalid("expired"));
+    }
+    let signature = decode_hex(signature).ok_or_else(|| invalid("invalid signature"))?;
+    mac(path, expires, secret)
+        .verify_slice(&signature)
+        .map_err(|_| invalid("signature doesn't match"))
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+impl<'ah, S: AssetStore> KVAssets<'ah, S> {
+    /// Gets the asset at `path` if `query`, the request's query string, has a valid signature
+    /// for the path that hasn't expired (see `sign_path`). Returns `Error::SignatureInvalid`
+    /// otherwise. Unlike `get_asset`, protected assets are served.
+    /// Returns Ok(None) if the asset is not in the index.
+    pub async fn get_asset_signed<K: ToAssetKey + ?Sized>(
+        &self,
+        path: &K,
+        query: &str,
+        secret: &[u8],
+    ) -> Result<Option<bytes::Bytes>, Error> {
+        verify(path.key_str(), query, secret, clock::now_secs())?;
+        let found = self
+            .fetch_hooked(path.key_str(), &mut ServerTiming::new(), true)
+            .await?;
+        Ok(found.map(|(_, body)| body))
+    }
+}
+
+#[test]
+fn test_verify() {
+    let secret = b"secret";
+    let query = sign_path("/files/a.pdf", 1000, secret);
+    assert!(query.starts_with("expires=1000&signature="));
+    assert!(verify("files/a.pdf", &query, secret, 999).is_ok());
+    assert!(verify("/files/a.pdf", &format!("?{}", query), secret, 999).is_ok());
+    let invalid = |path: &str, query: &str, secret: &[u8], now: u64| {
+        matches!(
+            verify(path, query, secret, now),
+            Err(Error::SignatureInvalid(_))
+        )
+    };
+    assert!(invalid("files/a.pdf", &query, secret, 1000));
+    assert!(invalid("files/b.pdf", &query, secret, 999));
+    assert!(invalid("files/a.pdf", &query, b"other", 999));
+    assert!(invalid(
+        "files/a.pdf",
+        &query.replace("1000", "2000"),
+        secret,
+        999
+    ));
+    assert!(invalid("files/a.pdf", "expires=1000", secret, 999));
+    assert!(invalid(
+        "files/a.pdf",
+        "expires=1000&signature=zz",
+        secret,
+        999
+    ));
+}
+
+/// Tests that protected assets require a signature (does not invoke cloudflare api)
+#[cfg(all(test, not(target_arch = "wasm32")))]
+#[tokio::test]
+async fn test_get_asset_signed() {
+    use crate::{AssetIndex, AssetMetadata};
+
+    let mut index = AssetIndex::new();
+    index.insert(
+        "private/a.txt".to_string(),
+        AssetMetadata {
+            path: "private/a.1.txt".to_string(),
+            inline: Some(b"secret".to_vec()),
+            protected: true,
+            ..Default::default()
+        },
+    );
+    let blob = crate::encode_index(&index).expect("serialize-index");
+    let kv = KVAssets::init(&blob, "123", "namespace", "token");
+
+    assert!(matches!(
+        kv.get_asset("/private/a.txt").await,
+        Err(Error::SignatureRequired(_))
+    ));
+    let query = sign_path("/private/a.txt", clock::now_secs() + 60, b"key");
+    assert_eq!(
+        kv.get_asset_signed("/private/a.txt", &query, b"key")
+            .await
+            .unwrap()
+            .unwrap()
+            .as_ref(),
+        b"secret"
+    );
+    assert!(kv
+        .get_asset_signed("/private/a.txt", &query, b"other")
+        .await
+        .is_err());
+    let resp = kv
+        .handle_request("GET", "/private/a.txt", &[])
+        .await
+        .unwrap();
+    assert_eq!(resp.status, 403);
+}
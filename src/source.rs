@@ -0,0 +1,446 @@
+#![cfg(not(target_arch = "wasm32"))]
+//! Reads asset files from a directory, or directly from a zip or tar archive.
+//!
+//! Keys are generated the same way as wrangler (`path/name.<hash>.ext`), so a site can
+//! switch between wrangler, directory, and archive sources without re-uploading unchanged
+//! files. The default wrangler filters (hidden files except `.well-known`, and `node_modules`)
+//! are applied, and `[site] include` and `exclude` patterns are matched as globs.
+//! Unlike wrangler, files larger than the KV value limit are allowed, and stored in chunks.
+//! Precompressed variants are stored under keys generated for the path with the coding's
+//! extension added (`app.js.br` -> `app.js.<hash>.br`).
+
+use crate::{
+    chunk::{chunk_count, chunk_key, CHUNK_SIZE},
+    encoding::{is_compressed_type, PRECOMPRESSED},
+    glob::glob_match,
+    sri::subresource_integrity,
+    template::Templates,
+    EncodedVariant, Error,
+};
+use cloudflare::endpoints::workerskv::write_bulk::KeyValuePair;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+use wrangler::{
+    settings::toml::Target,
+    sites::{generate_path_and_key, KEY_MAX_SIZE},
+};
+
+/// Asset files read from the source
+pub(crate) struct SourceFiles<'t> {
+    /// KV pairs, with base64-encoded values
+    pub(crate) pairs: Vec<KeyValuePair>,
+    /// Asset path to key, size, and modified time
+    pub(crate) assets: BTreeMap<String, SourceAsset>,
+    templates: Templates<'t>,
+    /// Add precompressed variants of compressible files
+    precompress: bool,
+}
+
+/// File in the asset source
+pub(crate) struct SourceAsset {
+    pub(crate) key: String,
+    pub(crate) size: u64,
+    /// Last modified time, in UTC seconds since EPOCH
+    pub(crate) modified: u64,
+    /// Number of chunks, if the file is larger than a KV value (see `chunk_key`)
+    pub(crate) chunks: u32,
+    /// SHA-256 digest of the contents
+    pub(crate) sha256: [u8; 32],
+    /// Subresource Integrity hash, for scripts and stylesheets
+    pub(crate) sri: Option<String>,
+    /// Precompressed variants, if enabled and smaller than the file
+    pub(crate) variants: Vec<EncodedVariant>,
+    /// SHA-256 digests of the variants' contents, in the same order
+    pub(crate) variants_sha256: Vec<[u8; 32]>,
+}
+
+/// Returns true if the path has the extension of a supported archive format
+pub(crate) fn is_archive(path: &Path) -> bool {
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    [".zip", ".tar", ".tar.gz", ".tgz"]
+        .iter()
+        .any(|ext| name.ends_with(ext))
+}
+
+/// Reads all asset files from the directory or archive, substituting variables
+/// into templates, and compressing files if `precompress` is set
+pub(crate) fn read_source<'t>(
+    target: &Target,
+    source: &Path,
+    templates: Templates<'t>,
+    precompress: bool,
+) -> Result<SourceFiles<'t>, Error> {
+    let mut files = SourceFiles {
+        pairs: Vec::new(),
+        assets: BTreeMap::new(),
+        templates,
+        precompress,
+    };
+    if source.is_dir() {
+        read_dir(target, source, &mut files)?;
+    } else {
+        read_archive(target, source, &mut files)?;
+    }
+    Ok(files)
+}
+
+fn read_dir(target: &Target, dir: &Path, files: &mut SourceFiles) -> Result<(), Error> {
+    let mut paths = Vec::new();
+    walk_dir(dir, Path::new(""), &mut paths)?;
+    paths.sort();
+    for path in paths.iter() {
+        let file_path = dir.join(path);
+        let io_err = |e: std::io::Error| {
+            Error::IO(format!(
+                "failed reading asset file {}: {}",
+                file_path.display(),
+                e
+            ))
+        };
+        let md = std::fs::metadata(&file_path).map_err(io_err)?;
+        let modified = md
+            .modified()
+            .unwrap_or_else(|_| {
+                panic!(
+                    "Can't read modified time of {}. Please fix or run on a different platform",
+                    &file_path.display()
+                )
+            })
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_else(|_| panic!("Invalid timestamp for file {}", &file_path.display()))
+            .as_secs();
+        let value = std::fs::read(&file_path).map_err(io_err)?;
+        files.add(target, path, value, modified)?;
+    }
+    Ok(())
+}
+
+/// Collects the paths of files under `dir`, relative to the source root.
+/// Like wrangler, symlinks to files are followed, but symlinks to directories are not.
+fn walk_dir(dir: &Path, rel: &Path, paths: &mut Vec<PathBuf>) -> Result<(), Error> {
+    let io_err = |e: std::io::Error| Error::IO(format!("reading {}: {}", dir.display(), e));
+    for entry in std::fs::read_dir(dir).map_err(io_err)? {
+        let entry = entry.map_err(io_err)?;
+        let file_type = entry.file_type().map_err(io_err)?;
+        let path = rel.join(entry.file_name());
+        if file_type.is_dir() {
+            walk_dir(&entry.path(), &path, paths)?;
+        } else if entry.path().is_file() {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn read_archive(target: &Target, archive: &Path, files: &mut SourceFiles) -> Result<(), Error> {
+    let io_err = |e: std::io::Error| Error::IO(format!("reading {}: {}", archive.display(), e));
+    let file = std::fs::File::open(archive).map_err(io_err)?;
+    let name = archive.to_string_lossy().to_ascii_lowercase();
+    if name.ends_with(".zip") {
+        let mut zip = zip::ZipArchive::new(file)
+            .map_err(|e| Error::Message(format!("reading {}: {}", archive.display(), e)))?;
+        for i in 0..zip.len() {
+            let mut entry = zip
+                .by_index(i)
+                .map_err(|e| Error::Message(format!("reading {}: {}", archive.display(), e)))?;
+            if !entry.is_file() {
+                continue;
+            }
+            let path = match entry.enclosed_name() {
+                Some(path) => path.to_path_buf(),
+                None => continue,
+            };
+            let modified = zip_time(&entry.last_modified());
+            let mut value = Vec::with_capacity(entry.size() as usize);
+            entry.read_to_end(&mut value).map_err(io_err)?;
+            files.add(target, &path, value, modified)?;
+        }
+    } else {
+        let reader: Box<dyn Read> = if name.ends_with(".tar") {
+            Box::new(file)
+        } else {
+            Box::new(flate2::read::GzDecoder::new(file))
+        };
+        let mut tar = tar::Archive::new(reader);
+        for entry in tar.entries().map_err(io_err)? {
+            let mut entry = entry.map_err(io_err)?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let path = entry.path().map_err(io_err)?.to_path_buf();
+            let modified = entry.header().mtime().unwrap_or_default();
+            let mut value = Vec::with_capacity(entry.size() as usize);
+            entry.read_to_end(&mut value).map_err(io_err)?;
+            files.add(target, &path, value, modified)?;
+        }
+    }
+    Ok(())
+}
+
+impl<'t> SourceFiles<'t> {
+    /// Adds a file, unless it's filtered out. Files larger than a KV value are split into chunks.
+    fn add(
+        &mut self,
+        target: &Target,
+        path: &Path,
+        value: Vec<u8>,
+        modified: u64,
+    ) -> Result<(), Error> {
+        // remove "./", and skip anything that could escape the root
+        let path: PathBuf = path
+            .components()
+            .filter(|c| !matches!(c, Component::CurDir))
+            .collect();
+        if !path.components().all(|c| matches!(c, Component::Normal(_))) {
+            return Ok(());
+        }
+        let url_path = path.to_string_lossy().replace('\\', "/");
+        if !is_included(target, &url_path) {
+            return Ok(());
+        }
+        let value = self.templates.apply(&url_path, value);
+        let (url_safe_path, key, chunks) = self.add_value(&path, &value)?;
+        let mut variants = Vec::new();
+        let mut variants_sha256 = Vec::new();
+        if self.precompress && !is_compressed_type(&url_path) {
+            for (encoding, ext) in PRECOMPRESSED.iter() {
+                let compressed = compress(encoding, &value)?;
+                // not worth serving if it isn't smaller
+                if compressed.len() >= value.len() {
+                    continue;
+                }
+                let mut variant_path = path.clone().into_os_string();
+                variant_path.push(format!(".{}", ext));
+                let (_, key, chunks) = self.add_value(Path::new(&variant_path), &compressed)?;
+                variants.push(EncodedVariant {
+                    encoding: encoding.to_string(),
+                    path: key,
+                    size: compressed.len() as u64,
+                    chunks,
+                });
+                variants_sha256.push(Sha256::digest(&compressed).into());
+            }
+        }
+        self.assets.insert(
+            url_safe_path,
+            SourceAsset {
+                key,
+                size: value.len() as u64,
+                modified,
+                chunks,
+                sha256: Sha256::digest(&value).into(),
+                sri: subresource_integrity(&url_path, &value),
+                variants,
+                variants_sha256,
+            },
+        );
+        Ok(())
+    }
+
+    /// Adds the KV pairs for a value, in chunks if it's larger than a KV value.
+    /// Returns the url-safe path, the key, and the number of chunks.
+    fn add_value(&mut self, path: &Path, value: &[u8]) -> Result<(String, String, u32), Error> {
+        let b64_value = base64::encode(value);
+        // generate_path_and_key only uses the path relative to the root
+        let root = Path::new("/");
+        let (url_safe_path, key) =
+            generate_path_and_key(&root.join(path), root, Some(b64_value.clone()))?;
+        let chunks = chunk_count(value.len() as u64);
+        let longest_key = match chunks {
+            0 => key.clone(),
+            n => chunk_key(&key, n - 1),
+        };
+        if longest_key.len() > KEY_MAX_SIZE {
+            return Err(Error::Message(format!(
+                "Path `{}` of {} bytes exceeds the maximum key size limit of {} bytes",
+                longest_key,
+                longest_key.len(),
+                KEY_MAX_SIZE
+            )));
+        }
+        if chunks == 0 {
+            self.pairs.push(pair(key.clone(), b64_value));
+        } else {
+            for (n, chunk) in value.chunks(CHUNK_SIZE as usize).enumerate() {
+                self.pairs
+                    .push(pair(chunk_key(&key, n as u32), base64::encode(chunk)));
+            }
+        }
+        Ok((url_safe_path, key, chunks))
+    }
+}
+
+/// Compresses a value with the coding ("br" or "gzip"), at the highest quality,
+/// since it's only done once per deploy
+fn compress(encoding: &str, value: &[u8]) -> Result<Vec<u8>, Error> {
+    use std::io::Write as _;
+
+    let io_err = |e: std::io::Error| Error::Message(format!("{} error: {}", encoding, e));
+    match encoding {
+        "br" => {
+            let mut out = Vec::with_capacity(value.len() / 2);
+            {
+                let mut writer = brotli::CompressorWriter::new(&mut out, 4096, 11, 22);
+                writer.write_all(value).map_err(io_err)?;
+            }
+            Ok(out)
+        }
+        _ => {
+            let mut encoder = flate2::write::GzEncoder::new(
+                Vec::with_capacity(value.len() / 2),
+                flate2::Compression::best(),
+            );
+            encoder.write_all(value).map_err(io_err)?;
+            encoder.finish().map_err(io_err)
+        }
+    }
+}
+
+/// Returns a KV pair for upload, with the base64-encoded value
+fn pair(key: String, value: String) -> KeyValuePair {
+    KeyValuePair {
+        key,
+        value,
+        expiration: None,
+        expiration_ttl: None,
+        base64: Some(true),
+    }
+}
+
+/// Applies wrangler's default filters and the site's include and exclude patterns
+fn is_included(target: &Target, path: &str) -> bool {
+    // like wrangler, nothing is filtered for targets without a site
+    let site = match &target.site {
+        Some(site) => site,
+        None => return true,
+    };
+    let components: Vec<&str> = path.split('/').collect();
+    if components.contains(&"node_modules") {
+        return false;
+    }
+    let matches_any = |patterns: &[String]| {
+        patterns.iter().any(|p| {
+            // a pattern matching a directory matches everything in it
+            (1..=components.len()).any(|n| glob_match(p, &components[..n].join("/")))
+        })
+    };
+    match &site.include {
+        Some(include) => matches_any(include),
+        None => {
+            let hidden = components
+                .iter()
+                .any(|c| c.starts_with('.') && *c != ".well-known");
+            let excluded = site
+                .exclude
+                .as_ref()
+                .is_some_and(|exclude| matches_any(exclude));
+            !hidden && !excluded
+        }
+    }
+}
+
+/// Converts a zip timestamp (local time, but treated as UTC) to seconds since EPOCH
+fn zip_time(t: &zip::DateTime) -> u64 {
+    chrono::NaiveDate::from_ymd_opt(t.year() as i32, t.month() as u32, t.day() as u32)
+        .and_then(|d| d.and_hms_opt(t.hour() as u32, t.minute() as u32, t.second() as u32))
+        .map(|dt| dt.and_utc().timestamp().max(0) as u64)
+        .unwrap_or_default()
+}
+
+#[test]
+fn test_read_archive() {
+    use std::io::Write;
+
+    let dir = std::env::temp_dir().join(format!("kv-assets-source-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("public/css")).unwrap();
+    std::fs::write(dir.join("public/index.html"), b"<html/>").unwrap();
+    std::fs::write(dir.join("public/css/site.css"), b"body{}").unwrap();
+    std::fs::write(dir.join("public/.env"), b"secret").unwrap();
+    let target = Target {
+        site: Some(wrangler::settings::toml::Site::new("public")),
+        ..Default::default()
+    };
+    let vars = BTreeMap::new();
+    let templates = Templates {
+        patterns: &[],
+        vars: &vars,
+    };
+    let from_dir = read_source(&target, &dir.join("public"), templates, false).unwrap();
+
+    // zip with the same files
+    let zip_path = dir.join("site.zip");
+    let mut zip = zip::ZipWriter::new(std::fs::File::create(&zip_path).unwrap());
+    for (name, data) in [
+        ("index.html", &b"<html/>"[..]),
+        ("css/site.css", b"body{}"),
+        (".env", b"secret"),
+        ("node_modules/x.js", b"x"),
+    ]
+    .iter()
+    {
+        zip.start_file(*name, zip::write::FileOptions::default())
+            .unwrap();
+        zip.write_all(data).unwrap();
+    }
+    zip.finish().unwrap();
+    let from_zip = read_source(&target, &zip_path, templates, false).unwrap();
+
+    // tar.gz with the same files
+    let tgz_path = dir.join("site.tar.gz");
+    let gz = flate2::write::GzEncoder::new(
+        std::fs::File::create(&tgz_path).unwrap(),
+        flate2::Compression::default(),
+    );
+    let mut tar = tar::Builder::new(gz);
+    tar.append_dir_all(".", dir.join("public")).unwrap();
+    tar.into_inner().unwrap().finish().unwrap();
+    let from_tar = read_source(&target, &tgz_path, templates, false).unwrap();
+
+    let keys = |files: &SourceFiles| {
+        files
+            .assets
+            .iter()
+            .map(|(path, a)| (path.clone(), a.key.clone(), a.size))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(keys(&from_dir).len(), 2);
+    assert_eq!(keys(&from_zip), keys(&from_dir));
+    assert_eq!(keys(&from_tar), keys(&from_dir));
+    assert!(is_archive(&tgz_path) && is_archive(&zip_path));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_precompress() {
+    let dir = std::env::temp_dir().join(format!("kv-assets-precompress-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("app.js"), "console.log(1);\n".repeat(100)).unwrap();
+    std::fs::write(dir.join("tiny.css"), b"a{}").unwrap();
+    std::fs::write(dir.join("logo.png"), "png".repeat(100)).unwrap();
+    let vars = BTreeMap::new();
+    let templates = Templates {
+        patterns: &[],
+        vars: &vars,
+    };
+    let files = read_source(&Target::default(), &dir, templates, true).unwrap();
+    let app = &files.assets["app.js"];
+    let encodings: Vec<&str> = app.variants.iter().map(|v| v.encoding.as_str()).collect();
+    assert_eq!(encodings, ["br", "gzip"]);
+    assert!(app.variants[0].path.starts_with("app.js.") && app.variants[0].path.ends_with(".br"));
+    assert!(app.variants.iter().all(|v| v.size < app.size));
+    assert_eq!(app.variants_sha256.len(), 2);
+    // too small to benefit, or already compressed
+    assert!(files.assets["tiny.css"].variants.is_empty());
+    assert!(files.assets["logo.png"].variants.is_empty());
+    assert_eq!(files.pairs.len(), 5);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
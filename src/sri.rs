@@ -0,0 +1,46 @@
+//! Subresource Integrity (SRI) hashes of scripts and stylesheets, computed at sync time,
+//! so server-rendered html can add `integrity` attributes to the tags that load them.
+
+use crate::{AssetStore, Error, KVAssets, ToAssetKey};
+
+/// Extensions of assets that get an SRI hash: those loaded by `<script>` and `<link>` tags
+#[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+const SRI_EXTENSIONS: &[&str] = &["css", "js", "mjs"];
+
+/// Returns the SRI hash of the contents, `sha384-` and the base64 digest, if the path
+/// is a script or stylesheet
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn subresource_integrity(path: &str, contents: &[u8]) -> Option<String> {
+    use sha2::{Digest, Sha384};
+
+    let (_, ext) = path.rsplit_once('.')?;
+    if !SRI_EXTENSIONS.iter().any(|e| e.eq_ignore_ascii_case(ext)) {
+        return None;
+    }
+    Some(format!(
+        "sha384-{}",
+        base64::encode(Sha384::digest(contents))
+    ))
+}
+
+impl<'ah, S: AssetStore> KVAssets<'ah, S> {
+    /// Returns the SRI hash of the script or stylesheet at `path`, for its tag's
+    /// `integrity` attribute, e.g. `sha384-oqVuAfXRKap7fdgcCY5uykM6+R9GqQ8K/uxy9rx7HNQlGYl1kPzQho1wx4JwY8wC`.
+    /// Returns Ok(None) if the asset isn't in the index, or has no hash (see `AssetMetadata::sri`).
+    pub fn sri<K: ToAssetKey + ?Sized>(&self, path: &K) -> Result<Option<String>, Error> {
+        Ok(self.lookup_key(path)?.and_then(|md| md.sri))
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[test]
+fn test_subresource_integrity() {
+    // example from the SRI specification
+    assert_eq!(
+        subresource_integrity("app.js", b"alert('Hello, world.');").as_deref(),
+        Some("sha384-H8BRh8j48O9oYatfu5AZzq6A9RINhZO5H16dQZngK7T62em8MUt1FLm52t+eX6xO")
+    );
+    assert!(subresource_integrity("styles/site.CSS", b"").is_some());
+    assert_eq!(subresource_integrity("index.html", b""), None);
+    assert_eq!(subresource_integrity("LICENSE", b""), None);
+}
@@ -0,0 +1,145 @@
+//! Key counts and approximate stored bytes of a namespace, for capacity monitoring.
+//!
+//! The KV key listing doesn't include value sizes, so bytes are counted from the sizes
+//! in an asset index, for the keys of its assets, chunks, and compressed variants.
+//! Keys that aren't in the index are counted, but their size is unknown.
+
+use crate::{chunk_key, AssetIndex, Error, CHUNK_SIZE, KV};
+use futures_util::TryStreamExt;
+use std::collections::HashMap;
+
+/// Statistics of a namespace (see `KV::namespace_stats`)
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NamespaceStats {
+    /// Number of keys
+    pub keys: u64,
+    /// Number of keys with an expiration
+    pub expiring: u64,
+    /// Number of keys whose size is known from the index
+    pub sized: u64,
+    /// Total size of the keys whose size is known
+    pub bytes: u64,
+}
+
+impl NamespaceStats {
+    /// Aggregates listed keys, with their expirations
+    pub(crate) fn collect<I>(keys: I, index: Option<&AssetIndex>) -> Self
+    where
+        I: IntoIterator<Item = (String, Option<u64>)>,
+    {
+        let sizes = index.map(key_sizes).unwrap_or_default();
+        let mut stats = NamespaceStats::default();
+        for (key, expiration) in keys {
+            stats.keys += 1;
+            if expiration.is_some() {
+                stats.expiring += 1;
+            }
+            if let Some(size) = sizes.get(&key) {
+                stats.sized += 1;
+                stats.bytes += size;
+            }
+        }
+        stats
+    }
+}
+
+/// Returns the size of each KV key of the index's assets, chunks, and encoded variants.
+/// Inline assets aren't stored in KV.
+fn key_sizes(index: &AssetIndex) -> HashMap<String, u64> {
+    let mut sizes = HashMap::new();
+    let mut add = |path: &str, size: u64, chunks: u32| {
+        if chunks == 0 {
+            sizes.insert(path.to_string(), size);
+            return;
+        }
+        for n in 0..chunks {
+            let start = n as u64 * CHUNK_SIZE;
+            sizes.insert(
+                chunk_key(path, n),
+                size.saturating_sub(start).min(CHUNK_SIZE),
+            );
+        }
+    };
+    for md in index.values().filter(|md| md.inline.is_none()) {
+        add(&md.path, md.size, md.chunks);
+        for variant in md.encoded.iter() {
+            add(&variant.path, variant.size, variant.chunks);
+        }
+    }
+    sizes
+}
+
+impl KV {
+    /// Counts the keys in the namespace, by listing them. If the `index` of the assets
+    /// in the namespace is given, also totals the sizes of the keys it describes.
+    pub async fn namespace_stats(
+        &self,
+        index: Option<&AssetIndex>,
+    ) -> Result<NamespaceStats, Error> {
+        let keys: Vec<(String, Option<u64>)> = self
+            .list_keys(None)
+            .map_ok(|key| (key.name, key.expiration))
+            .try_collect()
+            .await?;
+        Ok(NamespaceStats::collect(keys, index))
+    }
+}
+
+/// Statistics of the namespace synced with `args`, with sizes from the index
+/// at `args.output_path`, if it has been written
+#[cfg(not(target_arch = "wasm32"))]
+pub fn namespace_stats(args: crate::SyncConfig) -> Result<NamespaceStats, Error> {
+    let keys = crate::upload::open_destination(&args)?.list_expirations()?;
+    let index = std::fs::read(args.output_path)
+        .ok()
+        .and_then(|blob| crate::decode_index(&blob).ok());
+    Ok(NamespaceStats::collect(keys, index.as_ref()))
+}
+
+#[test]
+fn test_namespace_stats() {
+    use crate::{AssetMetadata, EncodedVariant};
+
+    let mut index = AssetIndex::new();
+    index.insert(
+        "index.html".to_string(),
+        AssetMetadata {
+            path: "index.abc.html".to_string(),
+            size: 100,
+            encoded: vec![EncodedVariant {
+                encoding: "gzip".to_string(),
+                path: "index.abc.html.gz".to_string(),
+                size: 40,
+                chunks: 0,
+            }],
+            ..Default::default()
+        },
+    );
+    index.insert(
+        "video.mp4".to_string(),
+        AssetMetadata {
+            path: "video.abc.mp4".to_string(),
+            size: CHUNK_SIZE + 10,
+            chunks: 2,
+            ..Default::default()
+        },
+    );
+    let keys = vec![
+        ("index.abc.html".to_string(), None),
+        ("index.abc.html.gz".to_string(), None),
+        (chunk_key("video.abc.mp4", 0), None),
+        (chunk_key("video.abc.mp4", 1), None),
+        ("session/1".to_string(), Some(1_700_000_000)),
+    ];
+    let stats = NamespaceStats::collect(keys.clone(), Some(&index));
+    assert_eq!(
+        stats,
+        NamespaceStats {
+            keys: 5,
+            expiring: 1,
+            sized: 4,
+            bytes: 100 + 40 + CHUNK_SIZE + 10,
+        }
+    );
+    assert_eq!(NamespaceStats::collect(keys, None).sized, 0);
+}
@@ -0,0 +1,39 @@
+//! Storage backends for asset contents
+
+use crate::Error;
+use futures_util::stream::{self, Stream};
+use std::pin::Pin;
+
+/// Stream of the parts of a value (see `AssetStore::get_stream`)
+pub type ByteStream<'a> = Pin<Box<dyn Stream<Item = Result<bytes::Bytes, Error>> + 'a>>;
+
+/// Key-value storage for asset contents. `KVAssets` reads asset bodies through this trait,
+/// so it can serve from Workers KV (`KV`), or, during development, from a local
+/// directory (`LocalKV`).
+#[cfg_attr(target_arch = "wasm32", async_trait::async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait::async_trait)]
+pub trait AssetStore {
+    /// Returns the value for the key. If the key is missing or expired,
+    /// returns `Error::KVKeyNotFound`.
+    async fn get_value(&self, key: &str) -> Result<bytes::Bytes, Error>;
+
+    /// Returns the value for the key as a stream, so large values don't have to be held
+    /// in memory. Nothing is read until the stream is polled. The default implementation
+    /// yields the value from `get_value` as one part.
+    fn get_stream(&self, key: &str) -> ByteStream<'_> {
+        let key = key.to_string();
+        Box::pin(stream::once(async move { self.get_value(&key).await }))
+    }
+
+    /// Stores a value. Optionally, set expiration TTL, number of seconds in future
+    /// when content should be automatically deleted. TTL must be at least 60.
+    async fn put_value(
+        &self,
+        key: &str,
+        value: bytes::Bytes,
+        expiration_ttl: Option<u64>,
+    ) -> Result<(), Error>;
+
+    /// Deletes the key. Deleting a key that doesn't exist is not an error.
+    async fn delete_value(&self, key: &str) -> Result<(), Error>;
+}
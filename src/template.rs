@@ -0,0 +1,106 @@
+#![cfg(not(target_arch = "wasm32"))]
+//! Substitution of variables into text assets at sync time.
+//!
+//! Assets matching `SyncConfig::templates` have each `{{NAME}}` replaced by the value of
+//! `NAME` in `SyncConfig::template_vars`, before their keys are hashed, so a page can show
+//! the deployed version without a separate templating step. Placeholders for names
+//! that aren't defined are left as they are.
+
+use crate::glob::glob_match;
+use std::collections::BTreeMap;
+
+/// Variable with the time of the sync, in RFC 3339 format (see `builtin_template_vars`)
+pub const DEPLOY_TIME_VAR: &str = "DEPLOY_TIME";
+/// Variable with the commit id of the working directory's git checkout
+pub const GIT_SHA_VAR: &str = "GIT_SHA";
+
+/// Assets to substitute, and the variable values
+#[derive(Clone, Copy)]
+pub(crate) struct Templates<'t> {
+    pub(crate) patterns: &'t [String],
+    pub(crate) vars: &'t BTreeMap<String, String>,
+}
+
+impl<'t> Templates<'t> {
+    /// Returns the value with variables substituted, if the path matches a pattern
+    pub(crate) fn apply(&self, path: &str, value: Vec<u8>) -> Vec<u8> {
+        if self.vars.is_empty() || !self.patterns.iter().any(|p| glob_match(p, path)) {
+            return value;
+        }
+        substitute(&value, self.vars)
+    }
+}
+
+/// Returns `DEPLOY_TIME` (now), and `GIT_SHA`, if the current directory is in a git
+/// checkout, for use in `SyncConfig::template_vars`
+pub fn builtin_template_vars() -> BTreeMap<String, String> {
+    let mut vars = BTreeMap::new();
+    vars.insert(
+        DEPLOY_TIME_VAR.to_string(),
+        chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+    );
+    let git = std::process::Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output();
+    if let Ok(output) = git {
+        let sha = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if output.status.success() && !sha.is_empty() {
+            vars.insert(GIT_SHA_VAR.to_string(), sha);
+        }
+    }
+    vars
+}
+
+/// Returns true if `name` can be used in a placeholder: letters, digits, and '_'
+pub(crate) fn is_var_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+}
+
+/// Replaces `{{NAME}}` with the value of each defined variable
+fn substitute(value: &[u8], vars: &BTreeMap<String, String>) -> Vec<u8> {
+    let mut out = Vec::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = find(rest, b"{{") {
+        out.extend_from_slice(&rest[..start]);
+        let after = &rest[start + 2..];
+        let var = find(after, b"}}")
+            .and_then(|end| std::str::from_utf8(&after[..end]).ok())
+            .and_then(|name| vars.get(name).map(|v| (name.len(), v)));
+        match var {
+            Some((len, v)) => {
+                out.extend_from_slice(v.as_bytes());
+                rest = &after[len + 2..];
+            }
+            None => {
+                out.extend_from_slice(b"{{");
+                rest = after;
+            }
+        }
+    }
+    out.extend_from_slice(rest);
+    out
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[test]
+fn test_templates() {
+    let mut vars = BTreeMap::new();
+    vars.insert("VERSION".to_string(), "1.2.0".to_string());
+    vars.insert(GIT_SHA_VAR.to_string(), "abc123".to_string());
+    let patterns = vec!["*.html".to_string()];
+    let templates = Templates {
+        patterns: &patterns,
+        vars: &vars,
+    };
+    let page = b"<p>v{{VERSION}} ({{GIT_SHA}}) {{ user }} {{OTHER}}{{</p>".to_vec();
+    assert_eq!(
+        templates.apply("docs/index.html", page.clone()),
+        b"<p>v1.2.0 (abc123) {{ user }} {{OTHER}}{{</p>".to_vec()
+    );
+    assert_eq!(templates.apply("app.js", page.clone()), page);
+    assert!(is_var_name("BUILD_2"));
+    assert!(!is_var_name("a-b"));
+}
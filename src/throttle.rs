@@ -0,0 +1,167 @@
+//! Pacing of KV requests below the Cloudflare API's rate limit (1200 requests per
+//! 5 minutes), so a bulk operation slows down instead of failing part way with 429s.
+//!
+//! Requests take tokens from a bucket that refills at the allowed rate. When the API
+//! reports, in its rate limit headers or with a 429, that few requests remain,
+//! requests wait until the limit resets.
+
+use crate::clock::now_millis;
+use std::{sync::Mutex, time::Duration};
+
+/// Token bucket shared by the requests of a `KV` client
+pub(crate) struct Throttle {
+    // requests per millisecond, or 0 if requests aren't throttled
+    rate: f64,
+    capacity: f64,
+    state: Mutex<Bucket>,
+}
+
+struct Bucket {
+    // may be negative: requests reserve tokens before they're available, and wait
+    tokens: f64,
+    updated: f64,
+    // no requests until this time, after the API reported the limit was reached
+    paused_until: f64,
+}
+
+impl Throttle {
+    /// Allows `requests` per `period`, with bursts of up to `requests`.
+    /// 0 requests disables throttling.
+    pub(crate) fn new(requests: u32, period: Duration) -> Self {
+        let millis = period.as_millis().max(1) as f64;
+        Self {
+            rate: requests as f64 / millis,
+            capacity: requests as f64,
+            state: Mutex::new(Bucket {
+                tokens: requests as f64,
+                updated: now_millis(),
+                paused_until: 0.0,
+            }),
+        }
+    }
+
+    /// Takes a token for a request, returning how long to wait before sending it
+    pub(crate) fn acquire(&self) -> Duration {
+        self.acquire_at(now_millis())
+    }
+
+    fn acquire_at(&self, now: f64) -> Duration {
+        if self.rate == 0.0 {
+            return Duration::ZERO;
+        }
+        let mut bucket = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if now > bucket.updated {
+            bucket.tokens = (bucket.tokens + (now - bucket.updated) * self.rate).min(self.capacity);
+            bucket.updated = now;
+        }
+        bucket.tokens -= 1.0;
+        let wait = match bucket.tokens < 0.0 {
+            true => -bucket.tokens / self.rate,
+            false => 0.0,
+        };
+        let wait = wait.max(bucket.paused_until - now);
+        Duration::from_millis(wait.max(0.0).ceil() as u64)
+    }
+
+    /// Updates the bucket from a response's status and headers
+    pub(crate) fn observe(
+        &self,
+        status: reqwest::StatusCode,
+        headers: &reqwest::header::HeaderMap,
+    ) {
+        self.observe_at(now_millis(), status, headers)
+    }
+
+    fn observe_at(
+        &self,
+        now: f64,
+        status: reqwest::StatusCode,
+        headers: &reqwest::header::HeaderMap,
+    ) {
+        if self.rate == 0.0 {
+            return;
+        }
+        let pause = match rate_limit(headers) {
+            Some((0, reset)) => Some(reset),
+            Some((remaining, _)) => {
+                let mut bucket = self.state.lock().unwrap_or_else(|e| e.into_inner());
+                bucket.tokens = bucket.tokens.min(remaining as f64);
+                None
+            }
+            None => None,
+        };
+        let pause = match status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            true => pause.or_else(|| crate::retry::retry_after(headers)),
+            false => pause,
+        };
+        if let Some(pause) = pause {
+            let mut bucket = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            let until = now + pause.as_millis() as f64;
+            bucket.paused_until = bucket.paused_until.max(until);
+        }
+    }
+}
+
+/// Remaining requests, and time until the limit resets, from the `Ratelimit` header
+/// (e.g. `"default";r=50;t=30`), or `Ratelimit-Remaining` and `Ratelimit-Reset`
+pub(crate) fn rate_limit(headers: &reqwest::header::HeaderMap) -> Option<(u64, Duration)> {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+    let number = |value: &str| value.trim().parse::<u64>().ok();
+    if let Some(value) = header("ratelimit") {
+        let param = |name: &str| {
+            value
+                .split(';')
+                .filter_map(|p| p.trim().split_once('='))
+                .find(|(key, _)| key.trim() == name)
+                .and_then(|(_, value)| number(value))
+        };
+        if let (Some(remaining), Some(reset)) = (param("r"), param("t")) {
+            return Some((remaining, Duration::from_secs(reset)));
+        }
+    }
+    let remaining = header("ratelimit-remaining").and_then(number)?;
+    let reset = header("ratelimit-reset").and_then(number).unwrap_or(0);
+    Some((remaining, Duration::from_secs(reset)))
+}
+
+#[test]
+fn test_throttle() {
+    use reqwest::{header::HeaderMap, StatusCode};
+
+    let throttle = Throttle::new(2, Duration::from_secs(1));
+    let now = now_millis();
+    assert_eq!(throttle.acquire_at(now), Duration::ZERO);
+    assert_eq!(throttle.acquire_at(now), Duration::ZERO);
+    assert_eq!(throttle.acquire_at(now), Duration::from_millis(500));
+    // refilled
+    assert_eq!(throttle.acquire_at(now + 2000.0), Duration::ZERO);
+
+    let mut headers = HeaderMap::new();
+    headers.insert("ratelimit", "\"default\";r=0;t=30".parse().unwrap());
+    throttle.observe_at(now + 2000.0, StatusCode::OK, &headers);
+    assert_eq!(
+        throttle.acquire_at(now + 10000.0),
+        Duration::from_millis(22000)
+    );
+
+    let mut headers = HeaderMap::new();
+    headers.insert(reqwest::header::RETRY_AFTER, "60".parse().unwrap());
+    let throttle = Throttle::new(10, Duration::from_secs(1));
+    throttle.observe_at(now, StatusCode::TOO_MANY_REQUESTS, &headers);
+    assert_eq!(throttle.acquire_at(now), Duration::from_secs(60));
+
+    let disabled = Throttle::new(0, Duration::from_secs(1));
+    disabled.observe_at(now, StatusCode::TOO_MANY_REQUESTS, &headers);
+    assert_eq!(disabled.acquire_at(now), Duration::ZERO);
+}
+
+#[test]
+fn test_rate_limit() {
+    let mut headers = reqwest::header::HeaderMap::new();
+    assert_eq!(rate_limit(&headers), None);
+    headers.insert("ratelimit-remaining", "12".parse().unwrap());
+    headers.insert("ratelimit-reset", "40".parse().unwrap());
+    assert_eq!(rate_limit(&headers), Some((12, Duration::from_secs(40))));
+    headers.insert("ratelimit", "\"default\";r=5;t=20".parse().unwrap());
+    assert_eq!(rate_limit(&headers), Some((5, Duration::from_secs(20))));
+}
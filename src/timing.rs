@@ -0,0 +1,66 @@
+//! Phase timings for serving an asset, formatted as a `Server-Timing` header
+//! so real-user monitoring can attribute latency to index lookups and KV.
+
+use crate::clock;
+
+/// Durations of each phase of serving a request, in milliseconds
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ServerTiming {
+    metrics: Vec<(String, f64)>,
+}
+
+impl ServerTiming {
+    /// Creates an empty set of timings
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a phase with its duration in milliseconds. Workers may add their own phases
+    /// so they are reported in the same header.
+    pub fn add<T: ToString>(&mut self, name: T, millis: f64) {
+        self.metrics.push((name.to_string(), millis));
+    }
+
+    /// Recorded phases and durations (milliseconds), in the order they were added
+    pub fn metrics(&self) -> &[(String, f64)] {
+        &self.metrics
+    }
+
+    /// Returns true if no phases have been recorded
+    pub fn is_empty(&self) -> bool {
+        self.metrics.is_empty()
+    }
+
+    /// Value for the `Server-Timing` response header, e.g., `index;dur=0.05, kv;dur=42.10`
+    pub fn header_value(&self) -> String {
+        self.metrics
+            .iter()
+            .map(|(name, dur)| format!("{};dur={:.2}", name, dur))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Start time of a phase
+pub(crate) struct Timer(f64);
+
+impl Timer {
+    pub(crate) fn start() -> Self {
+        Timer(clock::now_millis())
+    }
+
+    /// Records the time since start as the named phase
+    pub(crate) fn stop(self, timing: &mut ServerTiming, name: &str) {
+        timing.add(name, clock::now_millis() - self.0);
+    }
+}
+
+#[test]
+fn test_header_value() {
+    let mut timing = ServerTiming::new();
+    assert!(timing.is_empty());
+    assert_eq!(timing.header_value(), "");
+    timing.add("index", 0.051);
+    timing.add("kv", 42.1);
+    assert_eq!(timing.header_value(), "index;dur=0.05, kv;dur=42.10");
+}
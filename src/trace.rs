@@ -0,0 +1,34 @@
+//! Spans for the `tracing` feature, so KV fetch latency shows up in worker logs.
+//!
+//! `lookup_key`, `get_asset`, and the KV client's HTTP calls run in spans named after the
+//! method, with the path or key. When a KV response arrives, its span records `status`,
+//! `latency_ms`, and, for reads, `bytes`. Without the feature, nothing is recorded.
+
+/// KV request in progress, recorded in the current span when the response arrives
+pub(crate) struct KvRequest {
+    #[cfg(feature = "tracing")]
+    started: f64,
+}
+
+impl KvRequest {
+    pub(crate) fn start() -> Self {
+        KvRequest {
+            #[cfg(feature = "tracing")]
+            started: crate::clock::now_millis(),
+        }
+    }
+
+    /// Records the response status and latency, and the body size, if it's known
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    pub(crate) fn finish(&self, status: u16, bytes: Option<usize>) {
+        #[cfg(feature = "tracing")]
+        {
+            let span = tracing::Span::current();
+            span.record("status", status);
+            span.record("latency_ms", crate::clock::now_millis() - self.started);
+            if let Some(bytes) = bytes {
+                span.record("bytes", bytes as u64);
+            }
+        }
+    }
+}
@@ -1,29 +1,202 @@
 #![cfg(not(target_arch = "wasm32"))]
 
-use crate::{AssetIndex, AssetMetadata, Error};
+use crate::{
+    assets::is_reserved,
+    bulk::{BulkClient, KeyFailure, UploadMetadata},
+    chunk_key, clock,
+    credentials::{global_user, load_profile, selected_profile},
+    decode_index, encode_index,
+    error_page::{check_error_document, ERROR_DOCUMENTS_PATH},
+    history::{deployer, history_key, HISTORY_PREFIX},
+    kv::METADATA_MAX_BYTES,
+    parse_redirects,
+    plan::{IndexDiff, PlanDestination, SyncPlan, PLAN_FORMAT_VERSION},
+    policy::{headers_for, parse_headers, HEADERS_PATH},
+    queue::RetryQueue,
+    redirect::REDIRECTS_PATH,
+    source::{is_archive, read_source, SourceFiles},
+    split_language,
+    template::{is_var_name, Templates},
+    AssetIndex, AssetMetadata, CachePolicy, DeployRecord, Error, HeaderRule, LocalKV, RedirectRule,
+};
+use cloudflare::endpoints::workerskv::write_bulk::KeyValuePair;
 use indicatif::{ProgressBar, ProgressStyle};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::{Path, PathBuf};
 use wrangler::{
     kv::bulk,
-    settings::{global_user::GlobalUser, toml::Manifest},
-    sites::{add_namespace, AssetManifest},
+    settings::toml::{Manifest, Target},
+    sites::{add_namespace, KEY_MAX_SIZE},
     terminal::message::{Message, StdErr},
 };
 
 const UPLOAD_PROGRESS_TEMPLATE: &str = "{wide_bar} {pos}/{len}\n{msg}";
 const DELETE_PROGRESS_TEMPLATE: &str = "{wide_bar} {pos}/{len}\n{msg}";
 
+#[derive(Clone)]
 pub struct SyncConfig<'sync> {
     /// Path to wrangler.toml. defaults to "wrangler.toml"
     pub wrangler_path: &'sync Path,
-    /// Path to asset source folder, default: "public"
+    /// Path to asset source folder, or a zip or tar (.tar, .tar.gz, .tgz) archive
+    /// whose files are read without extracting it. default: "public"
     pub asset_dir: &'sync Path,
     /// Path to output diretory: default: "data"
     pub output_path: &'sync Path,
     /// Remove stale files. Use this flag only after 'wrangler publish' completes. default: false
     pub prune: bool,
+    /// Don't prune keys uploaded less than this many seconds ago, which may belong to
+    /// a concurrent or just-finished sync of another version of the site.
+    /// Keys uploaded by earlier versions of kv-sync have no upload time and aren't spared.
+    /// default: None (prune all stale keys)
+    pub prune_grace: Option<u64>,
     /// True if using a preview environment. default=false
     pub preview_env: bool,
+    /// Expire uploaded assets from KV this many seconds after the sync. Must be at least 60.
+    /// When set, all assets are re-uploaded on each sync so their expiration is refreshed.
+    /// default: None (never expire)
+    pub expiration_ttl: Option<u64>,
+    /// Embed the contents of assets up to this size (in bytes) in the index,
+    /// so they can be served without reading KV. default: None
+    pub inline_max_size: Option<u64>,
+    /// Sync to a local directory (see `LocalKV`) instead of Workers KV,
+    /// for offline development. default: None
+    pub local_dir: Option<&'sync Path>,
+    /// Response headers to store in the index for assets matching each rule's pattern.
+    /// If several rules set the same header for an asset, the last one wins. default: none
+    pub header_rules: Vec<HeaderRule>,
+    /// `Cache-Control` rules embedded in the index for matching assets, unless a header
+    /// rule sets `Cache-Control` for the asset. default: no rules
+    pub cache_policy: CachePolicy,
+    /// Header rules stored in the index, which `handle_request` applies to responses for
+    /// matching request paths, including redirects and listings (see `load_headers_file`).
+    /// Unlike `header_rules`, they can be changed without re-uploading assets. default: none
+    pub response_header_rules: Vec<HeaderRule>,
+    /// Redirect rules embedded in the index, for `KVAssets::resolve` and `handle_request`
+    /// (see `load_redirects`). default: none
+    pub redirects: Vec<RedirectRule>,
+    /// File where uploads and deletes that still fail after retries are saved, to be
+    /// finished later with `retry_queued`. default: None (failures are only reported)
+    pub retry_queue: Option<&'sync Path>,
+    /// Also upload the index to this KV key, after the assets, for workers that load it
+    /// at runtime with `KVAssets::load_from_kv`. The key is never pruned. default: None
+    pub publish_index: Option<&'sync str>,
+    /// Profile in `~/.config/kv-assets/credentials` with the account, API token, and
+    /// namespace to use (see `Profile`). default: None (`KV_ASSETS_PROFILE`, if set)
+    pub profile: Option<&'sync str>,
+    /// Profiles of more namespaces (e.g., in other accounts, for white-label deployments)
+    /// to sync the same assets to. Each is uploaded to and pruned like the destination,
+    /// and reported in `SyncReport::replicas`. `publish_index` is published to the
+    /// destination and every replica only if all uploads succeeded. Failures in replicas
+    /// aren't saved in the retry queue. default: none
+    pub replicas: Vec<&'sync str>,
+    /// Glob patterns of text assets, e.g. `*.html`, in which `{{NAME}}` is replaced by the
+    /// value of `NAME` in `template_vars` before upload. default: none
+    pub templates: Vec<String>,
+    /// Values of template variables, such as those from `builtin_template_vars`,
+    /// and a build version. default: none
+    pub template_vars: BTreeMap<String, String>,
+    /// After a successful sync, store the index and a `DeployRecord` in the namespace,
+    /// for `deploy_history`. default: false
+    pub record_history: bool,
+    /// Also upload brotli and gzip compressed variants of assets that aren't already
+    /// compressed, for `KVAssets::get_asset_encoded` to serve to clients that accept them.
+    /// Variants that aren't smaller than the asset are skipped. default: false
+    pub precompress: bool,
+    /// Path prefixes, e.g. `private/`, of assets that workers only serve with a signed URL
+    /// (see `KVAssets::get_asset_signed`). default: none
+    pub protected: Vec<String>,
+    /// Language tags, e.g. `en` and `de`, of assets with language suffixes, such as
+    /// `index.en.html`, to group as variants of the path without the suffix (see
+    /// `KVAssets::get_asset_negotiated`). The first is the default: if there's no asset
+    /// at `index.html`, its entry serves the variant in the first language. default: none
+    pub languages: Vec<String>,
+    /// Error statuses and the paths of assets served as the body of error responses with
+    /// the status, e.g. `(404, "404.html")`, stored in the index for `handle_request`
+    /// (see `KVAssets::with_error_document`). default: none
+    pub error_documents: Vec<(u16, String)>,
+}
+
+/// Reads header rules from a json file containing an array of rules, e.g.,
+/// `[{"pattern": "downloads/**", "headers": {"Content-Disposition": "attachment"}}]`
+pub fn load_header_rules(path: &Path) -> Result<Vec<HeaderRule>, Error> {
+    let json =
+        std::fs::read(path).map_err(|e| Error::IO(format!("reading {}: {}", path.display(), e)))?;
+    serde_json::from_slice(&json)
+        .map_err(|e| Error::Message(format!("invalid header rules in {}: {}", path.display(), e)))
+}
+
+/// Reads header rules from a Netlify `_headers` file (see `parse_headers`), or, if the
+/// file name ends with `.toml`, from its `[[headers]]` tables, e.g.
+/// `[[headers]] for = "/*" [headers.values] X-Frame-Options = "DENY"`
+pub fn load_headers_file(path: &Path) -> Result<Vec<HeaderRule>, Error> {
+    #[derive(Deserialize)]
+    struct HeadersToml {
+        #[serde(default)]
+        headers: Vec<HeadersTable>,
+    }
+    #[derive(Deserialize)]
+    struct HeadersTable {
+        #[serde(rename = "for")]
+        path: String,
+        values: BTreeMap<String, String>,
+    }
+
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| Error::IO(format!("reading {}: {}", path.display(), e)))?;
+    let invalid =
+        |e: String| Error::Message(format!("invalid headers in {}: {}", path.display(), e));
+    if path.extension().is_some_and(|ext| ext == "toml") {
+        let parsed: HeadersToml = toml::from_str(&text).map_err(|e| invalid(e.to_string()))?;
+        Ok(parsed
+            .headers
+            .into_iter()
+            .map(|table| HeaderRule {
+                pattern: HeaderRule::path_glob(&table.path),
+                headers: table.values,
+            })
+            .collect())
+    } else {
+        parse_headers(&text).map_err(|e| invalid(e.to_string()))
+    }
+}
+
+/// Reads redirect rules from a Netlify `_redirects` file (see `parse_redirects`), or, if the
+/// file name ends with `.toml`, from its `[[redirects]]` tables, e.g.
+/// `[[redirects]] from = "/blog/*" to = "/news/:splat" status = 302`
+pub fn load_redirects(path: &Path) -> Result<Vec<RedirectRule>, Error> {
+    #[derive(Deserialize)]
+    struct RedirectsToml {
+        #[serde(default)]
+        redirects: Vec<RedirectRule>,
+    }
+
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| Error::IO(format!("reading {}: {}", path.display(), e)))?;
+    let invalid =
+        |e: String| Error::Message(format!("invalid redirects in {}: {}", path.display(), e));
+    if path.extension().is_some_and(|ext| ext == "toml") {
+        let parsed: RedirectsToml = toml::from_str(&text).map_err(|e| invalid(e.to_string()))?;
+        Ok(parsed.redirects)
+    } else {
+        parse_redirects(&text).map_err(|e| invalid(e.to_string()))
+    }
+}
+
+/// Returns the headers for an asset from the header rules, with `Cache-Control` from the
+/// cache policy if no rule sets it
+fn configured_headers(args: &SyncConfig, path: &str) -> Vec<(String, String)> {
+    let mut headers = headers_for(&args.header_rules, path);
+    if let Some(value) = args.cache_policy.cache_control(&[path]) {
+        if !headers
+            .iter()
+            .any(|(n, _)| n.eq_ignore_ascii_case("cache-control"))
+        {
+            headers.push(("Cache-Control".to_string(), value.to_string()));
+        }
+    }
+    headers
 }
 
 impl<'sync> Default for SyncConfig<'sync> {
@@ -33,11 +206,237 @@ impl<'sync> Default for SyncConfig<'sync> {
             asset_dir: Path::new("public"),
             output_path: Path::new("data"),
             prune: false,
+            prune_grace: None,
             preview_env: false,
+            expiration_ttl: None,
+            inline_max_size: None,
+            local_dir: None,
+            header_rules: Vec::new(),
+            cache_policy: CachePolicy::default(),
+            response_header_rules: Vec::new(),
+            redirects: Vec::new(),
+            retry_queue: None,
+            publish_index: None,
+            profile: None,
+            replicas: Vec::new(),
+            templates: Vec::new(),
+            template_vars: BTreeMap::new(),
+            record_history: false,
+            precompress: false,
+            protected: Vec::new(),
+            languages: Vec::new(),
+            error_documents: Vec::new(),
+        }
+    }
+}
+
+impl<'sync> SyncConfig<'sync> {
+    /// Assets to substitute variables into, and the variables
+    pub(crate) fn templates(&self) -> Templates<'_> {
+        Templates {
+            patterns: &self.templates,
+            vars: &self.template_vars,
+        }
+    }
+
+    /// Checks paths, wrangler config, credentials, and options, without making any changes.
+    /// Returns all problems found, so they can be reported together before starting a sync.
+    pub fn validate(&self) -> Result<(), Vec<Error>> {
+        self.check(self.local_dir.is_none())
+    }
+
+    /// Checks the config, and Cloudflare credentials if `needs_credentials`
+    fn check(&self, needs_credentials: bool) -> Result<(), Vec<Error>> {
+        let mut errors = Vec::new();
+        let asset_dir_ok = match std::fs::metadata(self.asset_dir) {
+            Ok(md) => md.is_dir() || (md.is_file() && is_archive(self.asset_dir)),
+            Err(_) => false,
+        };
+        if asset_dir_ok {
+            if self.asset_dir.is_dir() {
+                if let Err(e) = wrangler::commands::publish::validate_bucket_location(
+                    &PathBuf::from(self.asset_dir),
+                ) {
+                    errors.push(e.into());
+                }
+            }
+        } else {
+            errors.push(Error::InvalidAssetPath(
+                self.asset_dir.to_string_lossy().to_string(),
+            ));
+        }
+        if matches!(std::fs::metadata(self.wrangler_path), Ok(md) if md.is_file()) {
+            if let Err(e) =
+                Manifest::new(self.wrangler_path).and_then(|m| m.get_target(None, self.preview_env))
+            {
+                errors.push(e.into());
+            }
+        } else {
+            errors.push(Error::MissingWranglerFile(
+                self.wrangler_path.to_string_lossy().to_string(),
+            ));
+        }
+        if self.output_path.is_dir() {
+            errors.push(Error::InvalidAssetsBinPath(format!(
+                "{} is a directory",
+                self.output_path.display()
+            )));
+        } else if self.output_path.parent().is_none() {
+            errors.push(Error::InvalidAssetsBinPath(
+                "Must not be in root dir".into(),
+            ));
+        }
+        if asset_dir_ok {
+            // the index, or the local kv, would be synced as an asset
+            if is_within(self.output_path, self.asset_dir) {
+                errors.push(Error::InvalidAssetsBinPath(format!(
+                    "{} must not be inside the asset dir",
+                    self.output_path.display()
+                )));
+            }
+            if let Some(dir) = self.local_dir.filter(|dir| is_within(dir, self.asset_dir)) {
+                errors.push(Error::Message(format!(
+                    "local kv dir {} must not be inside the asset dir",
+                    dir.display()
+                )));
+            }
+        }
+        if let Some(queue) = self.retry_queue.filter(|path| path.is_dir()) {
+            errors.push(Error::Message(format!(
+                "retry queue {} is a directory",
+                queue.display()
+            )));
+        }
+        if let Some(key) = self.publish_index {
+            if key.is_empty() || key.len() > KEY_MAX_SIZE {
+                errors.push(Error::Message(format!(
+                    "index key must be 1 to {} bytes",
+                    KEY_MAX_SIZE
+                )));
+            }
+        }
+        for name in self.template_vars.keys().filter(|name| !is_var_name(name)) {
+            errors.push(Error::Message(format!(
+                "invalid template variable name {:?}: use letters, digits, and '_'",
+                name
+            )));
+        }
+        if matches!(self.expiration_ttl, Some(ttl) if ttl < 60) {
+            errors.push(Error::TTLTooShort);
+        }
+        for rule in self
+            .header_rules
+            .iter()
+            .chain(self.response_header_rules.iter())
+        {
+            if rule.pattern.is_empty() {
+                errors.push(Error::Message("header rule has an empty pattern".into()));
+            }
+            for (name, value) in rule.headers.iter() {
+                let valid_name = !name.is_empty()
+                    && name
+                        .bytes()
+                        .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+                if !valid_name || value.contains(['\r', '\n']) {
+                    errors.push(Error::Message(format!(
+                        "invalid header '{}' in rule for {}",
+                        name, rule.pattern
+                    )));
+                }
+            }
+        }
+        for (pattern, value) in self.cache_policy.rules.iter() {
+            if pattern.is_empty() || value.is_empty() || value.contains(['\r', '\n']) {
+                errors.push(Error::Message(format!(
+                    "invalid cache rule {:?} -> {:?}",
+                    pattern, value
+                )));
+            }
+        }
+        for rule in self.redirects.iter() {
+            if let Err(e) = rule.check() {
+                errors.push(e);
+            }
+        }
+        for (status, path) in self.error_documents.iter() {
+            if let Err(e) = check_error_document(*status, path) {
+                errors.push(e);
+            }
+        }
+        if needs_credentials {
+            if let Err(e) = selected_profile(self.profile).and_then(|p| global_user(p.as_ref())) {
+                errors.push(e);
+            }
+        }
+        for replica in self.replicas.iter() {
+            if let Err(e) = load_profile(replica).and_then(|p| global_user(Some(&p))) {
+                errors.push(e);
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
         }
     }
 }
 
+/// Returns true if path is dir or is inside it. Paths that don't exist yet are compared
+/// by their nearest existing ancestor.
+fn is_within(path: &Path, dir: &Path) -> bool {
+    let dir = match dir.canonicalize() {
+        Ok(dir) => dir,
+        Err(_) => return false,
+    };
+    path.ancestors()
+        .filter_map(|p| {
+            let p = if p.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                p
+            };
+            p.canonicalize().ok()
+        })
+        .next()
+        .map(|p| p.starts_with(&dir))
+        .unwrap_or(false)
+}
+
+/// Summary of a sync
+#[derive(Clone, Debug, Default)]
+pub struct SyncReport {
+    /// Number of files uploaded
+    pub uploaded: usize,
+    /// Number of stale files deleted
+    pub deleted: usize,
+    /// Number of stale files not deleted, because prune was not enabled
+    pub deferred_prune: usize,
+    /// Files that could not be uploaded, after retries
+    pub upload_failures: Vec<KeyFailure>,
+    /// Stale files that could not be deleted, after retries
+    pub delete_failures: Vec<KeyFailure>,
+    /// Results for each replica (see `SyncConfig::replicas`)
+    pub replicas: Vec<ReplicaReport>,
+}
+
+/// Summary of the sync to one replica
+#[derive(Clone, Debug, Default)]
+pub struct ReplicaReport {
+    /// Profile of the replica
+    pub profile: String,
+    /// Uploads, deletes, and failures in the replica
+    pub report: SyncReport,
+}
+
+impl SyncReport {
+    /// Returns true if all uploads and deletes succeeded, including those to replicas
+    pub fn is_success(&self) -> bool {
+        self.upload_failures.is_empty()
+            && self.delete_failures.is_empty()
+            && self.replicas.iter().all(|r| r.report.is_success())
+    }
+}
+
 // Status of asset manifest
 enum Update {
     New,
@@ -50,125 +449,859 @@ enum Update {
 /// - upload new files
 /// - generate the manifest
 /// - if the prune option is set, remove unreferenced files in the KV namespace
-/// All the file system scanning and kv uploading is performed by wrangler library
-pub fn sync_assets(args: SyncConfig) -> Result<(), Error> {
-    // validate parameters
-    match std::fs::metadata(&args.asset_dir) {
-        Ok(md) if md.is_dir() => {}
-        _ => {
-            return Err(Error::InvalidAssetPath(
-                args.asset_dir.to_string_lossy().to_string(),
-            ))
-        }
+///
+/// All the file system scanning and kv uploading is performed by wrangler library.
+/// If `local_dir` is set, files are synced to a `LocalKV` directory instead.
+///
+/// If some keys can't be uploaded or deleted, the rest of the sync continues, and the
+/// failed keys are listed in the returned report. Check `SyncReport::is_success`.
+pub fn sync_assets(args: SyncConfig) -> Result<SyncReport, Error> {
+    let prepared = prepare(&args)?;
+    let mut replicas = Vec::new();
+    for profile in args.replicas.iter() {
+        let replica_args = SyncConfig {
+            local_dir: None,
+            profile: Some(profile),
+            ..args.clone()
+        };
+        replicas.push((profile.to_string(), prepare(&replica_args)?));
     }
-    match std::fs::metadata(&args.wrangler_path) {
-        Ok(md) if md.is_file() => {}
-        _ => {
-            return Err(Error::MissingWranglerFile(
-                args.wrangler_path.to_string_lossy().to_string(),
-            ))
-        }
+    execute(&args, prepared, replicas)
+}
+
+/// Determines the changes `sync_assets` would make, without making them (except that the
+/// site's KV namespace is created if it doesn't exist yet). Save the plan for review,
+/// and execute it later with `apply_plan`.
+pub fn plan_sync(args: SyncConfig) -> Result<SyncPlan, Error> {
+    if !args.replicas.is_empty() {
+        return Err(Error::Message("plans don't support replicas".into()));
     }
-    wrangler::commands::publish::validate_bucket_location(&PathBuf::from(args.asset_dir))?;
+    Ok(prepare(&args)?.plan)
+}
 
-    // create parent of output dir
+/// Executes a plan created by `plan_sync`. `args` must have the same wrangler config
+/// and asset dir; the destination, prune, expiration, and index are taken from the plan.
+/// Fails without making changes if the plan is for a different account,
+/// or a planned upload no longer matches the files in the asset dir.
+pub fn apply_plan(args: SyncConfig, plan: &SyncPlan) -> Result<SyncReport, Error> {
+    if !args.replicas.is_empty() {
+        return Err(Error::Message("plans don't support replicas".into()));
+    }
+    let args = SyncConfig {
+        local_dir: match &plan.destination {
+            PlanDestination::Local { dir } => Some(dir.as_path()),
+            PlanDestination::Remote { .. } => None,
+        },
+        expiration_ttl: None,
+        // so templates are rendered the same as when the plan was made
+        template_vars: plan.template_vars.clone(),
+        precompress: plan.index.values().any(|md| !md.encoded.is_empty()),
+        ..args
+    };
+    if let Err(mut errors) = args.validate() {
+        return Err(errors.remove(0));
+    }
+    let manifest = Manifest::new(args.wrangler_path)?;
+    let target = manifest.get_target(None, args.preview_env)?;
+    let dest = Destination::open(&args, &plan.destination)?;
+    let source = read_source(&target, args.asset_dir, args.templates(), args.precompress)?;
+    let metadata = key_metadata(&args, &source);
+    let mut pairs: HashMap<String, KeyValuePair> = source
+        .pairs
+        .into_iter()
+        .map(|p| (p.key.clone(), p))
+        .collect();
+    let uploads = plan
+        .uploads
+        .iter()
+        .map(|key| {
+            let mut pair = pairs.remove(key).ok_or_else(|| {
+                Error::Message(format!(
+                    "plan is out of date: {} is not in {}",
+                    key,
+                    args.asset_dir.display()
+                ))
+            })?;
+            pair.expiration = plan.expires.map(|exp| exp as i64);
+            Ok(pair)
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    execute(
+        &args,
+        Prepared {
+            dest,
+            uploads,
+            metadata,
+            plan: plan.clone(),
+        },
+        Vec::new(),
+    )
+}
+
+/// Retries the uploads and deletes saved in `args.retry_queue` by an earlier sync.
+/// Operations that fail again stay in the queue; the queue file is removed when it's empty.
+/// The index is not changed. Only `wrangler_path`, `preview_env`, and `retry_queue`
+/// are used from `args`.
+pub fn retry_queued(args: SyncConfig) -> Result<SyncReport, Error> {
+    let path = args
+        .retry_queue
+        .ok_or_else(|| Error::Message("no retry queue file configured".into()))?;
+    let mut queue = match RetryQueue::load(path)? {
+        Some(queue) if !queue.is_empty() => queue,
+        _ => return Ok(SyncReport::default()),
+    };
+    let dest = Destination::open(&args, &queue.destination)?;
+    StdErr::working(&format!(
+        "Retrying {} uploads and {} deletes",
+        queue.uploads.len(),
+        queue.deletes.len()
+    ));
+    let mut report = SyncReport::default();
+    let uploads = std::mem::take(&mut queue.uploads);
+    report.upload_failures = dest.put(&uploads, &HashMap::new(), &None)?;
+    report.uploaded = uploads.len() - report.upload_failures.len();
+    let deletes = std::mem::take(&mut queue.deletes);
+    let delete_count = deletes.len();
+    report.delete_failures = dest.delete(deletes, &None)?;
+    report.deleted = delete_count - report.delete_failures.len();
+
+    let failed: HashSet<&str> = report
+        .upload_failures
+        .iter()
+        .map(|f| f.key.as_str())
+        .collect();
+    queue.uploads = uploads
+        .into_iter()
+        .filter(|p| failed.contains(p.key.as_str()))
+        .collect();
+    queue.deletes = report
+        .delete_failures
+        .iter()
+        .map(|f| f.key.clone())
+        .collect();
+    queue.save(path)?;
+    Ok(report)
+}
+
+/// Writes an index with the contents of every asset embedded, for a worker that serves
+/// entirely from memory with `KVAssets::embedded`. Nothing is uploaded, and Cloudflare
+/// credentials aren't needed. Only the wrangler config, asset dir, output path, and header
+/// rules are used from `args`. Returns the number of assets.
+pub fn embed_assets(args: SyncConfig) -> Result<usize, Error> {
+    let args = SyncConfig {
+        local_dir: None,
+        expiration_ttl: None,
+        inline_max_size: Some(u64::MAX),
+        precompress: false,
+        retry_queue: None,
+        publish_index: None,
+        ..args
+    };
+    if let Err(mut errors) = args.check(false) {
+        return Err(errors.remove(0));
+    }
+    let manifest = Manifest::new(args.wrangler_path)?;
+    let target = manifest.get_target(None, args.preview_env)?;
+    let source = read_source(&target, args.asset_dir, args.templates(), args.precompress)?;
+    let index = make_index(&args, &source, None)?;
+    let count = source.assets.len();
     mkdir_bin_parent(args.output_path)?;
+    write_index(&args, index)?;
+    Ok(count)
+}
+
+/// A planned sync, with its destination and upload contents
+struct Prepared {
+    dest: Destination,
+    uploads: Vec<KeyValuePair>,
+    metadata: HashMap<String, UploadMetadata>,
+    plan: SyncPlan,
+}
+
+/// Determines which files to upload and delete, and generates the index
+fn prepare(args: &SyncConfig) -> Result<Prepared, Error> {
+    // validate parameters. Use SyncConfig::validate to get all problems, not just the first
+    if let Err(mut errors) = args.validate() {
+        return Err(errors.remove(0));
+    }
+    let expires = args.expiration_ttl.map(|ttl| clock::now_secs() + ttl);
 
     let manifest = Manifest::new(args.wrangler_path)?;
     let mut target = manifest.get_target(None, args.preview_env)?;
-    let user = GlobalUser::new()?;
 
-    let site_namespace = add_namespace(&user, &mut target, false)?;
-    let (to_upload, to_delete, asset_manifest) =
-        wrangler::sites::sync(&target, &user, &site_namespace.id, &args.asset_dir)?;
+    let (dest, destination, existing) = match args.local_dir {
+        Some(dir) => {
+            let local = LocalKV::open(dir)?;
+            StdErr::working(&format!("Using local kv in {}", dir.display()));
+            let mut existing = HashMap::new();
+            for key in local.keys()? {
+                let uploaded = UploadMetadata::uploaded(local.metadata(&key)?.as_ref());
+                existing.insert(key, uploaded);
+            }
+            (
+                Destination::Local(local),
+                PlanDestination::Local {
+                    dir: dir.to_path_buf(),
+                },
+                existing,
+            )
+        }
+        None => {
+            let (client, namespace_id) = connect_remote(args, &mut target)?;
+            let existing: HashMap<String, Option<u64>> = client
+                .list_keys()?
+                .into_iter()
+                .map(|k| {
+                    let uploaded = UploadMetadata::uploaded(k.metadata.as_ref());
+                    (k.name, uploaded)
+                })
+                .collect();
+            (
+                Destination::Remote(Box::new(client)),
+                PlanDestination::Remote {
+                    account_id: target.account_id.clone(),
+                    namespace_id,
+                },
+                existing,
+            )
+        }
+    };
 
-    let index = make_index(&args.asset_dir, asset_manifest)?;
-    write_index(&args, index)?;
+    let source = read_source(&target, args.asset_dir, args.templates(), args.precompress)?;
+    let metadata = key_metadata(args, &source);
+    let current: HashSet<&str> = source.pairs.iter().map(|p| p.key.as_str()).collect();
+    // keys uploaded within the grace period are kept
+    let grace_start = args
+        .prune_grace
+        .map(|grace| clock::now_secs().saturating_sub(grace));
+    let mut recent = 0;
+    let mut to_delete: Vec<String> = Vec::new();
+    for (key, uploaded) in existing.iter() {
+        if current.contains(key.as_str())
+            || Some(key.as_str()) == args.publish_index
+            || key.starts_with(HISTORY_PREFIX)
+        {
+            continue;
+        }
+        match (uploaded, grace_start) {
+            (Some(uploaded), Some(start)) if *uploaded > start => recent += 1,
+            _ => to_delete.push(key.clone()),
+        }
+    }
+    to_delete.sort();
+    if recent > 0 {
+        StdErr::info(&format!(
+            "Keeping {} unreferenced keys uploaded in the last {} seconds",
+            recent,
+            args.prune_grace.unwrap_or_default()
+        ));
+    }
+    let index = make_index(args, &source, expires)?;
+
+    // With a TTL, unchanged files would otherwise keep the expiration from a previous upload,
+    // so upload everything with the same expiration that is recorded in the index.
+    let to_upload: Vec<KeyValuePair> = source
+        .pairs
+        .into_iter()
+        .filter(|p| expires.is_some() || !existing.contains_key(&p.key))
+        .map(|mut pair| {
+            pair.expiration = expires.map(|exp| exp as i64);
+            pair
+        })
+        .collect();
+
+    // a missing or unreadable index is treated as empty
+    let old_index = std::fs::read(args.output_path)
+        .ok()
+        .and_then(|bytes| decode_index(&bytes).ok())
+        .unwrap_or_default();
+    let (deletes, deferred_prune) = if args.prune {
+        (to_delete, 0)
+    } else {
+        let count = to_delete.len();
+        (Vec::new(), count)
+    };
+    let plan = SyncPlan {
+        version: PLAN_FORMAT_VERSION,
+        created: clock::now_secs(),
+        destination,
+        uploads: to_upload.iter().map(|p| p.key.clone()).collect(),
+        deletes,
+        deferred_prune,
+        expires,
+        index_diff: IndexDiff::between(&old_index, &index),
+        index,
+        template_vars: args.template_vars.clone(),
+    };
+    Ok(Prepared {
+        dest,
+        uploads: to_upload,
+        metadata,
+        plan,
+    })
+}
+
+/// Writes the index, uploads files to the destination and any replicas, publishes the
+/// index if every upload succeeded, and deletes stale keys
+fn execute(
+    args: &SyncConfig,
+    prepared: Prepared,
+    replicas: Vec<(String, Prepared)>,
+) -> Result<SyncReport, Error> {
+    let Prepared {
+        dest,
+        uploads: to_upload,
+        metadata,
+        plan,
+    } = prepared;
+
+    let index_blob = encode_index(&plan.index)?;
+    let asset_count = plan.index.keys().filter(|path| !is_reserved(path)).count();
+    // create parent of output dir
+    mkdir_bin_parent(args.output_path)?;
+    write_index(args, plan.index)?;
+
+    let mut report = SyncReport {
+        deferred_prune: plan.deferred_prune,
+        ..Default::default()
+    };
+    let mut replica_reports: Vec<SyncReport> = replicas
+        .iter()
+        .map(|(_, replica)| SyncReport {
+            deferred_prune: replica.plan.deferred_prune,
+            ..Default::default()
+        })
+        .collect();
 
     // First, upload all existing files in asset_dir directory
     StdErr::working("Uploading site files");
-    let upload_progress_bar = make_progress_bar(to_upload.len(), UPLOAD_PROGRESS_TEMPLATE);
-    bulk::put(
-        &target,
-        &user,
-        &site_namespace.id,
-        to_upload,
-        &upload_progress_bar,
-    )?;
-
-    if let Some(pb) = upload_progress_bar {
-        pb.finish_with_message("Done Uploading");
+    upload_to(&dest, &to_upload, &metadata, &mut report)?;
+    for ((profile, replica), replica_report) in replicas.iter().zip(replica_reports.iter_mut()) {
+        StdErr::working(&format!("Uploading site files to {}", profile));
+        upload_to(
+            &replica.dest,
+            &replica.uploads,
+            &replica.metadata,
+            replica_report,
+        )?;
+    }
+
+    // Publish the index after the assets, so it never refers to keys that aren't uploaded yet.
+    // With replicas, it's published to all of them or none.
+    if let Some(key) = args.publish_index {
+        let complete = report.upload_failures.is_empty()
+            && replica_reports.iter().all(|r| r.upload_failures.is_empty());
+        publish_to(&dest, key, &index_blob, complete, &mut report)?;
+        for ((_, replica), replica_report) in replicas.iter().zip(replica_reports.iter_mut()) {
+            publish_to(&replica.dest, key, &index_blob, complete, replica_report)?;
+        }
     }
 
     // Finally, remove any stale files
-    if !to_delete.is_empty() {
-        if args.prune {
-            StdErr::info("Pruning stale files...");
-            let delete_progress_bar = make_progress_bar(to_delete.len(), DELETE_PROGRESS_TEMPLATE);
-            bulk::delete(
-                &target,
-                &user,
-                &site_namespace.id,
-                to_delete,
-                &delete_progress_bar,
-            )?;
-
-            if let Some(pb) = delete_progress_bar {
-                pb.finish_with_message("Done deleting");
-            }
-        } else {
-            StdErr::message(&format!(
-                "Deferred pruning [{}] stale files. Run with '--prune' later to remove them.",
-                to_delete.len()
-            ));
+    prune_in(&dest, plan.deletes, &mut report)?;
+    let mut replica_profiles = Vec::new();
+    for ((profile, replica), replica_report) in replicas.into_iter().zip(replica_reports.iter_mut())
+    {
+        prune_in(&replica.dest, replica.plan.deletes, replica_report)?;
+        if args.record_history {
+            record_to(&replica.dest, &index_blob, asset_count, replica_report)?;
         }
+        replica_profiles.push(profile);
+    }
+    if args.record_history {
+        record_to(&dest, &index_blob, asset_count, &mut report)?;
+    }
+    if report.deferred_prune > 0 {
+        StdErr::message(&format!(
+            "Deferred pruning [{}] stale files. Run with '--prune' later to remove them.",
+            report.deferred_prune
+        ));
+    }
+    if let Some(path) = args.retry_queue.filter(|_| !report.is_success()) {
+        let failed: HashSet<&str> = report
+            .upload_failures
+            .iter()
+            .map(|f| f.key.as_str())
+            .collect();
+        let uploads = to_upload
+            .into_iter()
+            .filter(|p| failed.contains(p.key.as_str()))
+            .collect();
+        let deletes = report
+            .delete_failures
+            .iter()
+            .map(|f| f.key.clone())
+            .collect();
+        // a queue for another destination is replaced
+        let destination = plan.destination;
+        let mut queue = RetryQueue::load(path)?
+            .filter(|q| q.destination == destination)
+            .unwrap_or_else(|| RetryQueue::new(destination.clone()));
+        queue.push(uploads, deletes);
+        queue.save(path)?;
+        StdErr::warn(&format!(
+            "Saved failed operations to {}. Run 'kv-sync retry' later to finish them.",
+            path.display()
+        ));
+    }
+    report.replicas = replica_profiles
+        .into_iter()
+        .zip(replica_reports)
+        .map(|(profile, report)| ReplicaReport { profile, report })
+        .collect();
+    Ok(report)
+}
+
+/// Uploads the pairs, adding the results to the report
+fn upload_to(
+    dest: &Destination,
+    pairs: &[KeyValuePair],
+    metadata: &HashMap<String, UploadMetadata>,
+    report: &mut SyncReport,
+) -> Result<(), Error> {
+    let progress_bar = make_progress_bar(pairs.len(), UPLOAD_PROGRESS_TEMPLATE);
+    report.upload_failures = dest.put(pairs, metadata, &progress_bar)?;
+    report.uploaded = pairs.len() - report.upload_failures.len();
+    if let Some(pb) = progress_bar {
+        pb.finish_with_message("Done Uploading");
     }
     Ok(())
 }
 
-/// Generates the asset manifest
-fn make_index(asset_dir: &Path, asset_manifest: AssetManifest) -> Result<AssetIndex, Error> {
-    use std::time::SystemTime;
+/// Uploads the index to `key`, or if `complete` is false, reports it as failed
+fn publish_to(
+    dest: &Destination,
+    key: &str,
+    blob: &[u8],
+    complete: bool,
+    report: &mut SyncReport,
+) -> Result<(), Error> {
+    let failures = if complete {
+        let pair = KeyValuePair {
+            key: key.to_string(),
+            value: base64::encode(blob),
+            expiration: None,
+            expiration_ttl: None,
+            base64: Some(true),
+        };
+        dest.put(&[pair], &HashMap::new(), &None)?
+    } else {
+        vec![KeyFailure {
+            key: key.to_string(),
+            error: "index not published, because some assets failed to upload".into(),
+        }]
+    };
+    report.upload_failures.extend(failures);
+    Ok(())
+}
 
+/// Stores the index with a deploy record, if the sync succeeded
+fn record_to(
+    dest: &Destination,
+    blob: &[u8],
+    assets: usize,
+    report: &mut SyncReport,
+) -> Result<(), Error> {
+    if !report.is_success() {
+        return Ok(());
+    }
+    let deployed = clock::now_secs();
+    let key = history_key(deployed);
+    let record = DeployRecord {
+        key: String::new(),
+        deployed,
+        index_sha256: hex::encode(Sha256::digest(blob)),
+        assets,
+        uploaded: report.uploaded,
+        deleted: report.deleted,
+        deployer: deployer(),
+    };
+    let mut metadata = HashMap::new();
+    metadata.insert(
+        key.clone(),
+        UploadMetadata {
+            deploy: Some(record),
+            ..Default::default()
+        },
+    );
+    let pair = KeyValuePair {
+        key,
+        value: base64::encode(blob),
+        expiration: None,
+        expiration_ttl: None,
+        base64: Some(true),
+    };
+    report
+        .upload_failures
+        .extend(dest.put(&[pair], &metadata, &None)?);
+    Ok(())
+}
+
+/// Deletes stale keys, adding the results to the report
+fn prune_in(
+    dest: &Destination,
+    deletes: Vec<String>,
+    report: &mut SyncReport,
+) -> Result<(), Error> {
+    if deletes.is_empty() {
+        return Ok(());
+    }
+    StdErr::info(&format!("Pruning {} stale files...", deletes.len()));
+    let progress_bar = make_progress_bar(deletes.len(), DELETE_PROGRESS_TEMPLATE);
+    let delete_count = deletes.len();
+    report.delete_failures = dest.delete(deletes, &progress_bar)?;
+    report.deleted = delete_count - report.delete_failures.len();
+    if let Some(pb) = progress_bar {
+        pb.finish_with_message("Done deleting");
+    }
+    Ok(())
+}
+
+/// Connects to the site's KV namespace, with the account, namespace, and API token
+/// of the selected profile, if any. Returns the client and namespace id.
+pub(crate) fn connect_remote(
+    args: &SyncConfig,
+    target: &mut Target,
+) -> Result<(BulkClient, String), Error> {
+    let profile = selected_profile(args.profile)?;
+    let user = global_user(profile.as_ref())?;
+    let profile = profile.unwrap_or_default();
+    if let Some(account_id) = profile.account_id {
+        target.account_id = account_id;
+    }
+    let namespace_id = match profile.namespace_id {
+        Some(namespace_id) => namespace_id,
+        None => add_namespace(&user, target, false)?.id,
+    };
+    wrangler::commands::kv::validate_target(target)?;
+    let client = BulkClient::new(&user, &target.account_id, &namespace_id)?;
+    Ok((client, namespace_id))
+}
+
+/// Opens the local kv dir, if set, or the site's namespace
+pub(crate) fn open_destination(args: &SyncConfig) -> Result<Destination, Error> {
+    match args.local_dir {
+        Some(dir) => Ok(Destination::Local(LocalKV::open(dir)?)),
+        None => {
+            let manifest = Manifest::new(args.wrangler_path)?;
+            let mut target = manifest.get_target(None, args.preview_env)?;
+            let (client, _) = connect_remote(args, &mut target)?;
+            Ok(Destination::Remote(Box::new(client)))
+        }
+    }
+}
+
+/// Where assets are synced to
+pub(crate) enum Destination {
+    /// KV namespace of the Workers Site
+    Remote(Box<BulkClient>),
+    /// Local directory, for offline development
+    Local(LocalKV),
+}
+
+impl Destination {
+    /// Connects to the destination of a plan or retry queue. For a remote destination,
+    /// the account must match the profile, if any, or the wrangler config.
+    fn open(args: &SyncConfig, destination: &PlanDestination) -> Result<Self, Error> {
+        match destination {
+            PlanDestination::Local { dir } => Ok(Destination::Local(LocalKV::open(dir)?)),
+            PlanDestination::Remote {
+                account_id,
+                namespace_id,
+            } => {
+                let manifest = Manifest::new(args.wrangler_path)?;
+                let target = manifest.get_target(None, args.preview_env)?;
+                let profile = selected_profile(args.profile)?;
+                let (expected, source) = match profile.as_ref().and_then(|p| p.account_id.as_ref())
+                {
+                    Some(profile_account) => (profile_account, "the profile".to_string()),
+                    None => (&target.account_id, args.wrangler_path.display().to_string()),
+                };
+                if expected != account_id {
+                    return Err(Error::Message(format!(
+                        "destination is in account {}, but {} has account {}",
+                        account_id, source, expected
+                    )));
+                }
+                let user = global_user(profile.as_ref())?;
+                Ok(Destination::Remote(Box::new(BulkClient::new(
+                    &user,
+                    account_id,
+                    namespace_id,
+                )?)))
+            }
+        }
+    }
+
+    /// Uploads key-value pairs (with base64-encoded values) and their metadata,
+    /// returning the keys that failed
+    fn put(
+        &self,
+        pairs: &[KeyValuePair],
+        metadata: &HashMap<String, UploadMetadata>,
+        progress_bar: &Option<ProgressBar>,
+    ) -> Result<Vec<KeyFailure>, Error> {
+        match self {
+            Destination::Remote(client) => client.put(pairs, metadata, progress_bar),
+            Destination::Local(local) => {
+                let mut failures = Vec::new();
+                for pair in pairs.iter() {
+                    let result = base64::decode(&pair.value)
+                        .map_err(|e| Error::Message(format!("base64 error: {}", e)))
+                        .and_then(|value| {
+                            let ttl = pair
+                                .expiration
+                                .map(|exp| (exp as u64).saturating_sub(clock::now_secs()).max(60));
+                            let metadata = serde_json::to_value(UploadMetadata {
+                                uploaded: clock::now_secs(),
+                                ..metadata.get(&pair.key).cloned().unwrap_or_default()
+                            })
+                            .ok();
+                            local.put_with_metadata(&pair.key, &value, ttl, metadata)
+                        });
+                    if let Err(e) = result {
+                        failures.push(KeyFailure {
+                            key: pair.key.clone(),
+                            error: e.to_string(),
+                        });
+                    }
+                    if let Some(pb) = progress_bar {
+                        pb.inc(1);
+                    }
+                }
+                Ok(failures)
+            }
+        }
+    }
+
+    /// Lists all keys, with their metadata
+    pub(crate) fn list_metadata(&self) -> Result<Vec<(String, Option<serde_json::Value>)>, Error> {
+        match self {
+            Destination::Remote(client) => Ok(client
+                .list_keys()?
+                .into_iter()
+                .map(|k| (k.name, k.metadata))
+                .collect()),
+            Destination::Local(local) => local
+                .keys()?
+                .into_iter()
+                .map(|key| {
+                    let metadata = local.metadata(&key)?;
+                    Ok((key, metadata))
+                })
+                .collect(),
+        }
+    }
+
+    /// Lists all keys, with their expirations (local keys don't expire)
+    pub(crate) fn list_expirations(&self) -> Result<Vec<(String, Option<u64>)>, Error> {
+        match self {
+            Destination::Remote(client) => Ok(client
+                .list_keys()?
+                .into_iter()
+                .map(|k| (k.name, k.expiration))
+                .collect()),
+            Destination::Local(local) => Ok(local.keys()?.into_iter().map(|k| (k, None)).collect()),
+        }
+    }
+
+    /// Reads a value
+    pub(crate) fn get(&self, key: &str) -> Result<Vec<u8>, Error> {
+        match self {
+            Destination::Remote(client) => client.get_value(key),
+            Destination::Local(local) => Ok(local.get(key)?.to_vec()),
+        }
+    }
+
+    /// Deletes keys, returning the keys that failed
+    fn delete(
+        &self,
+        keys: Vec<String>,
+        progress_bar: &Option<ProgressBar>,
+    ) -> Result<Vec<KeyFailure>, Error> {
+        match self {
+            Destination::Remote(client) => client.delete(keys, progress_bar),
+            Destination::Local(local) => {
+                let mut failures = Vec::new();
+                for key in keys.into_iter() {
+                    if let Err(e) = local.delete(&key) {
+                        failures.push(KeyFailure {
+                            key,
+                            error: e.to_string(),
+                        });
+                    }
+                    if let Some(pb) = progress_bar {
+                        pb.inc(1);
+                    }
+                }
+                Ok(failures)
+            }
+        }
+    }
+}
+
+/// Generates the asset manifest
+fn make_index(
+    args: &SyncConfig,
+    source: &SourceFiles,
+    expires: Option<u64>,
+) -> Result<AssetIndex, Error> {
+    let values: HashMap<&str, &str> = source
+        .pairs
+        .iter()
+        .map(|p| (p.key.as_str(), p.value.as_str()))
+        .collect();
     let mut index: AssetIndex = AssetIndex::new();
-    for (k, v) in asset_manifest.into_iter() {
-        let asset_path = asset_dir.join(&k);
-        let md = std::fs::metadata(&asset_path).map_err(|e| {
-            Error::IO(format!(
-                "failed reading asset file {}: {}",
-                &asset_path.display(),
-                e.to_string()
-            ))
-        })?;
-        let modified = md
-            .modified()
-            .unwrap_or_else(|_| {
-                panic!(
-                    "Can't read modified time of {}. Please fix or run on a different platform",
-                    &asset_path.display()
-                )
-            })
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap_or_else(|_| panic!("Invalid timestamp for file {}", &asset_path.display()))
-            .as_secs();
+    for (path, asset) in source.assets.iter() {
+        let inline = match args.inline_max_size {
+            Some(max) if asset.size <= max => {
+                let mut content = Vec::with_capacity(asset.size as usize);
+                let keys = match asset.chunks {
+                    0 => vec![asset.key.clone()],
+                    n => (0..n).map(|i| chunk_key(&asset.key, i)).collect(),
+                };
+                for key in keys.iter() {
+                    let value = values
+                        .get(key.as_str())
+                        .ok_or_else(|| Error::Message(format!("missing value for {}", key)))?;
+                    content.extend(
+                        base64::decode(value)
+                            .map_err(|e| Error::Message(format!("base64 error: {}", e)))?,
+                    );
+                }
+                Some(content)
+            }
+            _ => None,
+        };
+        index.insert(
+            path.clone(),
+            AssetMetadata {
+                path: asset.key.clone(),
+                size: asset.size,
+                modified: asset.modified,
+                expires,
+                headers: configured_headers(args, path),
+                chunks: asset.chunks,
+                sha256: Some(asset.sha256),
+                content_type: content_type(path),
+                // inline assets are served from the index, uncompressed
+                encoded: match inline {
+                    Some(_) => Vec::new(),
+                    None => asset.variants.clone(),
+                },
+                encoded_sha256: match inline {
+                    Some(_) => Vec::new(),
+                    None => asset.variants_sha256.clone(),
+                },
+                inline,
+                redirects: Vec::new(),
+                header_rules: Vec::new(),
+                protected: args
+                    .protected
+                    .iter()
+                    .any(|prefix| path.starts_with(prefix.trim_start_matches('/'))),
+                languages: Vec::new(),
+                sri: asset.sri.clone(),
+                error_documents: Vec::new(),
+            },
+        );
+    }
+    group_languages(&mut index, &args.languages);
+    if !args.response_header_rules.is_empty() {
+        index.insert(
+            HEADERS_PATH.to_string(),
+            AssetMetadata {
+                header_rules: args.response_header_rules.clone(),
+                ..Default::default()
+            },
+        );
+    }
+    if !args.redirects.is_empty() {
+        index.insert(
+            REDIRECTS_PATH.to_string(),
+            AssetMetadata {
+                redirects: args.redirects.clone(),
+                ..Default::default()
+            },
+        );
+    }
+    if !args.error_documents.is_empty() {
         index.insert(
-            k,
+            ERROR_DOCUMENTS_PATH.to_string(),
             AssetMetadata {
-                path: v,
-                size: md.len(),
-                modified,
+                error_documents: args.error_documents.clone(),
+                ..Default::default()
             },
         );
     }
     Ok(index)
 }
 
+/// Lists language variants of assets on the entry for the path without the language suffix,
+/// adding the entry, with the first variant's metadata, if there's no asset at the path
+fn group_languages(index: &mut AssetIndex, languages: &[String]) {
+    let mut groups: BTreeMap<String, Vec<(String, String)>> = BTreeMap::new();
+    for path in index.keys() {
+        if let Some((base, language)) = split_language(path, languages) {
+            let variants = groups.entry(base).or_default();
+            variants.push((language.to_string(), path.clone()));
+        }
+    }
+    for (base, mut variants) in groups {
+        variants.sort_by_key(|(language, _)| languages.iter().position(|l| l == language));
+        let mut md = match index.get(&base) {
+            Some(md) => md.clone(),
+            None => index[&variants[0].1].clone(),
+        };
+        md.languages = variants;
+        index.insert(base, md);
+    }
+}
+
+/// Returns the media type for the asset path's extension
+pub(crate) fn content_type(path: &str) -> Option<String> {
+    mime_guess::from_path(path)
+        .first()
+        .map(|mime| mime.to_string())
+}
+
+/// Returns the KV metadata of each asset's key, other than the upload time.
+/// Chunked assets have no key of their own, so they aren't included.
+fn key_metadata(args: &SyncConfig, source: &SourceFiles) -> HashMap<String, UploadMetadata> {
+    source
+        .assets
+        .iter()
+        .filter(|(_, asset)| asset.chunks == 0)
+        .map(|(path, asset)| {
+            let headers = configured_headers(args, path);
+            let header = |name: &str| {
+                headers
+                    .iter()
+                    .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                    .map(|(_, v)| v.clone())
+            };
+            let mut metadata = UploadMetadata {
+                uploaded: 0,
+                content_type: header("Content-Type").or_else(|| content_type(path)),
+                sha256: Some(hex::encode(asset.sha256)),
+                cache_control: header("Cache-Control"),
+                deploy: None,
+            };
+            // metadata that's too large would fail the upload
+            if serde_json::to_vec(&metadata).map_or(true, |m| m.len() > METADATA_MAX_BYTES) {
+                metadata.cache_control = None;
+                metadata.content_type = None;
+            }
+            (asset.key.clone(), metadata)
+        })
+        .collect()
+}
+
 /// Serializes the asset manifest. Before writing it to a file, loads the previous file
 /// to determine whether any changes are required. This lets us generate a friendlier and more
 /// specific console message, and avoiding an unnecessary file write may shorten the next build time.
-fn write_index(args: &SyncConfig, asset_index: AssetIndex) -> Result<(), Error> {
-    let bytes = bincode::serialize(&asset_index)
-        .map_err(|e| Error::IO(format!("serialization error: {}", e.to_string())))?;
+pub(crate) fn write_index(args: &SyncConfig, asset_index: AssetIndex) -> Result<(), Error> {
+    let bytes = encode_index(&asset_index)?;
 
     let update = match std::fs::read(args.output_path) {
         Ok(existing_bytes) => {
@@ -182,13 +1315,8 @@ fn write_index(args: &SyncConfig, asset_index: AssetIndex) -> Result<(), Error>
     };
     match update {
         Update::New | Update::Updated => {
-            std::fs::write(args.output_path, &bytes).map_err(|e| {
-                Error::IO(format!(
-                    "writing {}: {}",
-                    args.output_path.display(),
-                    e.to_string()
-                ))
-            })?;
+            std::fs::write(args.output_path, &bytes)
+                .map_err(|e| Error::IO(format!("writing {}: {}", args.output_path.display(), e)))?;
         }
         _ => {}
     }
@@ -206,7 +1334,7 @@ fn write_index(args: &SyncConfig, asset_index: AssetIndex) -> Result<(), Error>
 }
 
 /// create the parent dir of the output file, if it doesn't exist already
-fn mkdir_bin_parent(output_path: &Path) -> Result<(), Error> {
+pub(crate) fn mkdir_bin_parent(output_path: &Path) -> Result<(), Error> {
     if output_path.is_file() {
         return Ok(());
     }
@@ -221,7 +1349,7 @@ fn mkdir_bin_parent(output_path: &Path) -> Result<(), Error> {
         Error::IO(format!(
             "creating output directory {} for assets: {}",
             output_path.display(),
-            e.to_string()
+            e
         ))
     })?;
     Ok(())
@@ -237,3 +1365,121 @@ fn make_progress_bar(count: usize, template: &str) -> Option<ProgressBar> {
         None
     }
 }
+
+#[test]
+fn test_validate() {
+    let config = SyncConfig {
+        wrangler_path: Path::new("no-such-dir/wrangler.toml"),
+        asset_dir: Path::new("no-such-dir/public"),
+        expiration_ttl: Some(10),
+        local_dir: Some(Path::new("no-such-dir/kv")),
+        ..Default::default()
+    };
+    let errors = config.validate().unwrap_err();
+    assert!(matches!(
+        errors.as_slice(),
+        [
+            Error::InvalidAssetPath(_),
+            Error::MissingWranglerFile(_),
+            Error::TTLTooShort
+        ]
+    ));
+
+    // output inside the asset dir
+    let config = SyncConfig {
+        asset_dir: Path::new("src"),
+        output_path: Path::new("src/data/assets.bin"),
+        ..config
+    };
+    let errors = config.validate().unwrap_err();
+    assert!(errors
+        .iter()
+        .any(|e| matches!(e, Error::InvalidAssetsBinPath(_))));
+}
+
+#[test]
+fn test_load_redirects() {
+    let dir = std::env::temp_dir().join(format!("kv-sync-redirects-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let text = dir.join("_redirects");
+    std::fs::write(&text, "/a /b 302\n").unwrap();
+    let toml = dir.join("netlify.toml");
+    std::fs::write(
+        &toml,
+        "[[redirects]]\nfrom = \"/a\"\nto = \"/b\"\nstatus = 302\n",
+    )
+    .unwrap();
+    let rule = RedirectRule {
+        from: "/a".to_string(),
+        to: "/b".to_string(),
+        status: 302,
+    };
+    assert_eq!(load_redirects(&text).unwrap(), vec![rule.clone()]);
+    assert_eq!(load_redirects(&toml).unwrap(), vec![rule]);
+    std::fs::write(&text, "/a\n").unwrap();
+    assert!(load_redirects(&text).is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_key_metadata() {
+    let dir = std::env::temp_dir().join(format!("kv-assets-metadata-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("site.css"), b"body{}").unwrap();
+    std::fs::write(dir.join("data.unknown"), b"?").unwrap();
+    let args = SyncConfig {
+        header_rules: vec![HeaderRule {
+            pattern: "*.css".to_string(),
+            headers: vec![("cache-control".to_string(), "max-age=60".to_string())]
+                .into_iter()
+                .collect(),
+        }],
+        ..Default::default()
+    };
+    let source = read_source(&Target::default(), &dir, args.templates(), false).unwrap();
+    let metadata = key_metadata(&args, &source);
+    assert_eq!(
+        content_type("a/b.json").as_deref(),
+        Some("application/json")
+    );
+    let css = &metadata[&source.assets["site.css"].key];
+    assert_eq!(css.content_type.as_deref(), Some("text/css"));
+    assert_eq!(css.cache_control.as_deref(), Some("max-age=60"));
+    assert_eq!(
+        css.sha256.as_deref(),
+        Some(hex::encode(source.assets["site.css"].sha256).as_str())
+    );
+    let unknown = &metadata[&source.assets["data.unknown"].key];
+    assert_eq!(unknown.content_type, None);
+    assert_eq!(unknown.cache_control, None);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_group_languages() {
+    let md = |path: &str| AssetMetadata {
+        path: path.to_string(),
+        ..Default::default()
+    };
+    let mut index = AssetIndex::new();
+    index.insert("index.de.html".to_string(), md("index.de.1.html"));
+    index.insert("index.en.html".to_string(), md("index.en.2.html"));
+    index.insert("about.html".to_string(), md("about.3.html"));
+    index.insert("about.de.html".to_string(), md("about.de.4.html"));
+    index.insert("app.min.js".to_string(), md("app.min.5.js"));
+    group_languages(&mut index, &["en".to_string(), "de".to_string()]);
+
+    let home = &index["index.html"];
+    assert_eq!(home.path, "index.en.2.html");
+    assert_eq!(
+        home.languages,
+        vec![
+            ("en".to_string(), "index.en.html".to_string()),
+            ("de".to_string(), "index.de.html".to_string())
+        ]
+    );
+    assert_eq!(index["about.html"].path, "about.3.html");
+    assert_eq!(index["about.html"].languages.len(), 1);
+    assert!(index["app.min.js"].languages.is_empty());
+    assert_eq!(index.len(), 6);
+}
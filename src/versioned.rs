@@ -0,0 +1,103 @@
+//! Versioned URLs: the file name of an asset's KV key, which kv-sync fingerprints with a
+//! hash of its contents (`app.js` is stored as `app.3fa9c2ab12.js`), in place of its own.
+//! A versioned URL changes whenever the contents do, so its response can be cached
+//! forever; `handle_request` sends `Cache-Control: IMMUTABLE_CACHE_CONTROL` for it,
+//! unless the asset's headers or the cache policy set one.
+
+use crate::{clock, AssetIndex, AssetKey, AssetStore, Error, KVAssets, ToAssetKey};
+use std::collections::HashMap;
+
+/// `Cache-Control` for responses to versioned URLs (see `KVAssets::versioned_url`)
+pub const IMMUTABLE_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
+/// Returns the versioned form of an index path: its directory, and the file name of its key
+pub(crate) fn versioned_path(path: &str, key: &str) -> String {
+    let name = key.rsplit('/').next().unwrap_or(key);
+    match path.rsplit_once('/') {
+        Some((dir, _)) => format!("{}/{}", dir, name),
+        None => name.to_string(),
+    }
+}
+
+/// Maps the versioned form of each index path that has one to the index path
+pub(crate) fn versioned_index(map: &AssetIndex) -> HashMap<String, String> {
+    map.iter()
+        // skip reserved entries, such as the redirect rules
+        .filter(|(path, md)| !path.starts_with('\0') && !md.path.is_empty())
+        .map(|(path, md)| (versioned_path(path, &md.path), path.clone()))
+        .filter(|(versioned, path)| versioned != path)
+        .collect()
+}
+
+impl<'ah, S: AssetStore> KVAssets<'ah, S> {
+    /// Returns the versioned URL of the asset at `path`, e.g. `/js/app.3fa9c2ab12.js`
+    /// for `/js/app.js`, for links in server-rendered html. Requests for it are served
+    /// the same asset, with a long-lived immutable `Cache-Control` header.
+    /// Returns Ok(None) if `path` isn't in the index.
+    pub fn versioned_url<K: ToAssetKey + ?Sized>(&self, path: &K) -> Result<Option<String>, Error> {
+        let path = AssetKey::new(&self.lookup_options.normalize(path.key_str())?)?;
+        let found = self
+            .index_map()?
+            .get(path.as_str())
+            .filter(|md| !md.is_expired(clock::now_secs()));
+        Ok(found.map(|md| format!("/{}", versioned_path(path.as_str(), &md.path))))
+    }
+
+    /// Returns the index path of a versioned path (see `versioned_url`), if it is one
+    pub(crate) fn find_versioned(&self, map: &AssetIndex, path: &str) -> Option<&str> {
+        self.versioned
+            .get_or_init(|| versioned_index(map))
+            .get(path)
+            .map(|path| path.as_str())
+    }
+
+    /// Returns true if the path is the versioned form of an index path
+    pub(crate) fn is_versioned(&self, path: &str) -> bool {
+        self.index_map()
+            .is_ok_and(|map| self.find_versioned(map, path).is_some())
+    }
+}
+
+#[test]
+fn test_versioned_path() {
+    assert_eq!(
+        versioned_path("app.js", "app.3fa9c2ab12.js"),
+        "app.3fa9c2ab12.js"
+    );
+    assert_eq!(
+        versioned_path("docs/js/app.js", "js/app.3fa9c2ab12.js"),
+        "docs/js/app.3fa9c2ab12.js"
+    );
+}
+
+/// Tests versioned URLs (does not invoke cloudflare api)
+#[cfg(all(test, not(target_arch = "wasm32")))]
+#[tokio::test]
+async fn test_versioned_url() {
+    use crate::AssetMetadata;
+
+    let mut index = AssetIndex::new();
+    index.insert(
+        "js/app.js".to_string(),
+        AssetMetadata {
+            path: "js/app.3fa9c2ab12.js".to_string(),
+            inline: Some(b"app()".to_vec()),
+            ..Default::default()
+        },
+    );
+    let blob = crate::encode_index(&index).expect("serialize-index");
+    let kv = KVAssets::init(&blob, "123", "namespace", "token");
+
+    let url = kv.versioned_url("/js/app.js").unwrap().unwrap();
+    assert_eq!(url, "/js/app.3fa9c2ab12.js");
+    assert_eq!(kv.versioned_url("/js/missing.js").unwrap(), None);
+    assert_eq!(
+        kv.get_asset(url.as_str()).await.unwrap().unwrap().as_ref(),
+        b"app()"
+    );
+
+    let resp = kv.handle_request("GET", &url, &[]).await.unwrap();
+    assert_eq!(resp.header("cache-control"), Some(IMMUTABLE_CACHE_CONTROL));
+    let resp = kv.handle_request("GET", "/js/app.js", &[]).await.unwrap();
+    assert_eq!(resp.header("cache-control"), None);
+}